@@ -0,0 +1,399 @@
+use crate::config::CodeModeConfig;
+use crate::runtime::{ServerRequest, ServerRequestFuture, ToolCallFuture, ToolCaller};
+use rmcp::model::Tool;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+/// Renames from `CodeModeConfig::aliases`, in both directions, plus the
+/// optional `namespace`.
+///
+/// Downstream tools are renamed as soon as they are listed, so clients, the
+/// generated TypeScript and scripts only ever see the exposed names. Calls
+/// are translated back just before they reach downstream. With a namespace,
+/// passthrough tools are listed upstream as `<namespace>_<name>` and scripts
+/// call them as `tools.<namespace>.<name>`.
+#[derive(Debug, Default)]
+pub struct ToolAliases {
+    to_exposed: HashMap<String, String>,
+    to_downstream: HashMap<String, String>,
+    namespace: Option<String>,
+}
+
+impl ToolAliases {
+    pub fn from_config(config: &CodeModeConfig) -> Self {
+        let to_exposed: HashMap<String, String> = config
+            .aliases
+            .iter()
+            .map(|(downstream, exposed)| (downstream.clone(), exposed.clone()))
+            .collect();
+        let to_downstream = to_exposed
+            .iter()
+            .map(|(downstream, exposed)| (exposed.clone(), downstream.clone()))
+            .collect();
+        Self {
+            to_exposed,
+            to_downstream,
+            namespace: config.namespace.clone(),
+        }
+    }
+
+    /// The object scripts reach tools through, for TypeScript generation.
+    pub fn script_namespace(&self) -> String {
+        match &self.namespace {
+            Some(namespace) => format!("tools.{namespace}"),
+            None => "tools".to_string(),
+        }
+    }
+
+    /// Prefix passthrough tools with the namespace for listing upstream.
+    pub fn upstream_tools(&self, tools: Vec<Tool>) -> Vec<Tool> {
+        let Some(namespace) = &self.namespace else {
+            return tools;
+        };
+        tools
+            .into_iter()
+            .map(|mut tool| {
+                tool.name = format!("{namespace}_{}", tool.name).into();
+                tool
+            })
+            .collect()
+    }
+
+    /// The exposed name for a tool called upstream as `upstream`.
+    pub fn strip_namespace<'a>(&self, upstream: &'a str) -> &'a str {
+        self.namespace
+            .as_ref()
+            .and_then(|namespace| upstream.strip_prefix(namespace.as_str()))
+            .and_then(|rest| rest.strip_prefix('_'))
+            .unwrap_or(upstream)
+    }
+
+    /// The name downstream knows `exposed` by.
+    pub fn downstream<'a>(&'a self, exposed: &'a str) -> &'a str {
+        self.to_downstream
+            .get(exposed)
+            .map(String::as_str)
+            .unwrap_or(exposed)
+    }
+
+    pub fn rename_tools(&self, tools: Vec<Tool>) -> Vec<Tool> {
+        tools
+            .into_iter()
+            .map(|mut tool| {
+                if let Some(exposed) = self.to_exposed.get(tool.name.as_ref()) {
+                    tool.name = exposed.clone().into();
+                }
+                tool
+            })
+            .collect()
+    }
+}
+
+/// camelCase names for tools named in snake_case or kebab-case, for models
+/// that guess JavaScript-style names: alias to tool name. An alias that is a
+/// tool's own name, or that two tools would share, is left out.
+pub fn camel_case_aliases(tool_names: &[String]) -> BTreeMap<String, String> {
+    let mut aliases: BTreeMap<String, Option<String>> = BTreeMap::new();
+    for name in tool_names {
+        let alias = to_camel_case(name);
+        if alias != *name && !tool_names.contains(&alias) {
+            aliases
+                .entry(alias)
+                .and_modify(|taken| *taken = None)
+                .or_insert_with(|| Some(name.clone()));
+        }
+    }
+    aliases
+        .into_iter()
+        .filter_map(|(alias, name)| Some((alias, name?)))
+        .collect()
+}
+
+/// Words scripts can't use as `tools.<name>`: JavaScript's and Lua's
+/// reserved words.
+const KEYWORDS: &[&str] = &[
+    "and",
+    "await",
+    "break",
+    "case",
+    "catch",
+    "class",
+    "const",
+    "continue",
+    "debugger",
+    "default",
+    "delete",
+    "do",
+    "else",
+    "elseif",
+    "end",
+    "enum",
+    "export",
+    "extends",
+    "false",
+    "finally",
+    "for",
+    "function",
+    "goto",
+    "if",
+    "implements",
+    "import",
+    "in",
+    "instanceof",
+    "interface",
+    "let",
+    "local",
+    "new",
+    "nil",
+    "not",
+    "null",
+    "or",
+    "package",
+    "private",
+    "protected",
+    "public",
+    "repeat",
+    "return",
+    "static",
+    "super",
+    "switch",
+    "then",
+    "this",
+    "throw",
+    "true",
+    "try",
+    "typeof",
+    "until",
+    "var",
+    "void",
+    "while",
+    "with",
+    "yield",
+];
+
+/// Whether scripts in every language can call a tool named `name` as
+/// `tools.<name>`.
+pub fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !KEYWORDS.contains(&name)
+}
+
+/// `name` with every character an identifier can't have replaced by `_`,
+/// `_` put before a leading digit and after a reserved word.
+fn to_identifier(name: &str) -> String {
+    let mut identifier: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if !identifier.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        identifier.insert(0, '_');
+    }
+    if KEYWORDS.contains(&identifier.as_str()) {
+        identifier.push('_');
+    }
+    identifier
+}
+
+/// Identifiers for tools whose names aren't, like `repos.list` or
+/// `search-issues`, so scripts can call them without brackets: identifier
+/// to tool name. An identifier another tool already has, or that two tools
+/// would share, gets a `_2`, `_3`, ... suffix.
+pub fn identifier_aliases(tool_names: &[String]) -> BTreeMap<String, String> {
+    let mut aliases = BTreeMap::new();
+    for name in tool_names.iter().filter(|name| !is_identifier(name)) {
+        let base = to_identifier(name);
+        let mut alias = base.clone();
+        let mut n = 2;
+        while tool_names.contains(&alias) || aliases.contains_key(&alias) {
+            alias = format!("{base}_{n}");
+            n += 1;
+        }
+        aliases.insert(alias, name.clone());
+    }
+    aliases
+}
+
+/// Every name scripts can call a tool by besides its own: its identifier,
+/// if its name isn't one, and its camelCase alias when `camel_case` is set.
+/// Alias to tool name.
+pub fn script_aliases(tool_names: &[String], camel_case: bool) -> BTreeMap<String, String> {
+    let mut aliases = identifier_aliases(tool_names);
+    if camel_case {
+        let identifiers: HashMap<&str, &str> = aliases
+            .iter()
+            .map(|(alias, name)| (name.as_str(), alias.as_str()))
+            .collect();
+        let script_names: Vec<String> = tool_names
+            .iter()
+            .map(|name| identifiers.get(name.as_str()).copied().unwrap_or(name))
+            .map(String::from)
+            .collect();
+        let mut camel = BTreeMap::new();
+        for (alias, script_name) in camel_case_aliases(&script_names) {
+            let name = aliases.get(&script_name).unwrap_or(&script_name);
+            if is_identifier(&alias)
+                && !aliases.contains_key(&alias)
+                && !tool_names.contains(&alias)
+            {
+                camel.insert(alias, name.clone());
+            }
+        }
+        aliases.extend(camel);
+    }
+    aliases
+}
+
+fn to_camel_case(name: &str) -> String {
+    let mut words = name.split(['_', '-']).filter(|word| !word.is_empty());
+    let mut camel = words.next().unwrap_or_default().to_string();
+    for word in words {
+        let mut chars = word.chars();
+        if let Some(first) = chars.next() {
+            camel.extend(first.to_uppercase());
+            camel.push_str(chars.as_str());
+        }
+    }
+    camel
+}
+
+/// A `ToolCaller` that takes exposed tool names and calls downstream by its
+/// own names.
+pub struct AliasToolCaller<C: ToolCaller> {
+    inner: C,
+    aliases: Arc<ToolAliases>,
+}
+
+impl<C: ToolCaller> AliasToolCaller<C> {
+    pub fn new(inner: C, aliases: Arc<ToolAliases>) -> Self {
+        Self { inner, aliases }
+    }
+}
+
+impl<C: ToolCaller> ToolCaller for AliasToolCaller<C> {
+    fn call_tool<'a>(
+        &'a self,
+        name: &'a str,
+        args: Option<serde_json::Value>,
+    ) -> ToolCallFuture<'a> {
+        self.inner.call_tool(self.aliases.downstream(name), args)
+    }
+
+    fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+        self.inner.server_request(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rmcp::model::{CallToolResult, Content};
+
+    struct NameEcho;
+
+    impl ToolCaller for NameEcho {
+        fn call_tool<'a>(
+            &'a self,
+            name: &'a str,
+            _args: Option<serde_json::Value>,
+        ) -> ToolCallFuture<'a> {
+            Box::pin(async move { Ok(CallToolResult::success(vec![Content::text(name)])) })
+        }
+    }
+
+    fn text(result: &CallToolResult) -> &str {
+        &result.content[0].as_text().unwrap().text
+    }
+
+    #[tokio::test]
+    async fn test_aliases_translate_back_to_downstream() {
+        let config = CodeModeConfig::new().with_alias("repos/list", "list_repos");
+        let aliases = Arc::new(ToolAliases::from_config(&config));
+        let caller = AliasToolCaller::new(NameEcho, aliases.clone());
+
+        let result = caller.call_tool("list_repos", None).await.unwrap();
+        assert_eq!(text(&result), "repos/list");
+        let result = caller.call_tool("other", None).await.unwrap();
+        assert_eq!(text(&result), "other");
+
+        let tool = Tool::new("repos/list", "List repos", Arc::new(Default::default()));
+        let renamed = aliases.rename_tools(vec![tool]);
+        assert_eq!(renamed[0].name, "list_repos");
+    }
+
+    #[test]
+    fn test_camel_case_aliases() {
+        let names: Vec<String> = [
+            "move_footprint",
+            "get-board-info",
+            "list",
+            "search_v2",
+            "fetch_url",
+            "fetchUrl",
+            "set_x",
+            "set__x",
+        ]
+        .map(String::from)
+        .to_vec();
+        let aliases = camel_case_aliases(&names);
+        assert_eq!(
+            aliases.into_iter().collect::<Vec<_>>(),
+            [
+                ("getBoardInfo", "get-board-info"),
+                ("moveFootprint", "move_footprint"),
+                ("searchV2", "search_v2"),
+            ]
+            .map(|(a, n)| (a.to_string(), n.to_string()))
+        );
+    }
+
+    #[test]
+    fn test_identifier_aliases() {
+        let names: Vec<String> = [
+            "repos.list",
+            "search-issues",
+            "search_issues",
+            "2fa/verify",
+            "delete",
+            "list",
+        ]
+        .map(String::from)
+        .to_vec();
+        assert_eq!(
+            identifier_aliases(&names).into_iter().collect::<Vec<_>>(),
+            [
+                ("_2fa_verify", "2fa/verify"),
+                ("delete_", "delete"),
+                ("repos_list", "repos.list"),
+                ("search_issues_2", "search-issues"),
+            ]
+            .map(|(a, n)| (a.to_string(), n.to_string()))
+        );
+
+        // camelCase aliases of the identifiers lead back to the tools
+        let aliases = script_aliases(&names, true);
+        assert_eq!(aliases["reposList"], "repos.list");
+        assert_eq!(aliases["searchIssues"], "search_issues");
+        assert_eq!(aliases["repos_list"], "repos.list");
+        assert!(aliases.keys().all(|alias| is_identifier(alias)));
+        assert_eq!(script_aliases(&names, false).len(), 4);
+    }
+
+    #[test]
+    fn test_namespace() {
+        let aliases = ToolAliases::from_config(&CodeModeConfig::new().with_namespace("kicad"));
+        assert_eq!(aliases.script_namespace(), "tools.kicad");
+        assert_eq!(
+            aliases.strip_namespace("kicad_move_footprint"),
+            "move_footprint"
+        );
+        assert_eq!(aliases.strip_namespace("move_footprint"), "move_footprint");
+
+        let tool = Tool::new("move_footprint", "Move", Arc::new(Default::default()));
+        let upstream = aliases.upstream_tools(vec![tool]);
+        assert_eq!(upstream[0].name, "kicad_move_footprint");
+    }
+}
@@ -0,0 +1,292 @@
+//! Human approval of scripts and guarded tool calls.
+//!
+//! Scripts need approval when `approve_scripts` is set, and tool calls when
+//! their `tools.<name>.require_approval` is. The built-in `TtyApprover`
+//! prompts on the controlling terminal, since stdin and stdout carry MCP.
+//! Without an approver, anything that needs approval is denied.
+
+use crate::alias::identifier_aliases;
+use crate::config::CodeModeConfig;
+use crate::runtime::{ServerRequest, ServerRequestFuture, ToolCallFuture, ToolCaller};
+use anyhow::{Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::future::Future;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+
+pub type ApprovalFuture<'a> = Pin<Box<dyn Future<Output = Result<bool>> + Send + 'a>>;
+
+/// What is waiting for approval.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ApprovalRequest {
+    /// A whole script, with the tools it refers to.
+    Script {
+        code: String,
+        intended_calls: Vec<String>,
+    },
+    /// A single call to a guarded tool.
+    ToolCall {
+        name: String,
+        args: Option<serde_json::Value>,
+    },
+}
+
+impl fmt::Display for ApprovalRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApprovalRequest::Script {
+                code,
+                intended_calls,
+            } => {
+                writeln!(f, "Script:")?;
+                for line in code.lines() {
+                    writeln!(f, "  | {line}")?;
+                }
+                if intended_calls.is_empty() {
+                    write!(f, "Calls no tools")
+                } else {
+                    write!(f, "May call: {}", intended_calls.join(", "))
+                }
+            }
+            ApprovalRequest::ToolCall { name, args } => {
+                let args = args
+                    .as_ref()
+                    .and_then(|args| serde_json::to_string_pretty(args).ok())
+                    .unwrap_or_else(|| "{}".to_string());
+                write!(f, "Tool call: {name}\n{args}")
+            }
+        }
+    }
+}
+
+/// Decides whether a script or tool call may proceed.
+pub trait Approver: Send + Sync {
+    fn approve<'a>(&'a self, request: &'a ApprovalRequest) -> ApprovalFuture<'a>;
+}
+
+/// Prompts on the controlling terminal (`/dev/tty`) and waits for `y` or
+/// `n`. Prompts are shown one at a time.
+pub struct TtyApprover {
+    path: PathBuf,
+    prompt: tokio::sync::Mutex<()>,
+}
+
+impl Default for TtyApprover {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TtyApprover {
+    pub fn new() -> Self {
+        Self {
+            path: PathBuf::from("/dev/tty"),
+            prompt: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    fn ask(path: &PathBuf, text: &str) -> Result<bool> {
+        let tty = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Cannot open {} for approval", path.display()))?;
+        let mut writer = &tty;
+        let mut reader = BufReader::new(&tty);
+        loop {
+            write!(
+                writer,
+                "\n[codemoder] Approval needed\n{text}\nApprove? [y/n] "
+            )?;
+            writer.flush()?;
+            let mut answer = String::new();
+            if reader.read_line(&mut answer)? == 0 {
+                return Ok(false);
+            }
+            match answer.trim().to_ascii_lowercase().as_str() {
+                "y" | "yes" => return Ok(true),
+                "n" | "no" => return Ok(false),
+                _ => continue,
+            }
+        }
+    }
+}
+
+impl Approver for TtyApprover {
+    fn approve<'a>(&'a self, request: &'a ApprovalRequest) -> ApprovalFuture<'a> {
+        Box::pin(async move {
+            let _prompt = self.prompt.lock().await;
+            let path = self.path.clone();
+            let text = request.to_string();
+            tokio::task::spawn_blocking(move || Self::ask(&path, &text)).await?
+        })
+    }
+}
+
+/// The approver selected by `config`, if any.
+pub fn approver_from_config(config: &CodeModeConfig) -> Option<Arc<dyn Approver>> {
+    config
+        .approve_tty
+        .then(|| Arc::new(TtyApprover::new()) as Arc<dyn Approver>)
+}
+
+/// Whether `request` may proceed. Always false without an approver.
+pub async fn check(approver: Option<&Arc<dyn Approver>>, request: &ApprovalRequest) -> bool {
+    match approver {
+        Some(approver) => approver.approve(request).await.unwrap_or_else(|e| {
+            tracing::warn!("Approval failed: {e:#}");
+            false
+        }),
+        None => false,
+    }
+}
+
+/// Names of tools configured with `require_approval`.
+pub fn guarded_tools(config: &CodeModeConfig) -> HashSet<String> {
+    config
+        .tools
+        .iter()
+        .filter(|(_, settings)| settings.require_approval)
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+/// Tools from `tool_names` that `code` mentions, in order, for showing
+/// alongside a script awaiting approval.
+pub fn intended_calls(code: &str, tool_names: &[String]) -> Vec<String> {
+    let identifiers: HashMap<String, String> = identifier_aliases(tool_names)
+        .into_iter()
+        .map(|(identifier, name)| (name, identifier))
+        .collect();
+    tool_names
+        .iter()
+        .filter(|name| {
+            code.contains(&format!("tools.{name}"))
+                || identifiers
+                    .get(*name)
+                    .is_some_and(|identifier| code.contains(&format!("tools.{identifier}")))
+                || code.contains(&format!("tools[\"{name}\"]"))
+                || code.contains(&format!("tools['{name}']"))
+        })
+        .cloned()
+        .collect()
+}
+
+/// A `ToolCaller` that asks the approver before each call to a guarded tool.
+pub struct ApprovalToolCaller<C: ToolCaller> {
+    inner: C,
+    approver: Option<Arc<dyn Approver>>,
+    guarded: HashSet<String>,
+}
+
+impl<C: ToolCaller> ApprovalToolCaller<C> {
+    pub fn new(inner: C, approver: Option<Arc<dyn Approver>>, guarded: HashSet<String>) -> Self {
+        Self {
+            inner,
+            approver,
+            guarded,
+        }
+    }
+}
+
+impl<C: ToolCaller> ToolCaller for ApprovalToolCaller<C> {
+    fn call_tool<'a>(
+        &'a self,
+        name: &'a str,
+        args: Option<serde_json::Value>,
+    ) -> ToolCallFuture<'a> {
+        Box::pin(async move {
+            if self.guarded.contains(name) {
+                let request = ApprovalRequest::ToolCall {
+                    name: name.to_string(),
+                    args: args.clone(),
+                };
+                if !check(self.approver.as_ref(), &request).await {
+                    anyhow::bail!("Call to {name} was not approved");
+                }
+            }
+            self.inner.call_tool(name, args).await
+        })
+    }
+
+    fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+        self.inner.server_request(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rmcp::model::{CallToolResult, Content};
+    use std::sync::Mutex;
+
+    struct Done;
+
+    impl ToolCaller for Done {
+        fn call_tool<'a>(
+            &'a self,
+            _name: &'a str,
+            _args: Option<serde_json::Value>,
+        ) -> ToolCallFuture<'a> {
+            Box::pin(async { Ok(CallToolResult::success(vec![Content::text("done")])) })
+        }
+    }
+
+    /// Approves calls whose args contain `"safe": true`, recording requests.
+    #[derive(Default)]
+    struct Recording {
+        seen: Mutex<Vec<ApprovalRequest>>,
+    }
+
+    impl Approver for Recording {
+        fn approve<'a>(&'a self, request: &'a ApprovalRequest) -> ApprovalFuture<'a> {
+            self.seen.lock().unwrap().push(request.clone());
+            let approved = matches!(
+                request,
+                ApprovalRequest::ToolCall { args: Some(args), .. } if args["safe"] == true
+            );
+            Box::pin(async move { Ok(approved) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_guarded_calls_need_approval() {
+        let recording = Arc::new(Recording::default());
+        let caller = ApprovalToolCaller::new(
+            Done,
+            Some(recording.clone()),
+            HashSet::from(["delete".to_string()]),
+        );
+
+        assert!(caller.call_tool("read", None).await.is_ok());
+        let safe = Some(serde_json::json!({"safe": true}));
+        assert!(caller.call_tool("delete", safe).await.is_ok());
+        let err = caller.call_tool("delete", None).await.unwrap_err();
+        assert_eq!(err.to_string(), "Call to delete was not approved");
+        assert_eq!(recording.seen.lock().unwrap().len(), 2);
+
+        let unattended = ApprovalToolCaller::new(Done, None, HashSet::from(["delete".to_string()]));
+        assert!(unattended.call_tool("delete", None).await.is_err());
+    }
+
+    #[test]
+    fn test_intended_calls() {
+        let names = vec!["add".to_string(), "delete".to_string(), "echo".to_string()];
+        let code = "var x = tools.add({a: 1}); tools['echo']({text: x});";
+        assert_eq!(intended_calls(code, &names), vec!["add", "echo"]);
+        let names = vec!["repos.list".to_string()];
+        assert_eq!(intended_calls("tools.repos_list()", &names), names);
+
+        let request = ApprovalRequest::Script {
+            code: "tools.add({})".to_string(),
+            intended_calls: vec!["add".to_string()],
+        };
+        assert_eq!(
+            request.to_string(),
+            "Script:\n  | tools.add({})\nMay call: add"
+        );
+    }
+}
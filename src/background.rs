@@ -0,0 +1,298 @@
+//! Background executions, for scripts that run longer than a client waits
+//! for a tool call. Enabled with the `background_executions` setting.
+//!
+//! `start_execution` takes the same arguments as the execute tool and
+//! returns an id straight away; `get_execution` reports the execution's
+//! status, and its result, logs and error once it has finished;
+//! `cancel_execution` interrupts it. Finished executions are kept for the
+//! session TTL, counted from when they finished.
+
+use crate::proxy::{ExecuteCodeOutput, ExecuteCodeParams};
+use crate::runtime::{ExecutionResult, random_id};
+use rmcp::model::{
+    CallToolRequestParam, CallToolResult, Content, ErrorData, Tool, ToolAnnotations,
+};
+use rmcp::schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// Name of the tool that starts a background execution.
+pub const START_TOOL: &str = "start_execution";
+/// Name of the tool that reports on a background execution.
+pub const GET_TOOL: &str = "get_execution";
+/// Name of the tool that cancels a background execution.
+pub const CANCEL_TOOL: &str = "cancel_execution";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExecutionStatus {
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ExecutionIdParams {
+    #[schemars(description = "Id returned by `start_execution`.")]
+    pub id: String,
+}
+
+struct Execution {
+    status: ExecutionStatus,
+    result: Option<ExecutionResult>,
+    cancellation: CancellationToken,
+    started: Instant,
+    finished: Option<Instant>,
+}
+
+/// The background executions of one server, by id.
+#[derive(Clone)]
+pub struct BackgroundExecutions {
+    executions: Arc<Mutex<HashMap<String, Execution>>>,
+    ttl: Duration,
+}
+
+impl BackgroundExecutions {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            executions: Arc::default(),
+            ttl,
+        }
+    }
+
+    /// Register a new running execution, returning its id and the token
+    /// that cancels it.
+    pub fn start(&self) -> (String, CancellationToken) {
+        let id = random_id("bg");
+        let cancellation = CancellationToken::new();
+        let mut executions = self.executions.lock().unwrap();
+        self.expire(&mut executions);
+        executions.insert(
+            id.clone(),
+            Execution {
+                status: ExecutionStatus::Running,
+                result: None,
+                cancellation: cancellation.clone(),
+                started: Instant::now(),
+                finished: None,
+            },
+        );
+        (id, cancellation)
+    }
+
+    /// Record how the execution `id` ended: its result, or why it could not
+    /// run at all.
+    pub fn finish(&self, id: &str, result: Result<ExecutionResult, ErrorData>) {
+        let mut executions = self.executions.lock().unwrap();
+        let Some(execution) = executions.get_mut(id) else {
+            return;
+        };
+        let result = result.unwrap_or_else(|e| ExecutionResult {
+            is_error: true,
+            error_message: Some(e.message.to_string()),
+            ..Default::default()
+        });
+        execution.status = if execution.cancellation.is_cancelled() {
+            ExecutionStatus::Cancelled
+        } else if result.is_error {
+            ExecutionStatus::Failed
+        } else {
+            ExecutionStatus::Completed
+        };
+        execution.result = Some(result);
+        execution.finished = Some(Instant::now());
+    }
+
+    /// Interrupt the execution `id`, returning its status, or `None` if
+    /// there is no such execution.
+    pub fn cancel(&self, id: &str) -> Option<ExecutionStatus> {
+        let executions = self.executions.lock().unwrap();
+        let execution = executions.get(id)?;
+        execution.cancellation.cancel();
+        Some(execution.status)
+    }
+
+    /// What `get_execution` reports for `id`: `{id, status, elapsedMs}`, and
+    /// the execute tool's output fields once the execution has finished.
+    pub fn report(&self, id: &str) -> Option<serde_json::Value> {
+        let mut executions = self.executions.lock().unwrap();
+        self.expire(&mut executions);
+        let execution = executions.get(id)?;
+        let elapsed = execution.finished.unwrap_or_else(Instant::now) - execution.started;
+        let mut report = serde_json::json!({
+            "id": id,
+            "status": execution.status,
+            "elapsedMs": elapsed.as_millis() as u64,
+        });
+        if let Some(result) = &execution.result
+            && let Ok(serde_json::Value::Object(output)) =
+                serde_json::to_value(ExecuteCodeOutput::from(result))
+        {
+            report.as_object_mut().unwrap().extend(output);
+        }
+        Some(report)
+    }
+
+    pub fn len(&self) -> usize {
+        self.executions.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn expire(&self, executions: &mut HashMap<String, Execution>) {
+        executions.retain(|_, execution| {
+            execution
+                .finished
+                .is_none_or(|finished| finished.elapsed() < self.ttl)
+        });
+    }
+}
+
+/// The `start_execution`, `get_execution` and `cancel_execution` tools.
+pub fn make_background_tools() -> Vec<Tool> {
+    use rmcp::handler::server::common::schema_for_type;
+
+    let tool = |name: &'static str, description: &'static str, annotations: ToolAnnotations| Tool {
+        name: name.into(),
+        description: Some(description.into()),
+        input_schema: Arc::new(schema_for_type::<ExecutionIdParams>()),
+        title: None,
+        output_schema: None,
+        annotations: Some(annotations),
+        icons: None,
+        meta: None,
+    };
+    vec![
+        Tool {
+            input_schema: Arc::new(schema_for_type::<ExecuteCodeParams>()),
+            ..tool(
+                START_TOOL,
+                "Start running code in the background, like the execute tool, and return an id to pass to `get_execution`. Use it for scripts that may take minutes.",
+                ToolAnnotations::new(),
+            )
+        },
+        tool(
+            GET_TOOL,
+            "Get the status of a background execution: running, completed, failed or cancelled, with its result, logs and error once it has finished.",
+            ToolAnnotations::new().read_only(true),
+        ),
+        tool(
+            CANCEL_TOOL,
+            "Interrupt a running background execution.",
+            ToolAnnotations::new().idempotent(true),
+        ),
+    ]
+}
+
+fn execution_id(request: &CallToolRequestParam) -> Result<String, ErrorData> {
+    let args = request.arguments.clone().unwrap_or_default();
+    let params: ExecutionIdParams = serde_json::from_value(serde_json::Value::Object(args))
+        .map_err(|e| ErrorData::invalid_params(format!("Invalid parameters: {e}"), None))?;
+    Ok(params.id)
+}
+
+fn unknown(id: &str) -> CallToolResult {
+    CallToolResult::error(vec![Content::text(format!(
+        "Unknown or expired execution `{id}`"
+    ))])
+}
+
+/// The `start_execution` result for the execution `id`.
+pub fn started(id: &str) -> CallToolResult {
+    let value = serde_json::json!({"id": id, "status": ExecutionStatus::Running});
+    CallToolResult::success(vec![Content::text(value.to_string())])
+}
+
+/// The `get_execution` result for `request`.
+pub fn get_execution(
+    request: &CallToolRequestParam,
+    executions: &BackgroundExecutions,
+) -> Result<CallToolResult, ErrorData> {
+    let id = execution_id(request)?;
+    Ok(match executions.report(&id) {
+        Some(report) => CallToolResult::success(vec![Content::text(
+            serde_json::to_string_pretty(&report).unwrap_or_default(),
+        )]),
+        None => unknown(&id),
+    })
+}
+
+/// The `cancel_execution` result for `request`.
+pub fn cancel_execution(
+    request: &CallToolRequestParam,
+    executions: &BackgroundExecutions,
+) -> Result<CallToolResult, ErrorData> {
+    let id = execution_id(request)?;
+    Ok(match executions.cancel(&id) {
+        Some(ExecutionStatus::Running) => {
+            CallToolResult::success(vec![Content::text(format!("Cancelling execution `{id}`"))])
+        }
+        Some(status) => CallToolResult::success(vec![Content::text(format!(
+            "Execution `{id}` already finished ({})",
+            serde_json::to_value(status)
+                .unwrap_or_default()
+                .as_str()
+                .unwrap_or_default()
+        ))]),
+        None => unknown(&id),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lifecycle() {
+        let executions = BackgroundExecutions::new(Duration::from_secs(60));
+        let (id, cancellation) = executions.start();
+        assert_eq!(executions.report(&id).unwrap()["status"], "running");
+
+        executions.finish(
+            &id,
+            Ok(ExecutionResult {
+                value: serde_json::json!(42),
+                logs: vec!["done".to_string()],
+                ..Default::default()
+            }),
+        );
+        let report = executions.report(&id).unwrap();
+        assert_eq!(report["status"], "completed");
+        assert_eq!(report["result"], 42);
+        assert_eq!(report["logs"], serde_json::json!(["done"]));
+        assert!(!cancellation.is_cancelled());
+
+        let (id, cancellation) = executions.start();
+        assert_eq!(executions.cancel(&id), Some(ExecutionStatus::Running));
+        assert!(cancellation.is_cancelled());
+        executions.finish(
+            &id,
+            Ok(ExecutionResult {
+                is_error: true,
+                error_message: Some("Execution cancelled".to_string()),
+                ..Default::default()
+            }),
+        );
+        let report = executions.report(&id).unwrap();
+        assert_eq!(report["status"], "cancelled");
+        assert_eq!(report["error"], "Execution cancelled");
+        assert!(executions.report("bg-unknown").is_none());
+    }
+
+    #[test]
+    fn test_finished_executions_expire() {
+        let executions = BackgroundExecutions::new(Duration::ZERO);
+        let (running, _) = executions.start();
+        let (finished, _) = executions.start();
+        executions.finish(&finished, Ok(ExecutionResult::default()));
+        assert!(executions.report(&finished).is_none());
+        assert!(executions.report(&running).is_some());
+        assert_eq!(executions.len(), 1);
+    }
+}
@@ -3,9 +3,13 @@ use rmcp::{
     handler::server::{router::tool::ToolRouter, wrapper::Parameters},
     model::*,
     schemars::JsonSchema,
+    service::{Peer, RequestContext, RoleServer},
     tool, tool_handler, tool_router,
 };
 use serde::Deserialize;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use tokio_util::sync::CancellationToken;
 
 #[derive(Debug, Deserialize, JsonSchema)]
 pub struct AddParams {
@@ -23,6 +27,23 @@ pub struct MultiplyParams {
     pub b: i64,
 }
 
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct CountToParams {
+    #[schemars(description = "How many steps to report progress for")]
+    pub n: u32,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct DivideParams {
+    #[schemars(description = "Dividend")]
+    pub a: i64,
+    #[schemars(description = "Divisor")]
+    pub b: i64,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct EnableDivideParams {}
+
 #[derive(Debug, Deserialize, JsonSchema)]
 pub struct EchoParams {
     #[schemars(description = "Message to echo back")]
@@ -32,9 +53,44 @@ pub struct EchoParams {
 #[derive(Debug, Deserialize, JsonSchema)]
 pub struct GetItemsParams {}
 
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct PickItemParams {
+    #[schemars(description = "Question to ask the client's user")]
+    pub message: String,
+    #[schemars(description = "Give up on the answer after this many milliseconds")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct FlakyParams {
+    #[schemars(description = "How many calls fail before one succeeds")]
+    pub failures: u32,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct SleepParams {
+    #[schemars(description = "How long to take, in milliseconds")]
+    pub ms: u64,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct CancelledSleepsParams {}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct SummarizeParams {
+    #[schemars(description = "Text for the client's model to summarize")]
+    pub text: String,
+}
+
 #[derive(Clone)]
 pub struct MockServer {
     tool_router: ToolRouter<Self>,
+    /// `divide` is listed only once `enable_divide` has been called.
+    divide_enabled: Arc<AtomicBool>,
+    /// Calls to `flaky` so far.
+    flaky_calls: Arc<AtomicU32>,
+    /// Calls to `sleep` the client cancelled before they answered.
+    cancelled_sleeps: Arc<AtomicU32>,
 }
 
 impl Default for MockServer {
@@ -47,8 +103,19 @@ impl MockServer {
     pub fn new() -> Self {
         Self {
             tool_router: Self::tool_router(),
+            divide_enabled: Arc::default(),
+            flaky_calls: Arc::default(),
+            cancelled_sleeps: Arc::default(),
         }
     }
+
+    fn visible_tools(&self) -> ToolRouter<Self> {
+        let mut router = self.tool_router.clone();
+        if !self.divide_enabled.load(Ordering::SeqCst) {
+            router.remove_route("divide");
+        }
+        router
+    }
 }
 
 #[tool_router]
@@ -75,7 +142,111 @@ impl MockServer {
         )]))
     }
 
-    #[tool(description = "Echo a message back")]
+    #[tool(description = "Count to n, reporting each step as progress")]
+    async fn count_to(
+        &self,
+        Parameters(params): Parameters<CountToParams>,
+        meta: Meta,
+        peer: Peer<RoleServer>,
+    ) -> Result<CallToolResult, ErrorData> {
+        if let Some(token) = meta.get_progress_token() {
+            for step in 1..=params.n {
+                peer.notify_progress(ProgressNotificationParam {
+                    progress_token: token.clone(),
+                    progress: step as f64,
+                    total: Some(params.n as f64),
+                    message: Some(format!("step {step}")),
+                })
+                .await
+                .map_err(|e| ErrorData::internal_error(e.to_string(), None))?;
+            }
+            // Let the notifications arrive before the result
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        Ok(CallToolResult::success(vec![Content::text(
+            serde_json::json!({"counted": params.n}).to_string(),
+        )]))
+    }
+
+    #[tool(description = "Divide two integers")]
+    async fn divide(
+        &self,
+        Parameters(params): Parameters<DivideParams>,
+    ) -> Result<CallToolResult, ErrorData> {
+        let result = params
+            .a
+            .checked_div(params.b)
+            .ok_or_else(|| ErrorData::invalid_params("Division by zero", None))?;
+        Ok(CallToolResult::success(vec![Content::text(
+            serde_json::json!({"result": result}).to_string(),
+        )]))
+    }
+
+    #[tool(description = "Fail until called more than `failures` times")]
+    async fn flaky(
+        &self,
+        Parameters(params): Parameters<FlakyParams>,
+    ) -> Result<CallToolResult, ErrorData> {
+        let calls = self.flaky_calls.fetch_add(1, Ordering::SeqCst) + 1;
+        if calls <= params.failures {
+            return Err(ErrorData::internal_error("Temporarily unavailable", None));
+        }
+        Ok(CallToolResult::success(vec![Content::text(
+            serde_json::json!({"calls": calls}).to_string(),
+        )]))
+    }
+
+    #[tool(description = "Answer after `ms` milliseconds")]
+    async fn sleep(
+        &self,
+        Parameters(params): Parameters<SleepParams>,
+        ct: CancellationToken,
+    ) -> Result<CallToolResult, ErrorData> {
+        tokio::select! {
+            _ = tokio::time::sleep(std::time::Duration::from_millis(params.ms)) => {}
+            _ = ct.cancelled() => {
+                self.cancelled_sleeps.fetch_add(1, Ordering::SeqCst);
+                return Err(ErrorData::internal_error("Cancelled", None));
+            }
+        }
+        Ok(CallToolResult::success(vec![Content::text(
+            serde_json::json!({"slept": params.ms}).to_string(),
+        )]))
+    }
+
+    #[tool(description = "Count the sleep calls cancelled before they answered")]
+    async fn cancelled_sleeps(
+        &self,
+        Parameters(_params): Parameters<CancelledSleepsParams>,
+    ) -> Result<CallToolResult, ErrorData> {
+        let cancelled = self.cancelled_sleeps.load(Ordering::SeqCst);
+        Ok(CallToolResult::success(vec![Content::text(
+            serde_json::json!({"cancelled": cancelled}).to_string(),
+        )]))
+    }
+
+    #[tool(description = "Add the divide tool and notify the client that the tool list changed")]
+    async fn enable_divide(
+        &self,
+        Parameters(_params): Parameters<EnableDivideParams>,
+        peer: Peer<RoleServer>,
+    ) -> Result<CallToolResult, ErrorData> {
+        self.divide_enabled.store(true, Ordering::SeqCst);
+        peer.notify_tool_list_changed()
+            .await
+            .map_err(|e| ErrorData::internal_error(e.to_string(), None))?;
+        Ok(CallToolResult::success(vec![Content::text("{}")]))
+    }
+
+    #[tool(
+        description = "Echo a message back",
+        icons = vec![Icon {
+            src: "https://example.com/echo.svg".to_string(),
+            mime_type: Some("image/svg+xml".to_string()),
+            sizes: Some(vec!["any".to_string()]),
+        }],
+        meta = Meta(serde_json::json!({"category": "text"}).as_object().unwrap().clone())
+    )]
     async fn echo(
         &self,
         Parameters(params): Parameters<EchoParams>,
@@ -99,14 +270,83 @@ impl MockServer {
             serde_json::json!({"items": items}).to_string(),
         )]))
     }
+
+    #[tool(description = "Summarize text by sampling the client's model")]
+    async fn summarize(
+        &self,
+        Parameters(params): Parameters<SummarizeParams>,
+        peer: Peer<RoleServer>,
+    ) -> Result<CallToolResult, ErrorData> {
+        let result = peer
+            .create_message(CreateMessageRequestParam {
+                messages: vec![SamplingMessage {
+                    role: Role::User,
+                    content: Content::text(params.text),
+                }],
+                model_preferences: None,
+                system_prompt: Some("Summarize".to_string()),
+                include_context: None,
+                temperature: None,
+                max_tokens: 100,
+                stop_sequences: None,
+                metadata: None,
+            })
+            .await
+            .map_err(|e| ErrorData::internal_error(e.to_string(), None))?;
+        Ok(CallToolResult::success(vec![result.message.content]))
+    }
+
+    #[tool(description = "Ask the client's user to pick an item by eliciting it")]
+    async fn pick_item(
+        &self,
+        Parameters(params): Parameters<PickItemParams>,
+        peer: Peer<RoleServer>,
+    ) -> Result<CallToolResult, ErrorData> {
+        let requested_schema = serde_json::from_value(serde_json::json!({
+            "type": "object",
+            "properties": {"item": {"type": "string"}}
+        }))
+        .unwrap();
+        let request = ServerRequest::CreateElicitationRequest(CreateElicitationRequest {
+            method: Default::default(),
+            params: CreateElicitationRequestParam {
+                message: params.message,
+                requested_schema,
+            },
+            extensions: Default::default(),
+        });
+        let options = rmcp::service::PeerRequestOptions {
+            timeout: params.timeout_ms.map(std::time::Duration::from_millis),
+            meta: None,
+        };
+        let response = peer
+            .send_cancellable_request(request, options)
+            .await
+            .map_err(|e| ErrorData::internal_error(e.to_string(), None))?
+            .await_response()
+            .await
+            .map_err(|e| ErrorData::internal_error(e.to_string(), None))?;
+        let ClientResult::CreateElicitationResult(result) = response else {
+            return Err(ErrorData::internal_error("unexpected response", None));
+        };
+        Ok(CallToolResult::success(vec![Content::text(
+            serde_json::json!({"action": result.action, "content": result.content}).to_string(),
+        )]))
+    }
 }
 
-#[tool_handler]
+#[tool_handler(router = self.visible_tools())]
 impl ServerHandler for MockServer {
     fn get_info(&self) -> ServerInfo {
         ServerInfo {
             protocol_version: Default::default(),
-            capabilities: ServerCapabilities::builder().enable_tools().build(),
+            capabilities: ServerCapabilities::builder()
+                .enable_tools()
+                .enable_tool_list_changed()
+                .enable_prompts()
+                .enable_resources()
+                .enable_completions()
+                .build(),
             server_info: Implementation {
                 name: "mock-mcp-server".into(),
                 version: "1.0.0".into(),
@@ -117,6 +357,109 @@ impl ServerHandler for MockServer {
             instructions: Some("A mock MCP server for testing".to_string()),
         }
     }
+
+    async fn list_prompts(
+        &self,
+        _request: Option<PaginatedRequestParam>,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListPromptsResult, ErrorData> {
+        Ok(ListPromptsResult::with_all_items(vec![Prompt::new(
+            "summarize",
+            Some("Summarize a topic"),
+            Some(vec![PromptArgument {
+                name: "topic".to_string(),
+                title: None,
+                description: Some("What to summarize".to_string()),
+                required: Some(true),
+            }]),
+        )]))
+    }
+
+    async fn get_prompt(
+        &self,
+        request: GetPromptRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<GetPromptResult, ErrorData> {
+        if request.name != "summarize" {
+            return Err(ErrorData::invalid_params("Unknown prompt", None));
+        }
+        let topic = request
+            .arguments
+            .and_then(|args| {
+                args.get("topic")
+                    .and_then(|t| t.as_str())
+                    .map(str::to_string)
+            })
+            .unwrap_or_default();
+        Ok(GetPromptResult {
+            description: Some("Summarize a topic".to_string()),
+            messages: vec![PromptMessage::new_text(
+                PromptMessageRole::User,
+                format!("Summarize {topic}"),
+            )],
+        })
+    }
+
+    async fn list_resources(
+        &self,
+        _request: Option<PaginatedRequestParam>,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListResourcesResult, ErrorData> {
+        Ok(ListResourcesResult::with_all_items(vec![
+            RawResource::new("mock://config", "config").no_annotation(),
+        ]))
+    }
+
+    async fn list_resource_templates(
+        &self,
+        _request: Option<PaginatedRequestParam>,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListResourceTemplatesResult, ErrorData> {
+        Ok(ListResourceTemplatesResult::with_all_items(vec![
+            RawResourceTemplate {
+                uri_template: "mock://items/{id}".to_string(),
+                name: "item".to_string(),
+                title: None,
+                description: Some("An item by id".to_string()),
+                mime_type: Some("application/json".to_string()),
+            }
+            .no_annotation(),
+        ]))
+    }
+
+    async fn read_resource(
+        &self,
+        request: ReadResourceRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ReadResourceResult, ErrorData> {
+        let text = match request.uri.strip_prefix("mock://items/") {
+            Some(id) => serde_json::json!({"id": id}).to_string(),
+            None if request.uri == "mock://config" => "debug = true".to_string(),
+            None => return Err(ErrorData::resource_not_found("Unknown resource", None)),
+        };
+        Ok(ReadResourceResult {
+            contents: vec![ResourceContents::text(text, request.uri)],
+        })
+    }
+
+    async fn complete(
+        &self,
+        request: CompleteRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<CompleteResult, ErrorData> {
+        let values = match &request.r#ref {
+            Reference::Resource(r) if r.uri == "mock://items/{id}" => ["1", "2", "3"]
+                .into_iter()
+                .filter(|id| id.starts_with(&request.argument.value))
+                .map(str::to_string)
+                .collect(),
+            _ => Vec::new(),
+        };
+        Ok(CompleteResult {
+            completion: CompletionInfo::with_all_values(values)
+                .map_err(|e| ErrorData::internal_error(e, None))?,
+        })
+    }
 }
 
 #[tokio::main]
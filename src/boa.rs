@@ -0,0 +1,588 @@
+//! JavaScript on the pure-Rust Boa engine, enabled with the `boa` cargo
+//! feature, for platforms where rquickjs is hard to build.
+//!
+//! Scripts see the same `tools`, `console.log` and lazy-result readers as on
+//! QuickJS. Boa has no interrupt hook, so timeouts and cancellation are only
+//! checked when a script calls a tool or logs, and sessions are not supported.
+
+use crate::alias::script_aliases;
+use crate::config::ScriptEngine;
+use crate::engine::{ExecuteFuture, ScriptRuntime};
+use crate::hash::{HASH_PRELUDE, call_hash};
+use crate::runtime::{
+    ASSERT_PRELUDE, AssertionFailure, CONSOLE_PRELUDE, DEADLINE_PRELUDE, ExecutionMetrics,
+    ExecutionOptions, ExecutionResult, Interrupt, LLM_PRELUDE, SERVER_PRELUDE, ServerRequest,
+    ToolBridge, ToolCaller, call_raw_tool, call_server, read_lazy_result, run_blocking_script,
+    tools_prelude,
+};
+use crate::safe_regex::{SAFE_REGEX_PRELUDE, call_regex};
+use crate::stats::{STATS_PRELUDE, call_stats};
+use crate::time::{TIME_PRELUDE, call_time};
+use crate::workflow::{WORKFLOW_PRELUDE, call_batch};
+use anyhow::Result;
+use boa_engine::{
+    Context, JsError, JsNativeError, JsResult, JsValue, NativeFunction, Source, js_string,
+};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Wires the native hooks up to the names the shared preludes expect.
+const NATIVE_PRELUDE: &str = r#"
+    var console = { log: __console_log };
+    var __raw_tools = {};
+    for (var i = 0; i < __tool_names_native.length; i++) {
+        (function(toolName) {
+            __raw_tools[toolName] = function(args, options) {
+                return __call_tool(toolName, args, options);
+            };
+        })(__tool_names_native[i]);
+    }
+"#;
+
+#[derive(Debug, Default)]
+pub struct BoaRuntime;
+
+impl BoaRuntime {
+    pub fn new() -> Self {
+        Self
+    }
+
+    pub async fn execute_with_options<C: ToolCaller + ?Sized>(
+        &self,
+        code: &str,
+        tool_names: &[String],
+        caller: Arc<C>,
+        options: ExecutionOptions,
+    ) -> Result<ExecutionResult> {
+        if options.session.is_some() || options.continuation.is_some() {
+            return Ok(error_result(
+                "Sessions are not supported by the boa engine".to_string(),
+            ));
+        }
+        let code = if options.typescript {
+            match crate::transpile::strip_types(code) {
+                Ok(code) => code,
+                Err(message) => return Ok(error_result(message)),
+            }
+        } else {
+            code.to_string()
+        };
+        let tool_names = tool_names.to_vec();
+        run_blocking_script(caller, move |bridge| {
+            run_boa(&code, &tool_names, bridge, options)
+        })
+        .await
+    }
+}
+
+fn run_boa(
+    code: &str,
+    tool_names: &[String],
+    bridge: ToolBridge,
+    options: ExecutionOptions,
+) -> ExecutionResult {
+    let logs = Arc::new(Mutex::new(Vec::new()));
+    let tool_calls = Arc::new(AtomicUsize::new(0));
+    let timed_out = Arc::new(AtomicBool::new(false));
+    let lazy_tools: Vec<String> = tool_names
+        .iter()
+        .filter(|name| options.lazy_tools.contains(*name))
+        .cloned()
+        .collect();
+    let lazy_results = Arc::new(Mutex::new(Vec::<String>::new()));
+
+    let interrupt = Interrupt::new(&options);
+    let deadline = interrupt.deadline();
+    let check_timed_out = timed_out.clone();
+    let check_interrupt = interrupt.clone();
+    let interrupted = move || -> JsResult<()> {
+        let expired = check_interrupt.expired();
+        if expired {
+            check_timed_out.store(true, Ordering::Relaxed);
+        }
+        if expired || check_interrupt.cancelled() {
+            return Err(JsNativeError::error().with_message("interrupted").into());
+        }
+        Ok(())
+    };
+
+    let mut context = Context::default();
+    let setup = (|| -> JsResult<()> {
+        let log_logs = logs.clone();
+        let log_interrupted = interrupted.clone();
+        let log_bridge = options.stream_logs.then(|| bridge.clone());
+        // SAFETY: the closures only capture Rust values, none of which are
+        // garbage-collected JS values that would need tracing.
+        let log_fn = unsafe {
+            NativeFunction::from_closure(move |_, args, ctx| {
+                log_interrupted()?;
+                let line = args_string(args, 0, ctx)?;
+                if let Some(bridge) = &log_bridge {
+                    bridge.notify(ServerRequest::Log { line: line.clone() });
+                }
+                log_logs.lock().unwrap().push(line);
+                Ok(JsValue::undefined())
+            })
+        };
+        context.register_global_callable(js_string!("__console_log"), 1, log_fn)?;
+
+        let results_for_read = lazy_results.clone();
+        let read_fn = unsafe {
+            NativeFunction::from_closure(move |_, args, ctx| {
+                let mut index = |i: usize| -> JsResult<usize> {
+                    Ok(args.get(i).cloned().unwrap_or_default().to_number(ctx)? as usize)
+                };
+                let (handle, offset, len) = (index(0)?, index(1)?, index(2)?);
+                let text = read_lazy_result(&results_for_read, handle, offset, len);
+                Ok(JsValue::from(js_string!(text)))
+            })
+        };
+        context.register_global_callable(js_string!("__read_result"), 3, read_fn)?;
+
+        let server_interrupted = interrupted.clone();
+        let batch_interrupted = interrupted.clone();
+        let call_bridge = bridge.clone();
+        let call_count = tool_calls.clone();
+        let call_lazy_tools = lazy_tools.clone();
+        let call_results = lazy_results.clone();
+        let call_fn = unsafe {
+            NativeFunction::from_closure(move |_, args, ctx| {
+                interrupted()?;
+                let name = args_string(args, 0, ctx)?;
+                let tool_args = args_string(args, 1, ctx)?;
+                let options = match args.get(2) {
+                    Some(options) if !options.is_undefined() => Some(args_string(args, 2, ctx)?),
+                    _ => None,
+                };
+                call_count.fetch_add(1, Ordering::Relaxed);
+                let lazy_store = call_lazy_tools
+                    .contains(&name)
+                    .then_some(call_results.as_ref());
+                let text = call_raw_tool(
+                    &call_bridge,
+                    &name,
+                    &tool_args,
+                    options.as_deref(),
+                    lazy_store,
+                );
+                Ok(JsValue::from(js_string!(text)))
+            })
+        };
+        context.register_global_callable(js_string!("__call_tool"), 3, call_fn)?;
+
+        let batch_bridge = bridge.clone();
+        let batch_tools = tool_names.to_vec();
+        let batch_lazy_tools = lazy_tools.clone();
+        let batch_results = lazy_results.clone();
+        let batch_count = tool_calls.clone();
+        let batch_fn = unsafe {
+            NativeFunction::from_closure(move |_, args, ctx| {
+                batch_interrupted()?;
+                let request = args_string(args, 0, ctx)?;
+                Ok(JsValue::from(js_string!(call_batch(
+                    &batch_bridge,
+                    &request,
+                    deadline,
+                    &batch_tools,
+                    &batch_lazy_tools,
+                    &batch_results,
+                    &batch_count
+                ))))
+            })
+        };
+        context.register_global_callable(js_string!("__raw_batch"), 1, batch_fn)?;
+
+        let server_bridge = bridge.clone();
+        let server_fn = unsafe {
+            NativeFunction::from_closure(move |_, args, ctx| {
+                server_interrupted()?;
+                let request = args_string(args, 0, ctx)?;
+                Ok(JsValue::from(js_string!(call_server(
+                    &server_bridge,
+                    &request
+                ))))
+            })
+        };
+        context.register_global_callable(js_string!("__server_request"), 1, server_fn)?;
+
+        let remaining_interrupt = interrupt.clone();
+        let cancelled_fn = unsafe {
+            NativeFunction::from_closure(move |_, _, _| Ok(JsValue::from(interrupt.winding_down())))
+        };
+        context.register_global_callable(js_string!("cancelled"), 0, cancelled_fn)?;
+        let remaining_fn = unsafe {
+            NativeFunction::from_closure(move |_, _, _| {
+                Ok(JsValue::from(remaining_interrupt.remaining_ms()))
+            })
+        };
+        context.register_global_callable(js_string!("__remaining_ms"), 0, remaining_fn)?;
+
+        let time_fn = NativeFunction::from_fn_ptr(|_, args, ctx| {
+            let request = args_string(args, 0, ctx)?;
+            Ok(JsValue::from(js_string!(call_time(&request))))
+        });
+        context.register_global_callable(js_string!("__time"), 1, time_fn)?;
+
+        if options.stats {
+            let stats_fn = NativeFunction::from_fn_ptr(|_, args, ctx| {
+                let request = args_string(args, 0, ctx)?;
+                Ok(JsValue::from(js_string!(call_stats(&request))))
+            });
+            context.register_global_callable(js_string!("__stats"), 1, stats_fn)?;
+            context.eval(Source::from_bytes(STATS_PRELUDE))?;
+        }
+
+        if options.llm {
+            context.eval(Source::from_bytes(LLM_PRELUDE))?;
+        }
+
+        if options.hashes {
+            let hash_fn = NativeFunction::from_fn_ptr(|_, args, ctx| {
+                let request = args_string(args, 0, ctx)?;
+                Ok(JsValue::from(js_string!(call_hash(&request))))
+            });
+            context.register_global_callable(js_string!("__hash"), 1, hash_fn)?;
+            context.eval(Source::from_bytes(HASH_PRELUDE))?;
+        }
+
+        if options.safe_regex {
+            let regex_fn = NativeFunction::from_fn_ptr(|_, args, ctx| {
+                let request = args_string(args, 0, ctx)?;
+                Ok(JsValue::from(js_string!(call_regex(&request))))
+            });
+            context.register_global_callable(js_string!("__regex"), 1, regex_fn)?;
+            context.eval(Source::from_bytes(SAFE_REGEX_PRELUDE))?;
+        }
+
+        let names = serde_json::to_string(tool_names).unwrap_or("[]".to_string());
+        let aliases = script_aliases(tool_names, options.camel_case);
+        let setup = format!(
+            "var __tool_names_native = {names};\n{NATIVE_PRELUDE}\n{CONSOLE_PRELUDE}\n{ASSERT_PRELUDE}\n{DEADLINE_PRELUDE}\n{SERVER_PRELUDE}\n{TIME_PRELUDE}\n{}\n{WORKFLOW_PRELUDE}",
+            tools_prelude(
+                tool_names,
+                &lazy_tools,
+                &aliases,
+                options.namespace.as_deref()
+            )
+        );
+        context.eval(Source::from_bytes(&setup))?;
+        Ok(())
+    })();
+
+    let result = setup
+        .and_then(|_| context.eval(Source::from_bytes(&code)))
+        .and_then(|value| {
+            if value.is_undefined() {
+                Ok(serde_json::Value::Null)
+            } else {
+                value.to_json(&mut context)
+            }
+        });
+
+    let assertion = result
+        .as_ref()
+        .err()
+        .and_then(|e| assertion_failure(e, &mut context));
+    let (value, error) = match result {
+        Ok(value) => (value, None),
+        Err(_) if timed_out.load(Ordering::Relaxed) => (
+            serde_json::Value::Null,
+            Some(format!(
+                "Execution timed out after {}ms",
+                options.timeout.unwrap_or_default().as_millis()
+            )),
+        ),
+        Err(_) if options.cancellation.is_some_and(|ct| ct.is_cancelled()) => (
+            serde_json::Value::Null,
+            Some("Execution cancelled".to_string()),
+        ),
+        Err(e) => (
+            serde_json::Value::Null,
+            Some(error_message(e, &mut context)),
+        ),
+    };
+
+    ExecutionResult {
+        value,
+        logs: logs.lock().unwrap().clone(),
+        is_error: error.is_some(),
+        error_message: error,
+        metrics: ExecutionMetrics {
+            tool_calls: tool_calls.load(Ordering::Relaxed),
+            ..Default::default()
+        },
+        continuation: None,
+        assertion,
+        ..Default::default()
+    }
+}
+
+impl ScriptRuntime for BoaRuntime {
+    fn engine(&self) -> ScriptEngine {
+        ScriptEngine::Boa
+    }
+
+    fn execute<'a>(
+        &'a self,
+        code: &'a str,
+        tool_names: &'a [String],
+        caller: Arc<dyn ToolCaller>,
+        options: ExecutionOptions,
+    ) -> ExecuteFuture<'a> {
+        Box::pin(self.execute_with_options(code, tool_names, caller, options))
+    }
+}
+
+fn args_string(args: &[JsValue], index: usize, ctx: &mut Context) -> JsResult<String> {
+    Ok(args
+        .get(index)
+        .cloned()
+        .unwrap_or_default()
+        .to_string(ctx)?
+        .to_std_string_escaped())
+}
+
+/// The thrown error's message, like `exception.message` on QuickJS.
+fn error_message(error: JsError, ctx: &mut Context) -> String {
+    match error.try_native(ctx) {
+        Ok(native) => native.message().to_string(),
+        Err(_) => error.to_string(),
+    }
+}
+
+/// The details of a thrown `AssertionError` from the assert prelude.
+fn assertion_failure(error: &JsError, ctx: &mut Context) -> Option<AssertionFailure> {
+    let object = error.as_opaque()?.as_object()?.clone();
+    let json = object.get(js_string!("assertion"), ctx).ok()?;
+    AssertionFailure::from_json(&json.as_string()?.to_std_string_escaped())
+}
+
+fn error_result(message: String) -> ExecutionResult {
+    ExecutionResult {
+        is_error: true,
+        error_message: Some(message),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::ToolCallFuture;
+    use rmcp::model::{CallToolResult, Content};
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    struct Add;
+
+    impl ToolCaller for Add {
+        fn call_tool<'a>(
+            &'a self,
+            name: &'a str,
+            args: Option<serde_json::Value>,
+        ) -> ToolCallFuture<'a> {
+            Box::pin(async move {
+                if name != "add" {
+                    anyhow::bail!("unknown tool");
+                }
+                let args = args.unwrap();
+                let sum = args["a"].as_i64().unwrap() + args["b"].as_i64().unwrap();
+                Ok(CallToolResult::success(vec![Content::text(
+                    serde_json::json!({ "result": sum }).to_string(),
+                )]))
+            })
+        }
+    }
+
+    async fn run(code: &str, options: ExecutionOptions) -> ExecutionResult {
+        let names = vec!["add".to_string(), "broken".to_string()];
+        BoaRuntime::new()
+            .execute_with_options(code, &names, Arc::new(Add), options)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_tools_and_console() {
+        let result = run(
+            "const r: number = tools.add({a: 2, b: 3}).result; console.log('sum', r, {r}); r * 2",
+            ExecutionOptions {
+                typescript: true,
+                ..Default::default()
+            },
+        )
+        .await;
+        assert!(!result.is_error, "{:?}", result.error_message);
+        assert_eq!(result.value, 10);
+        assert_eq!(result.logs, vec![r#"sum 5 {"r":5}"#]);
+        assert_eq!(result.metrics.tool_calls, 1);
+    }
+
+    #[tokio::test]
+    async fn test_workflow_map() {
+        let result = run(
+            r#"
+            workflow.map([1, 2, 3], function(n) {
+                var once = tools.add({a: n, b: n}).result;
+                return tools.add({a: once, b: once}).result;
+            }, {concurrency: 2})
+            "#,
+            ExecutionOptions::default(),
+        )
+        .await;
+        assert!(!result.is_error, "{:?}", result.error_message);
+        assert_eq!(result.value, serde_json::json!([4, 8, 12]));
+        assert_eq!(result.metrics.tool_calls, 6);
+
+        let result = run(
+            "workflow.map([1, 2], function(n) { return tools.broken({n: n}); }, {continueOnError: true}).errors.length",
+            ExecutionOptions::default(),
+        )
+        .await;
+        assert_eq!(result.value, 2);
+    }
+
+    #[tokio::test]
+    async fn test_workflow_dag() {
+        let result = run(
+            r#"
+            workflow.dag({
+                steps: {
+                    a: function() { return tools.add({a: 1, b: 2}).result; },
+                    b: function() { return tools.add({a: 3, b: 4}).result; },
+                    c: function(deps) { return tools.add({a: deps.a, b: deps.b}).result; }
+                },
+                dependsOn: {c: ['a', 'b']}
+            }).results
+            "#,
+            ExecutionOptions::default(),
+        )
+        .await;
+        assert!(!result.is_error, "{:?}", result.error_message);
+        assert_eq!(result.value, serde_json::json!({"a": 3, "b": 7, "c": 10}));
+        assert_eq!(result.metrics.tool_calls, 3);
+    }
+
+    #[tokio::test]
+    async fn test_tool_error_throws() {
+        let result = run("tools.broken({})", ExecutionOptions::default()).await;
+        assert!(result.is_error);
+        assert_eq!(
+            result.error_message.as_deref(),
+            Some("Tool broken failed: unknown tool")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_assertion_failure() {
+        let result = run(
+            "const r = tools.add({a: 2, b: 2}); expectEqual(r.result, 5, 'bad sum')",
+            ExecutionOptions::default(),
+        )
+        .await;
+        assert!(result.is_error);
+        let assertion = result.assertion.unwrap();
+        assert_eq!(assertion.message, "bad sum");
+        assert_eq!(assertion.expected, Some(serde_json::json!(5)));
+        assert_eq!(assertion.actual, Some(serde_json::json!(4)));
+
+        let result = run("assert(true); 1", ExecutionOptions::default()).await;
+        assert!(!result.is_error, "{:?}", result.error_message);
+    }
+
+    #[tokio::test]
+    async fn test_time_helpers() {
+        let result = run(
+            "const now = formatDate(parseDate('2026-01-15T23:30:00Z'), '%d %H', 'Asia/Tokyo'); now",
+            ExecutionOptions::default(),
+        )
+        .await;
+        assert!(!result.is_error, "{:?}", result.error_message);
+        assert_eq!(result.value, "16 08");
+    }
+
+    #[tokio::test]
+    async fn test_stats_helpers() {
+        let result = run(
+            "stats.percentile([{ms: 10}, {ms: 30}, {ms: 20}], 50, 'ms')",
+            ExecutionOptions {
+                stats: true,
+                ..Default::default()
+            },
+        )
+        .await;
+        assert!(!result.is_error, "{:?}", result.error_message);
+        assert_eq!(result.value, 20);
+    }
+
+    #[tokio::test]
+    async fn test_safe_regex() {
+        let result = run(
+            "'2026-03-01'.replace(/(?<y>\\d+)-(\\d+)-(\\d+)/, '$3.$2.$<y>')",
+            ExecutionOptions {
+                safe_regex: true,
+                ..Default::default()
+            },
+        )
+        .await;
+        assert!(!result.is_error, "{:?}", result.error_message);
+        assert_eq!(result.value, "01.03.2026");
+    }
+
+    #[tokio::test]
+    async fn test_hash_helpers() {
+        let result = run(
+            "const uuid = 'mine'; [uuid, sha256('abc').slice(0, 8)]",
+            ExecutionOptions {
+                hashes: true,
+                ..Default::default()
+            },
+        )
+        .await;
+        assert!(!result.is_error, "{:?}", result.error_message);
+        assert_eq!(result.value, serde_json::json!(["mine", "ba7816bf"]));
+    }
+
+    #[tokio::test]
+    async fn test_lazy_result_reader() {
+        let result = run(
+            "const r = tools.add({a: 40, b: 2}); r.read(0, 5)",
+            ExecutionOptions {
+                lazy_tools: HashSet::from(["add".to_string()]),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(result.value, r#"{"res"#);
+    }
+
+    #[tokio::test]
+    async fn test_timeout_checked_at_tool_calls() {
+        let result = run(
+            "while (true) { tools.add({a: 1, b: 1}); }",
+            ExecutionOptions {
+                timeout: Some(Duration::from_millis(20)),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(
+            result.error_message.as_deref(),
+            Some("Execution timed out after 20ms")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_near_deadline() {
+        let result = run(
+            "var n = 0; while (!cancelled()) { n++; } n > 0",
+            ExecutionOptions {
+                timeout: Some(Duration::from_millis(200)),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert!(!result.is_error, "{:?}", result.error_message);
+        assert_eq!(result.value, true);
+    }
+}
@@ -0,0 +1,409 @@
+//! Repro bundles: one execution with everything needed to run it again
+//! without the downstream server.
+//!
+//! With `record_dir` set, every execution is written to its `runs`
+//! directory as `<execution id>.json`, or to the `runs` namespace of the
+//! `Storage` an embedder passed: the code, the config, a snapshot of the
+//! downstream tools, each downstream call with its response, and what
+//! `execute_tools` returned. `codemoder export-run` copies a bundle out, and
+//! `codemoder exec --replay` runs it again against a `ReplayServer` that
+//! answers from the recorded responses. Given a server command as well,
+//! `exec --replay` runs the bundle's code against that server instead, which
+//! it only does for scripts that are safe to retry unless forced.
+
+use crate::alias::ToolAliases;
+use crate::config::CodeModeConfig;
+use crate::downstream::DownstreamHandler;
+use crate::proxy::response_value;
+use crate::runtime::{
+    DownstreamClient, ExecutionResult, ServerRequest, ServerRequestFuture, ToolCallFuture,
+    ToolCaller,
+};
+use crate::storage::Storage;
+use anyhow::{Context, Result};
+use rmcp::model::{
+    CallToolRequestParam, CallToolResult, ErrorData, Implementation, JsonObject, ListToolsResult,
+    PaginatedRequestParam, ServerCapabilities, ServerInfo, Tool,
+};
+use rmcp::service::{RequestContext, RoleServer};
+use rmcp::{ServerHandler, ServiceExt};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Format version of `RunBundle`, bumped on incompatible changes.
+pub const BUNDLE_VERSION: u32 = 1;
+
+/// The storage namespace recorded executions are kept in.
+pub const RECORDINGS: &str = "runs";
+
+/// One downstream tool call and its response.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordedCall {
+    /// The downstream tool name.
+    pub name: String,
+    /// Arguments as sent downstream, after redaction.
+    pub arguments: Option<JsonObject>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub result: Option<CallToolResult>,
+    /// Why the call failed, when there is no `result`.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunBundle {
+    pub version: u32,
+    pub codemoder_version: String,
+    pub execution_id: String,
+    pub code: String,
+    pub config: CodeModeConfig,
+    /// The downstream tools, under their downstream names.
+    pub tools: Vec<Tool>,
+    pub calls: Vec<RecordedCall>,
+    /// What `execute_tools` returned, or null if it failed.
+    pub result: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub error: Option<String>,
+    /// Whether the script declared itself safe to retry.
+    #[serde(default)]
+    pub idempotent: bool,
+    /// Destructive tools the script called, under their exposed names.
+    #[serde(default)]
+    pub destructive_calls: Vec<String>,
+}
+
+impl RunBundle {
+    /// A bundle for an execution of `code` that saw the exposed `tools`.
+    pub fn new(
+        execution_id: &str,
+        code: &str,
+        config: &CodeModeConfig,
+        tools: &[Tool],
+        aliases: &ToolAliases,
+        calls: Vec<RecordedCall>,
+    ) -> Self {
+        let tools = tools
+            .iter()
+            .map(|tool| {
+                let mut tool = tool.clone();
+                tool.name = aliases.downstream(&tool.name).to_string().into();
+                tool
+            })
+            .collect();
+        Self {
+            version: BUNDLE_VERSION,
+            codemoder_version: env!("CARGO_PKG_VERSION").to_string(),
+            execution_id: execution_id.to_string(),
+            code: code.to_string(),
+            config: config.clone(),
+            tools,
+            calls,
+            result: serde_json::Value::Null,
+            error: None,
+            idempotent: false,
+            destructive_calls: Vec::new(),
+        }
+    }
+
+    /// Add what `execute_tools` returned for the execution.
+    pub fn with_result(mut self, result: &ExecutionResult) -> Self {
+        self.idempotent = result.idempotent;
+        self.destructive_calls = result.destructive_calls.clone();
+        if result.is_error {
+            self.error = Some(result.error_message.clone().unwrap_or_default());
+        } else {
+            self.result = response_value(result);
+        }
+        self
+    }
+
+    /// Whether the script can be run again against a live server; see
+    /// `crate::idempotent`.
+    pub fn retry_safe(&self) -> bool {
+        self.idempotent || self.destructive_calls.is_empty()
+    }
+
+    /// Save the bundle to `storage`, logging rather than failing the
+    /// execution if it can't be.
+    pub fn record(&self, storage: &dyn Storage) {
+        if let Err(e) = self.save(storage) {
+            tracing::warn!("Failed to record execution {}: {e:#}", self.execution_id);
+        }
+    }
+
+    /// Save the bundle to `storage` as `<execution id>.json` in `runs`.
+    pub fn save(&self, storage: &dyn Storage) -> Result<()> {
+        let key = bundle_key(&self.execution_id)?;
+        storage.put(
+            RECORDINGS,
+            &key,
+            serde_json::to_string_pretty(self)?.as_bytes(),
+        )
+    }
+
+    /// The bundle of `execution_id` saved to `storage`.
+    pub fn load(storage: &dyn Storage, execution_id: &str) -> Result<Self> {
+        let value = storage
+            .get(RECORDINGS, &bundle_key(execution_id)?)?
+            .with_context(|| format!("No recorded execution `{execution_id}`"))?;
+        let text = String::from_utf8(value)
+            .with_context(|| format!("Invalid bundle of execution {execution_id}"))?;
+        Self::parse(&text, &format!("Execution {execution_id}"))
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read bundle {}", path.display()))?;
+        Self::parse(&text, &path.display().to_string())
+    }
+
+    /// Parse a bundle, naming it `source` in errors.
+    fn parse(text: &str, source: &str) -> Result<Self> {
+        let bundle: Self =
+            serde_json::from_str(text).with_context(|| format!("Invalid bundle {source}"))?;
+        if bundle.version != BUNDLE_VERSION {
+            anyhow::bail!(
+                "{source} is a version {} bundle; this codemoder reads version {BUNDLE_VERSION}",
+                bundle.version
+            );
+        }
+        Ok(bundle)
+    }
+}
+
+/// The key of the bundle of `execution_id` in `runs`.
+fn bundle_key(execution_id: &str) -> Result<String> {
+    if execution_id.is_empty()
+        || !execution_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        anyhow::bail!("Invalid execution id `{execution_id}`");
+    }
+    Ok(format!("{execution_id}.json"))
+}
+
+/// A `ToolCaller` that records each call and its response, when enabled.
+pub struct RecordingToolCaller<C: ToolCaller> {
+    inner: C,
+    calls: Option<Arc<Mutex<Vec<RecordedCall>>>>,
+}
+
+impl<C: ToolCaller> RecordingToolCaller<C> {
+    pub fn new(inner: C, enabled: bool) -> Self {
+        Self {
+            inner,
+            calls: enabled.then(Arc::default),
+        }
+    }
+
+    /// The calls recorded so far, shared with this caller.
+    pub fn calls(&self) -> Arc<Mutex<Vec<RecordedCall>>> {
+        self.calls.clone().unwrap_or_default()
+    }
+}
+
+impl<C: ToolCaller> ToolCaller for RecordingToolCaller<C> {
+    fn call_tool<'a>(
+        &'a self,
+        name: &'a str,
+        args: Option<serde_json::Value>,
+    ) -> ToolCallFuture<'a> {
+        let Some(calls) = &self.calls else {
+            return self.inner.call_tool(name, args);
+        };
+        Box::pin(async move {
+            let arguments = args.as_ref().and_then(|v| v.as_object().cloned());
+            let result = self.inner.call_tool(name, args).await;
+            let (recorded, error) = match &result {
+                Ok(result) => (Some(result.clone()), None),
+                Err(e) => (None, Some(e.to_string())),
+            };
+            calls.lock().unwrap().push(RecordedCall {
+                name: name.to_string(),
+                arguments,
+                result: recorded,
+                error,
+            });
+            result
+        })
+    }
+
+    fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+        self.inner.server_request(request)
+    }
+}
+
+/// A downstream server that lists a bundle's tools and answers each call
+/// with the first unused recorded response to the same tool and arguments.
+pub struct ReplayServer {
+    tools: Vec<Tool>,
+    calls: Mutex<Vec<Option<RecordedCall>>>,
+}
+
+impl ReplayServer {
+    pub fn new(bundle: &RunBundle) -> Self {
+        Self {
+            tools: bundle.tools.clone(),
+            calls: Mutex::new(bundle.calls.iter().cloned().map(Some).collect()),
+        }
+    }
+
+    /// Serve the replay in-process and connect to it as a downstream client.
+    pub async fn connect(self) -> Result<DownstreamClient> {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            if let Ok(service) = self.serve(tokio::io::split(server)).await {
+                let _ = service.waiting().await;
+            }
+        });
+        DownstreamHandler::new()
+            .serve(tokio::io::split(client))
+            .await
+            .context("Failed to start replay")
+    }
+}
+
+impl ServerHandler for ReplayServer {
+    fn get_info(&self) -> ServerInfo {
+        ServerInfo {
+            protocol_version: Default::default(),
+            capabilities: ServerCapabilities::builder().enable_tools().build(),
+            server_info: Implementation {
+                name: "codemoder-replay".into(),
+                version: env!("CARGO_PKG_VERSION").into(),
+                title: None,
+                icons: None,
+                website_url: None,
+            },
+            instructions: None,
+        }
+    }
+
+    async fn list_tools(
+        &self,
+        _request: Option<PaginatedRequestParam>,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListToolsResult, ErrorData> {
+        Ok(ListToolsResult {
+            tools: self.tools.clone(),
+            next_cursor: None,
+            meta: None,
+        })
+    }
+
+    async fn call_tool(
+        &self,
+        request: CallToolRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, ErrorData> {
+        let mut calls = self.calls.lock().unwrap();
+        let recorded = calls
+            .iter_mut()
+            .find(|call| {
+                call.as_ref().is_some_and(|call| {
+                    call.name == request.name && call.arguments == request.arguments
+                })
+            })
+            .and_then(Option::take)
+            .ok_or_else(|| {
+                ErrorData::internal_error(
+                    format!(
+                        "No recorded response for `{}` with these arguments",
+                        request.name
+                    ),
+                    None,
+                )
+            })?;
+        match recorded.result {
+            Some(result) => Ok(result),
+            None => Err(ErrorData::internal_error(
+                recorded.error.unwrap_or_default(),
+                None,
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use rmcp::model::Content;
+
+    struct Echo;
+
+    impl ToolCaller for Echo {
+        fn call_tool<'a>(
+            &'a self,
+            name: &'a str,
+            args: Option<serde_json::Value>,
+        ) -> ToolCallFuture<'a> {
+            Box::pin(async move {
+                if name == "fail" {
+                    anyhow::bail!("Tool call failed: boom");
+                }
+                Ok(CallToolResult::success(vec![Content::text(
+                    args.unwrap_or_default().to_string(),
+                )]))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let caller = RecordingToolCaller::new(Echo, true);
+        let calls = caller.calls();
+        caller
+            .call_tool("echo", Some(serde_json::json!({"message": "hi"})))
+            .await
+            .unwrap();
+        caller.call_tool("fail", None).await.unwrap_err();
+
+        let config = CodeModeConfig::new();
+        let tool = Tool::new("echo", "Echo", Arc::new(JsonObject::new()));
+        let recorded = calls.lock().unwrap().clone();
+        assert_eq!(recorded.len(), 2);
+        assert_eq!(recorded[1].error.as_deref(), Some("Tool call failed: boom"));
+        let bundle = RunBundle::new(
+            "exec-1",
+            "tools.echo({message: 'hi'})",
+            &config,
+            &[tool],
+            &ToolAliases::from_config(&config),
+            recorded,
+        );
+
+        let storage = MemoryStorage::new();
+        bundle.save(&storage).unwrap();
+        assert_eq!(storage.list(RECORDINGS).unwrap(), ["exec-1.json"]);
+        let read = RunBundle::load(&storage, "exec-1").unwrap();
+        assert_eq!(read.calls, bundle.calls);
+        assert!(RunBundle::load(&storage, "exec-2").is_err());
+
+        let client = ReplayServer::new(&read).connect().await.unwrap();
+        let tools = client.peer().list_all_tools().await.unwrap();
+        assert_eq!(tools[0].name, "echo");
+        let request = CallToolRequestParam {
+            name: "echo".into(),
+            arguments: serde_json::json!({"message": "hi"}).as_object().cloned(),
+        };
+        let result = client.peer().call_tool(request.clone()).await.unwrap();
+        assert_eq!(
+            result.content[0].as_text().unwrap().text,
+            r#"{"message":"hi"}"#
+        );
+        // Each recorded response is used once
+        assert!(client.peer().call_tool(request).await.is_err());
+    }
+
+    #[test]
+    fn test_bundle_key_rejects_traversal() {
+        assert_eq!(bundle_key("exec-0a1b").unwrap(), "exec-0a1b.json");
+        assert!(bundle_key("../etc/passwd").is_err());
+        assert!(bundle_key("exec.1").is_err());
+    }
+}
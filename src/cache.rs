@@ -0,0 +1,281 @@
+//! Results of read-only tool calls shared across executions.
+//!
+//! Agents call the same list and search tools over and over, in one
+//! execution after another. With `cache_ttl_secs` set under
+//! `[tools.<name>]`, successful results of a read-only tool are kept for
+//! that long and identical calls from any script are answered from them.
+//! A script can skip the cache for one call with
+//! `tools.search(args, {cache: false})`, which refreshes the kept result.
+//! Calls are cached after arguments are stripped and hashed, and after
+//! policy checks, so those apply to every call.
+
+use crate::alias::ToolAliases;
+use crate::config::CodeModeConfig;
+use crate::dedup::args_key;
+use crate::runtime::{ServerRequest, ServerRequestFuture, ToolCallFuture, ToolCaller};
+use rmcp::model::CallToolResult;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Results kept at most; the oldest is dropped to make room.
+pub const MAX_ENTRIES: usize = 1000;
+
+tokio::task_local! {
+    /// Set while serving a call the script made with `cache: false`.
+    static BYPASS: ();
+}
+
+/// The options a script passes as the second argument of a tool call.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct CallOptions {
+    /// Whether the call may be answered from the cache.
+    pub cache: bool,
+}
+
+impl Default for CallOptions {
+    fn default() -> Self {
+        Self { cache: true }
+    }
+}
+
+/// Run `call` without answering it from the cache.
+pub async fn uncached<F: Future>(call: F) -> F::Output {
+    BYPASS.scope((), call).await
+}
+
+fn bypassed() -> bool {
+    BYPASS.try_with(|_| ()).is_ok()
+}
+
+type Key = (String, String);
+
+/// The cache of one server, shared by its executions.
+#[derive(Debug, Default)]
+pub struct ResultCache {
+    /// Downstream tool name to how long its results are kept.
+    ttls: HashMap<String, Duration>,
+    entries: Mutex<HashMap<Key, (Instant, CallToolResult)>>,
+}
+
+impl ResultCache {
+    pub fn from_config(config: &CodeModeConfig, aliases: &ToolAliases) -> Self {
+        let ttls = config
+            .tools
+            .iter()
+            .filter_map(|(name, settings)| {
+                let ttl = settings.cache_ttl_secs.filter(|ttl| *ttl > 0)?;
+                Some((
+                    aliases.downstream(name).to_string(),
+                    Duration::from_secs(ttl),
+                ))
+            })
+            .collect();
+        Self {
+            ttls,
+            entries: Mutex::default(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ttls.is_empty()
+    }
+
+    fn get(&self, key: &Key) -> Option<CallToolResult> {
+        let ttl = self.ttls.get(&key.0)?;
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some((stored, result)) if stored.elapsed() < *ttl => Some(result.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&self, key: Key, result: CallToolResult) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|(tool, _), (stored, _)| {
+            self.ttls
+                .get(tool)
+                .is_some_and(|ttl| stored.elapsed() < *ttl)
+        });
+        if entries.len() >= MAX_ENTRIES
+            && let Some(oldest) = entries
+                .iter()
+                .min_by_key(|(_, (stored, _))| *stored)
+                .map(|(key, _)| key.clone())
+        {
+            entries.remove(&oldest);
+        }
+        entries.insert(key, (Instant::now(), result));
+    }
+}
+
+/// A `ToolCaller` that answers calls to read-only tools with a
+/// `cache_ttl_secs` from the server's `ResultCache`. Takes downstream tool
+/// names.
+pub struct CacheToolCaller<C: ToolCaller> {
+    inner: C,
+    cache: Arc<ResultCache>,
+    /// Downstream names of the read-only tools.
+    read_only: HashSet<String>,
+    hits: Arc<AtomicUsize>,
+}
+
+impl<C: ToolCaller> CacheToolCaller<C> {
+    pub fn new(inner: C, cache: Arc<ResultCache>, read_only: HashSet<String>) -> Self {
+        Self {
+            inner,
+            cache,
+            read_only,
+            hits: Arc::default(),
+        }
+    }
+
+    /// Number of calls answered from the cache, shared with this caller.
+    pub fn hits(&self) -> Arc<AtomicUsize> {
+        self.hits.clone()
+    }
+}
+
+impl<C: ToolCaller> ToolCaller for CacheToolCaller<C> {
+    fn call_tool<'a>(
+        &'a self,
+        name: &'a str,
+        args: Option<serde_json::Value>,
+    ) -> ToolCallFuture<'a> {
+        if !self.read_only.contains(name) || !self.cache.ttls.contains_key(name) {
+            return self.inner.call_tool(name, args);
+        }
+        Box::pin(async move {
+            let key = (name.to_string(), args_key(args.as_ref()));
+            if !bypassed()
+                && let Some(result) = self.cache.get(&key)
+            {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(result);
+            }
+            let result = self.inner.call_tool(name, args).await?;
+            if result.is_error != Some(true) {
+                self.cache.insert(key, result.clone());
+            }
+            Ok(result)
+        })
+    }
+
+    fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+        self.inner.server_request(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rmcp::model::Content;
+
+    #[derive(Default)]
+    struct CountingCaller {
+        calls: AtomicUsize,
+    }
+
+    impl ToolCaller for CountingCaller {
+        fn call_tool<'a>(
+            &'a self,
+            _name: &'a str,
+            _args: Option<serde_json::Value>,
+        ) -> ToolCallFuture<'a> {
+            let n = self.calls.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move { Ok(CallToolResult::success(vec![Content::text(n.to_string())])) })
+        }
+    }
+
+    fn cache(ttl: u64) -> Arc<ResultCache> {
+        let mut config = CodeModeConfig::new();
+        config
+            .aliases
+            .insert("list".to_string(), "list_parts".to_string());
+        config
+            .tools
+            .entry("list_parts".to_string())
+            .or_default()
+            .cache_ttl_secs = Some(ttl);
+        Arc::new(ResultCache::from_config(
+            &config,
+            &ToolAliases::from_config(&config),
+        ))
+    }
+
+    fn text(result: &CallToolResult) -> &str {
+        &result.content[0].as_text().unwrap().text
+    }
+
+    #[tokio::test]
+    async fn test_shared_across_callers() {
+        let cache = cache(60);
+        let read_only = HashSet::from(["list".to_string()]);
+        let first =
+            CacheToolCaller::new(CountingCaller::default(), cache.clone(), read_only.clone());
+        let second = CacheToolCaller::new(CountingCaller::default(), cache, read_only);
+
+        let args = Some(serde_json::json!({"q": "R1"}));
+        assert_eq!(
+            text(&first.call_tool("list", args.clone()).await.unwrap()),
+            "0"
+        );
+        assert_eq!(
+            text(&second.call_tool("list", args.clone()).await.unwrap()),
+            "0"
+        );
+        assert_eq!(second.inner.calls.load(Ordering::SeqCst), 0);
+        assert_eq!(second.hits().load(Ordering::Relaxed), 1);
+
+        // Other arguments, and calls that skip the cache
+        second
+            .call_tool("list", Some(serde_json::json!({"q": "R2"})))
+            .await
+            .unwrap();
+        let fresh = uncached(second.call_tool("list", args.clone()))
+            .await
+            .unwrap();
+        assert_eq!(text(&fresh), "1");
+        assert_eq!(second.inner.calls.load(Ordering::SeqCst), 2);
+        // The fresh result replaced the kept one
+        assert_eq!(text(&first.call_tool("list", args).await.unwrap()), "1");
+    }
+
+    #[tokio::test]
+    async fn test_not_cached() {
+        // Not read-only
+        let caller = CacheToolCaller::new(CountingCaller::default(), cache(60), HashSet::new());
+        caller.call_tool("list", None).await.unwrap();
+        caller.call_tool("list", None).await.unwrap();
+        assert_eq!(caller.inner.calls.load(Ordering::SeqCst), 2);
+
+        // Expired
+        let caller = CacheToolCaller::new(
+            CountingCaller::default(),
+            cache(60),
+            HashSet::from(["list".to_string()]),
+        );
+        caller.call_tool("list", None).await.unwrap();
+        for (stored, _) in caller.cache.entries.lock().unwrap().values_mut() {
+            *stored -= Duration::from_secs(61);
+        }
+        caller.call_tool("list", None).await.unwrap();
+        assert_eq!(caller.inner.calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_call_options() {
+        let options: CallOptions = serde_json::from_str(r#"{"cache": false}"#).unwrap();
+        assert!(!options.cache);
+        let options: CallOptions = serde_json::from_str("{}").unwrap();
+        assert!(options.cache);
+    }
+}
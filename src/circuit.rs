@@ -0,0 +1,300 @@
+//! Failing fast while the downstream server keeps failing.
+//!
+//! With `[circuit_breaker]` set, `failure_threshold` consecutive downstream
+//! calls that fail outright (a timeout, a closed transport, a JSON-RPC error
+//! other than invalid params or an unknown method) open the circuit. While
+//! it is open, calls fail at once with `CircuitOpen`, which scripts can catch
+//! with `code === "circuit_open"`. After `open_secs` the circuit is half
+//! open: one call goes through as a probe and closes the circuit if it
+//! succeeds or opens it again if it fails. Transitions are logged and the
+//! state is reported by `codemode_stats`.
+
+use crate::config::CodeModeConfig;
+use crate::retry::PERMANENT_ERRORS;
+use crate::runtime::{ServerRequest, ServerRequestFuture, ToolCallFuture, ToolCaller};
+use rmcp::model::CallToolResult;
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Closed,
+    Open {
+        since: Instant,
+    },
+    /// A probe call started at `since` is in flight.
+    HalfOpen {
+        since: Instant,
+    },
+}
+
+#[derive(Debug)]
+struct Circuit {
+    state: State,
+    /// Consecutive failures, up to the one that last opened the circuit.
+    failures: u32,
+    /// How many times the circuit opened.
+    opened: u64,
+}
+
+/// Why a call was refused: the circuit is open.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error(
+    "Downstream server unavailable: circuit open after {failures} consecutive failures, next attempt in {}s",
+    retry_in.as_secs()
+)]
+pub struct CircuitOpen {
+    pub failures: u32,
+    /// How long until a probe call is let through.
+    pub retry_in: Duration,
+}
+
+impl CircuitOpen {
+    /// The `code` of the exception scripts see.
+    pub const CODE: &str = "circuit_open";
+}
+
+/// The circuit of one downstream server, or nothing when
+/// `[circuit_breaker]` isn't set.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    open_for: Duration,
+    circuit: Mutex<Circuit>,
+}
+
+impl CircuitBreaker {
+    pub fn from_config(config: &CodeModeConfig) -> Self {
+        let settings = config.circuit_breaker.clone().unwrap_or_default();
+        Self {
+            threshold: if config.circuit_breaker.is_some() {
+                settings.failure_threshold
+            } else {
+                0
+            },
+            open_for: Duration::from_secs(settings.open_secs),
+            circuit: Mutex::new(Circuit {
+                state: State::Closed,
+                failures: 0,
+                opened: 0,
+            }),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.threshold > 0
+    }
+
+    /// Whether a call may go through now. Once the circuit has been open for
+    /// `open_secs`, lets the first caller through as the probe. A probe that
+    /// never reports back is given up on after as long again.
+    pub fn check(&self) -> Result<(), CircuitOpen> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let mut circuit = self.circuit.lock().unwrap();
+        let since = match circuit.state {
+            State::Closed => return Ok(()),
+            State::Open { since } | State::HalfOpen { since } => since,
+        };
+        let elapsed = since.elapsed();
+        if elapsed < self.open_for {
+            return Err(CircuitOpen {
+                failures: circuit.failures,
+                retry_in: self.open_for - elapsed,
+            });
+        }
+        if matches!(circuit.state, State::Open { .. }) {
+            tracing::info!("Circuit half open: probing the downstream server");
+        }
+        circuit.state = State::HalfOpen {
+            since: Instant::now(),
+        };
+        Ok(())
+    }
+
+    /// Count the outcome of a call that `check` let through.
+    pub fn record<E: Display>(&self, outcome: &Result<CallToolResult, E>) {
+        if !self.is_enabled() {
+            return;
+        }
+        let failed = match outcome {
+            Ok(_) => false,
+            Err(e) => {
+                let error = e.to_string();
+                if PERMANENT_ERRORS.iter().any(|code| error.contains(code)) {
+                    return;
+                }
+                true
+            }
+        };
+        let mut circuit = self.circuit.lock().unwrap();
+        if !failed {
+            if circuit.state != State::Closed {
+                tracing::info!("Circuit closed: the downstream server is responding");
+            }
+            circuit.state = State::Closed;
+            circuit.failures = 0;
+            return;
+        }
+        circuit.failures += 1;
+        match circuit.state {
+            State::HalfOpen { .. } => {
+                tracing::warn!("Circuit open again: the probe call failed");
+            }
+            State::Closed if circuit.failures >= self.threshold => {
+                tracing::warn!(
+                    "Circuit open: {} consecutive downstream calls failed; failing fast for {}s",
+                    circuit.failures,
+                    self.open_for.as_secs()
+                );
+            }
+            _ => return,
+        }
+        circuit.state = State::Open {
+            since: Instant::now(),
+        };
+        circuit.opened += 1;
+    }
+
+    /// The circuit's state as `codemode_stats` reports it.
+    pub fn report(&self) -> serde_json::Value {
+        let circuit = self.circuit.lock().unwrap();
+        let state = match circuit.state {
+            State::Closed => "closed",
+            State::Open { .. } => "open",
+            State::HalfOpen { .. } => "half-open",
+        };
+        serde_json::json!({
+            "state": state,
+            "consecutiveFailures": circuit.failures,
+            "timesOpened": circuit.opened,
+        })
+    }
+}
+
+/// A `ToolCaller` that refuses calls while the circuit is open.
+pub struct CircuitToolCaller<C: ToolCaller> {
+    inner: C,
+    breaker: Arc<CircuitBreaker>,
+}
+
+impl<C: ToolCaller> CircuitToolCaller<C> {
+    pub fn new(inner: C, breaker: Arc<CircuitBreaker>) -> Self {
+        Self { inner, breaker }
+    }
+}
+
+impl<C: ToolCaller> ToolCaller for CircuitToolCaller<C> {
+    fn call_tool<'a>(
+        &'a self,
+        name: &'a str,
+        args: Option<serde_json::Value>,
+    ) -> ToolCallFuture<'a> {
+        if !self.breaker.is_enabled() {
+            return self.inner.call_tool(name, args);
+        }
+        Box::pin(async move {
+            self.breaker.check()?;
+            let result = self.inner.call_tool(name, args).await;
+            self.breaker.record(&result);
+            result
+        })
+    }
+
+    fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+        self.inner.server_request(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CircuitBreakerSettings;
+    use rmcp::model::Content;
+
+    fn breaker(open_secs: u64) -> CircuitBreaker {
+        CircuitBreaker::from_config(&CodeModeConfig::new().with_circuit_breaker(
+            CircuitBreakerSettings {
+                failure_threshold: 2,
+                open_secs,
+            },
+        ))
+    }
+
+    fn failed() -> Result<CallToolResult, String> {
+        Err("Transport closed".to_string())
+    }
+
+    fn succeeded() -> Result<CallToolResult, String> {
+        Ok(CallToolResult::success(vec![Content::text("ok")]))
+    }
+
+    #[test]
+    fn test_opens_after_consecutive_failures() {
+        let breaker = breaker(30);
+        breaker.record(&failed());
+        breaker.record(&succeeded());
+        breaker.record(&failed());
+        assert!(breaker.check().is_ok());
+
+        // The request's own fault says nothing about the server
+        breaker.record(&Err::<CallToolResult, _>("Mcp error: -32602: bad id"));
+        assert!(breaker.check().is_ok());
+
+        breaker.record(&failed());
+        let open = breaker.check().unwrap_err();
+        assert_eq!(open.failures, 2);
+        assert!(open.retry_in > Duration::from_secs(29));
+        assert!(
+            open.to_string()
+                .starts_with("Downstream server unavailable: circuit open after 2")
+        );
+        assert_eq!(breaker.report()["state"], "open");
+        assert_eq!(breaker.report()["timesOpened"], 1);
+    }
+
+    #[test]
+    fn test_half_open_probe() {
+        let breaker = breaker(0);
+        breaker.record(&failed());
+        breaker.record(&failed());
+
+        // One probe at a time; a failed one opens the circuit again
+        assert!(breaker.check().is_ok());
+        assert_eq!(breaker.report()["state"], "half-open");
+        breaker.record(&failed());
+        assert_eq!(breaker.report()["state"], "open");
+        assert_eq!(breaker.report()["timesOpened"], 2);
+
+        assert!(breaker.check().is_ok());
+        breaker.record(&succeeded());
+        assert_eq!(
+            breaker.report(),
+            serde_json::json!({"state": "closed", "consecutiveFailures": 0, "timesOpened": 2})
+        );
+    }
+
+    #[test]
+    fn test_probe_in_flight() {
+        let breaker = breaker(30);
+        {
+            let mut circuit = breaker.circuit.lock().unwrap();
+            circuit.state = State::Open {
+                since: Instant::now() - Duration::from_secs(31),
+            };
+        }
+        assert!(breaker.check().is_ok());
+        assert!(breaker.check().is_err());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let breaker = CircuitBreaker::from_config(&CodeModeConfig::new());
+        for _ in 0..10 {
+            breaker.record(&failed());
+        }
+        assert!(breaker.check().is_ok());
+    }
+}
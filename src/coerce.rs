@@ -0,0 +1,267 @@
+//! Converting script tool call arguments to the types their schema declares.
+//!
+//! Models often write `"5"` where a tool takes a number, and many servers
+//! reject that outright. With `coerce_args` set, globally or under
+//! `[tools.<name>]`, an argument whose type doesn't match its schema is
+//! converted when the conversion is unambiguous: a numeric string to a
+//! number or integer, a whole number like `2.0` to an integer, a number to
+//! a string, `"true"` or `"false"` to a boolean. Nested objects and array items are converted too. Each
+//! conversion is logged as a warning.
+
+use crate::config::CodeModeConfig;
+use crate::runtime::{ServerRequest, ServerRequestFuture, ToolCallFuture, ToolCaller};
+use rmcp::model::Tool;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// A converted argument: its dotted path, and the types it was and is now.
+pub type Conversion = (String, &'static str, &'static str);
+
+/// The input schemas of the tools whose arguments are coerced, keyed by
+/// exposed tool name.
+#[derive(Debug, Default)]
+pub struct ArgCoercion {
+    schemas: HashMap<String, Value>,
+}
+
+impl ArgCoercion {
+    pub fn from_config(config: &CodeModeConfig, tools: &[Tool]) -> Self {
+        let schemas = tools
+            .iter()
+            .filter(|tool| {
+                config
+                    .tools
+                    .get(tool.name.as_ref())
+                    .and_then(|settings| settings.coerce_args)
+                    .unwrap_or(config.coerce_args)
+            })
+            .map(|tool| {
+                (
+                    tool.name.to_string(),
+                    Value::Object(tool.input_schema.as_ref().clone()),
+                )
+            })
+            .collect();
+        Self { schemas }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.schemas.is_empty()
+    }
+
+    /// Convert `args` of a call to `tool` in place, returning what was
+    /// converted.
+    pub fn apply(&self, tool: &str, args: &mut Value) -> Vec<Conversion> {
+        let mut converted = Vec::new();
+        if let Some(schema) = self.schemas.get(tool) {
+            coerce(schema, args, "", &mut converted);
+        }
+        converted
+    }
+}
+
+fn coerce(schema: &Value, value: &mut Value, path: &str, converted: &mut Vec<Conversion>) {
+    match value {
+        Value::Object(object) => {
+            let Some(properties) = schema.get("properties").and_then(Value::as_object) else {
+                return;
+            };
+            for (name, field) in object.iter_mut() {
+                if let Some(field_schema) = properties.get(name) {
+                    let field_path = match path {
+                        "" => name.clone(),
+                        parent => format!("{parent}.{name}"),
+                    };
+                    coerce(field_schema, field, &field_path, converted);
+                }
+            }
+        }
+        Value::Array(items) => {
+            if let Some(item_schema) = schema.get("items") {
+                for (i, item) in items.iter_mut().enumerate() {
+                    coerce(item_schema, item, &format!("{path}[{i}]"), converted);
+                }
+            }
+        }
+        _ => {
+            let types = declared_types(schema);
+            if types.is_empty() || types.iter().any(|t| matches_type(value, t)) {
+                return;
+            }
+            let from = type_name(value);
+            if let Some((to, coerced)) = types.iter().find_map(|t| convert(value, t)) {
+                *value = coerced;
+                converted.push((path.to_string(), from, to));
+            }
+        }
+    }
+}
+
+/// The types in a schema's `type`, whether one or a list.
+fn declared_types(schema: &Value) -> Vec<&str> {
+    match schema.get("type") {
+        Some(Value::String(t)) => vec![t.as_str()],
+        Some(Value::Array(types)) => types.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn matches_type(value: &Value, declared: &str) -> bool {
+    match declared {
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "string" => value.is_string(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        _ => false,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// `value` as `declared`, when the conversion is unambiguous.
+fn convert(value: &Value, declared: &str) -> Option<(&'static str, Value)> {
+    match (declared, value) {
+        ("integer", Value::String(s)) => Some(("integer", s.trim().parse::<i64>().ok()?.into())),
+        ("integer", Value::Number(n)) => {
+            let n = n
+                .as_f64()
+                .filter(|n| n.fract() == 0.0 && n.abs() < 2f64.powi(53))?;
+            Some(("integer", (n as i64).into()))
+        }
+        ("number", Value::String(s)) => {
+            let s = s.trim();
+            if let Ok(n) = s.parse::<i64>() {
+                return Some(("number", n.into()));
+            }
+            let n = s.parse::<f64>().ok().filter(|n| n.is_finite())?;
+            Some(("number", serde_json::Number::from_f64(n)?.into()))
+        }
+        ("string", Value::Number(n)) => Some(("string", n.to_string().into())),
+        ("boolean", Value::String(s)) => match s.as_str() {
+            "true" => Some(("boolean", true.into())),
+            "false" => Some(("boolean", false.into())),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// A `ToolCaller` that converts arguments to their declared types.
+pub struct CoerceToolCaller<C: ToolCaller> {
+    inner: C,
+    coercion: ArgCoercion,
+}
+
+impl<C: ToolCaller> CoerceToolCaller<C> {
+    pub fn new(inner: C, coercion: ArgCoercion) -> Self {
+        Self { inner, coercion }
+    }
+}
+
+impl<C: ToolCaller> ToolCaller for CoerceToolCaller<C> {
+    fn call_tool<'a>(&'a self, name: &'a str, mut args: Option<Value>) -> ToolCallFuture<'a> {
+        if let Some(args) = args.as_mut() {
+            for (path, from, to) in self.coercion.apply(name, args) {
+                tracing::warn!("Converted argument `{path}` of {name} from {from} to {to}");
+            }
+        }
+        self.inner.call_tool(name, args)
+    }
+
+    fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+        self.inner.server_request(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn coercion() -> ArgCoercion {
+        let tool = Tool::new(
+            "move_footprint",
+            "",
+            Arc::new(
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "id": {"type": "string"},
+                        "x_mm": {"type": "number"},
+                        "layer": {"type": "integer"},
+                        "locked": {"type": "boolean"},
+                        "rotation": {"type": ["number", "null"]},
+                        "points": {"type": "array", "items": {"type": "number"}},
+                        "options": {
+                            "type": "object",
+                            "properties": {"grid": {"type": "number"}}
+                        }
+                    }
+                })
+                .as_object()
+                .unwrap()
+                .clone(),
+            ),
+        );
+        ArgCoercion::from_config(&CodeModeConfig::new().with_coerce_args(true), &[tool])
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut args = json!({
+            "id": 7,
+            "x_mm": " 5.5",
+            "layer": 2.0,
+            "locked": "true",
+            "rotation": null,
+            "points": ["1", 2],
+            "options": {"grid": "0.25"},
+        });
+        let converted = coercion().apply("move_footprint", &mut args);
+        assert_eq!(
+            args,
+            json!({
+                "id": "7",
+                "x_mm": 5.5,
+                "layer": 2,
+                "locked": true,
+                "rotation": null,
+                "points": [1, 2],
+                "options": {"grid": 0.25},
+            })
+        );
+        assert_eq!(converted.len(), 6);
+        assert!(converted.contains(&("points[0]".to_string(), "string", "number")));
+        assert!(converted.contains(&("options.grid".to_string(), "string", "number")));
+    }
+
+    #[test]
+    fn test_ambiguous_values_are_left() {
+        let mut args = json!({"x_mm": "five", "layer": "2.5", "locked": "yes"});
+        let converted = coercion().apply("move_footprint", &mut args);
+        assert!(converted.is_empty());
+        assert_eq!(
+            args,
+            json!({"x_mm": "five", "layer": "2.5", "locked": "yes"})
+        );
+
+        // Off for tools it isn't enabled for
+        let mut args = json!({"x_mm": "5"});
+        assert!(coercion().apply("other", &mut args).is_empty());
+        let off = ArgCoercion::from_config(&CodeModeConfig::new(), &[]);
+        assert!(off.is_empty());
+    }
+}
@@ -0,0 +1,124 @@
+use crate::config::CodeModeConfig;
+use crate::runtime::{ServerRequest, ServerRequestFuture, ToolCallFuture, ToolCaller};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Per-tool concurrency limits built from `ToolSettings::max_concurrent`.
+///
+/// The semaphores are shared by every execution (and by passthrough calls), so
+/// a tool limited to one concurrent call stays serialized across the whole proxy.
+#[derive(Debug, Default)]
+pub struct ConcurrencyLimits {
+    semaphores: HashMap<String, Arc<Semaphore>>,
+}
+
+impl ConcurrencyLimits {
+    pub fn from_config(config: &CodeModeConfig) -> Self {
+        let semaphores = config
+            .tools
+            .iter()
+            .filter_map(|(name, settings)| {
+                settings
+                    .max_concurrent
+                    .map(|limit| (name.clone(), Arc::new(Semaphore::new(limit.max(1)))))
+            })
+            .collect();
+        Self { semaphores }
+    }
+
+    /// Wait for a slot for `tool_name`. Returns `None` for unlimited tools.
+    pub async fn acquire(&self, tool_name: &str) -> Option<OwnedSemaphorePermit> {
+        let semaphore = self.semaphores.get(tool_name)?.clone();
+        semaphore.acquire_owned().await.ok()
+    }
+}
+
+/// A `ToolCaller` that holds a per-tool permit for the duration of each call.
+pub struct LimitedToolCaller<C: ToolCaller> {
+    inner: C,
+    limits: Arc<ConcurrencyLimits>,
+}
+
+impl<C: ToolCaller> LimitedToolCaller<C> {
+    pub fn new(inner: C, limits: Arc<ConcurrencyLimits>) -> Self {
+        Self { inner, limits }
+    }
+}
+
+impl<C: ToolCaller> ToolCaller for LimitedToolCaller<C> {
+    fn call_tool<'a>(
+        &'a self,
+        name: &'a str,
+        args: Option<serde_json::Value>,
+    ) -> ToolCallFuture<'a> {
+        Box::pin(async move {
+            let _permit = self.limits.acquire(name).await;
+            self.inner.call_tool(name, args).await
+        })
+    }
+
+    fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+        self.inner.server_request(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rmcp::model::{CallToolResult, Content};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct CountingCaller {
+        active: AtomicUsize,
+        max_seen: AtomicUsize,
+    }
+
+    impl ToolCaller for Arc<CountingCaller> {
+        fn call_tool<'a>(
+            &'a self,
+            _name: &'a str,
+            _args: Option<serde_json::Value>,
+        ) -> ToolCallFuture<'a> {
+            Box::pin(async move {
+                let now = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+                self.max_seen.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                self.active.fetch_sub(1, Ordering::SeqCst);
+                Ok(CallToolResult::success(vec![Content::text("ok")]))
+            })
+        }
+    }
+
+    async fn max_concurrency(config: &CodeModeConfig, tool: &'static str) -> usize {
+        let counter = Arc::new(CountingCaller::default());
+        let limits = Arc::new(ConcurrencyLimits::from_config(config));
+        let caller = Arc::new(LimitedToolCaller::new(counter.clone(), limits));
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let caller = caller.clone();
+                tokio::spawn(async move { caller.call_tool(tool, None).await.unwrap() })
+            })
+            .collect();
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        counter.max_seen.load(Ordering::SeqCst)
+    }
+
+    #[tokio::test]
+    async fn test_limited_tool_is_serialized() {
+        let config = CodeModeConfig::new().with_max_concurrent("render_board", 1);
+        assert_eq!(max_concurrency(&config, "render_board").await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_unlimited_tool_runs_in_parallel() {
+        let config = CodeModeConfig::new().with_max_concurrent("render_board", 1);
+        assert!(max_concurrency(&config, "get_items").await > 1);
+    }
+}
@@ -1,18 +1,355 @@
+use anyhow::{Context, Result};
+use rmcp::model::{Icon, Implementation, JsonObject};
+use rmcp::schemars::{self, JsonSchema};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
 pub enum CodeModeExposure {
+    #[serde(alias = "replace")]
     ReplaceTools,
     #[default]
     Add,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// What happens when a downstream result doesn't match the tool's declared
+/// `outputSchema`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SchemaDriftAction {
+    /// Results are not validated.
+    #[default]
+    Ignore,
+    /// The call fails with a schema drift error, which scripts see as a
+    /// thrown error.
+    Error,
+    /// The result is passed on, and the tool is listed under `schemaDrift`
+    /// in the execution's metrics.
+    Quarantine,
+}
+
+/// What happens when the downstream server already exposes a tool named
+/// `tool_name`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolNameCollision {
+    /// The execute tool is exposed as `<tool_name>_2`, or the first such
+    /// name that is free, with a warning.
+    #[default]
+    Rename,
+    /// The proxy refuses to start.
+    Fail,
+}
+
+/// How much of the tools' TypeScript interface the execute tool's
+/// description embeds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum InterfaceDetail {
+    /// Full signatures with JSDoc from tool and parameter descriptions.
+    Full,
+    /// Signatures without JSDoc.
+    Signatures,
+    /// One line per tool with the first sentence of its description.
+    Summaries,
+    /// One line per tool with just its name.
+    Names,
+    /// Nothing beyond `tool_description`.
+    None,
+}
+
+/// The language and engine used to run `execute_tools` code.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ScriptEngine {
+    /// JavaScript (and TypeScript) on QuickJS.
+    #[default]
+    Javascript,
+    /// Lua 5.4 via mlua. Requires the `lua` cargo feature.
+    Lua,
+    /// JavaScript (and TypeScript) on the pure-Rust Boa engine. Requires the
+    /// `boa` cargo feature.
+    Boa,
+}
+
+/// Settings that apply to a single downstream tool, keyed by tool name in
+/// `CodeModeConfig::tools` (`[tools.render_board]` in a config file).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct ToolSettings {
+    /// Maximum number of calls to this tool that may be in flight at once.
+    pub max_concurrent: Option<usize>,
+    /// Overrides the tool's `readOnlyHint` annotation.
+    pub read_only: Option<bool>,
+    /// Reuse the result of identical calls to this tool within one
+    /// execution. Defaults to `dedupe_read_only_calls` for read-only tools.
+    pub memoize: Option<bool>,
+    /// Keep successful results of this read-only tool for this long and
+    /// answer identical calls from any execution with them.
+    pub cache_ttl_secs: Option<u64>,
+    /// Hand results to scripts as a reader instead of a string, for tools
+    /// that return very large text.
+    pub lazy_result: bool,
+    /// Ask the configured approver before each call to this tool.
+    pub require_approval: bool,
+    /// Argument fields removed before the call is sent downstream. Dotted
+    /// paths such as `user.email` reach into nested objects.
+    pub strip_args: Vec<String>,
+    /// Argument fields replaced by `sha256:<hex>` of their value before the
+    /// call is sent downstream, so they still correlate without being
+    /// disclosed.
+    pub hash_args: Vec<String>,
+    /// Argument fields holding file paths, which scripts may only point
+    /// inside the client's roots. Dotted paths reach into nested objects.
+    pub path_args: Vec<String>,
+    /// Cost charged for each successful call, in whatever unit the operator
+    /// accounts downstream spend in.
+    pub cost: Option<f64>,
+    /// Cost charged per KiB of result.
+    pub cost_per_kb: Option<f64>,
+    /// Cost charged per second the call takes.
+    pub cost_per_second: Option<f64>,
+    /// Warn, and tell the client, when the 95th percentile latency of recent
+    /// calls to this tool exceeds this many milliseconds.
+    pub latency_slo_ms: Option<u64>,
+    /// Retry failed calls to this tool per `[retry]`. Defaults to true for
+    /// read-only tools and idempotent ones that aren't destructive, and to
+    /// false for the rest.
+    pub retry: Option<bool>,
+    /// Override the global `strict_args` for this tool.
+    pub strict_args: Option<bool>,
+    /// Override the global `coerce_args` for this tool.
+    pub coerce_args: Option<bool>,
+    /// Example calls, rendered as `@example` tags under the tool's function
+    /// in the TypeScript interface. Replaces the examples the downstream
+    /// server gives in the tool's `_meta.examples`.
+    pub examples: Vec<String>,
+}
+
+/// Where tool calls are checked against organizational policy
+/// (`[policy]` in a config file). Set exactly one source.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicySettings {
+    /// Cedar policy file evaluated in-process (`cedar` feature).
+    pub cedar_file: Option<PathBuf>,
+    /// OPA decision endpoint, e.g. `http://localhost:8181/v1/data/codemoder/allow`
+    /// (`opa` feature).
+    pub opa_url: Option<String>,
+}
+
+/// Retries of failed downstream tool calls, from scripts and passed through
+/// (`[retry]` in a config file).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetrySettings {
+    /// Attempts per call, including the first.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled before each one after.
+    pub backoff_ms: u64,
+    /// Longest delay between attempts.
+    pub max_backoff_ms: u64,
+    /// Regular expressions matched against the error. When set, only calls
+    /// whose error matches one are retried, including results the tool
+    /// marked as errors. When empty, calls that failed outright (a timeout,
+    /// a closed transport, a JSON-RPC error) are retried.
+    pub retry_on: Vec<String>,
+}
+
+impl Default for RetrySettings {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            backoff_ms: 200,
+            max_backoff_ms: 5000,
+            retry_on: Vec::new(),
+        }
+    }
+}
+
+/// Failing fast while the downstream server keeps failing
+/// (`[circuit_breaker]` in a config file).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct CircuitBreakerSettings {
+    /// Consecutive failed calls that open the circuit.
+    pub failure_threshold: u32,
+    /// How long calls fail fast before one is let through as a probe.
+    pub open_secs: u64,
+}
+
+impl Default for CircuitBreakerSettings {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            open_secs: 30,
+        }
+    }
+}
+
+/// Timeouts of single downstream tool calls, from scripts and passed
+/// through (`[call_timeout]` in a config file).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct CallTimeoutSettings {
+    /// Timeout of every call, and the longest an adaptive timeout gets.
+    pub max_ms: u64,
+    /// Derive each tool's timeout from the p99 latency of its recent calls.
+    pub adaptive: bool,
+    /// The shortest an adaptive timeout gets.
+    pub min_ms: u64,
+    /// Adaptive timeouts are this many times the tool's p99 latency.
+    pub p99_multiplier: f64,
+}
+
+impl Default for CallTimeoutSettings {
+    fn default() -> Self {
+        Self {
+            max_ms: 60_000,
+            adaptive: false,
+            min_ms: 1000,
+            p99_multiplier: 3.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
 pub struct CodeModeConfig {
     pub mode: CodeModeExposure,
     pub tool_name: String,
+    /// What to do when a downstream tool is already named `tool_name`.
+    pub tool_name_collision: ToolNameCollision,
     pub tool_description: String,
+    /// Icons shown for the execute tool by GUI clients.
+    pub tool_icons: Option<Vec<Icon>>,
     pub include_tools: Option<Vec<String>>,
+    /// Tools to hide even when `include_tools` would allow them.
+    pub exclude_tools: Vec<String>,
+    /// Downstream tool name to the name it is exposed as, for names that are
+    /// awkward or invalid as JavaScript identifiers. `include_tools` and
+    /// `exclude_tools` match downstream names; everything else, including
+    /// `tools.<name>` settings, uses the exposed name.
+    pub aliases: BTreeMap<String, String>,
+    /// Prefix for passthrough tools (`kicad_move_footprint`) and the object
+    /// scripts call them through (`tools.kicad.move_footprint`).
+    pub namespace: Option<String>,
+    /// Also let scripts call snake_case and kebab-case tools by their
+    /// camelCase names (`tools.moveFootprint`), which the TypeScript
+    /// interface declares too.
+    pub camel_case_aliases: bool,
+    pub tools: BTreeMap<String, ToolSettings>,
+    /// Reuse results of identical read-only tool calls within one execution.
+    pub dedupe_read_only_calls: bool,
+    /// Validate results handed to scripts against the tool's `outputSchema`.
+    pub schema_drift: SchemaDriftAction,
+    /// Add a `get_tool_docs` tool that returns full signatures on request.
+    /// Implies `interface_detail = "summaries"` unless that is set.
+    pub lazy_tool_docs: bool,
+    /// How much of the tool interface the execute tool's description embeds;
+    /// see `CodeModeConfig::interface_detail()` for the default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interface_detail: Option<InterfaceDetail>,
+    /// Wall-clock limit for a single script, in milliseconds.
+    pub timeout_ms: Option<u64>,
+    /// How long a cancelled script may keep running, with `cancelled()`
+    /// returning true, to return what it has.
+    pub cancel_grace_ms: u64,
+    /// Sessions and continuations unused for this many seconds are discarded.
+    pub session_ttl_secs: u64,
+    /// List the downstream tools again before an execution once the list
+    /// scripts see is this many seconds old. Without it, the list is only
+    /// refreshed when the downstream server says it changed or a client
+    /// lists the tools.
+    pub tools_ttl_secs: Option<u64>,
+    /// Answer `tools/list` in pages of this many tools, with a cursor to the
+    /// next. Without it, every tool is listed in one response.
+    pub tools_page_size: Option<usize>,
+    /// How many executions paused with `yieldToAgent` may wait to be
+    /// continued; 0 disables `yieldToAgent`. JavaScript engine only.
+    pub max_continuations: usize,
+    /// Add `start_execution`, `get_execution` and `cancel_execution` tools
+    /// for running scripts in the background. Finished executions are kept
+    /// for `session_ttl_secs`.
+    pub background_executions: bool,
+    /// Tool calls fail with a "budget exceeded" error once a single
+    /// execution has cost this much; see `ToolSettings::cost`.
+    pub max_execution_cost: Option<f64>,
+    /// Like `max_execution_cost`, for all executions in a session together.
+    pub max_session_cost: Option<f64>,
+    /// Add a `codemode_stats` tool reporting calls, errors and durations
+    /// per downstream tool since startup.
+    pub stats_tool: bool,
+    /// Report in each result's `_meta.provenance` the downstream calls whose
+    /// results ended up in its value.
+    pub provenance: bool,
+    /// How many recent executions the `codemode://summary` resource lists;
+    /// 0 disables it.
+    pub summary_history: usize,
+    /// Write a repro bundle of every execution to this directory, for
+    /// `codemoder export-run` and `codemoder exec --replay`.
+    pub record_dir: Option<PathBuf>,
+    /// How long `sample()` waits for the client's model to reply.
+    pub sampling_timeout_secs: u64,
+    /// How long `elicit()` waits for the user to answer.
+    pub elicitation_timeout_secs: u64,
+    /// Accept TypeScript in `code` by stripping type annotations before running.
+    pub typescript: bool,
+    /// Give scripts the `stats` global (sum, mean, median, percentile,
+    /// histogram) and describe it in the execute tool's description.
+    pub stats_helpers: bool,
+    /// Give scripts `uuid()`, `sha256(value)` and `hmac(key, value)`.
+    pub hash_helpers: bool,
+    /// Give scripts `llm.complete(prompt, options)`, which asks the client's
+    /// model for a completion through sampling.
+    pub llm_helpers: bool,
+    /// Run JavaScript regular expressions on a linear-time engine, so a
+    /// pattern can't backtrack past the timeout.
+    pub safe_regex: bool,
+    /// Send each `console.log` line to the client as a logging notification
+    /// while the script runs, as well as returning it in the result.
+    pub stream_logs: bool,
+    /// Refuse script tool calls whose path-like arguments fall outside the
+    /// client's roots, as well as those in `ToolSettings::path_args`.
+    pub check_paths: bool,
+    /// Note what was wrong with the arguments of failed script tool calls
+    /// under the tool in the execute tool's description.
+    pub failure_notes: bool,
+    /// Refuse script tool calls with argument fields the tool's input schema
+    /// doesn't declare, unless it allows additional properties.
+    pub strict_args: bool,
+    /// Convert script tool call arguments of the wrong type to the one the
+    /// tool's input schema declares when that's unambiguous, like `"5"` to
+    /// `5`.
+    pub coerce_args: bool,
+    /// How long a drain waits for running executions before shutting down.
+    pub drain_timeout_secs: u64,
+    /// Which script engine runs `execute_tools` code.
+    pub engine: ScriptEngine,
+    /// Ask the configured approver before running each script.
+    pub approve_scripts: bool,
+    /// Ask the human at the controlling terminal to approve guarded scripts
+    /// and tool calls.
+    pub approve_tty: bool,
+    /// Check every downstream tool call against this policy.
+    pub policy: Option<PolicySettings>,
+    /// Retry failed downstream tool calls.
+    pub retry: Option<RetrySettings>,
+    /// Fail calls fast while the downstream server keeps failing.
+    pub circuit_breaker: Option<CircuitBreakerSettings>,
+    /// Fail downstream calls that take too long.
+    pub call_timeout: Option<CallTimeoutSettings>,
+    /// Named partial configs (`[profiles.prod]`) merged over the top-level
+    /// settings when selected with `--profile`.
+    #[serde(skip_serializing)]
+    #[schemars(with = "BTreeMap<String, JsonObject>")]
+    pub profiles: BTreeMap<String, toml::Table>,
+    /// The profile this config was loaded with, if any.
+    #[serde(skip)]
+    pub profile: Option<String>,
 }
 
 impl Default for CodeModeConfig {
@@ -20,7 +357,8 @@ impl Default for CodeModeConfig {
         Self {
             mode: CodeModeExposure::default(),
             tool_name: "execute_tools".to_string(),
-            tool_description: r#"Execute JavaScript code with access to MCP tools. The code has access to a `tools` object with synchronous functions for each tool. The last expression is returned as the result. Use `console.log()` to debug.
+            tool_name_collision: ToolNameCollision::default(),
+            tool_description: r#"Execute JavaScript (or TypeScript) code with access to MCP tools. The code has access to a `tools` object with synchronous functions for each tool. The last expression is returned as the result. Use `console.log()` to debug.
 
 ## Important syntax rules
 
@@ -53,7 +391,52 @@ var items = tools.get_items({}).filter(function(x) { return x.value > 10; });
 items.map(function(x) { return x.name; });
 ```"#
                 .to_string(),
+            tool_icons: None,
             include_tools: None,
+            exclude_tools: Vec::new(),
+            aliases: BTreeMap::new(),
+            namespace: None,
+            camel_case_aliases: false,
+            tools: BTreeMap::new(),
+            dedupe_read_only_calls: false,
+            schema_drift: SchemaDriftAction::default(),
+            lazy_tool_docs: false,
+            interface_detail: None,
+            timeout_ms: None,
+            cancel_grace_ms: 1000,
+            session_ttl_secs: 30 * 60,
+            tools_ttl_secs: None,
+            tools_page_size: None,
+            max_continuations: 0,
+            background_executions: false,
+            max_execution_cost: None,
+            max_session_cost: None,
+            summary_history: 20,
+            record_dir: None,
+            sampling_timeout_secs: 60,
+            elicitation_timeout_secs: 10 * 60,
+            typescript: true,
+            stats_helpers: false,
+            hash_helpers: false,
+            llm_helpers: false,
+            stats_tool: false,
+            provenance: false,
+            safe_regex: false,
+            stream_logs: false,
+            check_paths: false,
+            failure_notes: false,
+            strict_args: false,
+            coerce_args: false,
+            drain_timeout_secs: 30,
+            engine: ScriptEngine::default(),
+            approve_scripts: false,
+            approve_tty: false,
+            policy: None,
+            retry: None,
+            circuit_breaker: None,
+            call_timeout: None,
+            profiles: BTreeMap::new(),
+            profile: None,
         }
     }
 }
@@ -63,6 +446,154 @@ impl CodeModeConfig {
         Self::default()
     }
 
+    /// Parse and validate a TOML config file. Missing fields fall back to
+    /// their defaults; unknown fields are rejected. Errors carry the line and
+    /// column of the offending value.
+    pub fn from_toml_str(s: &str) -> Result<Self> {
+        Self::from_toml_str_with_profile(s, None)
+    }
+
+    /// Like `from_toml_str`, with `[profiles.<profile>]` merged over the
+    /// top-level settings. Tables merge key by key; other values replace.
+    pub fn from_toml_str_with_profile(s: &str, profile: Option<&str>) -> Result<Self> {
+        Self::parse(s, profile, &|name| std::env::var(name).ok())
+    }
+
+    fn parse(s: &str, profile: Option<&str>, env: &dyn Fn(&str) -> Option<String>) -> Result<Self> {
+        // Parse once as-is so structural errors keep their line and column.
+        let base: Self = toml::from_str(s).map_err(|e| anyhow::anyhow!("{e}"))?;
+        let mut table: toml::Table = toml::from_str(s)?;
+        table.remove("profiles");
+
+        if let Some(name) = profile {
+            let Some(overrides) = base.profiles.get(name) else {
+                let available: Vec<&str> = base.profiles.keys().map(String::as_str).collect();
+                anyhow::bail!(
+                    "Unknown profile `{name}` (available: {})",
+                    if available.is_empty() {
+                        "none".to_string()
+                    } else {
+                        available.join(", ")
+                    }
+                );
+            };
+            merge_tables(&mut table, overrides.clone());
+        }
+
+        let in_profile = |e: anyhow::Error| match profile {
+            Some(name) => e.context(format!("In profile `{name}`")),
+            None => e,
+        };
+        interpolate_table(&mut table, "", env).map_err(in_profile)?;
+        let mut config: Self = toml::Value::Table(table)
+            .try_into()
+            .map_err(|e| in_profile(anyhow::anyhow!("{e}")))?;
+        config.validate().map_err(in_profile)?;
+        config.profiles = base.profiles;
+        config.profile = profile.map(str::to_string);
+        Ok(config)
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_file_with_profile(path, None)
+    }
+
+    pub fn from_file_with_profile(path: impl AsRef<Path>, profile: Option<&str>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        Self::from_toml_str_with_profile(&contents, profile)
+            .with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    /// JSON Schema describing the config file.
+    pub fn json_schema() -> serde_json::Value {
+        serde_json::to_value(schemars::schema_for!(CodeModeConfig)).unwrap_or_default()
+    }
+
+    /// Check constraints the TOML types alone can't express.
+    pub fn validate(&self) -> Result<()> {
+        if self.tool_name.is_empty() {
+            anyhow::bail!("tool_name must not be empty");
+        }
+        if self.timeout_ms == Some(0) {
+            anyhow::bail!("timeout_ms must be greater than 0");
+        }
+        if self.tools_ttl_secs == Some(0) {
+            anyhow::bail!("tools_ttl_secs must be greater than 0");
+        }
+        if self.tools_page_size == Some(0) {
+            anyhow::bail!("tools_page_size must be greater than 0");
+        }
+        for (name, settings) in &self.tools {
+            if settings.max_concurrent == Some(0) {
+                anyhow::bail!("tools.{name}.max_concurrent must be greater than 0");
+            }
+            if settings.latency_slo_ms == Some(0) {
+                anyhow::bail!("tools.{name}.latency_slo_ms must be greater than 0");
+            }
+        }
+        if let Some(policy) = &self.policy
+            && policy.cedar_file.is_some() == policy.opa_url.is_some()
+        {
+            anyhow::bail!("policy must set exactly one of cedar_file and opa_url");
+        }
+        if let Some(retry) = &self.retry {
+            if retry.max_attempts == 0 {
+                anyhow::bail!("retry.max_attempts must be greater than 0");
+            }
+            for pattern in &retry.retry_on {
+                if let Err(e) = regex::Regex::new(pattern) {
+                    anyhow::bail!("retry.retry_on pattern `{pattern}` is invalid: {e}");
+                }
+            }
+        }
+        if let Some(circuit) = &self.circuit_breaker
+            && circuit.failure_threshold == 0
+        {
+            anyhow::bail!("circuit_breaker.failure_threshold must be greater than 0");
+        }
+        if let Some(timeout) = &self.call_timeout {
+            if timeout.max_ms == 0 {
+                anyhow::bail!("call_timeout.max_ms must be greater than 0");
+            }
+            if timeout.adaptive && timeout.min_ms > timeout.max_ms {
+                anyhow::bail!("call_timeout.min_ms must not be greater than max_ms");
+            }
+            if !(timeout.p99_multiplier >= 1.0 && timeout.p99_multiplier.is_finite()) {
+                anyhow::bail!("call_timeout.p99_multiplier must be at least 1");
+            }
+        }
+        if cfg!(feature = "minimal") && self.record_dir.is_some() {
+            anyhow::bail!("record_dir writes to disk and can't be used in a `minimal` build");
+        }
+        if let Some(namespace) = &self.namespace {
+            let valid = namespace
+                .chars()
+                .next()
+                .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                && namespace
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !valid {
+                anyhow::bail!("namespace `{namespace}` must be a valid identifier");
+            }
+        }
+        let mut exposed = HashSet::new();
+        for (name, alias) in &self.aliases {
+            if alias.is_empty() {
+                anyhow::bail!("aliases.\"{name}\" must not be empty");
+            }
+            if *alias == self.tool_name {
+                anyhow::bail!("aliases.\"{name}\" conflicts with tool_name `{alias}`");
+            }
+            if !exposed.insert(alias) {
+                anyhow::bail!("More than one tool is aliased to `{alias}`");
+            }
+        }
+        Ok(())
+    }
+
     pub fn replace_tools(mut self) -> Self {
         self.mode = CodeModeExposure::ReplaceTools;
         self
@@ -78,15 +609,498 @@ impl CodeModeConfig {
         self
     }
 
+    pub fn with_tool_name_collision(mut self, collision: ToolNameCollision) -> Self {
+        self.tool_name_collision = collision;
+        self
+    }
+
+    /// Keep `tool_name` from shadowing one of `exposed`, the downstream
+    /// tools as clients see them, per `tool_name_collision`. Passthrough
+    /// tools aren't listed in `replace_tools` mode, so nothing collides.
+    pub fn resolve_tool_name(&mut self, exposed: &[String]) -> Result<()> {
+        if matches!(self.mode, CodeModeExposure::ReplaceTools) || !exposed.contains(&self.tool_name)
+        {
+            return Ok(());
+        }
+        if self.tool_name_collision == ToolNameCollision::Fail {
+            anyhow::bail!(
+                "The downstream server already has a tool named `{}`; set tool_name to another name",
+                self.tool_name
+            );
+        }
+        let renamed = (2..)
+            .map(|n| format!("{}_{n}", self.tool_name))
+            .find(|name| !exposed.contains(name))
+            .unwrap_or_default();
+        tracing::warn!(
+            "The downstream server already has a tool named `{}`; exposing the execute tool as `{renamed}`",
+            self.tool_name
+        );
+        self.tool_name = renamed;
+        Ok(())
+    }
+
     pub fn with_description(mut self, desc: impl Into<String>) -> Self {
         self.tool_description = desc.into();
         self
     }
 
+    pub fn with_tool_icon(mut self, icon: Icon) -> Self {
+        self.tool_icons.get_or_insert_default().push(icon);
+        self
+    }
+
     pub fn only_tools(mut self, tools: Vec<String>) -> Self {
         self.include_tools = Some(tools);
         self
     }
+
+    pub fn exclude_tools(mut self, tools: Vec<String>) -> Self {
+        self.exclude_tools = tools;
+        self
+    }
+
+    /// Expose downstream tool `name` as `alias`.
+    pub fn with_alias(mut self, name: impl Into<String>, alias: impl Into<String>) -> Self {
+        self.aliases.insert(name.into(), alias.into());
+        self
+    }
+
+    pub fn with_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.namespace = Some(namespace.into());
+        self
+    }
+
+    pub fn with_camel_case_aliases(mut self, enabled: bool) -> Self {
+        self.camel_case_aliases = enabled;
+        self
+    }
+
+    /// Whether a downstream tool passes `include_tools` and `exclude_tools`.
+    pub fn includes_tool(&self, name: &str) -> bool {
+        self.include_tools
+            .as_ref()
+            .is_none_or(|include| include.iter().any(|n| n == name))
+            && !self.exclude_tools.iter().any(|n| n == name)
+    }
+
+    /// Allow at most `limit` concurrent calls to `tool`.
+    pub fn with_max_concurrent(mut self, tool: impl Into<String>, limit: usize) -> Self {
+        self.tools.entry(tool.into()).or_default().max_concurrent = Some(limit);
+        self
+    }
+
+    /// Return `tool`'s results to scripts as a lazy reader.
+    pub fn with_lazy_result(mut self, tool: impl Into<String>) -> Self {
+        self.tools.entry(tool.into()).or_default().lazy_result = true;
+        self
+    }
+
+    /// Ask the approver before each call to `tool`.
+    pub fn with_required_approval(mut self, tool: impl Into<String>) -> Self {
+        self.tools.entry(tool.into()).or_default().require_approval = true;
+        self
+    }
+
+    /// Names of tools configured with `lazy_result`.
+    pub fn lazy_tools(&self) -> HashSet<String> {
+        self.tools
+            .iter()
+            .filter(|(_, settings)| settings.lazy_result)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    pub fn dedupe_read_only_calls(mut self, enabled: bool) -> Self {
+        self.dedupe_read_only_calls = enabled;
+        self
+    }
+
+    pub fn with_schema_drift(mut self, action: SchemaDriftAction) -> Self {
+        self.schema_drift = action;
+        self
+    }
+
+    pub fn with_lazy_tool_docs(mut self, enabled: bool) -> Self {
+        self.lazy_tool_docs = enabled;
+        self
+    }
+
+    pub fn with_interface_detail(mut self, detail: InterfaceDetail) -> Self {
+        self.interface_detail = Some(detail);
+        self
+    }
+
+    /// The configured `interface_detail`, or `summaries` with
+    /// `lazy_tool_docs` and `full` otherwise.
+    pub fn interface_detail(&self) -> InterfaceDetail {
+        self.interface_detail.unwrap_or(if self.lazy_tool_docs {
+            InterfaceDetail::Summaries
+        } else {
+            InterfaceDetail::Full
+        })
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout_ms = Some(timeout.as_millis() as u64);
+        self
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout_ms.map(Duration::from_millis)
+    }
+
+    pub fn with_cancel_grace(mut self, grace: Duration) -> Self {
+        self.cancel_grace_ms = grace.as_millis() as u64;
+        self
+    }
+
+    pub fn cancel_grace(&self) -> Duration {
+        Duration::from_millis(self.cancel_grace_ms)
+    }
+
+    pub fn with_session_ttl(mut self, ttl: Duration) -> Self {
+        self.session_ttl_secs = ttl.as_secs();
+        self
+    }
+
+    pub fn with_max_continuations(mut self, max: usize) -> Self {
+        self.max_continuations = max;
+        self
+    }
+
+    pub fn with_summary_history(mut self, executions: usize) -> Self {
+        self.summary_history = executions;
+        self
+    }
+
+    pub fn with_record_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.record_dir = Some(dir.into());
+        self
+    }
+
+    pub fn with_sampling_timeout(mut self, timeout: Duration) -> Self {
+        self.sampling_timeout_secs = timeout.as_secs();
+        self
+    }
+
+    pub fn sampling_timeout(&self) -> Duration {
+        Duration::from_secs(self.sampling_timeout_secs)
+    }
+
+    pub fn with_elicitation_timeout(mut self, timeout: Duration) -> Self {
+        self.elicitation_timeout_secs = timeout.as_secs();
+        self
+    }
+
+    pub fn elicitation_timeout(&self) -> Duration {
+        Duration::from_secs(self.elicitation_timeout_secs)
+    }
+
+    pub fn with_max_execution_cost(mut self, max: f64) -> Self {
+        self.max_execution_cost = Some(max);
+        self
+    }
+
+    pub fn with_max_session_cost(mut self, max: f64) -> Self {
+        self.max_session_cost = Some(max);
+        self
+    }
+
+    /// Whether scripts may call `yieldToAgent`.
+    pub fn continuations_enabled(&self) -> bool {
+        self.max_continuations > 0 && self.engine == ScriptEngine::Javascript
+    }
+
+    pub fn with_background_executions(mut self, enabled: bool) -> Self {
+        self.background_executions = enabled;
+        self
+    }
+
+    pub fn typescript(mut self, enabled: bool) -> Self {
+        self.typescript = enabled;
+        self
+    }
+
+    pub fn with_stats_helpers(mut self, enabled: bool) -> Self {
+        self.stats_helpers = enabled;
+        self
+    }
+
+    pub fn with_hash_helpers(mut self, enabled: bool) -> Self {
+        self.hash_helpers = enabled;
+        self
+    }
+
+    pub fn with_stats_tool(mut self, enabled: bool) -> Self {
+        self.stats_tool = enabled;
+        self
+    }
+
+    pub fn with_provenance(mut self, enabled: bool) -> Self {
+        self.provenance = enabled;
+        self
+    }
+
+    pub fn with_llm_helpers(mut self, enabled: bool) -> Self {
+        self.llm_helpers = enabled;
+        self
+    }
+
+    pub fn with_safe_regex(mut self, enabled: bool) -> Self {
+        self.safe_regex = enabled;
+        self
+    }
+
+    pub fn with_stream_logs(mut self, enabled: bool) -> Self {
+        self.stream_logs = enabled;
+        self
+    }
+
+    pub fn with_check_paths(mut self, enabled: bool) -> Self {
+        self.check_paths = enabled;
+        self
+    }
+
+    pub fn with_failure_notes(mut self, enabled: bool) -> Self {
+        self.failure_notes = enabled;
+        self
+    }
+
+    pub fn with_strict_args(mut self, enabled: bool) -> Self {
+        self.strict_args = enabled;
+        self
+    }
+
+    pub fn with_coerce_args(mut self, enabled: bool) -> Self {
+        self.coerce_args = enabled;
+        self
+    }
+
+    pub fn with_retry(mut self, retry: RetrySettings) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    pub fn with_circuit_breaker(mut self, circuit_breaker: CircuitBreakerSettings) -> Self {
+        self.circuit_breaker = Some(circuit_breaker);
+        self
+    }
+
+    pub fn with_call_timeout(mut self, call_timeout: CallTimeoutSettings) -> Self {
+        self.call_timeout = Some(call_timeout);
+        self
+    }
+
+    pub fn with_engine(mut self, engine: ScriptEngine) -> Self {
+        self.engine = engine;
+        self
+    }
+
+    pub fn with_drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout_secs = timeout.as_secs();
+        self
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.drain_timeout_secs)
+    }
+
+    pub fn session_ttl(&self) -> Duration {
+        Duration::from_secs(self.session_ttl_secs)
+    }
+
+    pub fn with_tools_ttl(mut self, ttl: Duration) -> Self {
+        self.tools_ttl_secs = Some(ttl.as_secs());
+        self
+    }
+
+    pub fn tools_ttl(&self) -> Option<Duration> {
+        self.tools_ttl_secs.map(Duration::from_secs)
+    }
+
+    pub fn with_tools_page_size(mut self, size: usize) -> Self {
+        self.tools_page_size = Some(size);
+        self
+    }
+}
+
+/// Expand `${VAR}` references in every string value. `${VAR:-default}`
+/// falls back to `default` when `VAR` is unset or empty, `${VAR:?message}`
+/// fails with `message`, and `$$` is a literal `$`.
+fn interpolate_table(
+    table: &mut toml::Table,
+    prefix: &str,
+    env: &dyn Fn(&str) -> Option<String>,
+) -> Result<()> {
+    for (key, value) in table.iter_mut() {
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{prefix}.{key}")
+        };
+        interpolate_value(value, &path, env)?;
+    }
+    Ok(())
+}
+
+fn interpolate_value(
+    value: &mut toml::Value,
+    path: &str,
+    env: &dyn Fn(&str) -> Option<String>,
+) -> Result<()> {
+    match value {
+        toml::Value::String(s) => {
+            *s = interpolate_env(s, env).with_context(|| format!("Invalid value for {path}"))?;
+        }
+        toml::Value::Array(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                interpolate_value(item, &format!("{path}[{i}]"), env)?;
+            }
+        }
+        toml::Value::Table(table) => interpolate_table(table, path, env)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+fn interpolate_env(s: &str, env: &dyn Fn(&str) -> Option<String>) -> Result<String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(pos) = rest.find('$') {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        if let Some(after) = rest.strip_prefix("$$") {
+            out.push('$');
+            rest = after;
+            continue;
+        }
+        let Some(body) = rest.strip_prefix("${") else {
+            out.push('$');
+            rest = &rest[1..];
+            continue;
+        };
+        let Some(end) = body.find('}') else {
+            anyhow::bail!("Unterminated `${{` in \"{s}\"");
+        };
+        let (expr, after) = (&body[..end], &body[end + 1..]);
+        let (name, fallback) = match expr.split_once(":-") {
+            Some((name, default)) => (name, Some(Ok(default))),
+            None => match expr.split_once(":?") {
+                Some((name, message)) => (name, Some(Err(message))),
+                None => (expr, None),
+            },
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            anyhow::bail!("Invalid environment variable name `{name}`");
+        }
+        match (env(name).filter(|v| !v.is_empty()), fallback) {
+            (Some(value), _) => out.push_str(&value),
+            (None, Some(Ok(default))) => out.push_str(default),
+            (None, Some(Err(message))) => {
+                anyhow::bail!("Environment variable {name} is required: {message}")
+            }
+            (None, None) => anyhow::bail!("Environment variable {name} is not set"),
+        }
+        rest = after;
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn merge_tables(base: &mut toml::Table, overrides: toml::Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(value)) => {
+                merge_tables(base, value)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Extra line for the execute tool's "Notes" section describing lazy results.
+pub fn lazy_tools_note(config: &CodeModeConfig) -> String {
+    let mut lazy: Vec<String> = config.lazy_tools().into_iter().collect();
+    if lazy.is_empty() {
+        return String::new();
+    }
+    lazy.sort();
+    format!(
+        "\n- These tools return a reader instead of a string, because their results can be very large: {}. Use `result.length`, `result.read(offset, len)` and `result.text()`",
+        lazy.join(", ")
+    )
+}
+
+/// How this build and config are set up, advertised to clients under the
+/// `codemoder` key of the server's experimental capabilities.
+pub fn capability_report(
+    config: &CodeModeConfig,
+    downstream: Option<&Implementation>,
+) -> JsonObject {
+    let max_concurrent: BTreeMap<&String, usize> = config
+        .tools
+        .iter()
+        .filter_map(|(name, settings)| settings.max_concurrent.map(|limit| (name, limit)))
+        .collect();
+
+    let report = serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "engine": config.engine,
+        "features": crate::features::enabled(),
+        "mode": config.mode,
+        "profile": config.profile,
+        "toolName": config.tool_name,
+        "toolNameCollision": config.tool_name_collision,
+        "typescript": config.typescript,
+        "statsHelpers": config.stats_helpers,
+        "hashHelpers": config.hash_helpers,
+        "llmHelpers": config.llm_helpers,
+        "safeRegex": config.safe_regex,
+        "streamLogs": config.stream_logs,
+        "checkPaths": config.check_paths,
+        "failureNotes": config.failure_notes,
+        "strictArgs": config.strict_args,
+        "coerceArgs": config.coerce_args,
+        "camelCaseAliases": config.camel_case_aliases,
+        "dedupeReadOnlyCalls": config.dedupe_read_only_calls,
+        "schemaDrift": config.schema_drift,
+        "lazyToolDocs": config.lazy_tool_docs,
+        "backgroundExecutions": config.background_executions,
+        "statsTool": config.stats_tool,
+        "provenance": config.provenance,
+        "interfaceDetail": config.interface_detail(),
+        "limits": {
+            "timeoutMs": config.timeout_ms,
+            "cancelGraceMs": config.cancel_grace_ms,
+            "sessionTtlSecs": config.session_ttl_secs,
+            "toolsTtlSecs": config.tools_ttl_secs,
+            "toolsPageSize": config.tools_page_size,
+            "maxContinuations": config.max_continuations,
+            "maxExecutionCost": config.max_execution_cost,
+            "maxSessionCost": config.max_session_cost,
+            "summaryHistory": config.summary_history,
+            "recording": config.record_dir.is_some(),
+            "samplingTimeoutSecs": config.sampling_timeout_secs,
+            "elicitationTimeoutSecs": config.elicitation_timeout_secs,
+            "drainTimeoutSecs": config.drain_timeout_secs,
+            "maxConcurrent": max_concurrent,
+            "retryAttempts": config.retry.as_ref().map(|r| r.max_attempts),
+            "circuitFailureThreshold": config
+                .circuit_breaker
+                .as_ref()
+                .map(|c| c.failure_threshold),
+            "callTimeoutMs": config.call_timeout.as_ref().map(|t| t.max_ms),
+            "adaptiveCallTimeouts": config.call_timeout.as_ref().is_some_and(|t| t.adaptive),
+        },
+        "downstream": downstream.map(|info| serde_json::json!({
+            "name": info.name,
+            "version": info.version,
+        })),
+    });
+    report.as_object().cloned().unwrap_or_default()
 }
 
 #[cfg(test)]
@@ -101,6 +1115,25 @@ mod tests {
         assert!(config.include_tools.is_none());
     }
 
+    #[test]
+    fn test_resolve_tool_name() {
+        let exposed = vec!["search".to_string(), "search_2".to_string()];
+        let mut config = CodeModeConfig::new().with_tool_name("search");
+        config.resolve_tool_name(&exposed).unwrap();
+        assert_eq!(config.tool_name, "search_3");
+
+        let mut config = CodeModeConfig::new()
+            .with_tool_name("search")
+            .with_tool_name_collision(ToolNameCollision::Fail);
+        assert!(config.resolve_tool_name(&exposed).is_err());
+        config.resolve_tool_name(&["other".to_string()]).unwrap();
+
+        // Nothing is shadowed when passthrough tools aren't listed
+        let mut config = config.replace_tools();
+        config.resolve_tool_name(&exposed).unwrap();
+        assert_eq!(config.tool_name, "search");
+    }
+
     #[test]
     fn test_builder_pattern() {
         let config = CodeModeConfig::new()
@@ -115,4 +1148,184 @@ mod tests {
             Some(vec!["tool1".to_string(), "tool2".to_string()])
         );
     }
+
+    #[test]
+    fn test_capability_report() {
+        let config = CodeModeConfig::new()
+            .with_timeout(Duration::from_secs(5))
+            .with_max_concurrent("render_board", 2);
+        let downstream = Implementation {
+            name: "pcb-server".to_string(),
+            version: "1.2.0".to_string(),
+            ..Default::default()
+        };
+
+        let report = capability_report(&config, Some(&downstream));
+        assert_eq!(report["engine"], "javascript");
+        assert_eq!(report["limits"]["timeoutMs"], 5000);
+        assert_eq!(report["limits"]["maxConcurrent"]["render_board"], 2);
+        assert_eq!(report["downstream"]["name"], "pcb-server");
+    }
+
+    #[test]
+    fn test_from_toml_str() {
+        let config = CodeModeConfig::from_toml_str(
+            r#"
+            mode = "replace"
+            tool_name = "run_script"
+
+            [[tool_icons]]
+            src = "https://example.com/code.svg"
+            mimeType = "image/svg+xml"
+
+            [tools.render_board]
+            max_concurrent = 1
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.tool_name, "run_script");
+        assert!(matches!(config.mode, CodeModeExposure::ReplaceTools));
+        assert_eq!(config.tools["render_board"].max_concurrent, Some(1));
+        assert!(config.tool_description.contains("Execute JavaScript"));
+        let icons = config.tool_icons.unwrap();
+        assert_eq!(icons[0].mime_type.as_deref(), Some("image/svg+xml"));
+    }
+
+    #[test]
+    fn test_invalid_config_errors() {
+        let err = CodeModeConfig::from_toml_str("tool_name = \"x\"\ntimeout = 5\n").unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("line 2, column 1"), "{message}");
+        assert!(message.contains("unknown field `timeout`"), "{message}");
+
+        let err =
+            CodeModeConfig::from_toml_str("[tools.render]\nmax_concurrent = 0\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "tools.render.max_concurrent must be greater than 0"
+        );
+
+        let err = CodeModeConfig::from_toml_str("[retry]\nretry_on = [\"(\"]\n").unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("retry.retry_on pattern `(` is invalid"),
+            "{err}"
+        );
+
+        let err = CodeModeConfig::from_toml_str(
+            "[call_timeout]\nadaptive = true\nmin_ms = 5000\nmax_ms = 2000\n",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "call_timeout.min_ms must not be greater than max_ms"
+        );
+
+        let recording = CodeModeConfig::from_toml_str("record_dir = \"runs\"\n");
+        assert_eq!(recording.is_err(), cfg!(feature = "minimal"));
+    }
+
+    #[test]
+    fn test_interface_detail() {
+        assert_eq!(
+            CodeModeConfig::default().interface_detail(),
+            InterfaceDetail::Full
+        );
+        let lazy = CodeModeConfig::new().with_lazy_tool_docs(true);
+        assert_eq!(lazy.interface_detail(), InterfaceDetail::Summaries);
+        let config =
+            CodeModeConfig::from_toml_str("lazy_tool_docs = true\ninterface_detail = \"names\"\n")
+                .unwrap();
+        assert_eq!(config.interface_detail(), InterfaceDetail::Names);
+    }
+
+    #[test]
+    fn test_profiles() {
+        let toml = r#"
+            timeout_ms = 30000
+
+            [tools.render_board]
+            max_concurrent = 4
+            read_only = true
+
+            [profiles.prod]
+            timeout_ms = 5000
+            tools.render_board.max_concurrent = 1
+        "#;
+
+        let base = CodeModeConfig::from_toml_str(toml).unwrap();
+        assert_eq!(base.timeout_ms, Some(30000));
+        assert_eq!(base.profile, None);
+
+        let prod = CodeModeConfig::from_toml_str_with_profile(toml, Some("prod")).unwrap();
+        assert_eq!(prod.timeout_ms, Some(5000));
+        assert_eq!(prod.tools["render_board"].max_concurrent, Some(1));
+        assert_eq!(prod.tools["render_board"].read_only, Some(true));
+        assert_eq!(prod.profile.as_deref(), Some("prod"));
+
+        let err = CodeModeConfig::from_toml_str_with_profile(toml, Some("dev")).unwrap_err();
+        assert_eq!(err.to_string(), "Unknown profile `dev` (available: prod)");
+    }
+
+    #[test]
+    fn test_env_interpolation() {
+        let env = |name: &str| match name {
+            "TEAM" => Some("pcb".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        };
+        let toml = r#"
+            tool_name = "${TEAM}_tools"
+            tool_description = "${REGION:-us} ${EMPTY:-none} costs $$5"
+            include_tools = ["${TEAM}_search"]
+
+            [profiles.prod]
+            tool_description = "${TOKEN:?set TOKEN for prod}"
+        "#;
+
+        let config = CodeModeConfig::parse(toml, None, &env).unwrap();
+        assert_eq!(config.tool_name, "pcb_tools");
+        assert_eq!(config.tool_description, "us none costs $5");
+        assert_eq!(config.include_tools, Some(vec!["pcb_search".to_string()]));
+
+        let err = CodeModeConfig::parse(toml, Some("prod"), &env).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "In profile `prod`: Invalid value for tool_description: \
+             Environment variable TOKEN is required: set TOKEN for prod"
+        );
+
+        let err = CodeModeConfig::parse(r#"tool_name = "${MISSING}""#, None, &env).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "Invalid value for tool_name: Environment variable MISSING is not set"
+        );
+    }
+
+    #[test]
+    fn test_aliases() {
+        let config = CodeModeConfig::from_toml_str(
+            r#"
+            [aliases]
+            "repos/list" = "list_repos"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.aliases["repos/list"], "list_repos");
+
+        let err = CodeModeConfig::new()
+            .with_alias("a", "same")
+            .with_alias("b", "same")
+            .validate()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "More than one tool is aliased to `same`");
+    }
+
+    #[test]
+    fn test_json_schema() {
+        let schema = CodeModeConfig::json_schema();
+        assert!(schema["properties"]["timeout_ms"].is_object());
+        assert!(schema["properties"]["tools"].is_object());
+    }
 }
@@ -0,0 +1,268 @@
+//! Turns a recorded sequence of MCP tool calls into an equivalent script,
+//! used by `codemoder convert-trace`.
+//!
+//! A trace is a JSON array, or JSON lines, of either plain call records
+//! (`{"name", "arguments", "result"?}`, with `tool`/`args`/`input` accepted
+//! as aliases) or raw JSON-RPC messages, where `tools/call` requests are
+//! paired with their responses by `id`. Other messages are skipped.
+//!
+//! When results were recorded, string arguments that appeared in an earlier
+//! result are replaced by a reference into that result, so the script
+//! carries data from one call to the next the way the agent did.
+
+use anyhow::{Context, Result};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// Strings shorter than this are too likely to match by accident to be
+/// turned into references.
+const MIN_REFERENCE_LEN: usize = 4;
+
+/// One recorded tool call.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceCall {
+    pub name: String,
+    pub arguments: Map<String, Value>,
+    /// What the script would have seen as the call's result, if recorded.
+    pub result: Option<Value>,
+}
+
+/// Parse a trace file's contents into its tool calls, in order.
+pub fn parse_trace(text: &str) -> Result<Vec<TraceCall>> {
+    let entries: Vec<Value> = match serde_json::from_str(text) {
+        Ok(Value::Array(entries)) => entries,
+        Ok(Value::Object(entry)) => vec![Value::Object(entry)],
+        Ok(_) => anyhow::bail!("A trace must be a JSON array or JSON lines of objects"),
+        Err(_) => text
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                serde_json::from_str(line)
+                    .with_context(|| format!("Invalid JSON on line {}", i + 1))
+            })
+            .collect::<Result<_>>()?,
+    };
+
+    let mut calls = Vec::new();
+    // JSON-RPC request id to the index of its call, for pairing responses
+    let mut pending: HashMap<String, usize> = HashMap::new();
+    for entry in entries {
+        let Value::Object(entry) = entry else {
+            continue;
+        };
+        if entry.contains_key("jsonrpc") || entry.contains_key("method") {
+            match entry.get("method").and_then(Value::as_str) {
+                Some("tools/call") => {
+                    let params = entry.get("params").and_then(Value::as_object);
+                    let Some(call) = params.and_then(|p| call_record(p, None)) else {
+                        continue;
+                    };
+                    if let Some(id) = entry.get("id") {
+                        pending.insert(id.to_string(), calls.len());
+                    }
+                    calls.push(call);
+                }
+                Some(_) => {}
+                None => {
+                    let index = entry
+                        .get("id")
+                        .and_then(|id| pending.remove(&id.to_string()));
+                    if let (Some(index), Some(result)) = (index, entry.get("result")) {
+                        calls[index].result = script_value(result);
+                    }
+                }
+            }
+        } else if let Some(call) = call_record(&entry, entry.get("result")) {
+            calls.push(call);
+        }
+    }
+    Ok(calls)
+}
+
+fn call_record(entry: &Map<String, Value>, result: Option<&Value>) -> Option<TraceCall> {
+    let name = ["name", "tool"]
+        .iter()
+        .find_map(|key| entry.get(*key).and_then(Value::as_str))?;
+    let arguments = ["arguments", "args", "input"]
+        .iter()
+        .find_map(|key| entry.get(*key).and_then(Value::as_object))
+        .cloned()
+        .unwrap_or_default();
+    Some(TraceCall {
+        name: name.to_string(),
+        arguments,
+        result: result.and_then(script_value),
+    })
+}
+
+/// What a script sees for a recorded `CallToolResult`: its structured
+/// content, or its single text content parsed as JSON. Other values are
+/// taken as the result itself.
+fn script_value(result: &Value) -> Option<Value> {
+    let Some(object) = result.as_object() else {
+        return Some(result.clone());
+    };
+    if object.get("isError").and_then(Value::as_bool) == Some(true) {
+        return None;
+    }
+    if let Some(structured) = object.get("structuredContent") {
+        return Some(structured.clone());
+    }
+    match object.get("content").and_then(Value::as_array) {
+        Some(content) => match content.as_slice() {
+            [item] => {
+                let text = item.get("text")?.as_str()?;
+                Some(serde_json::from_str(text).unwrap_or_else(|_| Value::String(text.into())))
+            }
+            _ => None,
+        },
+        None => Some(result.clone()),
+    }
+}
+
+/// A JavaScript script making the same calls as `calls`, ending with the last
+/// call's result.
+pub fn trace_to_script(calls: &[TraceCall], namespace: Option<&str>) -> String {
+    let tools = match namespace {
+        Some(namespace) => format!("tools.{namespace}"),
+        None => "tools".to_string(),
+    };
+    let mut script = format!(
+        "// Converted from a trace of {} tool call{}\n",
+        calls.len(),
+        if calls.len() == 1 { "" } else { "s" }
+    );
+    // Earlier results, as (variable, result), to resolve references against
+    let mut results: Vec<(String, &Value)> = Vec::new();
+    for (i, call) in calls.iter().enumerate() {
+        let var = format!("r{}", i + 1);
+        let args = Value::Object(call.arguments.clone());
+        let args = if call.arguments.is_empty() {
+            String::new()
+        } else {
+            to_js(&args, &results)
+        };
+        let tool = match is_identifier(&call.name) {
+            true => format!(".{}", call.name),
+            false => format!("[{}]", Value::String(call.name.clone())),
+        };
+        writeln!(script, "var {var} = {tools}{tool}({args});").unwrap();
+        if let Some(result) = &call.result {
+            results.push((var, result));
+        }
+    }
+    if !calls.is_empty() {
+        writeln!(script, "r{}", calls.len()).unwrap();
+    }
+    script
+}
+
+/// `value` as a JavaScript expression with object keys in order, with
+/// strings found in `results` replaced by references to them.
+fn to_js(value: &Value, results: &[(String, &Value)]) -> String {
+    match value {
+        Value::String(s) if s.chars().count() >= MIN_REFERENCE_LEN => results
+            .iter()
+            .find_map(|(var, result)| find_path(result, s).map(|path| format!("{var}{path}")))
+            .unwrap_or_else(|| value.to_string()),
+        Value::Array(items) => {
+            let items: Vec<String> = items.iter().map(|v| to_js(v, results)).collect();
+            format!("[{}]", items.join(", "))
+        }
+        Value::Object(fields) => {
+            let fields: Vec<String> = sorted_fields(fields)
+                .map(|(k, v)| format!("{}: {}", js_key(k), to_js(v, results)))
+                .collect();
+            format!("{{{}}}", fields.join(", "))
+        }
+        _ => value.to_string(),
+    }
+}
+
+/// The accessor path (`.items[0].id`) to the first string in `value` equal
+/// to `needle`, looking through object fields in key order.
+fn find_path(value: &Value, needle: &str) -> Option<String> {
+    match value {
+        Value::String(s) if s == needle => Some(String::new()),
+        Value::Array(items) => items
+            .iter()
+            .enumerate()
+            .find_map(|(i, v)| find_path(v, needle).map(|path| format!("[{i}]{path}"))),
+        Value::Object(fields) => sorted_fields(fields).find_map(|(k, v)| {
+            find_path(v, needle).map(|path| {
+                if is_identifier(k) {
+                    format!(".{k}{path}")
+                } else {
+                    format!("[{}]{path}", Value::String(k.clone()))
+                }
+            })
+        }),
+        _ => None,
+    }
+}
+
+/// The fields of an object in key order, so scripts come out the same
+/// whatever order serde_json keeps maps in.
+fn sorted_fields(fields: &Map<String, Value>) -> impl Iterator<Item = (&String, &Value)> {
+    let mut fields: Vec<(&String, &Value)> = fields.iter().collect();
+    fields.sort_by_key(|(k, _)| *k);
+    fields.into_iter()
+}
+
+fn js_key(key: &str) -> String {
+    if is_identifier(key) {
+        key.to_string()
+    } else {
+        Value::String(key.to_string()).to_string()
+    }
+}
+
+fn is_identifier(key: &str) -> bool {
+    let mut chars = key.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '$')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert_json_rpc_trace() {
+        let trace = r#"
+{"jsonrpc":"2.0","id":1,"method":"tools/call","params":{"name":"search","arguments":{"query":"pcb"}}}
+{"jsonrpc":"2.0","method":"notifications/progress","params":{}}
+{"jsonrpc":"2.0","id":1,"result":{"content":[{"type":"text","text":"{\"items\":[{\"id\":\"ab-123\"}]}"}]}}
+{"jsonrpc":"2.0","id":2,"method":"tools/call","params":{"name":"get-item","arguments":{"id":"ab-123","full name":true}}}
+"#;
+        let calls = parse_trace(trace).unwrap();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(
+            trace_to_script(&calls, None),
+            "// Converted from a trace of 2 tool calls\n\
+             var r1 = tools.search({query: \"pcb\"});\n\
+             var r2 = tools[\"get-item\"]({\"full name\": true, id: r1.items[0].id});\n\
+             r2\n"
+        );
+    }
+
+    #[test]
+    fn test_convert_call_records() {
+        let trace = r#"[
+            {"tool": "list", "args": {}, "result": {"structuredContent": {"next": "page-2"}}},
+            {"name": "list", "arguments": {"cursor": "page-2", "kind": "abc"}}
+        ]"#;
+        let calls = parse_trace(trace).unwrap();
+        assert_eq!(
+            trace_to_script(&calls, Some("kicad")),
+            "// Converted from a trace of 2 tool calls\n\
+             var r1 = tools.kicad.list();\n\
+             var r2 = tools.kicad.list({cursor: r1.next, kind: \"abc\"});\n\
+             r2\n"
+        );
+    }
+}
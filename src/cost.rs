@@ -0,0 +1,296 @@
+//! Attributing downstream spend to agent activity.
+//!
+//! Each tool call from a script is charged `tools.<name>.cost`, plus
+//! `cost_per_kb` for every KiB of result and `cost_per_second` of call
+//! duration. Totals are reported per execution and, for executions in a
+//! session, per session in the execution's metrics.
+//!
+//! `max_execution_cost` and `max_session_cost` turn the totals into budgets:
+//! once one is spent, further calls fail with `BudgetExceeded`, which scripts
+//! can catch as an exception with `code === "budget_exceeded"`.
+
+use crate::config::CodeModeConfig;
+use crate::runtime::{ServerRequest, ServerRequestFuture, ToolCallFuture, ToolCaller};
+use crate::savings::result_bytes;
+use rmcp::model::CallToolResult;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Rates {
+    per_call: f64,
+    per_kb: f64,
+    per_second: f64,
+}
+
+/// Cost rates by exposed tool name, from `ToolSettings`.
+#[derive(Debug, Default)]
+pub struct CostModel {
+    rates: HashMap<String, Rates>,
+}
+
+impl CostModel {
+    pub fn from_config(config: &CodeModeConfig) -> Self {
+        let rates = config
+            .tools
+            .iter()
+            .map(|(name, settings)| {
+                let rates = Rates {
+                    per_call: settings.cost.unwrap_or(0.0),
+                    per_kb: settings.cost_per_kb.unwrap_or(0.0),
+                    per_second: settings.cost_per_second.unwrap_or(0.0),
+                };
+                (name.clone(), rates)
+            })
+            .filter(|(_, rates)| *rates != Rates::default())
+            .collect();
+        Self { rates }
+    }
+
+    /// Whether no tool has a cost, so there's nothing to report.
+    pub fn is_empty(&self) -> bool {
+        self.rates.is_empty()
+    }
+
+    /// What a call to `name` that returned `result` after `elapsed` costs.
+    pub fn cost(&self, name: &str, result: &CallToolResult, elapsed: Duration) -> f64 {
+        let Some(rates) = self.rates.get(name) else {
+            return 0.0;
+        };
+        let mut cost = rates.per_call + rates.per_second * elapsed.as_secs_f64();
+        if rates.per_kb != 0.0 {
+            cost += rates.per_kb * result_bytes(result) as f64 / 1024.0;
+        }
+        cost
+    }
+}
+
+/// Why a tool call was refused: the execution's or session's cost budget
+/// is spent.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("Budget exceeded: {scope} cost {spent} reached the limit of {limit}")]
+pub struct BudgetExceeded {
+    /// `"execution"` or `"session"`.
+    pub scope: &'static str,
+    pub spent: f64,
+    pub limit: f64,
+}
+
+impl BudgetExceeded {
+    /// The `code` of the exception scripts see.
+    pub const CODE: &str = "budget_exceeded";
+}
+
+/// A `ToolCaller` that adds the cost of each successful call to a running
+/// total and refuses calls once a budget is spent.
+pub struct CostToolCaller<C: ToolCaller> {
+    inner: C,
+    model: Arc<CostModel>,
+    total: Arc<Mutex<f64>>,
+    execution_budget: Option<f64>,
+    /// The session's limit and what it had spent before this execution.
+    session_budget: Option<(f64, f64)>,
+}
+
+impl<C: ToolCaller> CostToolCaller<C> {
+    pub fn new(inner: C, model: Arc<CostModel>) -> Self {
+        Self {
+            inner,
+            model,
+            total: Arc::default(),
+            execution_budget: None,
+            session_budget: None,
+        }
+    }
+
+    /// Refuse calls once this execution has cost `limit`.
+    pub fn with_execution_budget(mut self, limit: Option<f64>) -> Self {
+        self.execution_budget = limit;
+        self
+    }
+
+    /// Refuse calls once the session, which had already cost `spent` before
+    /// this execution, has cost `limit`.
+    pub fn with_session_budget(mut self, limit: Option<f64>, spent: f64) -> Self {
+        self.session_budget = limit.map(|limit| (limit, spent));
+        self
+    }
+
+    fn check_budget(&self) -> Result<(), BudgetExceeded> {
+        let spent = *self.total.lock().unwrap();
+        if let Some(limit) = self.execution_budget
+            && spent >= limit
+        {
+            return Err(BudgetExceeded {
+                scope: "execution",
+                spent,
+                limit,
+            });
+        }
+        if let Some((limit, before)) = self.session_budget
+            && before + spent >= limit
+        {
+            return Err(BudgetExceeded {
+                scope: "session",
+                spent: before + spent,
+                limit,
+            });
+        }
+        Ok(())
+    }
+
+    /// The running total, shared with this caller.
+    pub fn total(&self) -> Arc<Mutex<f64>> {
+        self.total.clone()
+    }
+}
+
+impl<C: ToolCaller> ToolCaller for CostToolCaller<C> {
+    fn call_tool<'a>(
+        &'a self,
+        name: &'a str,
+        args: Option<serde_json::Value>,
+    ) -> ToolCallFuture<'a> {
+        Box::pin(async move {
+            self.check_budget()?;
+            let started = Instant::now();
+            let result = self.inner.call_tool(name, args).await?;
+            let cost = self.model.cost(name, &result, started.elapsed());
+            *self.total.lock().unwrap() += cost;
+            Ok(result)
+        })
+    }
+
+    fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+        self.inner.server_request(request)
+    }
+}
+
+struct SessionCost {
+    total: f64,
+    last_used: Instant,
+}
+
+/// Cost totals per session, forgotten after the session TTL like the
+/// sessions themselves.
+pub struct CostLedger {
+    sessions: Mutex<HashMap<String, SessionCost>>,
+    ttl: Duration,
+}
+
+impl CostLedger {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            sessions: Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    /// The session's total so far, without adding to it.
+    pub fn total(&self, session: &str) -> f64 {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, s| s.last_used.elapsed() < self.ttl);
+        sessions.get(session).map_or(0.0, |s| s.total)
+    }
+
+    /// Add `cost` to the session's total and return the new total.
+    pub fn add(&self, session: &str, cost: f64) -> f64 {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, s| s.last_used.elapsed() < self.ttl);
+        let entry = sessions.entry(session.to_string()).or_insert(SessionCost {
+            total: 0.0,
+            last_used: Instant::now(),
+        });
+        entry.total += cost;
+        entry.last_used = Instant::now();
+        entry.total
+    }
+
+    /// Forget the session's total, e.g. when the session is reset.
+    pub fn reset(&self, session: &str) {
+        self.sessions.lock().unwrap().remove(session);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ToolSettings;
+    use rmcp::model::Content;
+
+    struct Kilobyte;
+
+    impl ToolCaller for Kilobyte {
+        fn call_tool<'a>(
+            &'a self,
+            _name: &'a str,
+            _args: Option<serde_json::Value>,
+        ) -> ToolCallFuture<'a> {
+            // Serialized as [{"type":"text","text":"..."}]: 27 bytes of framing
+            let text = "x".repeat(1024 - 27);
+            Box::pin(async move { Ok(CallToolResult::success(vec![Content::text(text)])) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_costs_accumulate() {
+        let mut config = CodeModeConfig::new();
+        config.tools.insert(
+            "search".to_string(),
+            ToolSettings {
+                cost: Some(0.5),
+                cost_per_kb: Some(2.0),
+                ..Default::default()
+            },
+        );
+        let caller = CostToolCaller::new(Kilobyte, Arc::new(CostModel::from_config(&config)));
+        let total = caller.total();
+
+        caller.call_tool("search", None).await.unwrap();
+        caller.call_tool("search", None).await.unwrap();
+        caller.call_tool("free", None).await.unwrap();
+        assert_eq!(*total.lock().unwrap(), 5.0);
+
+        let ledger = CostLedger::new(Duration::from_secs(60));
+        assert_eq!(ledger.add("a", 1.5), 1.5);
+        assert_eq!(ledger.add("a", 2.0), 3.5);
+        assert_eq!(ledger.total("b"), 0.0);
+        ledger.reset("a");
+        assert_eq!(ledger.total("a"), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_budget_exceeded() {
+        let mut config = CodeModeConfig::new();
+        config.tools.insert(
+            "search".to_string(),
+            ToolSettings {
+                cost: Some(1.0),
+                ..Default::default()
+            },
+        );
+        let model = Arc::new(CostModel::from_config(&config));
+
+        let caller = CostToolCaller::new(Kilobyte, model.clone()).with_execution_budget(Some(2.0));
+        caller.call_tool("search", None).await.unwrap();
+        caller.call_tool("search", None).await.unwrap();
+        let err = caller.call_tool("search", None).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<BudgetExceeded>(),
+            Some(&BudgetExceeded {
+                scope: "execution",
+                spent: 2.0,
+                limit: 2.0,
+            })
+        );
+
+        let caller = CostToolCaller::new(Kilobyte, model).with_session_budget(Some(5.0), 4.0);
+        caller.call_tool("search", None).await.unwrap();
+        let err = caller.call_tool("free", None).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Budget exceeded: session cost 5 reached the limit of 5"
+        );
+    }
+}
@@ -0,0 +1,257 @@
+//! Memoizing identical tool calls within one execution.
+//!
+//! A script that calls `get_items({})` inside a loop makes the same call
+//! over and over. Identical calls (same tool, same arguments) to memoized
+//! tools are answered with the first one's result for the rest of the
+//! execution, including calls made concurrently while the first is in
+//! flight. With `dedupe_read_only_calls` the read-only tools are memoized;
+//! the `memoize` tool setting adds or removes a tool either way. Error
+//! results are never reused.
+
+use crate::config::CodeModeConfig;
+use crate::runtime::{ServerRequest, ServerRequestFuture, ToolCallFuture, ToolCaller};
+use rmcp::model::{CallToolResult, Tool};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// `args` as JSON with sorted keys, so identical calls get the same key
+/// however serde_json orders maps (dependencies can turn `preserve_order`
+/// on).
+pub(crate) fn args_key(args: Option<&Value>) -> String {
+    args.map(|args| sorted_keys(args).to_string())
+        .unwrap_or_default()
+}
+
+fn sorted_keys(value: &Value) -> Value {
+    match value {
+        Value::Object(object) => {
+            let mut fields: Vec<(&String, &Value)> = object.iter().collect();
+            fields.sort_by_key(|(name, _)| *name);
+            Value::Object(
+                fields
+                    .into_iter()
+                    .map(|(name, field)| (name.clone(), sorted_keys(field)))
+                    .collect(),
+            )
+        }
+        Value::Array(items) => Value::Array(items.iter().map(sorted_keys).collect()),
+        other => other.clone(),
+    }
+}
+
+/// Names of tools that are safe to deduplicate: those annotated with
+/// `readOnlyHint`, unless overridden by `ToolSettings::read_only`.
+pub fn read_only_tools(tools: &[Tool], config: &CodeModeConfig) -> HashSet<String> {
+    tools
+        .iter()
+        .filter(|tool| {
+            let annotated = tool
+                .annotations
+                .as_ref()
+                .and_then(|a| a.read_only_hint)
+                .unwrap_or(false);
+            config
+                .tools
+                .get(tool.name.as_ref())
+                .and_then(|s| s.read_only)
+                .unwrap_or(annotated)
+        })
+        .map(|tool| tool.name.to_string())
+        .collect()
+}
+
+/// Names of tools whose identical calls are answered once per execution:
+/// read-only ones with `dedupe_read_only_calls`, unless overridden by
+/// `ToolSettings::memoize`.
+pub fn memoized_tools(tools: &[Tool], config: &CodeModeConfig) -> HashSet<String> {
+    let read_only = read_only_tools(tools, config);
+    tools
+        .iter()
+        .map(|tool| tool.name.to_string())
+        .filter(|name| {
+            config
+                .tools
+                .get(name)
+                .and_then(|s| s.memoize)
+                .unwrap_or(config.dedupe_read_only_calls && read_only.contains(name))
+        })
+        .collect()
+}
+
+type Slot = Arc<tokio::sync::Mutex<Option<CallToolResult>>>;
+
+/// A `ToolCaller` that reuses the first result of identical memoized calls.
+///
+/// One instance is created per execution, so results never leak between scripts.
+pub struct DedupToolCaller<C: ToolCaller> {
+    inner: C,
+    memoized: HashSet<String>,
+    /// Per call, its result once one succeeded. Identical calls wait on the
+    /// slot while the first is in flight.
+    results: Mutex<HashMap<(String, String), Slot>>,
+    hits: Arc<AtomicUsize>,
+}
+
+impl<C: ToolCaller> DedupToolCaller<C> {
+    pub fn new(inner: C, memoized: HashSet<String>) -> Self {
+        Self {
+            inner,
+            memoized,
+            results: Mutex::new(HashMap::new()),
+            hits: Arc::default(),
+        }
+    }
+
+    /// Number of calls answered from a previous identical call, shared
+    /// with this caller.
+    pub fn hits(&self) -> Arc<AtomicUsize> {
+        self.hits.clone()
+    }
+}
+
+impl<C: ToolCaller> ToolCaller for DedupToolCaller<C> {
+    fn call_tool<'a>(
+        &'a self,
+        name: &'a str,
+        args: Option<serde_json::Value>,
+    ) -> ToolCallFuture<'a> {
+        Box::pin(async move {
+            if !self.memoized.contains(name) {
+                return self.inner.call_tool(name, args).await;
+            }
+
+            let key = (name.to_string(), args_key(args.as_ref()));
+            let slot = self.results.lock().unwrap().entry(key).or_default().clone();
+            let mut slot = slot.lock().await;
+            if let Some(result) = slot.as_ref() {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(result.clone());
+            }
+
+            let result = self.inner.call_tool(name, args).await?;
+            if !result.is_error.unwrap_or(false) {
+                *slot = Some(result.clone());
+            }
+            Ok(result)
+        })
+    }
+
+    fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+        self.inner.server_request(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rmcp::model::Content;
+    use std::sync::Arc;
+
+    #[derive(Default)]
+    struct CountingCaller {
+        calls: AtomicUsize,
+    }
+
+    impl ToolCaller for Arc<CountingCaller> {
+        fn call_tool<'a>(
+            &'a self,
+            _name: &'a str,
+            _args: Option<serde_json::Value>,
+        ) -> ToolCallFuture<'a> {
+            let n = self.calls.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move { Ok(CallToolResult::success(vec![Content::text(n.to_string())])) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dedupes_identical_read_only_calls() {
+        let counter = Arc::new(CountingCaller::default());
+        let read_only = HashSet::from(["get_items".to_string()]);
+        let caller = DedupToolCaller::new(counter.clone(), read_only);
+
+        let args = Some(serde_json::json!({"page": 1}));
+        let first = caller.call_tool("get_items", args.clone()).await.unwrap();
+        let second = caller.call_tool("get_items", args).await.unwrap();
+        caller
+            .call_tool("get_items", Some(serde_json::json!({"page": 2})))
+            .await
+            .unwrap();
+
+        assert_eq!(first, second);
+        assert_eq!(counter.calls.load(Ordering::SeqCst), 2);
+        assert_eq!(caller.hits().load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_identical_calls_share_one() {
+        let counter = Arc::new(CountingCaller::default());
+        let memoized = HashSet::from(["get_items".to_string()]);
+        let caller = DedupToolCaller::new(counter.clone(), memoized);
+
+        let (first, second) = tokio::join!(
+            caller.call_tool("get_items", None),
+            caller.call_tool("get_items", None)
+        );
+        assert_eq!(first.unwrap(), second.unwrap());
+        assert_eq!(counter.calls.load(Ordering::SeqCst), 1);
+        assert_eq!(caller.hits().load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_args_key_sorts_keys() {
+        let args: Value =
+            serde_json::from_str(r#"{"b": 1, "a": {"d": [{"f": 2, "e": 3}], "c": 4}}"#).unwrap();
+        assert_eq!(
+            args_key(Some(&args)),
+            r#"{"a":{"c":4,"d":[{"e":3,"f":2}]},"b":1}"#
+        );
+        assert_eq!(args_key(None), "");
+    }
+
+    #[test]
+    fn test_memoized_tools() {
+        let mut read_only = Tool::new("get_items", "", Arc::new(Default::default()));
+        read_only.annotations = Some(rmcp::model::ToolAnnotations::new().read_only(true));
+        let tools = vec![
+            read_only,
+            Tool::new("get_time", "", Arc::new(Default::default())),
+            Tool::new("search", "", Arc::new(Default::default())),
+        ];
+        let mut config = CodeModeConfig::new();
+        config
+            .tools
+            .entry("search".to_string())
+            .or_default()
+            .memoize = Some(true);
+        assert_eq!(
+            memoized_tools(&tools, &config),
+            HashSet::from(["search".to_string()])
+        );
+
+        config = config.dedupe_read_only_calls(true);
+        config
+            .tools
+            .entry("get_time".to_string())
+            .or_default()
+            .read_only = Some(true);
+        config.tools.get_mut("get_time").unwrap().memoize = Some(false);
+        assert_eq!(
+            memoized_tools(&tools, &config),
+            HashSet::from(["get_items".to_string(), "search".to_string()])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_does_not_dedupe_other_tools() {
+        let counter = Arc::new(CountingCaller::default());
+        let caller = DedupToolCaller::new(counter.clone(), HashSet::new());
+
+        caller.call_tool("delete_item", None).await.unwrap();
+        caller.call_tool("delete_item", None).await.unwrap();
+
+        assert_eq!(counter.calls.load(Ordering::SeqCst), 2);
+        assert_eq!(caller.hits().load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,50 @@
+//! Reference for the script helpers that are always defined, served as the
+//! `codemode://docs` resource.
+//!
+//! The execute tool's description only names these helpers and points here,
+//! so it stays short for agents that never use them. Helpers that depend on
+//! configuration or client capabilities are described in the description
+//! itself, when they are available.
+
+use rmcp::model::{AnnotateAble, RawResource, Resource};
+
+/// URI of the resource documenting the script helpers.
+pub const DOCS_URI: &str = "codemode://docs";
+
+/// The `codemode://docs` resource as listed in `resources/list`.
+pub fn docs_resource() -> Resource {
+    let mut resource = RawResource::new(DOCS_URI, "codemode-docs");
+    resource.description = Some(
+        "Reference for the workflow, progress, assertion and date helpers scripts can use"
+            .to_string(),
+    );
+    resource.mime_type = Some("text/markdown".to_string());
+    resource.no_annotation()
+}
+
+/// The line in the execute tool's description pointing at the resource.
+pub const DOCS_NOTE: &str = "\n- `workflow.map` and `workflow.dag` run tool calls concurrently or in dependency order, `progress()`, `cancelled()` and `deadline` help long scripts report progress and stop in time, `assert` and `expectEqual` check results, and `now`, `parseDate`, `formatDate` and `convertTimezone` handle dates; read the `codemode://docs` resource for how to use them";
+
+/// The text of the `codemode://docs` resource.
+pub const HELPER_DOCS: &str = r#"# Script helpers
+
+## Workflows
+
+`workflow.map(items, fn, {concurrency, continueOnError, reserveMs})` calls `fn(item, index)` for every item with up to `concurrency` (default 4) tool calls in flight and returns the results in order. `fn` is re-run from the start for each tool call it makes, so it should only call tools and compute. With `continueOnError: true` it returns `{results, errors}` instead of throwing the first failure, and with `reserveMs` items not yet started when only that much time is left are skipped and it returns `{results, errors, skipped}`.
+
+`workflow.dag({steps: {name: fn(deps)}, dependsOn: {name: [names]}}, opts)` runs each step once its dependencies have finished, passing their results by name, and returns `{results, timings}` by step name.
+
+Both take `timeout` (ms per attempt), `retries` and `backoff` (ms, doubling) options, which `dag` steps can override in `options: {name: {...}}`.
+
+## Progress and cancellation
+
+Call `progress(current, total, message)` in long scripts to show the client how far they have got. Check `cancelled()`, which turns true when the call is cancelled or nearly out of time, to stop and return what they have so far. `deadline.remainingMs()` is how long is left before the timeout (`Infinity` without one).
+
+## Assertions
+
+`assert(condition, message)` and `expectEqual(actual, expected, message)` stop the script with an `assertion` in the result saying what failed.
+
+## Dates and times
+
+Use `now()`, `parseDate(text, {format, timezone})`, `formatDate(date, format, timezone)` (strftime formats) and `convertTimezone(date, timezone)` (IANA names) rather than parsing or formatting with `Date`. They return ISO 8601 strings.
+"#;
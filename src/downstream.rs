@@ -0,0 +1,366 @@
+//! The client side of the downstream connection.
+//!
+//! Downstream servers can make requests of their client as well as answer
+//! it. `DownstreamHandler` answers them for codemoder by forwarding to the
+//! upstream client, the agent's host, once it has connected:
+//! `sampling/createMessage` goes to the client's model and
+//! `elicitation/create` to the user. Without an upstream client that
+//! supports the request, the downstream server gets an error.
+//!
+//! Each forwarded request is a new upstream request whose response becomes
+//! the downstream response. When the downstream server cancels its request,
+//! the upstream one is cancelled too.
+//!
+//! Notifications that the downstream tool list changed mark the proxy's
+//! cached tools stale and are passed on upstream, so clients list them again.
+//! Progress notifications for a passthrough tool call are passed on to the
+//! client that made it, under the progress token it chose.
+
+use crate::drain::Drain;
+use rmcp::ClientHandler;
+use rmcp::model::{
+    CancelledNotificationParam, ClientCapabilities, ClientInfo, ClientResult,
+    CreateElicitationRequest, CreateElicitationRequestParam, CreateElicitationResult,
+    CreateMessageRequest, CreateMessageRequestParam, CreateMessageResult, ErrorCode, ErrorData,
+    Implementation, Meta, NumberOrString, ProgressNotificationParam, ProgressToken,
+    ServerCapabilities, ServerInfo, ServerRequest,
+};
+use rmcp::service::{
+    NotificationContext, Peer, PeerRequestOptions, RequestContext, RoleClient, RoleServer,
+};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// The upstream client, shared between the proxy that serves it and the
+/// handler of the downstream connection, which is made first.
+#[derive(Clone, Default)]
+pub struct UpstreamPeer(Arc<RwLock<Option<Peer<RoleServer>>>>);
+
+impl UpstreamPeer {
+    pub fn set(&self, peer: Peer<RoleServer>) {
+        *self.0.write().unwrap() = Some(peer);
+    }
+
+    pub fn get(&self) -> Option<Peer<RoleServer>> {
+        self.0.read().unwrap().clone()
+    }
+
+    /// The upstream client, if it declared the capability `request` needs.
+    fn client(
+        &self,
+        request: &str,
+        capability: &str,
+        supports: impl Fn(&ClientCapabilities) -> bool,
+    ) -> Result<Peer<RoleServer>, ErrorData> {
+        self.get()
+            .filter(|peer| {
+                peer.peer_info()
+                    .is_some_and(|info| supports(&info.capabilities))
+            })
+            .ok_or_else(|| {
+                ErrorData::new(
+                    ErrorCode::METHOD_NOT_FOUND,
+                    format!(
+                        "{request} is not available: the upstream client does not support {capability}"
+                    ),
+                    None,
+                )
+            })
+    }
+}
+
+/// What the downstream server in use said about itself when it was
+/// initialized, replaced when a standby takes its place.
+#[derive(Clone, Default)]
+pub struct DownstreamServer(Arc<RwLock<Option<ServerInfo>>>);
+
+impl DownstreamServer {
+    pub fn set(&self, info: Option<ServerInfo>) {
+        *self.0.write().unwrap() = info;
+    }
+
+    /// The server's identity from its initialize response.
+    pub fn implementation(&self) -> Option<Implementation> {
+        let info = self.0.read().unwrap();
+        info.as_ref().map(|info| info.server_info.clone())
+    }
+
+    /// What the server supports besides tools.
+    pub fn capabilities(&self) -> ServerCapabilities {
+        let info = self.0.read().unwrap();
+        info.as_ref()
+            .map(|info| info.capabilities.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// Set when the downstream server says its tool list changed, or a refresh
+/// is forced, and taken by whoever refreshes the cached tools. Also keeps
+/// when the tools were last listed, for `tools_ttl_secs`.
+#[derive(Clone, Default)]
+pub struct ToolsChanged {
+    changed: Arc<AtomicBool>,
+    listed: Arc<Mutex<Option<Instant>>>,
+}
+
+impl ToolsChanged {
+    pub fn mark(&self) {
+        self.changed.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether the tools changed since the last call.
+    pub fn take(&self) -> bool {
+        self.changed.swap(false, Ordering::SeqCst)
+    }
+
+    /// Record that the tools were just listed.
+    pub fn listed(&self) {
+        *self.listed.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// Whether the tools were last listed more than `ttl` ago.
+    pub fn expired(&self, ttl: Option<Duration>) -> bool {
+        let (Some(ttl), Some(listed)) = (ttl, *self.listed.lock().unwrap()) else {
+            return false;
+        };
+        listed.elapsed() >= ttl
+    }
+}
+
+/// The client that asked for progress, and the token it asked for it under.
+type Route = (Peer<RoleServer>, ProgressToken);
+
+/// Where progress reported for downstream requests goes, by the token the
+/// downstream server was given.
+#[derive(Clone, Default)]
+pub struct ProgressRoutes {
+    routes: Arc<Mutex<HashMap<ProgressToken, Route>>>,
+    next: Arc<AtomicU64>,
+}
+
+impl ProgressRoutes {
+    /// Route progress for one downstream request to `token` at `peer` until
+    /// the returned `ProgressRoute` is dropped.
+    pub fn route(&self, peer: Peer<RoleServer>, token: ProgressToken) -> ProgressRoute {
+        // Tokens of our own, since different clients may pick the same ones
+        let downstream = ProgressToken(NumberOrString::String(
+            format!("codemoder-{}", self.next.fetch_add(1, Ordering::Relaxed)).into(),
+        ));
+        self.routes
+            .lock()
+            .unwrap()
+            .insert(downstream.clone(), (peer, token));
+        ProgressRoute {
+            routes: self.clone(),
+            token: downstream,
+        }
+    }
+
+    async fn relay(&self, params: ProgressNotificationParam) {
+        let route = self
+            .routes
+            .lock()
+            .unwrap()
+            .get(&params.progress_token)
+            .cloned();
+        if let Some((peer, token)) = route {
+            let _ = peer
+                .notify_progress(ProgressNotificationParam {
+                    progress_token: token,
+                    ..params
+                })
+                .await;
+        }
+    }
+}
+
+/// A registered progress route, removed when dropped.
+pub struct ProgressRoute {
+    routes: ProgressRoutes,
+    token: ProgressToken,
+}
+
+impl ProgressRoute {
+    /// `_meta` carrying the token for the downstream request.
+    pub fn meta(&self) -> Meta {
+        let mut meta = Meta::new();
+        meta.set_progress_token(self.token.clone());
+        meta
+    }
+}
+
+impl Drop for ProgressRoute {
+    fn drop(&mut self) {
+        self.routes.routes.lock().unwrap().remove(&self.token);
+    }
+}
+
+/// Handles requests from the downstream server by forwarding them upstream.
+#[derive(Clone, Default)]
+pub struct DownstreamHandler {
+    upstream: UpstreamPeer,
+    tools_changed: ToolsChanged,
+    progress: ProgressRoutes,
+    /// Requests in flight to the server this handler serves.
+    requests: Drain,
+}
+
+impl DownstreamHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Where the proxy puts the upstream client once it connects.
+    pub fn upstream(&self) -> UpstreamPeer {
+        self.upstream.clone()
+    }
+
+    /// Marked when the downstream tool list changes.
+    pub fn tools_changed(&self) -> ToolsChanged {
+        self.tools_changed.clone()
+    }
+
+    /// Where to register requests whose progress should go upstream.
+    pub fn progress(&self) -> ProgressRoutes {
+        self.progress.clone()
+    }
+
+    /// Counts the requests in flight to the server this handler serves.
+    pub fn requests(&self) -> Drain {
+        self.requests.clone()
+    }
+
+    /// A handler for another server, sharing this one's upstream client,
+    /// tool list changes and progress routes but counting its own requests.
+    pub fn for_another_server(&self) -> Self {
+        Self {
+            requests: Drain::new(),
+            ..self.clone()
+        }
+    }
+}
+
+/// Send `request` to `peer` and wait for its response, cancelling it
+/// upstream if `ct` is cancelled first.
+async fn forward(
+    peer: &Peer<RoleServer>,
+    request: ServerRequest,
+    ct: CancellationToken,
+) -> Result<ClientResult, ErrorData> {
+    let forwarding_failed =
+        |e| ErrorData::internal_error(format!("Forwarding upstream failed: {e}"), None);
+    let handle = peer
+        .send_cancellable_request(request, PeerRequestOptions::no_options())
+        .await
+        .map_err(forwarding_failed)?;
+    tokio::select! {
+        response = handle.rx => response
+            .map_err(|_| ErrorData::internal_error("The upstream client disconnected", None))?
+            .map_err(forwarding_failed),
+        _ = ct.cancelled() => {
+            let _ = peer
+                .notify_cancelled(CancelledNotificationParam {
+                    request_id: handle.id,
+                    reason: Some("Cancelled by the downstream server".to_string()),
+                })
+                .await;
+            Err(ErrorData::internal_error("Request cancelled", None))
+        }
+    }
+}
+
+fn unexpected_response() -> ErrorData {
+    ErrorData::internal_error("Unexpected response from the upstream client", None)
+}
+
+impl ClientHandler for DownstreamHandler {
+    async fn create_message(
+        &self,
+        params: CreateMessageRequestParam,
+        context: RequestContext<RoleClient>,
+    ) -> Result<CreateMessageResult, ErrorData> {
+        let peer = self
+            .upstream
+            .client("sampling/createMessage", "sampling", |c| {
+                c.sampling.is_some()
+            })?;
+        let request = ServerRequest::CreateMessageRequest(CreateMessageRequest {
+            method: Default::default(),
+            params,
+            extensions: Default::default(),
+        });
+        match forward(&peer, request, context.ct).await? {
+            ClientResult::CreateMessageResult(result) => Ok(*result),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    async fn create_elicitation(
+        &self,
+        params: CreateElicitationRequestParam,
+        context: RequestContext<RoleClient>,
+    ) -> Result<CreateElicitationResult, ErrorData> {
+        let peer = self
+            .upstream
+            .client("elicitation/create", "elicitation", |c| {
+                c.elicitation.is_some()
+            })?;
+        let request = ServerRequest::CreateElicitationRequest(CreateElicitationRequest {
+            method: Default::default(),
+            params,
+            extensions: Default::default(),
+        });
+        match forward(&peer, request, context.ct).await? {
+            ClientResult::CreateElicitationResult(result) => Ok(result),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    async fn on_tool_list_changed(&self, _context: NotificationContext<RoleClient>) {
+        self.tools_changed.mark();
+        if let Some(peer) = self.upstream.get() {
+            let _ = peer.notify_tool_list_changed().await;
+        }
+    }
+
+    async fn on_progress(
+        &self,
+        params: ProgressNotificationParam,
+        _context: NotificationContext<RoleClient>,
+    ) {
+        self.progress.relay(params).await;
+    }
+
+    fn get_info(&self) -> ClientInfo {
+        ClientInfo {
+            capabilities: ClientCapabilities::builder()
+                .enable_elicitation()
+                .enable_sampling()
+                .build(),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tools_changed() {
+        let changed = ToolsChanged::default();
+        // Nothing to expire before the first listing
+        assert!(!changed.expired(Some(Duration::ZERO)));
+        changed.listed();
+        assert!(changed.expired(Some(Duration::ZERO)));
+        assert!(!changed.expired(Some(Duration::from_secs(60))));
+        assert!(!changed.expired(None));
+
+        changed.mark();
+        assert!(changed.take());
+        assert!(!changed.take());
+    }
+}
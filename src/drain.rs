@@ -0,0 +1,114 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Tracks in-flight executions so a deployment can stop taking new scripts
+/// and wait for running ones before shutting down. Also counts the requests
+/// in flight to a downstream server.
+#[derive(Debug, Clone, Default)]
+pub struct Drain {
+    inner: Arc<DrainInner>,
+}
+
+#[derive(Debug, Default)]
+struct DrainInner {
+    draining: AtomicBool,
+    in_flight: AtomicUsize,
+    idle: Notify,
+}
+
+impl Drain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stop accepting new executions. Running ones are unaffected.
+    pub fn start(&self) {
+        self.inner.draining.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.inner.draining.load(Ordering::SeqCst)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.inner.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Register a new execution, or `None` if draining has started.
+    pub fn enter(&self) -> Option<DrainGuard> {
+        self.inner.in_flight.fetch_add(1, Ordering::SeqCst);
+        if self.is_draining() {
+            self.leave();
+            return None;
+        }
+        Some(DrainGuard {
+            drain: self.clone(),
+        })
+    }
+
+    /// Wait until no executions are running. Returns `false` if `timeout`
+    /// elapsed first.
+    pub async fn wait_idle(&self, timeout: Duration) -> bool {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let notified = self.inner.idle.notified();
+            if self.in_flight() == 0 {
+                return true;
+            }
+            if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                return self.in_flight() == 0;
+            }
+        }
+    }
+
+    fn leave(&self) {
+        if self.inner.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.inner.idle.notify_waiters();
+        }
+    }
+}
+
+/// Marks one execution as in flight until dropped.
+#[derive(Debug)]
+pub struct DrainGuard {
+    drain: Drain,
+}
+
+impl Drop for DrainGuard {
+    fn drop(&mut self) {
+        self.drain.leave();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rejects_new_work_while_draining() {
+        let drain = Drain::new();
+        let guard = drain.enter();
+        assert!(guard.is_some());
+
+        drain.start();
+        assert!(drain.enter().is_none());
+        assert_eq!(drain.in_flight(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_wait_idle_waits_for_in_flight() {
+        let drain = Drain::new();
+        let guard = drain.enter().unwrap();
+        drain.start();
+
+        assert!(!drain.wait_idle(Duration::from_millis(20)).await);
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(guard);
+        });
+        assert!(drain.wait_idle(Duration::from_secs(5)).await);
+    }
+}
@@ -0,0 +1,117 @@
+use crate::config::{CodeModeConfig, ScriptEngine};
+use crate::runtime::{ExecutionOptions, ExecutionResult, JsRuntime, ToolCaller};
+use anyhow::Result;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+pub type ExecuteFuture<'a> = Pin<Box<dyn Future<Output = Result<ExecutionResult>> + Send + 'a>>;
+
+/// A script engine that runs agent-written code with `tools.*` bindings.
+///
+/// `CodeModeProxy` and `CodeModeWrapper` only talk to this trait, so engines
+/// can be swapped through `CodeModeConfig::engine` without touching them.
+pub trait ScriptRuntime: Send + Sync {
+    /// The engine this runtime implements.
+    fn engine(&self) -> ScriptEngine;
+
+    fn execute<'a>(
+        &'a self,
+        code: &'a str,
+        tool_names: &'a [String],
+        caller: Arc<dyn ToolCaller>,
+        options: ExecutionOptions,
+    ) -> ExecuteFuture<'a>;
+
+    /// Drop the paused execution kept for a `yieldToAgent` continuation
+    /// token. False if there was none.
+    fn abandon_continuation(&self, _token: &str) -> bool {
+        false
+    }
+}
+
+impl ScriptRuntime for JsRuntime {
+    fn engine(&self) -> ScriptEngine {
+        ScriptEngine::Javascript
+    }
+
+    fn execute<'a>(
+        &'a self,
+        code: &'a str,
+        tool_names: &'a [String],
+        caller: Arc<dyn ToolCaller>,
+        options: ExecutionOptions,
+    ) -> ExecuteFuture<'a> {
+        Box::pin(self.execute_with_options(code, tool_names, caller, options))
+    }
+
+    fn abandon_continuation(&self, token: &str) -> bool {
+        JsRuntime::abandon_continuation(self, token)
+    }
+}
+
+/// Build the runtime selected by `config.engine`.
+pub async fn create_runtime(config: &CodeModeConfig) -> Result<Box<dyn ScriptRuntime>> {
+    match config.engine {
+        ScriptEngine::Javascript => Ok(Box::new(
+            JsRuntime::new()
+                .await?
+                .with_session_ttl(config.session_ttl())
+                .with_max_continuations(config.max_continuations),
+        )),
+        #[cfg(feature = "lua")]
+        ScriptEngine::Lua => Ok(Box::new(
+            crate::lua::LuaRuntime::new().with_session_ttl(config.session_ttl()),
+        )),
+        #[cfg(not(feature = "lua"))]
+        ScriptEngine::Lua => {
+            anyhow::bail!("The lua engine requires building codemoder with `--features lua`")
+        }
+        #[cfg(feature = "boa")]
+        ScriptEngine::Boa => Ok(Box::new(crate::boa::BoaRuntime::new())),
+        #[cfg(not(feature = "boa"))]
+        ScriptEngine::Boa => {
+            anyhow::bail!("The boa engine requires building codemoder with `--features boa`")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::ToolCallFuture;
+    use rmcp::model::{CallToolResult, Content};
+
+    struct Echo;
+
+    impl ToolCaller for Echo {
+        fn call_tool<'a>(
+            &'a self,
+            name: &'a str,
+            _args: Option<serde_json::Value>,
+        ) -> ToolCallFuture<'a> {
+            Box::pin(async move {
+                Ok(CallToolResult::success(vec![Content::text(
+                    serde_json::json!({ "tool": name }).to_string(),
+                )]))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_default_engine_runs_javascript() {
+        let runtime = create_runtime(&CodeModeConfig::default()).await.unwrap();
+        assert_eq!(runtime.engine(), ScriptEngine::Javascript);
+
+        let result = runtime
+            .execute(
+                "tools.ping({}).tool",
+                &["ping".to_string()],
+                Arc::new(Echo),
+                ExecutionOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.value, "ping");
+    }
+}
@@ -0,0 +1,74 @@
+//! Compile-time feature audit, printed by `codemoder features` and included
+//! in the capability report.
+//!
+//! Building with `--features minimal` promises that nothing beyond the
+//! stdio proxy is compiled in: no telemetry or metrics exporters, no network
+//! listeners, no state persistence and no outbound networking helpers. Cargo
+//! features that would add any of those refuse to build alongside it, and
+//! configs that set `record_dir` are rejected so nothing is written to disk.
+
+#[cfg(all(feature = "minimal", feature = "opa"))]
+compile_error!(
+    "The `opa` feature calls an HTTP policy endpoint and cannot be combined with `minimal`"
+);
+
+#[cfg(all(feature = "minimal", feature = "otel"))]
+compile_error!("The `otel` feature exports spans over OTLP and cannot be combined with `minimal`");
+
+/// Cargo features and built-in capabilities of this build.
+pub fn enabled() -> Vec<&'static str> {
+    let mut features = vec!["typescript"];
+    if cfg!(feature = "lua") {
+        features.push("lua");
+    }
+    if cfg!(feature = "boa") {
+        features.push("boa");
+    }
+    if cfg!(feature = "cedar") {
+        features.push("cedar");
+    }
+    if cfg!(feature = "opa") {
+        features.push("opa");
+    }
+    if cfg!(feature = "otel") {
+        features.push("otel");
+    }
+    if cfg!(feature = "minimal") {
+        features.push("minimal");
+    }
+    features
+}
+
+/// What this binary is able to do beyond talking MCP over stdio, so
+/// security reviewers can check a build without reading its Cargo.toml.
+pub fn audit() -> serde_json::Value {
+    serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "features": enabled(),
+        "minimal": cfg!(feature = "minimal"),
+        "telemetry": cfg!(feature = "otel"),
+        "listeners": Vec::<String>::new(),
+        "persistence": !cfg!(feature = "minimal"),
+        "outboundNetwork": cfg!(feature = "opa") || cfg!(feature = "otel"),
+        "downstream": "child process over stdio",
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audit_reports_no_network_surface() {
+        let audit = audit();
+        assert_eq!(audit["telemetry"], cfg!(feature = "otel"));
+        assert_eq!(audit["persistence"], !cfg!(feature = "minimal"));
+        assert_eq!(audit["listeners"], serde_json::json!([]));
+        assert!(
+            audit["features"]
+                .as_array()
+                .unwrap()
+                .contains(&serde_json::json!("typescript"))
+        );
+    }
+}
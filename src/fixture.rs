@@ -0,0 +1,173 @@
+//! Compares script results against expected JSON fixtures for
+//! `codemoder exec --expect`.
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CompareOptions {
+    /// Numbers within this absolute difference are equal.
+    pub tolerance: f64,
+    /// Compare arrays as multisets instead of element by element.
+    pub ignore_order: bool,
+}
+
+/// One mismatch, located by a JSONPath-like `path` such as `$.items[2].id`.
+/// `expected` or `actual` is absent when the value is missing on that side.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Difference {
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actual: Option<Value>,
+}
+
+/// Every difference between `actual` and `expected`; empty when they match.
+/// Object fields are compared in key order, the fields `actual` lacks before
+/// the ones it adds.
+pub fn compare(expected: &Value, actual: &Value, options: CompareOptions) -> Vec<Difference> {
+    let mut differences = Vec::new();
+    diff(expected, actual, "$", options, &mut differences);
+    differences
+}
+
+fn diff(
+    expected: &Value,
+    actual: &Value,
+    path: &str,
+    options: CompareOptions,
+    out: &mut Vec<Difference>,
+) {
+    match (expected, actual) {
+        (Value::Number(e), Value::Number(a)) => {
+            let equal = match (e.as_f64(), a.as_f64()) {
+                (Some(e), Some(a)) => (e - a).abs() <= options.tolerance,
+                _ => e == a,
+            };
+            if !equal {
+                out.push(mismatch(path, expected, actual));
+            }
+        }
+        // Keys in order, whatever order serde_json keeps maps in
+        (Value::Object(e), Value::Object(a)) => {
+            for (key, e_value) in sorted(e) {
+                let path = format!("{path}.{key}");
+                match a.get(key) {
+                    Some(a_value) => diff(e_value, a_value, &path, options, out),
+                    None => out.push(Difference {
+                        path,
+                        expected: Some(e_value.clone()),
+                        actual: None,
+                    }),
+                }
+            }
+            for (key, a_value) in sorted(a).filter(|(key, _)| !e.contains_key(*key)) {
+                out.push(Difference {
+                    path: format!("{path}.{key}"),
+                    expected: None,
+                    actual: Some(a_value.clone()),
+                });
+            }
+        }
+        (Value::Array(e), Value::Array(a)) if options.ignore_order => {
+            let mut unmatched: Vec<&Value> = a.iter().collect();
+            for e_item in e {
+                let found = unmatched
+                    .iter()
+                    .position(|a_item| compare(e_item, a_item, options).is_empty());
+                match found {
+                    Some(i) => {
+                        unmatched.remove(i);
+                    }
+                    None => out.push(Difference {
+                        path: format!("{path}[*]"),
+                        expected: Some(e_item.clone()),
+                        actual: None,
+                    }),
+                }
+            }
+            for a_item in unmatched {
+                out.push(Difference {
+                    path: format!("{path}[*]"),
+                    expected: None,
+                    actual: Some(a_item.clone()),
+                });
+            }
+        }
+        (Value::Array(e), Value::Array(a)) => {
+            for i in 0..e.len().max(a.len()) {
+                let path = format!("{path}[{i}]");
+                match (e.get(i), a.get(i)) {
+                    (Some(e_item), Some(a_item)) => diff(e_item, a_item, &path, options, out),
+                    (e_item, a_item) => out.push(Difference {
+                        path,
+                        expected: e_item.cloned(),
+                        actual: a_item.cloned(),
+                    }),
+                }
+            }
+        }
+        _ if expected != actual => out.push(mismatch(path, expected, actual)),
+        _ => {}
+    }
+}
+
+fn mismatch(path: &str, expected: &Value, actual: &Value) -> Difference {
+    Difference {
+        path: path.to_string(),
+        expected: Some(expected.clone()),
+        actual: Some(actual.clone()),
+    }
+}
+
+fn sorted(object: &Map<String, Value>) -> impl Iterator<Item = (&String, &Value)> {
+    let mut fields: Vec<(&String, &Value)> = object.iter().collect();
+    fields.sort_by_key(|(key, _)| *key);
+    fields.into_iter()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_compare_reports_paths() {
+        let expected = json!({"count": 2, "items": [{"id": "a"}, {"id": "b"}], "gone": true});
+        let actual = json!({"count": 3, "items": [{"id": "a"}, {"id": "c"}], "extra": 1});
+
+        let differences = compare(&expected, &actual, CompareOptions::default());
+        let paths: Vec<&str> = differences.iter().map(|d| d.path.as_str()).collect();
+        assert_eq!(paths, vec!["$.count", "$.gone", "$.items[1].id", "$.extra"]);
+        assert_eq!(differences[1].actual, None);
+        assert_eq!(differences[3].expected, None);
+    }
+
+    #[test]
+    fn test_compare_tolerance_and_order() {
+        let options = CompareOptions {
+            tolerance: 0.01,
+            ignore_order: true,
+        };
+        assert!(compare(&json!([1.0, 2, 3]), &json!([3, 2.005, 1]), options).is_empty());
+        assert!(!compare(&json!([1, 2]), &json!([2, 1]), CompareOptions::default()).is_empty());
+
+        let differences = compare(&json!([1, 2]), &json!([2, 5]), options);
+        assert_eq!(
+            differences,
+            vec![
+                Difference {
+                    path: "$[*]".to_string(),
+                    expected: Some(json!(1)),
+                    actual: None,
+                },
+                Difference {
+                    path: "$[*]".to_string(),
+                    expected: None,
+                    actual: Some(json!(5)),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,128 @@
+//! The optional id and hashing globals, for scripts that need stable ids or
+//! content hashes to pass between tools. Enabled with the `hash_helpers`
+//! setting.
+//!
+//! - `uuid()`: a random (version 4) UUID
+//! - `sha256(value)`: the SHA-256 of `value` as lowercase hex
+//! - `hmac(key, value)`: the HMAC-SHA256 of `value` with `key`, as lowercase
+//!   hex
+//!
+//! Strings are hashed as UTF-8; any other value is hashed as its JSON text,
+//! the same way `hash_args` redaction does.
+
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+
+/// Wraps `__hash(json)` (JSON string in, JSON string out) into the script
+/// functions, assigned to the global object so scripts can still declare
+/// their own `uuid`.
+pub(crate) const HASH_PRELUDE: &str = r#"
+    (function() {
+        function text(value) {
+            return typeof value === 'string' ? value : JSON.stringify(value);
+        }
+        function request(args) {
+            var response = JSON.parse(__hash(JSON.stringify(args)));
+            if (response.error) {
+                throw new Error(response.error);
+            }
+            return response.result;
+        }
+        globalThis.uuid = function() {
+            return request({fn: 'uuid'});
+        };
+        globalThis.sha256 = function(value) {
+            return request({fn: 'sha256', value: text(value)});
+        };
+        globalThis.hmac = function(key, value) {
+            return request({fn: 'hmac', key: text(key), value: text(value)});
+        };
+    })();
+"#;
+
+/// A call from the hash prelude.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "fn", rename_all = "camelCase")]
+enum HashRequest {
+    Uuid,
+    Sha256 { value: String },
+    Hmac { key: String, value: String },
+}
+
+/// Serve `__hash(json)`, returning `{"result": ...}` or `{"error": ...}` as
+/// JSON text.
+pub(crate) fn call_hash(request: &str) -> String {
+    match serde_json::from_str::<HashRequest>(request) {
+        Ok(HashRequest::Uuid) => serde_json::json!({ "result": uuid() }),
+        Ok(HashRequest::Sha256 { value }) => serde_json::json!({ "result": sha256(&value) }),
+        Ok(HashRequest::Hmac { key, value }) => {
+            serde_json::json!({ "result": hmac_sha256(&key, &value) })
+        }
+        Err(e) => serde_json::json!({ "error": format!("Invalid hash request: {e}") }),
+    }
+    .to_string()
+}
+
+/// The text a script value is hashed as: a string's contents, or the JSON
+/// text of anything else.
+pub fn text(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+pub fn uuid() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+pub fn sha256(value: &str) -> String {
+    hex(&Sha256::digest(value.as_bytes()))
+}
+
+pub fn hmac_sha256(key: &str, value: &str) -> String {
+    // HMAC takes keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("any key length");
+    mac.update(value.as_bytes());
+    hex(&mac.finalize().into_bytes())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(hex, "{byte:02x}");
+    }
+    hex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hashes() {
+        assert_eq!(
+            sha256("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        // RFC 4231 test case 2
+        assert_eq!(
+            hmac_sha256("Jefe", "what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(text(&serde_json::json!({"a": 1})), r#"{"a":1}"#);
+    }
+
+    #[test]
+    fn test_call_hash() {
+        let id: serde_json::Value = serde_json::from_str(&call_hash(r#"{"fn": "uuid"}"#)).unwrap();
+        let id = uuid::Uuid::parse_str(id["result"].as_str().unwrap()).unwrap();
+        assert_eq!(id.get_version_num(), 4);
+        assert_eq!(
+            call_hash(r#"{"fn": "hmac", "key": "k"}"#),
+            r#"{"error":"Invalid hash request: missing field `value`"}"#
+        );
+    }
+}
@@ -0,0 +1,123 @@
+//! Whether an execution is safe to run again.
+//!
+//! A script asserts that it can be retried by starting with the
+//! `"use idempotent";` directive, or by being sent with `idempotent: true`.
+//! Without that, an execution that called a destructive tool is not safe to
+//! re-run: `_meta.retrySafe` says so, and `codemoder exec --replay` refuses
+//! to run its bundle against a live server without `--force`.
+//!
+//! A tool is destructive unless it is read-only (by its `readOnlyHint` or the
+//! `read_only` tool setting) or says `destructiveHint: false`, following the
+//! MCP defaults for unannotated tools.
+
+use crate::config::CodeModeConfig;
+use rmcp::model::Tool;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+const DIRECTIVE: &str = "use idempotent";
+
+/// Whether `code` starts with the `"use idempotent"` directive, among any
+/// other directives like `"use strict"`.
+pub fn declares_idempotent(code: &str) -> bool {
+    let mut rest = code;
+    loop {
+        rest = skip_comments(rest);
+        let Some(quote) = rest.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+            return false;
+        };
+        let Some(end) = rest[1..].find(quote) else {
+            return false;
+        };
+        if &rest[1..end + 1] == DIRECTIVE {
+            return true;
+        }
+        rest = rest[end + 2..].trim_start();
+        rest = rest.strip_prefix(';').unwrap_or(rest);
+    }
+}
+
+fn skip_comments(mut code: &str) -> &str {
+    loop {
+        code = code.trim_start();
+        if let Some(comment) = code.strip_prefix("//") {
+            code = comment.split_once('\n').map_or("", |(_, rest)| rest);
+        } else if let Some(comment) = code.strip_prefix("/*") {
+            code = comment.split_once("*/").map_or("", |(_, rest)| rest);
+        } else {
+            return code;
+        }
+    }
+}
+
+/// The names of the `tools` that may have side effects a retry would repeat.
+pub fn destructive_tools(tools: &[Tool], config: &CodeModeConfig) -> HashSet<String> {
+    tools
+        .iter()
+        .filter(|tool| {
+            let annotations = tool.annotations.as_ref();
+            let read_only = config
+                .tools
+                .get(tool.name.as_ref())
+                .and_then(|s| s.read_only)
+                .or_else(|| annotations.and_then(|a| a.read_only_hint))
+                .unwrap_or(false);
+            !read_only && annotations.and_then(|a| a.destructive_hint) != Some(false)
+        })
+        .map(|tool| tool.name.to_string())
+        .collect()
+}
+
+/// The destructive tools among those called, from per-tool call counts.
+pub fn destructive_calls(
+    calls: &BTreeMap<String, usize>,
+    destructive: &HashSet<String>,
+) -> BTreeSet<String> {
+    calls
+        .iter()
+        .filter(|(name, count)| **count > 0 && destructive.contains(*name))
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rmcp::model::{JsonObject, ToolAnnotations};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_declares_idempotent() {
+        assert!(declares_idempotent("\"use idempotent\";\ntools.sync({})"));
+        assert!(declares_idempotent(
+            "// Sync labels\n/* safe */ 'use strict'; 'use idempotent'\ntools.sync({})"
+        ));
+        assert!(!declares_idempotent("tools.sync({}); 'use idempotent'"));
+        assert!(!declares_idempotent("'use idempotently'"));
+        assert!(!declares_idempotent("'use idempotent"));
+        assert!(!declares_idempotent(""));
+    }
+
+    #[test]
+    fn test_destructive_tools() {
+        let tool = |name: &str, annotations: Option<ToolAnnotations>| {
+            let mut tool = Tool::new(name.to_string(), "", Arc::new(JsonObject::new()));
+            tool.annotations = annotations;
+            tool
+        };
+        let tools = [
+            tool("delete", None),
+            tool("get", Some(ToolAnnotations::new().read_only(true))),
+            tool("tag", Some(ToolAnnotations::new().destructive(false))),
+            tool("list", None),
+        ];
+        let config: CodeModeConfig = toml::from_str("[tools.list]\nread_only = true").unwrap();
+        let destructive = destructive_tools(&tools, &config);
+        assert_eq!(destructive, HashSet::from(["delete".to_string()]));
+
+        let calls = BTreeMap::from([("delete".to_string(), 2), ("get".to_string(), 1)]);
+        assert_eq!(
+            destructive_calls(&calls, &destructive),
+            BTreeSet::from(["delete".to_string()])
+        );
+    }
+}
@@ -1,9 +1,65 @@
+#![recursion_limit = "256"]
+
+pub mod alias;
+pub mod approval;
+pub mod background;
+#[cfg(feature = "boa")]
+pub mod boa;
+pub mod bundle;
+pub mod cache;
+pub mod circuit;
+pub mod coerce;
+pub mod concurrency;
 pub mod config;
+pub mod convert;
+pub mod cost;
+pub mod dedup;
+pub mod docs;
+pub mod downstream;
+pub mod drain;
+pub mod engine;
+pub mod features;
+pub mod fixture;
+pub mod hash;
+pub mod idempotent;
+#[cfg(feature = "lua")]
+pub mod lua;
+pub mod notes;
+pub mod policy;
+pub mod provenance;
 pub mod proxy;
+pub mod redact;
+pub mod retry;
+pub mod roots;
 pub mod runtime;
+pub mod safe_regex;
+pub mod savings;
+pub mod scaffold;
+pub mod schema;
+pub mod selftest;
+pub mod slo;
+pub mod stack;
+pub mod standby;
+pub mod stats;
+pub mod storage;
+pub mod strict;
+pub mod summary;
+pub mod telemetry;
+pub mod time;
+pub mod timeout;
+pub mod trace;
+pub mod transpile;
 pub mod typescript;
+pub mod upstream;
+pub mod usage;
+pub mod workflow;
 pub mod wrapper;
 
-pub use config::{CodeModeConfig, CodeModeExposure};
+pub use config::{
+    CodeModeConfig, CodeModeExposure, InterfaceDetail, SchemaDriftAction, ScriptEngine,
+    ToolNameCollision, ToolSettings,
+};
+pub use drain::Drain;
+pub use engine::ScriptRuntime;
 pub use proxy::CodeModeProxy;
 pub use wrapper::CodeModeWrapper;
@@ -0,0 +1,970 @@
+//! Lua 5.4 script engine, enabled with the `lua` cargo feature.
+//!
+//! Scripts get the same `tools` table as JavaScript (`tools.search({query = "x"})`
+//! returns the decoded result) and `print` is captured into the execution
+//! logs. Only the `string`, `table`, `math`, `utf8` and `coroutine` libraries
+//! are loaded, so there is no file or OS access.
+
+use crate::alias::script_aliases;
+use crate::cache::CallOptions;
+use crate::config::ScriptEngine;
+use crate::engine::{ExecuteFuture, ScriptRuntime};
+use crate::runtime::{
+    AssertionFailure, ExecutionMetrics, ExecutionOptions, ExecutionResult, Interrupt,
+    ServerRequest, ToolBridge, ToolCaller, raw_tool_result, run_blocking_script,
+};
+use crate::{hash, stats, time};
+use anyhow::Result;
+use mlua::{
+    Function, HookTriggers, Lua, LuaOptions, LuaSerdeExt, MultiValue, StdLib, Table, Value,
+};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often, in VM instructions, the timeout and cancellation are checked.
+const HOOK_INSTRUCTIONS: u32 = 1000;
+
+struct Session {
+    lua: Arc<Mutex<Lua>>,
+    last_used: Instant,
+}
+
+pub struct LuaRuntime {
+    sessions: Mutex<HashMap<String, Session>>,
+    session_ttl: Duration,
+}
+
+impl Default for LuaRuntime {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LuaRuntime {
+    pub fn new() -> Self {
+        Self {
+            sessions: Mutex::new(HashMap::new()),
+            session_ttl: Duration::from_secs(30 * 60),
+        }
+    }
+
+    /// Discard sessions that have been idle for longer than `ttl`.
+    pub fn with_session_ttl(mut self, ttl: Duration) -> Self {
+        self.session_ttl = ttl;
+        self
+    }
+
+    fn new_state() -> Result<Lua> {
+        let libs = StdLib::STRING | StdLib::TABLE | StdLib::MATH | StdLib::UTF8 | StdLib::COROUTINE;
+        let lua = Lua::new_with(libs, LuaOptions::default())?;
+        {
+            let globals = lua.globals();
+            for name in ["dofile", "loadfile"] {
+                globals.set(name, Value::Nil)?;
+            }
+        }
+        Ok(lua)
+    }
+
+    /// A fresh state, or the session's persistent one so globals carry over.
+    fn state_for(&self, session: Option<&str>, reset: bool) -> Result<Arc<Mutex<Lua>>> {
+        let Some(id) = session else {
+            return Ok(Arc::new(Mutex::new(Self::new_state()?)));
+        };
+
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, s| s.last_used.elapsed() < self.session_ttl);
+        if reset {
+            sessions.remove(id);
+        }
+        if let Some(session) = sessions.get_mut(id) {
+            session.last_used = Instant::now();
+            return Ok(session.lua.clone());
+        }
+
+        let lua = Arc::new(Mutex::new(Self::new_state()?));
+        sessions.insert(
+            id.to_string(),
+            Session {
+                lua: lua.clone(),
+                last_used: Instant::now(),
+            },
+        );
+        Ok(lua)
+    }
+
+    pub async fn execute_with_options<C: ToolCaller + ?Sized>(
+        &self,
+        code: &str,
+        tool_names: &[String],
+        caller: Arc<C>,
+        options: ExecutionOptions,
+    ) -> Result<ExecutionResult> {
+        if options.continuation.is_some() {
+            return Ok(ExecutionResult {
+                is_error: true,
+                error_message: Some(
+                    "Continuations are not supported by the lua engine".to_string(),
+                ),
+                ..Default::default()
+            });
+        }
+        let state = self.state_for(options.session.as_deref(), options.reset_session)?;
+        let code = code.to_string();
+        let tool_names = tool_names.to_vec();
+        run_blocking_script(caller, move |bridge| {
+            run_lua(&state, &code, &tool_names, bridge, options)
+        })
+        .await
+    }
+}
+
+fn run_lua(
+    state: &Mutex<Lua>,
+    code: &str,
+    tool_names: &[String],
+    bridge: ToolBridge,
+    options: ExecutionOptions,
+) -> ExecutionResult {
+    let lua = state.lock().unwrap();
+
+    let logs = Arc::new(Mutex::new(Vec::new()));
+    let tool_calls = Arc::new(AtomicUsize::new(0));
+    let assertion = Arc::new(Mutex::new(None));
+    let timed_out = Arc::new(AtomicBool::new(false));
+
+    let interrupt = Interrupt::new(&options);
+    let hook_interrupt = interrupt.clone();
+    let hook_timed_out = timed_out.clone();
+    lua.set_hook(
+        HookTriggers::new().every_nth_instruction(HOOK_INSTRUCTIONS),
+        move |_, _| {
+            if hook_interrupt.expired() {
+                hook_timed_out.store(true, Ordering::Relaxed);
+                return Err(mlua::Error::runtime("interrupted"));
+            }
+            if hook_interrupt.cancelled() {
+                return Err(mlua::Error::runtime("interrupted"));
+            }
+            Ok(())
+        },
+    );
+
+    let result = install_globals(
+        &lua,
+        tool_names,
+        &options,
+        bridge,
+        logs.clone(),
+        tool_calls.clone(),
+        assertion.clone(),
+    )
+    .and_then(|_| {
+        let remaining_interrupt = interrupt.clone();
+        let cancelled = lua.create_function(move |_, ()| Ok(interrupt.winding_down()))?;
+        lua.globals().set("cancelled", cancelled)?;
+        let deadline = lua.create_table()?;
+        deadline.set(
+            "remainingMs",
+            lua.create_function(move |_, ()| Ok(remaining_interrupt.remaining_ms()))?,
+        )?;
+        lua.globals().set("deadline", deadline)?;
+        if options.stats {
+            install_stats_globals(&lua, &lua.globals())?;
+        }
+        if options.hashes {
+            install_hash_globals(&lua, &lua.globals())?;
+        }
+        Ok(())
+    })
+    .and_then(|_| lua.load(code).set_name("script").eval::<Value>())
+    .and_then(|value| lua.from_value::<serde_json::Value>(value));
+    lua.remove_hook();
+
+    let (value, error) = match result {
+        Ok(value) => (value, None),
+        Err(_) if timed_out.load(Ordering::Relaxed) => (
+            serde_json::Value::Null,
+            Some(format!(
+                "Execution timed out after {}ms",
+                options.timeout.unwrap_or_default().as_millis()
+            )),
+        ),
+        Err(_) if options.cancellation.is_some_and(|ct| ct.is_cancelled()) => (
+            serde_json::Value::Null,
+            Some("Execution cancelled".to_string()),
+        ),
+        Err(e) => (serde_json::Value::Null, Some(error_message(&e))),
+    };
+    // A failed assertion the script caught doesn't explain a later error
+    let assertion = assertion
+        .lock()
+        .unwrap()
+        .take()
+        .filter(|failure: &AssertionFailure| {
+            error
+                .as_ref()
+                .is_some_and(|error| error.contains(&failure.message))
+        });
+
+    ExecutionResult {
+        value,
+        logs: logs.lock().unwrap().clone(),
+        is_error: error.is_some(),
+        error_message: error,
+        metrics: ExecutionMetrics {
+            tool_calls: tool_calls.load(Ordering::Relaxed),
+            ..Default::default()
+        },
+        continuation: None,
+        assertion,
+        ..Default::default()
+    }
+}
+
+impl ScriptRuntime for LuaRuntime {
+    fn engine(&self) -> ScriptEngine {
+        ScriptEngine::Lua
+    }
+
+    fn execute<'a>(
+        &'a self,
+        code: &'a str,
+        tool_names: &'a [String],
+        caller: Arc<dyn ToolCaller>,
+        options: ExecutionOptions,
+    ) -> ExecuteFuture<'a> {
+        Box::pin(self.execute_with_options(code, tool_names, caller, options))
+    }
+}
+
+fn install_globals(
+    lua: &Lua,
+    tool_names: &[String],
+    options: &ExecutionOptions,
+    bridge: ToolBridge,
+    logs: Arc<Mutex<Vec<String>>>,
+    tool_calls: Arc<AtomicUsize>,
+    assertion: Arc<Mutex<Option<AssertionFailure>>>,
+) -> mlua::Result<()> {
+    let globals = lua.globals();
+
+    let print_bridge = options.stream_logs.then(|| bridge.clone());
+    let print = lua.create_function(move |lua, args: MultiValue| {
+        let parts: Vec<String> = args
+            .into_iter()
+            .map(|arg| match arg {
+                Value::Table(_) => lua
+                    .from_value::<serde_json::Value>(arg)
+                    .map(|v| v.to_string())
+                    .unwrap_or_else(|_| "table".to_string()),
+                other => other.to_string().unwrap_or_default(),
+            })
+            .collect();
+        let line = parts.join(" ");
+        if let Some(bridge) = &print_bridge {
+            bridge.notify(ServerRequest::Log { line: line.clone() });
+        }
+        logs.lock().unwrap().push(line);
+        Ok(())
+    })?;
+    globals.set("print", print)?;
+
+    // `assert` and `expectEqual` record what failed for the structured
+    // `assertion` in the result, like the JavaScript assert prelude.
+    let assert_failure = assertion.clone();
+    let assert = lua.create_function(move |_, args: MultiValue| {
+        let args = args.into_vec();
+        if matches!(
+            args.first(),
+            None | Some(Value::Nil) | Some(Value::Boolean(false))
+        ) {
+            let message = match args.get(1) {
+                Some(Value::Nil) | None => "Assertion failed".to_string(),
+                Some(message) => message.to_string()?,
+            };
+            *assert_failure.lock().unwrap() = Some(AssertionFailure {
+                message: message.clone(),
+                expected: None,
+                actual: None,
+            });
+            return Err(mlua::Error::runtime(message));
+        }
+        Ok(MultiValue::from_vec(args))
+    })?;
+    globals.set("assert", assert)?;
+
+    let expect_equal = lua.create_function(
+        move |lua, (actual, expected, message): (Value, Value, Option<String>)| {
+            let actual: serde_json::Value = lua.from_value(actual)?;
+            let expected: serde_json::Value = lua.from_value(expected)?;
+            if actual == expected {
+                return Ok(());
+            }
+            let message = message.unwrap_or_else(|| format!("Expected {expected}, got {actual}"));
+            *assertion.lock().unwrap() = Some(AssertionFailure {
+                message: message.clone(),
+                expected: Some(expected),
+                actual: Some(actual),
+            });
+            Err(mlua::Error::runtime(message))
+        },
+    )?;
+    globals.set("expectEqual", expect_equal)?;
+
+    let tools = lua.create_table()?;
+    for tool_name in tool_names {
+        let name = tool_name.clone();
+        let bridge = bridge.clone();
+        let tool_calls = tool_calls.clone();
+        let func = lua.create_function(
+            move |lua, (args, options): (Option<Value>, Option<Table>)| {
+                tool_calls.fetch_add(1, Ordering::Relaxed);
+                let options = CallOptions {
+                    cache: match options {
+                        Some(options) => options.get::<_, Option<bool>>("cache")?.unwrap_or(true),
+                        None => true,
+                    },
+                };
+                let args = match args {
+                    Some(Value::Table(t)) if t.is_empty() => Some(serde_json::json!({})),
+                    Some(args) => Some(lua.from_value::<serde_json::Value>(args)?),
+                    None => Some(serde_json::json!({})),
+                };
+
+                let text = raw_tool_result(bridge.call_tool(&name, args, options), None);
+                let result = serde_json::from_str(&text).unwrap_or(serde_json::Value::String(text));
+                if let Some(error) = result.get("error").filter(|e| !e.is_null()) {
+                    let error = error
+                        .as_str()
+                        .map(str::to_string)
+                        .unwrap_or(error.to_string());
+                    return Err(mlua::Error::runtime(format!("Tool {name} failed: {error}")));
+                }
+                lua.to_value(&result)
+            },
+        )?;
+        tools.set(tool_name.as_str(), func)?;
+    }
+    for (alias, name) in script_aliases(tool_names, options.camel_case) {
+        tools.set(alias, tools.get::<_, Function>(name)?)?;
+    }
+
+    let server = move |request: ServerRequest| {
+        bridge
+            .server_request(request)
+            .map_err(|e| mlua::Error::runtime(e.to_string()))
+    };
+
+    let resources = lua.create_table()?;
+    let read_server = server.clone();
+    let read = lua.create_function(move |lua, uri: String| {
+        lua.to_value(&read_server(ServerRequest::ReadResource { uri })?)
+    })?;
+    resources.set("read", read)?;
+    globals.set("resources", resources)?;
+
+    let prompts = lua.create_table()?;
+    let list_server = server.clone();
+    let list = lua
+        .create_function(move |lua, ()| lua.to_value(&list_server(ServerRequest::ListPrompts)?))?;
+    prompts.set("list", list)?;
+    let get_server = server.clone();
+    let get = lua.create_function(move |lua, (name, args): (String, Option<Value>)| {
+        let arguments = match args {
+            Some(Value::Table(t)) if t.is_empty() => None,
+            Some(args) => lua.from_value(args)?,
+            None => None,
+        };
+        lua.to_value(&get_server(ServerRequest::GetPrompt { name, arguments })?)
+    })?;
+    prompts.set("get", get)?;
+    globals.set("prompts", prompts)?;
+
+    install_time_globals(lua, &globals)?;
+
+    let roots_server = server.clone();
+    let roots =
+        lua.create_function(move |lua, ()| lua.to_value(&roots_server(ServerRequest::ListRoots)?))?;
+    globals.set("roots", roots)?;
+
+    let progress_server = server.clone();
+    let progress = lua.create_function(
+        move |_, (progress, total, message): (f64, Option<f64>, Option<String>)| {
+            progress_server(ServerRequest::Progress {
+                progress,
+                total,
+                message,
+            })?;
+            Ok(())
+        },
+    )?;
+    globals.set("progress", progress)?;
+
+    let elicit_server = server.clone();
+    let ask_server = server.clone();
+    let llm_server = server.clone();
+    let sample = lua.create_function(move |lua, (prompt, opts): (Value, Option<Value>)| {
+        let prompt = match prompt {
+            Value::String(s) => s.to_str()?.to_string(),
+            other => lua.from_value::<serde_json::Value>(other)?.to_string(),
+        };
+        let options = match opts {
+            Some(Value::Table(t)) if t.is_empty() => Default::default(),
+            Some(opts) => lua.from_value(opts)?,
+            None => Default::default(),
+        };
+        lua.to_value(&server(ServerRequest::CreateMessage { prompt, options })?)
+    })?;
+    globals.set("sample", sample)?;
+
+    let elicit = lua.create_function(move |lua, (schema, message): (Value, String)| {
+        let schema = lua.from_value(schema)?;
+        lua.to_value(&elicit_server(ServerRequest::Elicit { schema, message })?)
+    })?;
+    globals.set("elicit", elicit)?;
+
+    let ask = lua.create_function(move |lua, (prompt, schema): (String, Option<Value>)| {
+        let schema = schema.map(|schema| lua.from_value(schema)).transpose()?;
+        lua.to_value(&ask_server(ServerRequest::Ask { prompt, schema })?)
+    })?;
+    globals.set("ask", ask)?;
+
+    if options.llm {
+        let llm = lua.create_table()?;
+        let complete =
+            lua.create_function(move |lua, (prompt, opts): (Value, Option<Table>)| {
+                let prompt = match prompt {
+                    Value::String(s) => s.to_str()?.to_string(),
+                    other => lua.from_value::<serde_json::Value>(other)?.to_string(),
+                };
+                let mut json = false;
+                let options = match opts {
+                    Some(opts) => {
+                        json = opts.get::<_, Option<bool>>("json")?.unwrap_or(false);
+                        opts.set("json", Value::Nil)?;
+                        if opts.is_empty() {
+                            Default::default()
+                        } else {
+                            lua.from_value(Value::Table(opts))?
+                        }
+                    }
+                    None => Default::default(),
+                };
+                lua.to_value(&llm_server(ServerRequest::Complete {
+                    prompt,
+                    options,
+                    json,
+                })?)
+            })?;
+        llm.set("complete", complete)?;
+        globals.set("llm", llm)?;
+    }
+
+    match options.namespace.as_deref() {
+        Some(namespace) => {
+            let namespaced = lua.create_table()?;
+            namespaced.set(namespace, tools)?;
+            globals.set("tools", namespaced)
+        }
+        None => globals.set("tools", tools),
+    }
+}
+
+/// The message a script author would expect, without mlua's wrapping.
+fn error_message(error: &mlua::Error) -> String {
+    match error {
+        mlua::Error::RuntimeError(message) | mlua::Error::SyntaxError { message, .. } => {
+            message.clone()
+        }
+        mlua::Error::CallbackError { cause, .. } => error_message(cause),
+        other => other.to_string(),
+    }
+}
+
+/// `now`, `parseDate`, `formatDate` and `convertTimezone`, as in
+/// JavaScript; dates are ISO 8601 strings or epoch milliseconds.
+fn install_time_globals(lua: &Lua, globals: &mlua::Table) -> mlua::Result<()> {
+    fn date(lua: &Lua, value: Value) -> mlua::Result<chrono::DateTime<chrono::Utc>> {
+        let date: time::DateArg = lua.from_value(value)?;
+        date.to_datetime().map_err(mlua::Error::external)
+    }
+
+    globals.set("now", lua.create_function(|_, ()| Ok(time::now()))?)?;
+    let parse_date = lua.create_function(|lua, (text, opts): (String, Option<Value>)| {
+        #[derive(Default, serde::Deserialize)]
+        struct Opts {
+            format: Option<String>,
+            timezone: Option<String>,
+        }
+        let opts: Opts = match opts {
+            Some(Value::Table(t)) if t.is_empty() => Opts::default(),
+            Some(opts) => lua.from_value(opts)?,
+            None => Opts::default(),
+        };
+        time::parse_date(&text, opts.format.as_deref(), opts.timezone.as_deref())
+            .map(time::iso)
+            .map_err(mlua::Error::external)
+    })?;
+    globals.set("parseDate", parse_date)?;
+    let format_date = lua.create_function(
+        |lua, (value, format, timezone): (Value, String, Option<String>)| {
+            time::format_date(date(lua, value)?, &format, timezone.as_deref())
+                .map_err(mlua::Error::external)
+        },
+    )?;
+    globals.set("formatDate", format_date)?;
+    let convert_timezone = lua.create_function(|lua, (value, timezone): (Value, String)| {
+        time::convert_timezone(date(lua, value)?, &timezone).map_err(mlua::Error::external)
+    })?;
+    globals.set("convertTimezone", convert_timezone)?;
+    Ok(())
+}
+
+/// The `stats` table, as in JavaScript; `key` is a field name or a
+/// function that picks the number out of each item.
+fn install_stats_globals(lua: &Lua, globals: &mlua::Table) -> mlua::Result<()> {
+    fn numbers(
+        lua: &Lua,
+        function: &str,
+        values: mlua::Table,
+        key: Option<Value>,
+    ) -> mlua::Result<Vec<f64>> {
+        let values = values
+            .sequence_values::<Value>()
+            .map(|item| {
+                let value = match &key {
+                    None | Some(Value::Nil) => item?,
+                    Some(Value::Function(pick)) => pick.call(item?)?,
+                    Some(key) => match item? {
+                        Value::Table(item) => item.get(key.clone())?,
+                        _ => Value::Nil,
+                    },
+                };
+                lua.from_value::<serde_json::Value>(value)
+            })
+            .collect::<mlua::Result<Vec<_>>>()?;
+        stats::numbers(function, &values).map_err(mlua::Error::external)
+    }
+
+    let table = lua.create_table()?;
+    let sum = lua.create_function(|lua, (values, key): (mlua::Table, Option<Value>)| {
+        Ok(stats::sum(&numbers(lua, "sum", values, key)?))
+    })?;
+    table.set("sum", sum)?;
+    let mean = lua.create_function(|lua, (values, key): (mlua::Table, Option<Value>)| {
+        Ok(stats::mean(&numbers(lua, "mean", values, key)?))
+    })?;
+    table.set("mean", mean)?;
+    let median = lua.create_function(|lua, (values, key): (mlua::Table, Option<Value>)| {
+        Ok(stats::median(&numbers(lua, "median", values, key)?))
+    })?;
+    table.set("median", median)?;
+    let percentile =
+        lua.create_function(|lua, (values, p, key): (mlua::Table, f64, Option<Value>)| {
+            stats::percentile(&numbers(lua, "percentile", values, key)?, p)
+                .map_err(mlua::Error::external)
+        })?;
+    table.set("percentile", percentile)?;
+    let histogram = lua.create_function(
+        |lua, (values, bins, key): (mlua::Table, Option<usize>, Option<Value>)| {
+            let bins = bins.unwrap_or(stats::DEFAULT_BINS);
+            if bins == 0 {
+                return Err(mlua::Error::runtime(
+                    "stats.histogram: bins must be a positive integer",
+                ));
+            }
+            let values = numbers(lua, "histogram", values, key)?;
+            lua.to_value(&stats::histogram(&values, bins))
+        },
+    )?;
+    table.set("histogram", histogram)?;
+    globals.set("stats", table)?;
+    Ok(())
+}
+
+/// `uuid`, `sha256` and `hmac`, as in JavaScript; tables are hashed as
+/// their JSON text.
+fn install_hash_globals(lua: &Lua, globals: &mlua::Table) -> mlua::Result<()> {
+    fn text(lua: &Lua, value: Value) -> mlua::Result<String> {
+        Ok(hash::text(&lua.from_value(value)?))
+    }
+
+    globals.set("uuid", lua.create_function(|_, ()| Ok(hash::uuid()))?)?;
+    let sha256 = lua.create_function(|lua, value: Value| Ok(hash::sha256(&text(lua, value)?)))?;
+    globals.set("sha256", sha256)?;
+    let hmac = lua.create_function(|lua, (key, value): (Value, Value)| {
+        Ok(hash::hmac_sha256(&text(lua, key)?, &text(lua, value)?))
+    })?;
+    globals.set("hmac", hmac)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::{ServerRequestFuture, ToolCallFuture};
+    use rmcp::model::{CallToolResult, Content};
+    use tokio_util::sync::CancellationToken;
+
+    struct Search;
+
+    impl ToolCaller for Search {
+        fn call_tool<'a>(
+            &'a self,
+            name: &'a str,
+            args: Option<serde_json::Value>,
+        ) -> ToolCallFuture<'a> {
+            let body = match name {
+                "search" => serde_json::json!({ "query": args.unwrap()["query"], "hits": [1, 2] }),
+                _ => serde_json::json!({ "error": "not found" }),
+            };
+            Box::pin(async move {
+                Ok(CallToolResult::success(vec![Content::text(
+                    body.to_string(),
+                )]))
+            })
+        }
+
+        fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+            Box::pin(async move {
+                match request {
+                    ServerRequest::GetPrompt { name, arguments } => {
+                        Ok(serde_json::json!([{ "name": name, "arguments": arguments }]))
+                    }
+                    other => anyhow::bail!("{} is not available", other.function()),
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_requests() {
+        let runtime = LuaRuntime::new();
+        let code = r#"
+            local ok, err = pcall(resources.read, "mem://x")
+            return { prompt = prompts.get("summarize", { topic = "pcb" }), err = tostring(err) }
+        "#;
+        let result = runtime
+            .execute_with_options(code, &[], Arc::new(Search), ExecutionOptions::default())
+            .await
+            .unwrap();
+
+        assert!(!result.is_error, "{:?}", result.error_message);
+        assert_eq!(
+            result.value["prompt"],
+            serde_json::json!([{ "name": "summarize", "arguments": { "topic": "pcb" } }])
+        );
+        assert!(
+            result.value["err"]
+                .as_str()
+                .unwrap()
+                .contains("resources.read is not available")
+        );
+    }
+
+    fn tool_names() -> Vec<String> {
+        vec!["search".to_string(), "missing".to_string()]
+    }
+
+    #[tokio::test]
+    async fn test_tools_and_print() {
+        let runtime = LuaRuntime::new();
+        let code = r#"
+            local result = tools.search({ query = "pcb" })
+            print("hits:", #result.hits, result)
+            return result.query
+        "#;
+        let result = runtime
+            .execute_with_options(
+                code,
+                &tool_names(),
+                Arc::new(Search),
+                ExecutionOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        assert!(!result.is_error, "{:?}", result.error_message);
+        assert_eq!(result.value, "pcb");
+        assert_eq!(result.metrics.tool_calls, 1);
+        assert!(result.logs[0].starts_with("hits: 2 {"));
+    }
+
+    #[tokio::test]
+    async fn test_tool_error_is_raised() {
+        let runtime = LuaRuntime::new();
+        let result = runtime
+            .execute_with_options(
+                "return tools.missing()",
+                &tool_names(),
+                Arc::new(Search),
+                ExecutionOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        assert!(result.is_error);
+        assert_eq!(
+            result.error_message.as_deref(),
+            Some("Tool missing failed: not found")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_assertion_failure() {
+        let runtime = LuaRuntime::new();
+        let run = |code: &'static str| {
+            runtime.execute_with_options(code, &[], Arc::new(Search), ExecutionOptions::default())
+        };
+
+        let result =
+            run("expectEqual({ a = 1, b = { 2, 3 } }, { b = { 2, 3 }, a = 1 }) return assert(5)")
+                .await
+                .unwrap();
+        assert!(!result.is_error, "{:?}", result.error_message);
+        assert_eq!(result.value, 5);
+
+        let result = run("expectEqual(#{ 1, 2 }, 3)").await.unwrap();
+        assert!(result.is_error);
+        let assertion = result.assertion.unwrap();
+        assert_eq!(assertion.message, "Expected 3, got 2");
+        assert_eq!(assertion.expected, Some(serde_json::json!(3)));
+
+        let result = run("pcall(assert, false, 'caught') error('boom')")
+            .await
+            .unwrap();
+        assert!(result.is_error);
+        assert_eq!(result.assertion, None);
+    }
+
+    #[tokio::test]
+    async fn test_time_helpers() {
+        let runtime = LuaRuntime::new();
+        let code = r#"
+            local start = parseDate("01/03/2026", { format = "%d/%m/%Y" })
+            local ok, err = pcall(convertTimezone, start, "Nowhere")
+            return {
+                start = start,
+                ny = convertTimezone(start, "America/New_York"),
+                epoch = formatDate(0, "%Y-%m-%d"),
+                err = tostring(err),
+            }
+        "#;
+        let result = runtime
+            .execute_with_options(code, &[], Arc::new(Search), ExecutionOptions::default())
+            .await
+            .unwrap();
+
+        assert!(!result.is_error, "{:?}", result.error_message);
+        assert_eq!(result.value["start"], "2026-03-01T00:00:00Z");
+        assert_eq!(result.value["ny"], "2026-02-28T19:00:00-05:00");
+        assert_eq!(result.value["epoch"], "1970-01-01");
+        assert!(
+            result.value["err"]
+                .as_str()
+                .unwrap()
+                .contains("Unknown time zone `Nowhere`")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stats_helpers() {
+        let runtime = LuaRuntime::new();
+        let code = r#"
+            local items = { { ms = 10 }, { ms = 30 }, { ms = 20 } }
+            local ok, err = pcall(stats.sum, { 1, "two" })
+            return {
+                sum = stats.sum(items, "ms"),
+                median = stats.median(items, function(item) return item.ms end),
+                bins = #stats.histogram({ 1, 2, 3 }),
+                err = tostring(err),
+            }
+        "#;
+        let options = ExecutionOptions {
+            stats: true,
+            ..Default::default()
+        };
+        let result = runtime
+            .execute_with_options(code, &[], Arc::new(Search), options)
+            .await
+            .unwrap();
+
+        assert!(!result.is_error, "{:?}", result.error_message);
+        assert_eq!(result.value["sum"], 60.0);
+        assert_eq!(result.value["median"], 20.0);
+        assert_eq!(result.value["bins"], 10);
+        assert!(
+            result.value["err"]
+                .as_str()
+                .unwrap()
+                .contains("stats.sum: value at index 1 is not a number: \"two\"")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hash_helpers() {
+        let runtime = LuaRuntime::new();
+        let code = r#"
+            return {
+                id = #uuid(),
+                sha = sha256("abc"),
+                hmac = hmac("Jefe", "what do ya want for nothing?"),
+            }
+        "#;
+        let options = ExecutionOptions {
+            hashes: true,
+            ..Default::default()
+        };
+        let result = runtime
+            .execute_with_options(code, &[], Arc::new(Search), options)
+            .await
+            .unwrap();
+
+        assert!(!result.is_error, "{:?}", result.error_message);
+        assert_eq!(result.value["id"], 36);
+        assert_eq!(
+            result.value["sha"],
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            result.value["hmac"],
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_no_os_access() {
+        let runtime = LuaRuntime::new();
+        let result = runtime
+            .execute_with_options(
+                "return os == nil and io == nil and dofile == nil",
+                &[],
+                Arc::new(Search),
+                ExecutionOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.value, true);
+    }
+
+    #[tokio::test]
+    async fn test_timeout_interrupts_infinite_loop() {
+        let runtime = LuaRuntime::new();
+        let options = ExecutionOptions {
+            timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let result = runtime
+            .execute_with_options("while true do end", &[], Arc::new(Search), options)
+            .await
+            .unwrap();
+        assert_eq!(
+            result.error_message.as_deref(),
+            Some("Execution timed out after 50ms")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deadline_remaining() {
+        let runtime = LuaRuntime::new();
+        let result = runtime
+            .execute_with_options(
+                "return deadline.remainingMs() == math.huge",
+                &[],
+                Arc::new(Search),
+                ExecutionOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.value, true);
+
+        let options = ExecutionOptions {
+            timeout: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let result = runtime
+            .execute_with_options(
+                "local left = deadline.remainingMs() return left > 9000 and left <= 10000",
+                &[],
+                Arc::new(Search),
+                options,
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.value, true);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_script_winds_down() {
+        let ct = CancellationToken::new();
+        ct.cancel();
+        let options = ExecutionOptions {
+            cancellation: Some(ct),
+            cancel_grace: Duration::from_secs(5),
+            ..Default::default()
+        };
+        let result = LuaRuntime::new()
+            .execute_with_options(
+                "local n = 0 while not cancelled() do n = n + 1 end return 'partial'",
+                &[],
+                Arc::new(Search),
+                options,
+            )
+            .await
+            .unwrap();
+        assert!(!result.is_error, "{:?}", result.error_message);
+        assert_eq!(result.value, "partial");
+
+        // Without a grace period it is interrupted straight away
+        let ct = CancellationToken::new();
+        ct.cancel();
+        let options = ExecutionOptions {
+            cancellation: Some(ct),
+            ..Default::default()
+        };
+        let result = LuaRuntime::new()
+            .execute_with_options("while true do end", &[], Arc::new(Search), options)
+            .await
+            .unwrap();
+        assert_eq!(result.error_message.as_deref(), Some("Execution cancelled"));
+    }
+
+    #[tokio::test]
+    async fn test_session_keeps_globals() {
+        let runtime = LuaRuntime::new();
+        let options = ExecutionOptions {
+            session: Some("s1".to_string()),
+            ..Default::default()
+        };
+        runtime
+            .execute_with_options("counter = 41", &[], Arc::new(Search), options.clone())
+            .await
+            .unwrap();
+        let result = runtime
+            .execute_with_options("return counter + 1", &[], Arc::new(Search), options)
+            .await
+            .unwrap();
+        assert_eq!(result.value.as_f64(), Some(42.0));
+    }
+
+    #[tokio::test]
+    async fn test_namespace_nests_tools() {
+        let options = ExecutionOptions {
+            namespace: Some("kicad".to_string()),
+            ..Default::default()
+        };
+        let result = LuaRuntime::new()
+            .execute_with_options(
+                "return tools.kicad.search({ query = 'x' }).query",
+                &tool_names(),
+                Arc::new(Search),
+                options,
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.value, "x");
+    }
+}
@@ -1,77 +1,843 @@
-use anyhow::Result;
-use clap::Parser;
-use codemoder::{CodeModeConfig, CodeModeProxy};
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use codemoder::bundle::{ReplayServer, RunBundle};
+use codemoder::config::{CallTimeoutSettings, CircuitBreakerSettings, RetrySettings};
+use codemoder::downstream::DownstreamHandler;
+use codemoder::proxy::resolve_tool_name;
+use codemoder::runtime::DownstreamClient;
+#[cfg(unix)]
+use codemoder::standby::Switchover;
+use codemoder::storage::FsStorage;
+use codemoder::{
+    CodeModeConfig, CodeModeProxy, Drain, convert, features, fixture, policy, scaffold, selftest,
+    telemetry,
+};
 use rmcp::{ServiceExt, transport::TokioChildProcess};
+use std::path::PathBuf;
+use std::time::Duration;
 use tokio::process::Command;
 use tracing::info;
 
 #[derive(Parser, Debug)]
 #[command(name = "codemoder")]
 #[command(about = "MCP proxy that adds code-mode capability to any MCP server")]
+#[command(args_conflicts_with_subcommands = true)]
 struct Args {
-    /// Mode: "replace" to only expose execute_tools, "add" to expose both
-    #[arg(long, default_value = "add")]
-    mode: String,
+    #[command(subcommand)]
+    subcommand: Option<Commands>,
 
-    /// Name of the code execution tool
-    #[arg(long, default_value = "execute_tools")]
-    tool_name: String,
+    /// Path to a TOML config file. Command-line flags override its values.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Apply the named `[profiles.<name>]` section of the config file
+    #[arg(long, requires = "config")]
+    profile: Option<String>,
+
+    /// Mode: "replace" to only expose execute_tools, "add" to expose both [default: add]
+    #[arg(long)]
+    mode: Option<String>,
+
+    /// Name of the code execution tool [default: execute_tools]
+    #[arg(long)]
+    tool_name: Option<String>,
+
+    /// When a downstream tool already has the execute tool's name: "rename"
+    /// the execute tool or "fail" to start [default: rename]
+    #[arg(long)]
+    tool_name_collision: Option<String>,
 
     /// Only include these tools (comma-separated). If not specified, includes all.
     #[arg(long)]
     include_tools: Option<String>,
 
+    /// Expose passthrough tools as `<namespace>_<tool>` and to scripts as
+    /// `tools.<namespace>.<tool>`
+    #[arg(long)]
+    namespace: Option<String>,
+
+    /// Also let scripts call snake_case tools by their camelCase names
+    #[arg(long)]
+    camel_case_aliases: bool,
+
+    /// Answer identical calls to read-only tools once per execution
+    #[arg(long)]
+    dedupe_read_only_calls: bool,
+
+    /// Hide these tools (comma-separated), even if included
+    #[arg(long)]
+    exclude_tools: Option<String>,
+
+    /// Interrupt scripts that run longer than this many milliseconds
+    #[arg(long)]
+    timeout_ms: Option<u64>,
+
+    /// Let cancelled scripts run this many more milliseconds to return what
+    /// they have [default: 1000]
+    #[arg(long)]
+    cancel_grace_ms: Option<u64>,
+
+    /// Make failed downstream tool calls up to this many times in all, with
+    /// exponential backoff; see `[retry]` for more settings
+    #[arg(long)]
+    retry_attempts: Option<u32>,
+
+    /// Fail calls fast for a while after this many consecutive downstream
+    /// failures; see `[circuit_breaker]` for more settings
+    #[arg(long)]
+    circuit_failure_threshold: Option<u32>,
+
+    /// Fail a downstream tool call after this many milliseconds; see
+    /// `[call_timeout]` for more settings
+    #[arg(long)]
+    call_timeout_ms: Option<u64>,
+
+    /// Derive each tool's call timeout from the p99 latency of its recent
+    /// calls, up to --call-timeout-ms
+    #[arg(long)]
+    adaptive_call_timeouts: bool,
+
+    /// Discard execute_tools sessions idle for this many seconds [default: 1800]
+    #[arg(long)]
+    session_ttl_secs: Option<u64>,
+
+    /// List the downstream tools again for scripts once the list is this
+    /// many seconds old
+    #[arg(long)]
+    tools_ttl_secs: Option<u64>,
+
+    /// List tools to clients in pages of this many, with a cursor to the
+    /// next [default: all at once]
+    #[arg(long)]
+    tools_page_size: Option<usize>,
+
+    /// Let scripts pause with `yieldToAgent`, keeping at most this many
+    /// paused executions [default: 0, disabled]
+    #[arg(long)]
+    max_continuations: Option<usize>,
+
+    /// Add start_execution, get_execution and cancel_execution tools for
+    /// running long scripts in the background
+    #[arg(long)]
+    background_executions: bool,
+
+    /// Add a codemode_stats tool reporting per-tool calls, errors and
+    /// durations since startup
+    #[arg(long)]
+    stats_tool: bool,
+
+    /// Report in each result's `_meta.provenance` the downstream calls its
+    /// value came from
+    #[arg(long)]
+    provenance: bool,
+
+    /// On SIGUSR1, wait this long for running executions before exiting [default: 30]
+    #[arg(long)]
+    drain_timeout_secs: Option<u64>,
+
+    /// List only tool names and summaries in the execute tool's description,
+    /// with full signatures available from `get_tool_docs`
+    #[arg(long)]
+    lazy_tool_docs: bool,
+
+    /// Give scripts the `stats` global (sum, mean, median, percentile,
+    /// histogram)
+    #[arg(long)]
+    stats_helpers: bool,
+
+    /// Give scripts `uuid()`, `sha256(value)` and `hmac(key, value)`
+    #[arg(long)]
+    hash_helpers: bool,
+
+    /// Give scripts `llm.complete(prompt, options)` for completions from the
+    /// client's model
+    #[arg(long)]
+    llm_helpers: bool,
+
+    /// Run regular expressions in scripts on a linear-time engine
+    #[arg(long)]
+    safe_regex: bool,
+
+    /// Send `console.log` lines to the client as logging notifications while
+    /// scripts run
+    #[arg(long)]
+    stream_logs: bool,
+
+    /// Refuse script tool calls with path-like arguments outside the
+    /// client's roots
+    #[arg(long)]
+    check_paths: bool,
+
+    /// Note what was wrong with the arguments of failed script tool calls
+    /// in the execute tool's description
+    #[arg(long)]
+    failure_notes: bool,
+
+    /// Refuse script tool calls with argument fields the tool's input schema
+    /// doesn't declare
+    #[arg(long)]
+    strict_args: bool,
+
+    /// Convert script tool call arguments to the types the tool's input
+    /// schema declares when that's unambiguous, like "5" to 5
+    #[arg(long)]
+    coerce_args: bool,
+
+    /// How much of the tool interface to embed in the execute tool's
+    /// description: full, signatures, summaries, names or none [default: full]
+    #[arg(long)]
+    interface_detail: Option<String>,
+
+    /// Write a repro bundle of every execution to this directory, for
+    /// `export-run`
+    #[arg(long)]
+    record_dir: Option<PathBuf>,
+
+    /// Ask at the controlling terminal before running scripts (or, if any
+    /// tools set `require_approval`, before calling those tools)
+    #[arg(long)]
+    approve_tty: bool,
+
+    /// Export tracing spans over OTLP/HTTP to the collector at this URL
+    /// (needs the `otel` feature) [default: $OTEL_EXPORTER_OTLP_ENDPOINT]
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
+
+    /// Command and arguments to run a standby downstream MCP server, up to
+    /// the `--` before the primary command. It is kept initialized, and
+    /// SIGUSR2 switches traffic to it
+    #[arg(long, num_args = 1.., allow_hyphen_values = true, value_terminator = "--")]
+    standby_command: Option<Vec<String>>,
+
     /// Command to run the downstream MCP server
-    #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
     command: Vec<String>,
 }
 
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Inspect config files
+    Config {
+        #[command(subcommand)]
+        action: ConfigCommand,
+    },
+    /// Convert a recorded sequence of tool calls (JSON-RPC messages or
+    /// `{name, arguments, result}` records) into an equivalent script
+    ConvertTrace {
+        /// Trace file: a JSON array or JSON lines
+        trace: PathBuf,
+        /// Call tools as `tools.<namespace>.<tool>`, to match `--namespace`
+        #[arg(long)]
+        namespace: Option<String>,
+        /// Write to this file instead of stdout
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Run a script once against a downstream server and print its result
+    Exec {
+        /// Script file to run
+        #[arg(required_unless_present = "replay", conflicts_with = "replay")]
+        script: Option<PathBuf>,
+        /// Run a bundle from `export-run` against its recorded responses,
+        /// or against the given downstream server, with its config unless
+        /// `--config` is given
+        #[arg(long)]
+        replay: Option<PathBuf>,
+        /// Run a replayed bundle against a live server even though its
+        /// script made destructive calls without declaring itself idempotent
+        #[arg(long, requires = "replay")]
+        force: bool,
+        /// Fail with a JSON diff unless the result matches this JSON file
+        #[arg(long)]
+        expect: Option<PathBuf>,
+        /// Treat numbers within this absolute difference as equal
+        #[arg(long, default_value_t = 0.0, requires = "expect")]
+        tolerance: f64,
+        /// Compare arrays ignoring element order
+        #[arg(long, requires = "expect")]
+        ignore_order: bool,
+        /// Path to a TOML config file
+        #[arg(long)]
+        config: Option<PathBuf>,
+        /// Apply the named `[profiles.<name>]` section of the config file
+        #[arg(long, requires = "config")]
+        profile: Option<String>,
+        /// Command to run the downstream MCP server
+        #[arg(last = true, required_unless_present = "replay")]
+        command: Vec<String>,
+    },
+    /// Copy the repro bundle of a recorded execution, for `exec --replay`
+    ExportRun {
+        /// Execution id, from the result's `_meta.executionId` or the
+        /// `codemode://summary` resource
+        execution_id: String,
+        /// Directory the proxy recorded to [default: `record_dir` from --config]
+        #[arg(long)]
+        record_dir: Option<PathBuf>,
+        /// Path to a TOML config file
+        #[arg(long)]
+        config: Option<PathBuf>,
+        /// Apply the named `[profiles.<name>]` section of the config file
+        #[arg(long, requires = "config")]
+        profile: Option<String>,
+        /// Write to this file instead of stdout
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Print the features compiled into this binary as JSON
+    Features,
+    /// Generate a main.rs that serves an rmcp server through CodeModeWrapper
+    ScaffoldWrapper {
+        /// Crate that exports the server type
+        #[arg(long)]
+        server_crate: String,
+        /// The `ServerHandler` type to wrap
+        #[arg(long, default_value = "Server")]
+        server_type: String,
+        /// How to construct the server, after `ServerType::`
+        #[arg(long, default_value = "new()")]
+        constructor: String,
+        /// Write to this file instead of stdout
+        #[arg(long)]
+        output: Option<PathBuf>,
+        /// Overwrite `--output` if it already exists
+        #[arg(long)]
+        force: bool,
+    },
+    /// Run MCP conformance checks against the proxy in front of a
+    /// downstream server and print a report
+    Selftest {
+        /// Path to a TOML config file
+        #[arg(long)]
+        config: Option<PathBuf>,
+        /// Apply the named `[profiles.<name>]` section of the config file
+        #[arg(long, requires = "config")]
+        profile: Option<String>,
+        /// Command to run the downstream MCP server
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Print the JSON Schema for config files
+    Schema,
+    /// Check a config file and each of its profiles for errors without
+    /// starting the proxy
+    Validate { file: PathBuf },
+}
+
+fn run_config_command(action: ConfigCommand) -> Result<()> {
+    match action {
+        ConfigCommand::Schema => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&CodeModeConfig::json_schema())?
+            );
+        }
+        ConfigCommand::Validate { file } => {
+            let base = CodeModeConfig::from_file(&file)?;
+            for profile in base.profiles.keys() {
+                CodeModeConfig::from_file_with_profile(&file, Some(profile))?;
+            }
+            if base.profiles.is_empty() {
+                println!("{}: ok", file.display());
+            } else {
+                let names: Vec<&str> = base.profiles.keys().map(String::as_str).collect();
+                println!("{}: ok (profiles: {})", file.display(), names.join(", "));
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn connect_downstream(
+    command: &[String],
+    handler: DownstreamHandler,
+) -> Result<DownstreamClient> {
+    info!("Starting downstream MCP server: {:?}", command);
+
+    let mut cmd = Command::new(&command[0]);
+    if command.len() > 1 {
+        cmd.args(&command[1..]);
+    }
+
+    let transport = TokioChildProcess::new(cmd)?;
+
+    info!("Connecting to downstream server...");
+    let downstream = handler.serve(transport).await?;
+    if let Some(peer) = downstream.peer_info() {
+        info!(
+            "Connected to {} {}",
+            peer.server_info.name, peer.server_info.version
+        );
+    }
+    Ok(downstream)
+}
+
+/// Start a standby downstream server from `command` and keep it ready in
+/// `switchover`.
+#[cfg(unix)]
+async fn start_standby(switchover: Switchover, command: Vec<String>) {
+    match connect_downstream(&command, switchover.handler()).await {
+        Ok(standby) => {
+            switchover.set_standby(standby).await;
+            info!("Standby downstream server ready");
+        }
+        Err(e) => tracing::warn!("Failed to start the standby downstream server: {e}"),
+    }
+}
+
+/// Run `code` once and print its result, or its differences from the
+/// `expect` fixture. Returns whether the run succeeded.
+async fn run_exec(
+    code: String,
+    expect: Option<PathBuf>,
+    options: fixture::CompareOptions,
+    config: CodeModeConfig,
+    downstream: DownstreamClient,
+) -> Result<bool> {
+    let expected: Option<serde_json::Value> = match &expect {
+        Some(path) => {
+            let text = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read fixture {}", path.display()))?;
+            Some(
+                serde_json::from_str(&text)
+                    .with_context(|| format!("Invalid fixture {}", path.display()))?,
+            )
+        }
+        None => None,
+    };
+
+    let policy = policy::policy_from_config(&config)?;
+    let mut proxy = CodeModeProxy::new(downstream, config);
+    if let Some(policy) = policy {
+        proxy = proxy.with_policy(policy);
+    }
+    let actual = match proxy.execute_code_direct(&code).await {
+        Ok(value) => value,
+        Err(e) => {
+            let mut report = serde_json::json!({ "ok": false, "error": e.message });
+            if let Some(assertion) = e.data.as_ref().and_then(|data| data.get("assertion")) {
+                report["assertion"] = assertion.clone();
+            }
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            return Ok(false);
+        }
+    };
+
+    let Some(expected) = expected else {
+        println!("{}", serde_json::to_string_pretty(&actual)?);
+        return Ok(true);
+    };
+    let differences = fixture::compare(&expected, &actual, options);
+    let report = serde_json::json!({
+        "ok": differences.is_empty(),
+        "differences": differences,
+    });
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(differences.is_empty())
+}
+
+/// Run the conformance suite against a proxy from `config` in front of
+/// `downstream` and print its report. Returns whether every check passed.
+async fn run_selftest(mut config: CodeModeConfig, downstream: DownstreamClient) -> Result<bool> {
+    resolve_tool_name(&downstream, &mut config).await?;
+    // Without a page size the tool list is a single page, which leaves
+    // cursors untested
+    if config.tools_page_size.is_none() {
+        config = config.with_tools_page_size(1);
+    }
+    let policy = policy::policy_from_config(&config)?;
+    let mut proxy = CodeModeProxy::new(downstream, config.clone());
+    if let Some(policy) = policy {
+        proxy = proxy.with_policy(policy);
+    }
+    let report = selftest::run(proxy, &config).await;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(report.ok)
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::from_default_env()
-                .add_directive(tracing::Level::INFO.into()),
-        )
-        .with_writer(std::io::stderr)
-        .init();
-
     let args = Args::parse();
+    let _telemetry = telemetry::init(args.otlp_endpoint.clone())?;
+
+    match args.subcommand {
+        Some(Commands::Config { action }) => return run_config_command(action),
+        Some(Commands::Exec {
+            script,
+            replay,
+            force,
+            expect,
+            tolerance,
+            ignore_order,
+            config,
+            profile,
+            command,
+        }) => {
+            let config = match config {
+                Some(path) => Some(CodeModeConfig::from_file_with_profile(
+                    path,
+                    profile.as_deref(),
+                )?),
+                None => None,
+            };
+            let options = fixture::CompareOptions {
+                tolerance,
+                ignore_order,
+            };
+            let (code, config, downstream) = match (replay, script) {
+                (Some(path), _) => {
+                    let bundle = RunBundle::read(&path)?;
+                    let downstream = if command.is_empty() {
+                        ReplayServer::new(&bundle).connect().await?
+                    } else {
+                        if !bundle.retry_safe() && !force {
+                            anyhow::bail!(
+                                "{} called destructive tools ({}) and is not marked idempotent; \
+                                 pass --force to run it against a live server anyway",
+                                path.display(),
+                                bundle.destructive_calls.join(", ")
+                            );
+                        }
+                        connect_downstream(&command, DownstreamHandler::new()).await?
+                    };
+                    (bundle.code, config.unwrap_or(bundle.config), downstream)
+                }
+                (None, Some(script)) => {
+                    let code = std::fs::read_to_string(&script)
+                        .with_context(|| format!("Failed to read script {}", script.display()))?;
+                    let config = config.unwrap_or_default();
+                    (
+                        code,
+                        config,
+                        connect_downstream(&command, DownstreamHandler::new()).await?,
+                    )
+                }
+                (None, None) => unreachable!("clap requires a script or --replay"),
+            };
+            if !run_exec(code, expect, options, config, downstream).await? {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Commands::ExportRun {
+            execution_id,
+            record_dir,
+            config,
+            profile,
+            output,
+        }) => {
+            let record_dir = match (record_dir, config) {
+                (Some(dir), _) => dir,
+                (None, Some(path)) => {
+                    CodeModeConfig::from_file_with_profile(&path, profile.as_deref())?
+                        .record_dir
+                        .with_context(|| format!("{} doesn't set record_dir", path.display()))?
+                }
+                (None, None) => anyhow::bail!("Pass --record-dir or a --config that sets it"),
+            };
+            let bundle = RunBundle::load(&FsStorage::new(record_dir), &execution_id)?;
+            let json = serde_json::to_string_pretty(&bundle)?;
+            match output {
+                Some(path) => {
+                    std::fs::write(&path, json)?;
+                    eprintln!(
+                        "Wrote {} ({} recorded tool calls); replay it with `codemoder exec --replay {}`",
+                        path.display(),
+                        bundle.calls.len(),
+                        path.display()
+                    );
+                }
+                None => println!("{json}"),
+            }
+            return Ok(());
+        }
+        Some(Commands::ConvertTrace {
+            trace,
+            namespace,
+            output,
+        }) => {
+            let text = std::fs::read_to_string(&trace)
+                .with_context(|| format!("Failed to read trace {}", trace.display()))?;
+            let calls = convert::parse_trace(&text)
+                .with_context(|| format!("Invalid trace {}", trace.display()))?;
+            let script = convert::trace_to_script(&calls, namespace.as_deref());
+            match output {
+                Some(path) => {
+                    std::fs::write(&path, script)?;
+                    eprintln!("Wrote {} ({} tool calls)", path.display(), calls.len());
+                }
+                None => print!("{script}"),
+            }
+            return Ok(());
+        }
+        Some(Commands::Features) => {
+            println!("{}", serde_json::to_string_pretty(&features::audit())?);
+            return Ok(());
+        }
+        Some(Commands::ScaffoldWrapper {
+            server_crate,
+            server_type,
+            constructor,
+            output,
+            force,
+        }) => {
+            let code = scaffold::wrapper_main(&server_crate, &server_type, &constructor)?;
+            match output {
+                Some(path) => {
+                    if path.exists() && !force {
+                        anyhow::bail!(
+                            "{} already exists; pass --force to overwrite",
+                            path.display()
+                        );
+                    }
+                    std::fs::write(&path, code)?;
+                    eprintln!("Wrote {}", path.display());
+                }
+                None => print!("{code}"),
+            }
+            eprintln!(
+                "Add to [dependencies] in Cargo.toml:\n\n{}",
+                scaffold::dependencies()
+            );
+            return Ok(());
+        }
+        Some(Commands::Selftest {
+            config,
+            profile,
+            command,
+        }) => {
+            let config = match config {
+                Some(path) => CodeModeConfig::from_file_with_profile(path, profile.as_deref())?,
+                None => CodeModeConfig::new(),
+            };
+            let downstream = connect_downstream(&command, DownstreamHandler::new()).await?;
+            if !run_selftest(config, downstream).await? {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        None => {}
+    }
 
     if args.command.is_empty() {
         anyhow::bail!("Must provide a command to run the downstream MCP server");
     }
 
-    let config = {
-        let mut cfg = CodeModeConfig::new().with_tool_name(&args.tool_name);
+    let mut config = {
+        let mut cfg = match &args.config {
+            Some(path) => CodeModeConfig::from_file_with_profile(path, args.profile.as_deref())?,
+            None => CodeModeConfig::new(),
+        };
 
-        cfg = match args.mode.as_str() {
-            "replace" => cfg.replace_tools(),
-            _ => cfg.add(),
+        if let Some(tool_name) = &args.tool_name {
+            cfg = cfg.with_tool_name(tool_name);
+        }
+
+        if let Some(collision) = args.tool_name_collision {
+            let collision = serde_json::from_value(serde_json::Value::String(collision))
+                .context("Invalid --tool-name-collision")?;
+            cfg = cfg.with_tool_name_collision(collision);
+        }
+
+        cfg = match args.mode.as_deref() {
+            Some("replace") => cfg.replace_tools(),
+            Some(_) => cfg.add(),
+            None => cfg,
         };
 
+        if let Some(timeout_ms) = args.timeout_ms {
+            cfg = cfg.with_timeout(Duration::from_millis(timeout_ms));
+        }
+
+        if let Some(grace) = args.cancel_grace_ms {
+            cfg = cfg.with_cancel_grace(Duration::from_millis(grace));
+        }
+
+        if let Some(attempts) = args.retry_attempts {
+            let retry = cfg.retry.clone().unwrap_or_default();
+            cfg = cfg.with_retry(RetrySettings {
+                max_attempts: attempts,
+                ..retry
+            });
+            cfg.validate()?;
+        }
+
+        if let Some(threshold) = args.circuit_failure_threshold {
+            let circuit = cfg.circuit_breaker.clone().unwrap_or_default();
+            cfg = cfg.with_circuit_breaker(CircuitBreakerSettings {
+                failure_threshold: threshold,
+                ..circuit
+            });
+            cfg.validate()?;
+        }
+
+        if args.call_timeout_ms.is_some() || args.adaptive_call_timeouts {
+            let timeout = cfg.call_timeout.clone().unwrap_or_default();
+            cfg = cfg.with_call_timeout(CallTimeoutSettings {
+                max_ms: args.call_timeout_ms.unwrap_or(timeout.max_ms),
+                adaptive: timeout.adaptive || args.adaptive_call_timeouts,
+                ..timeout
+            });
+            cfg.validate()?;
+        }
+
+        if let Some(ttl) = args.session_ttl_secs {
+            cfg = cfg.with_session_ttl(Duration::from_secs(ttl));
+        }
+
+        if let Some(ttl) = args.tools_ttl_secs {
+            cfg = cfg.with_tools_ttl(Duration::from_secs(ttl));
+            cfg.validate()?;
+        }
+
+        if let Some(size) = args.tools_page_size {
+            cfg = cfg.with_tools_page_size(size);
+            cfg.validate()?;
+        }
+
+        if let Some(max) = args.max_continuations {
+            cfg = cfg.with_max_continuations(max);
+        }
+
+        if args.background_executions {
+            cfg = cfg.with_background_executions(true);
+        }
+
+        if args.stats_tool {
+            cfg = cfg.with_stats_tool(true);
+        }
+
+        if args.provenance {
+            cfg = cfg.with_provenance(true);
+        }
+
+        if let Some(timeout) = args.drain_timeout_secs {
+            cfg = cfg.with_drain_timeout(Duration::from_secs(timeout));
+        }
+
+        if let Some(namespace) = args.namespace {
+            cfg = cfg.with_namespace(namespace);
+            cfg.validate()?;
+        }
+
+        if args.camel_case_aliases {
+            cfg = cfg.with_camel_case_aliases(true);
+        }
+
+        if args.dedupe_read_only_calls {
+            cfg = cfg.dedupe_read_only_calls(true);
+        }
+
+        if args.lazy_tool_docs {
+            cfg = cfg.with_lazy_tool_docs(true);
+        }
+
+        if args.stats_helpers {
+            cfg = cfg.with_stats_helpers(true);
+        }
+
+        if args.hash_helpers {
+            cfg = cfg.with_hash_helpers(true);
+        }
+
+        if args.llm_helpers {
+            cfg = cfg.with_llm_helpers(true);
+        }
+
+        if args.safe_regex {
+            cfg = cfg.with_safe_regex(true);
+        }
+
+        if args.stream_logs {
+            cfg = cfg.with_stream_logs(true);
+        }
+
+        if args.check_paths {
+            cfg = cfg.with_check_paths(true);
+        }
+
+        if args.failure_notes {
+            cfg = cfg.with_failure_notes(true);
+        }
+
+        if args.strict_args {
+            cfg = cfg.with_strict_args(true);
+        }
+
+        if args.coerce_args {
+            cfg = cfg.with_coerce_args(true);
+        }
+
+        if let Some(detail) = args.interface_detail {
+            let detail = serde_json::from_value(serde_json::Value::String(detail))
+                .context("Invalid --interface-detail")?;
+            cfg = cfg.with_interface_detail(detail);
+        }
+
+        if let Some(dir) = args.record_dir {
+            cfg = cfg.with_record_dir(dir);
+            cfg.validate()?;
+        }
+
+        if args.approve_tty {
+            cfg.approve_tty = true;
+            if !cfg.tools.values().any(|settings| settings.require_approval) {
+                cfg.approve_scripts = true;
+            }
+        }
+
         if let Some(tools) = args.include_tools {
             let tool_list: Vec<String> = tools.split(',').map(|s| s.trim().to_string()).collect();
             cfg = cfg.only_tools(tool_list);
         }
 
+        if let Some(tools) = args.exclude_tools {
+            let tool_list: Vec<String> = tools.split(',').map(|s| s.trim().to_string()).collect();
+            cfg = cfg.exclude_tools(tool_list);
+        }
+
         cfg
     };
 
-    info!("Starting downstream MCP server: {:?}", args.command);
-
-    let mut cmd = Command::new(&args.command[0]);
-    if args.command.len() > 1 {
-        cmd.args(&args.command[1..]);
-    }
-
-    let transport = TokioChildProcess::new(cmd)?;
-
-    info!("Connecting to downstream server...");
-    let downstream = ().serve(transport).await?;
+    let downstream = connect_downstream(&args.command, DownstreamHandler::new()).await?;
+    resolve_tool_name(&downstream, &mut config).await?;
+    info!(
+        "codemoder {}: profile={} engine={:?} mode={:?} tool={} timeout_ms={:?}",
+        env!("CARGO_PKG_VERSION"),
+        config.profile.as_deref().unwrap_or("none"),
+        config.engine,
+        config.mode,
+        config.tool_name,
+        config.timeout_ms
+    );
 
     info!("Starting proxy server on stdio...");
-    let proxy = CodeModeProxy::new(downstream, config);
+    let drain = Drain::new();
+    let drain_timeout = config.drain_timeout();
+    let policy = policy::policy_from_config(&config)?;
+    let mut proxy = CodeModeProxy::new(downstream, config).with_drain(drain.clone());
+    if let Some(policy) = policy {
+        proxy = proxy.with_policy(policy);
+    }
+    #[cfg(unix)]
+    let tools_changed = proxy.tools_changed();
+    #[cfg(unix)]
+    let switchover = match args.standby_command.clone() {
+        Some(command) => {
+            let switchover = proxy.switchover().await;
+            tokio::spawn(start_standby(switchover.clone(), command.clone()));
+            Some((switchover, command))
+        }
+        None => None,
+    };
+    #[cfg(not(unix))]
+    if args.standby_command.is_some() {
+        anyhow::bail!("--standby-command needs SIGUSR2, which this platform doesn't have");
+    }
 
     let stdin = tokio::io::stdin();
     let stdout = tokio::io::stdout();
@@ -79,6 +845,65 @@ async fn main() -> Result<()> {
 
     let service = proxy.serve(server_transport).await?;
 
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut sigusr1 = signal(SignalKind::user_defined1())?;
+        let ct = service.cancellation_token();
+        tokio::spawn(async move {
+            sigusr1.recv().await;
+            info!(
+                "Draining: rejecting new executions, waiting for {} in flight",
+                drain.in_flight()
+            );
+            drain.start();
+            if !drain.wait_idle(drain_timeout).await {
+                tracing::warn!(
+                    "Drain timed out with {} executions still running",
+                    drain.in_flight()
+                );
+            }
+            ct.cancel();
+        });
+
+        // SIGHUP refreshes the tools, for downstream servers that change
+        // them without saying so
+        let mut sighup = signal(SignalKind::hangup())?;
+        let peer = service.peer().clone();
+        tokio::spawn(async move {
+            while sighup.recv().await.is_some() {
+                info!("Refreshing the downstream tools");
+                tools_changed.mark();
+                if let Err(e) = peer.notify_tool_list_changed().await {
+                    tracing::debug!("Failed to notify the client of refreshed tools: {e}");
+                }
+            }
+        });
+    }
+
+    // SIGUSR2 switches to the standby downstream server
+    #[cfg(unix)]
+    if let Some((switchover, command)) = switchover {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut sigusr2 = signal(SignalKind::user_defined2())?;
+        let peer = service.peer().clone();
+        tokio::spawn(async move {
+            while sigusr2.recv().await.is_some() {
+                if let Err(e) = switchover.switch().await {
+                    tracing::warn!("{e}");
+                    continue;
+                }
+                info!("Switched to the standby downstream server");
+                if let Err(e) = peer.notify_tool_list_changed().await {
+                    tracing::debug!("Failed to notify the client of switched tools: {e}");
+                }
+                tokio::spawn(start_standby(switchover.clone(), command.clone()));
+            }
+        });
+    }
+
     info!("Proxy server running. Waiting for shutdown...");
     service.waiting().await?;
 
@@ -0,0 +1,266 @@
+//! Notes on tools that scripts keep calling wrong.
+//!
+//! With `failure_notes` set, the arguments of every failed script tool call
+//! are checked against the tool's input schema. What's wrong with them
+//! (a misspelled field, a number where a UUID string belongs) becomes a note
+//! under the tool in the execute tool's description, so the next script gets
+//! it right. Clients are told the tool list changed when a note is added.
+
+use crate::runtime::{ServerRequest, ServerRequestFuture, ToolCallFuture, ToolCaller};
+use jsonschema::ValidationError;
+use jsonschema::error::ValidationErrorKind;
+use rmcp::model::Tool;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Notes kept per tool; later mistakes are dropped.
+pub const MAX_NOTES_PER_TOOL: usize = 3;
+
+/// The notes of one server, or nothing when `failure_notes` is off.
+#[derive(Default)]
+pub struct FailureNotes {
+    enabled: bool,
+    notes: Mutex<BTreeMap<String, Vec<String>>>,
+    changed: AtomicBool,
+}
+
+impl FailureNotes {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Note what's wrong with `args` of a failed call to `tool`, per its
+    /// input schema.
+    pub fn record(&self, tool: &Tool, args: &Value) {
+        let schema = Value::Object(tool.input_schema.as_ref().clone());
+        let validator = match jsonschema::options()
+            .should_validate_formats(true)
+            .build(&schema)
+        {
+            Ok(validator) => validator,
+            Err(e) => {
+                tracing::debug!("Not checking arguments of {}: {e}", tool.name);
+                return;
+            }
+        };
+        let found: Vec<String> = validator.iter_errors(args).map(|e| note(&e)).collect();
+        if found.is_empty() {
+            return;
+        }
+        let mut notes = self.notes.lock().unwrap();
+        let tool_notes = notes.entry(tool.name.to_string()).or_default();
+        for note in found {
+            if tool_notes.len() < MAX_NOTES_PER_TOOL && !tool_notes.contains(&note) {
+                tool_notes.push(note);
+                self.changed.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Whether notes were added since the last call.
+    pub fn take_changed(&self) -> bool {
+        self.changed.swap(false, Ordering::SeqCst)
+    }
+
+    /// `tools` with their notes appended to their descriptions.
+    pub fn annotate(&self, tools: &[Tool]) -> Vec<Tool> {
+        let notes = self.notes.lock().unwrap();
+        tools
+            .iter()
+            .cloned()
+            .map(|mut tool| {
+                if let Some(tool_notes) = notes.get(tool.name.as_ref()) {
+                    let mut description =
+                        tool.description.as_deref().unwrap_or_default().to_string();
+                    for note in tool_notes {
+                        if !description.is_empty() {
+                            description.push('\n');
+                        }
+                        description.push_str(&format!("Note: {note}"));
+                    }
+                    tool.description = Some(description.into());
+                }
+                tool
+            })
+            .collect()
+    }
+}
+
+/// A validation error as a note that doesn't depend on the value passed.
+fn note(error: &ValidationError) -> String {
+    let path = error.instance_path.to_string();
+    let field = path.trim_start_matches('/').replace('/', ".");
+    match &error.kind {
+        ValidationErrorKind::Required { property } => {
+            let property = property.as_str().unwrap_or_default();
+            match field.as_str() {
+                "" => format!("`{property}` is required"),
+                parent => format!("`{parent}.{property}` is required"),
+            }
+        }
+        ValidationErrorKind::AdditionalProperties { unexpected } => {
+            let fields: Vec<String> = unexpected.iter().map(|name| format!("`{name}`")).collect();
+            let (noun, verb) = match fields.len() {
+                1 => ("parameter", "is"),
+                _ => ("parameters", "are"),
+            };
+            match field.as_str() {
+                "" => format!("{} {verb} not a {noun}", fields.join(", ")),
+                parent => format!("{} {verb} not allowed in `{parent}`", fields.join(", ")),
+            }
+        }
+        ValidationErrorKind::Type { .. } => format!(
+            "{} (got {})",
+            error.masked_with(format!("`{field}`")),
+            json_type(&error.instance)
+        ),
+        _ if field.is_empty() => error.masked_with("the arguments").to_string(),
+        _ => error.masked_with(format!("`{field}`")).to_string(),
+    }
+}
+
+fn json_type(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+/// A `ToolCaller` that notes what's wrong with the arguments of failed calls.
+pub struct FailureNoteToolCaller<C: ToolCaller> {
+    inner: C,
+    notes: Arc<FailureNotes>,
+    tools: HashMap<String, Tool>,
+}
+
+impl<C: ToolCaller> FailureNoteToolCaller<C> {
+    pub fn new(inner: C, notes: Arc<FailureNotes>, tools: &[Tool]) -> Self {
+        let tools = if notes.is_enabled() {
+            tools
+                .iter()
+                .map(|tool| (tool.name.to_string(), tool.clone()))
+                .collect()
+        } else {
+            HashMap::new()
+        };
+        Self {
+            inner,
+            notes,
+            tools,
+        }
+    }
+}
+
+impl<C: ToolCaller> ToolCaller for FailureNoteToolCaller<C> {
+    fn call_tool<'a>(&'a self, name: &'a str, args: Option<Value>) -> ToolCallFuture<'a> {
+        let Some(tool) = self.tools.get(name) else {
+            return self.inner.call_tool(name, args);
+        };
+        Box::pin(async move {
+            let sent = args
+                .clone()
+                .unwrap_or_else(|| Value::Object(Default::default()));
+            let result = self.inner.call_tool(name, args).await;
+            if result.as_ref().map_or(true, |r| r.is_error == Some(true)) {
+                self.notes.record(tool, &sent);
+            }
+            result
+        })
+    }
+
+    fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+        self.inner.server_request(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn tool() -> Tool {
+        Tool::new(
+            "move_footprint",
+            "Move a footprint",
+            Arc::new(
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "id": {"type": "string", "format": "uuid"},
+                        "x_mm": {"type": "number"},
+                        "options": {
+                            "type": "object",
+                            "properties": {"snap": {"type": "boolean"}},
+                            "additionalProperties": false
+                        }
+                    },
+                    "required": ["id", "x_mm"],
+                    "additionalProperties": false
+                })
+                .as_object()
+                .unwrap()
+                .clone(),
+            ),
+        )
+    }
+
+    #[test]
+    fn test_notes() {
+        let notes = FailureNotes::new(true);
+        let tool = tool();
+
+        notes.record(&tool, &json!({"id": "R1", "x_mm": 5}));
+        notes.record(&tool, &json!({"id": 7, "x_mm": 5}));
+        notes.record(&tool, &json!({"id": 8, "x_mm": 6}));
+        notes.record(&tool, &json!({"id": "R1", "x_mm": 5, "xMm": 5}));
+        assert!(notes.take_changed());
+        assert!(!notes.take_changed());
+
+        let annotated = notes.annotate(std::slice::from_ref(&tool));
+        assert_eq!(
+            annotated[0].description.as_deref(),
+            Some(
+                "Move a footprint\n\
+                 Note: `id` is not a \"uuid\"\n\
+                 Note: `id` is not of type \"string\" (got a number)\n\
+                 Note: `xMm` is not a parameter"
+            )
+        );
+
+        // Valid arguments say nothing about why the call failed
+        let valid = FailureNotes::new(true);
+        valid.record(
+            &tool,
+            &json!({"id": "9b2f4c1e-0a3d-4c57-8e6b-2f1d9a7c5e30", "x_mm": 1}),
+        );
+        assert!(!valid.take_changed());
+        assert_eq!(valid.annotate(std::slice::from_ref(&tool))[0], tool);
+    }
+
+    #[test]
+    fn test_note() {
+        let notes = FailureNotes::new(true);
+        let tool = tool();
+        notes.record(
+            &tool,
+            &json!({"id": "9b2f4c1e-0a3d-4c57-8e6b-2f1d9a7c5e30", "options": {"snap": true, "grid": 1}}),
+        );
+        let annotated = notes.annotate(std::slice::from_ref(&tool));
+        let description = annotated[0].description.as_deref().unwrap();
+        assert!(description.contains("Note: `x_mm` is required"));
+        assert!(description.contains("Note: `grid` is not allowed in `options`"));
+    }
+}
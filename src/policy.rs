@@ -0,0 +1,485 @@
+//! Organizational policy checks on every downstream tool call.
+//!
+//! Each call, whether from a script or passed through, is described as a
+//! `PolicyInput` (client, tool, arguments and the tool's annotations) and
+//! handed to a `Policy`. Policies can be embedded Cedar rules (`cedar`
+//! feature) or an OPA decision endpoint (`opa` feature). A policy that fails
+//! to evaluate denies the call.
+
+use crate::alias::ToolAliases;
+use crate::config::CodeModeConfig;
+use crate::runtime::{ServerRequest, ServerRequestFuture, ToolCallFuture, ToolCaller};
+use anyhow::Result;
+use rmcp::Peer;
+use rmcp::model::{Tool, ToolAnnotations};
+use rmcp::service::RoleServer;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+pub type PolicyFuture<'a> = Pin<Box<dyn Future<Output = Result<PolicyDecision>> + Send + 'a>>;
+
+/// What a policy sees for one tool call.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PolicyInput {
+    /// The MCP client's name from its initialize request.
+    pub client: Option<String>,
+    /// The tool's downstream name.
+    pub tool: String,
+    pub args: serde_json::Value,
+    pub annotations: Option<ToolAnnotations>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PolicyDecision {
+    pub allow: bool,
+    /// Why the call was denied, when the policy says.
+    pub reason: Option<String>,
+}
+
+impl PolicyDecision {
+    pub fn allow() -> Self {
+        Self {
+            allow: true,
+            reason: None,
+        }
+    }
+
+    pub fn deny(reason: impl Into<String>) -> Self {
+        Self {
+            allow: false,
+            reason: Some(reason.into()),
+        }
+    }
+}
+
+/// Decides whether a tool call may proceed.
+pub trait Policy: Send + Sync {
+    fn evaluate<'a>(&'a self, input: &'a PolicyInput) -> PolicyFuture<'a>;
+}
+
+/// The policy described by `config.policy`, if any.
+pub fn policy_from_config(config: &CodeModeConfig) -> Result<Option<Arc<dyn Policy>>> {
+    let Some(settings) = &config.policy else {
+        return Ok(None);
+    };
+    if let Some(path) = &settings.cedar_file {
+        #[cfg(feature = "cedar")]
+        return Ok(Some(Arc::new(CedarPolicy::from_file(path)?)));
+        #[cfg(not(feature = "cedar"))]
+        anyhow::bail!(
+            "policy.cedar_file ({}) requires building codemoder with `--features cedar`",
+            path.display()
+        );
+    }
+    if let Some(url) = &settings.opa_url {
+        #[cfg(feature = "opa")]
+        return Ok(Some(Arc::new(OpaPolicy::new(url.clone()))));
+        #[cfg(not(feature = "opa"))]
+        anyhow::bail!("policy.opa_url ({url}) requires building codemoder with `--features opa`");
+    }
+    Ok(None)
+}
+
+/// The name the client gave in its initialize request.
+pub fn client_name(peer: &Peer<RoleServer>) -> Option<String> {
+    peer.peer_info().map(|info| info.client_info.name.clone())
+}
+
+/// Annotations of exposed `tools`, keyed by downstream name.
+pub fn tool_annotations(tools: &[Tool], aliases: &ToolAliases) -> HashMap<String, ToolAnnotations> {
+    tools
+        .iter()
+        .filter_map(|tool| {
+            let annotations = tool.annotations.clone()?;
+            Some((aliases.downstream(&tool.name).to_string(), annotations))
+        })
+        .collect()
+}
+
+/// `Ok` if `policy` allows the call, otherwise the message to report.
+pub async fn check(
+    policy: Option<&Arc<dyn Policy>>,
+    input: &PolicyInput,
+) -> std::result::Result<(), String> {
+    let Some(policy) = policy else {
+        return Ok(());
+    };
+    let decision = policy.evaluate(input).await.unwrap_or_else(|e| {
+        tracing::warn!("Policy evaluation for {} failed: {e:#}", input.tool);
+        PolicyDecision::deny(format!("policy evaluation failed: {e}"))
+    });
+    if decision.allow {
+        return Ok(());
+    }
+    Err(match decision.reason {
+        Some(reason) => format!("Call to {} denied by policy: {reason}", input.tool),
+        None => format!("Call to {} denied by policy", input.tool),
+    })
+}
+
+/// A `ToolCaller` that asks the policy before each call. Sits below
+/// `AliasToolCaller`, so policies see downstream tool names.
+pub struct PolicyToolCaller<C: ToolCaller> {
+    inner: C,
+    policy: Option<Arc<dyn Policy>>,
+    client: Option<String>,
+    annotations: HashMap<String, ToolAnnotations>,
+}
+
+impl<C: ToolCaller> PolicyToolCaller<C> {
+    pub fn new(inner: C, policy: Option<Arc<dyn Policy>>) -> Self {
+        Self {
+            inner,
+            policy,
+            client: None,
+            annotations: HashMap::new(),
+        }
+    }
+
+    pub fn with_client(mut self, client: Option<String>) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Annotations to include for each tool, keyed by downstream name.
+    pub fn with_annotations(mut self, annotations: HashMap<String, ToolAnnotations>) -> Self {
+        self.annotations = annotations;
+        self
+    }
+}
+
+impl<C: ToolCaller> ToolCaller for PolicyToolCaller<C> {
+    fn call_tool<'a>(
+        &'a self,
+        name: &'a str,
+        args: Option<serde_json::Value>,
+    ) -> ToolCallFuture<'a> {
+        Box::pin(async move {
+            if self.policy.is_some() {
+                let input = PolicyInput {
+                    client: self.client.clone(),
+                    tool: name.to_string(),
+                    args: args.clone().unwrap_or_else(|| serde_json::json!({})),
+                    annotations: self.annotations.get(name).cloned(),
+                };
+                check(self.policy.as_ref(), &input)
+                    .await
+                    .map_err(anyhow::Error::msg)?;
+            }
+            self.inner.call_tool(name, args).await
+        })
+    }
+
+    fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+        self.inner.server_request(request)
+    }
+}
+
+/// Embedded Cedar policies. Each call is the request
+/// `principal: Client::"<client>"`, `action: Action::"call_tool"`,
+/// `resource: Tool::"<tool>"`, with `context.args` and
+/// `context.annotations`. Nulls are dropped and non-integer numbers become
+/// strings, since Cedar has neither.
+#[cfg(feature = "cedar")]
+pub struct CedarPolicy {
+    policies: cedar_policy::PolicySet,
+}
+
+#[cfg(feature = "cedar")]
+impl CedarPolicy {
+    pub fn from_file(path: &std::path::Path) -> Result<Self> {
+        use anyhow::Context as _;
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read policy file {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("Invalid policy file {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let policies = text
+            .parse()
+            .map_err(|e: cedar_policy::ParseErrors| anyhow::anyhow!("{e}"))?;
+        Ok(Self { policies })
+    }
+
+    fn decide(&self, input: &PolicyInput) -> Result<PolicyDecision> {
+        use cedar_policy::{
+            Authorizer, Context, Decision, Entities, EntityId, EntityTypeName, EntityUid, Request,
+        };
+        use std::str::FromStr;
+
+        let uid = |kind: &str, id: &str| -> Result<EntityUid> {
+            Ok(EntityUid::from_type_name_and_id(
+                EntityTypeName::from_str(kind).map_err(|e| anyhow::anyhow!("{e}"))?,
+                EntityId::from_str(id).map_err(|e| anyhow::anyhow!("{e}"))?,
+            ))
+        };
+        let mut context = serde_json::Map::new();
+        context.insert(
+            "args".to_string(),
+            cedar_json(&input.args).unwrap_or_default(),
+        );
+        if let Some(annotations) = &input.annotations
+            && let Some(value) = cedar_json(&serde_json::to_value(annotations)?)
+        {
+            context.insert("annotations".to_string(), value);
+        }
+        let request = Request::new(
+            Some(uid("Client", input.client.as_deref().unwrap_or("unknown"))?),
+            Some(uid("Action", "call_tool")?),
+            Some(uid("Tool", &input.tool)?),
+            Context::from_json_value(serde_json::Value::Object(context), None)?,
+        );
+
+        let response =
+            Authorizer::new().is_authorized(&request, &self.policies, &Entities::empty());
+        for error in response.diagnostics().errors() {
+            tracing::warn!("Cedar policy error for {}: {error}", input.tool);
+        }
+        if response.decision() == Decision::Allow {
+            return Ok(PolicyDecision::allow());
+        }
+        let mut forbidding: Vec<String> = response
+            .diagnostics()
+            .reason()
+            .map(|id| id.to_string())
+            .collect();
+        forbidding.sort();
+        Ok(PolicyDecision::deny(if forbidding.is_empty() {
+            "no policy permits it".to_string()
+        } else {
+            format!("forbidden by {}", forbidding.join(", "))
+        }))
+    }
+}
+
+#[cfg(feature = "cedar")]
+impl Policy for CedarPolicy {
+    fn evaluate<'a>(&'a self, input: &'a PolicyInput) -> PolicyFuture<'a> {
+        Box::pin(async move { self.decide(input) })
+    }
+}
+
+#[cfg(feature = "cedar")]
+fn cedar_json(value: &serde_json::Value) -> Option<serde_json::Value> {
+    use serde_json::Value;
+    match value {
+        Value::Null => None,
+        Value::Number(n) if n.is_i64() => Some(value.clone()),
+        Value::Number(n) => Some(Value::String(n.to_string())),
+        Value::Array(items) => Some(Value::Array(items.iter().filter_map(cedar_json).collect())),
+        Value::Object(fields) => Some(Value::Object(
+            fields
+                .iter()
+                .filter_map(|(k, v)| cedar_json(v).map(|v| (k.clone(), v)))
+                .collect(),
+        )),
+        other => Some(other.clone()),
+    }
+}
+
+/// An OPA decision endpoint, e.g. `http://localhost:8181/v1/data/codemoder/allow`.
+/// Receives `{"input": <PolicyInput>}` and must return `{"result": true}` or
+/// `{"result": {"allow": bool, "reason": "..."}}`. An undefined result denies.
+#[cfg(feature = "opa")]
+pub struct OpaPolicy {
+    url: String,
+    agent: ureq::Agent,
+}
+
+#[cfg(feature = "opa")]
+impl OpaPolicy {
+    pub fn new(url: String) -> Self {
+        let agent = ureq::Agent::config_builder()
+            .timeout_global(Some(std::time::Duration::from_secs(5)))
+            .build()
+            .into();
+        Self { url, agent }
+    }
+
+    fn decide(agent: &ureq::Agent, url: &str, body: serde_json::Value) -> Result<PolicyDecision> {
+        let mut response = agent.post(url).send_json(&body)?;
+        let reply: serde_json::Value = response.body_mut().read_json()?;
+        Ok(match &reply["result"] {
+            serde_json::Value::Bool(true) => PolicyDecision::allow(),
+            serde_json::Value::Object(result) if result.get("allow") == Some(&true.into()) => {
+                PolicyDecision::allow()
+            }
+            serde_json::Value::Object(result) => PolicyDecision {
+                allow: false,
+                reason: result
+                    .get("reason")
+                    .and_then(|r| r.as_str())
+                    .map(str::to_string),
+            },
+            serde_json::Value::Null => PolicyDecision::deny("the policy result is undefined"),
+            _ => PolicyDecision {
+                allow: false,
+                reason: None,
+            },
+        })
+    }
+}
+
+#[cfg(feature = "opa")]
+impl Policy for OpaPolicy {
+    fn evaluate<'a>(&'a self, input: &'a PolicyInput) -> PolicyFuture<'a> {
+        Box::pin(async move {
+            let agent = self.agent.clone();
+            let url = self.url.clone();
+            let body = serde_json::json!({ "input": input });
+            tokio::task::spawn_blocking(move || Self::decide(&agent, &url, body)).await?
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rmcp::model::{CallToolResult, Content};
+
+    struct Done;
+
+    impl ToolCaller for Done {
+        fn call_tool<'a>(
+            &'a self,
+            _name: &'a str,
+            _args: Option<serde_json::Value>,
+        ) -> ToolCallFuture<'a> {
+            Box::pin(async { Ok(CallToolResult::success(vec![Content::text("done")])) })
+        }
+    }
+
+    /// Denies destructive tools unless the client is `admin`.
+    struct NoDestruction;
+
+    impl Policy for NoDestruction {
+        fn evaluate<'a>(&'a self, input: &'a PolicyInput) -> PolicyFuture<'a> {
+            let destructive = input
+                .annotations
+                .as_ref()
+                .and_then(|a| a.destructive_hint)
+                .unwrap_or(false);
+            let decision = if destructive && input.client.as_deref() != Some("admin") {
+                PolicyDecision::deny("destructive tools need an admin client")
+            } else {
+                PolicyDecision::allow()
+            };
+            Box::pin(async move { Ok(decision) })
+        }
+    }
+
+    fn annotations() -> HashMap<String, ToolAnnotations> {
+        HashMap::from([("wipe".to_string(), ToolAnnotations::new().destructive(true))])
+    }
+
+    #[tokio::test]
+    async fn test_policy_sees_client_and_annotations() {
+        let policy: Arc<dyn Policy> = Arc::new(NoDestruction);
+        let caller = PolicyToolCaller::new(Done, Some(policy.clone()))
+            .with_client(Some("agent".to_string()))
+            .with_annotations(annotations());
+
+        assert!(caller.call_tool("read", None).await.is_ok());
+        let err = caller.call_tool("wipe", None).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Call to wipe denied by policy: destructive tools need an admin client"
+        );
+
+        let admin = PolicyToolCaller::new(Done, Some(policy))
+            .with_client(Some("admin".to_string()))
+            .with_annotations(annotations());
+        assert!(admin.call_tool("wipe", None).await.is_ok());
+    }
+
+    #[cfg(feature = "cedar")]
+    #[test]
+    fn test_cedar_policy() {
+        let policy = CedarPolicy::parse(
+            r#"
+            permit(principal, action == Action::"call_tool", resource);
+            forbid(principal, action, resource == Tool::"delete_board")
+                unless { principal == Client::"admin" };
+            forbid(principal, action, resource)
+                when { context.args has count && context.args.count > 100 };
+            "#,
+        )
+        .unwrap();
+        let input = |client: &str, tool: &str, args: serde_json::Value| PolicyInput {
+            client: Some(client.to_string()),
+            tool: tool.to_string(),
+            args,
+            annotations: None,
+        };
+
+        let decide = |client: &str, tool: &str, args: serde_json::Value| {
+            policy.decide(&input(client, tool, args)).unwrap()
+        };
+
+        let args = serde_json::json!({"x": 1.5, "n": null});
+        assert!(decide("agent", "move", args).allow);
+        let denied = decide("agent", "delete_board", serde_json::json!({}));
+        assert_eq!(denied.reason.as_deref(), Some("forbidden by policy1"));
+        assert!(decide("admin", "delete_board", serde_json::json!({})).allow);
+        assert!(!decide("agent", "move", serde_json::json!({"count": 500})).allow);
+    }
+
+    #[cfg(feature = "opa")]
+    #[tokio::test]
+    async fn test_opa_policy() {
+        use std::io::{BufRead, BufReader, Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "http://{}/v1/data/codemoder",
+            listener.local_addr().unwrap()
+        );
+        std::thread::spawn(move || {
+            for stream in listener.incoming().take(2) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                let request: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                let result = if request["input"]["tool"] == "delete_board" {
+                    serde_json::json!({"allow": false, "reason": "not on Fridays"})
+                } else {
+                    serde_json::json!(true)
+                };
+                let reply = serde_json::json!({ "result": result }).to_string();
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{reply}",
+                    reply.len()
+                )
+                .unwrap();
+            }
+        });
+
+        let policy = OpaPolicy::new(url);
+        let input = |tool: &str| PolicyInput {
+            client: None,
+            tool: tool.to_string(),
+            args: serde_json::json!({}),
+            annotations: None,
+        };
+        assert!(policy.evaluate(&input("move")).await.unwrap().allow);
+        let denied = policy.evaluate(&input("delete_board")).await.unwrap();
+        assert_eq!(denied, PolicyDecision::deny("not on Fridays"));
+    }
+}
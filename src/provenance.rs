@@ -0,0 +1,196 @@
+//! Which downstream calls an execution's result came from.
+//!
+//! With `provenance` set, the result of every downstream call a script makes
+//! is remembered, and once the script has finished its value is searched
+//! for pieces of each: equal non-empty objects and arrays, and equal
+//! non-empty strings. The calls found are listed in the result's
+//! `_meta.provenance` as `{call, tool}`, where `call` is the call's position
+//! in the execution's `trace`. Numbers and booleans on their own are too
+//! common to tell calls apart, so a value built only from them, or computed
+//! by the script, isn't traced back to a call.
+
+use crate::runtime::{
+    ServerRequest, ServerRequestFuture, ToolCallFuture, ToolCaller, format_call_result,
+};
+use rmcp::schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+/// A downstream call that contributed to an execution's result.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ProvenanceEntry {
+    /// Position of the call in the execution's trace.
+    pub call: usize,
+    /// The tool's downstream name.
+    pub tool: String,
+}
+
+/// What is kept of one call: its tool, and the pieces of its result, or
+/// nothing if it failed.
+type Call = (String, Option<HashSet<String>>);
+
+/// The calls of one execution, in the order they finished.
+#[derive(Debug, Default)]
+pub struct CallResults {
+    calls: Mutex<Vec<Call>>,
+}
+
+impl CallResults {
+    fn record(&self, tool: &str, result: Option<&str>) {
+        let pieces = result.map(|text| {
+            let value = serde_json::from_str(text).unwrap_or_else(|_| Value::from(text));
+            let mut pieces = HashSet::new();
+            walk(&value, &mut |piece| {
+                pieces.insert(piece);
+            });
+            pieces
+        });
+        self.calls.lock().unwrap().push((tool.to_string(), pieces));
+    }
+
+    /// The calls with pieces of their results in `value`.
+    pub fn sources(&self, value: &Value) -> Vec<ProvenanceEntry> {
+        let calls = self.calls.lock().unwrap();
+        let mut found = vec![false; calls.len()];
+        walk(value, &mut |piece| {
+            for (i, (_, pieces)) in calls.iter().enumerate() {
+                if !found[i] && pieces.as_ref().is_some_and(|p| p.contains(&piece)) {
+                    found[i] = true;
+                }
+            }
+        });
+        calls
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| found[*i])
+            .map(|(call, (tool, _))| ProvenanceEntry {
+                call,
+                tool: tool.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Call `piece` with the canonical form of every distinctive part of
+/// `value`, itself included.
+fn walk(value: &Value, piece: &mut impl FnMut(String)) {
+    match value {
+        Value::String(s) if !s.is_empty() => piece(canonical(value)),
+        Value::Array(items) if !items.is_empty() => {
+            piece(canonical(value));
+            for item in items {
+                walk(item, piece);
+            }
+        }
+        Value::Object(object) if !object.is_empty() => {
+            piece(canonical(value));
+            for field in object.values() {
+                walk(field, piece);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// `value` as JSON with sorted keys and every number a float, since
+/// JavaScript hands back `10` as `10.0`.
+fn canonical(value: &Value) -> String {
+    match value {
+        Value::Number(n) => n.as_f64().unwrap_or_default().to_string(),
+        Value::Array(items) => {
+            let items: Vec<String> = items.iter().map(canonical).collect();
+            format!("[{}]", items.join(","))
+        }
+        Value::Object(object) => {
+            let mut fields: Vec<(&String, &Value)> = object.iter().collect();
+            fields.sort_by_key(|(name, _)| *name);
+            let fields: Vec<String> = fields
+                .into_iter()
+                .map(|(name, field)| format!("{}:{}", Value::from(name.as_str()), canonical(field)))
+                .collect();
+            format!("{{{}}}", fields.join(","))
+        }
+        other => other.to_string(),
+    }
+}
+
+/// A `ToolCaller` that keeps the results of calls when enabled. It wraps
+/// `TraceToolCaller`, so calls are numbered as in the trace.
+pub struct ProvenanceToolCaller<C: ToolCaller> {
+    inner: C,
+    results: Option<Arc<CallResults>>,
+}
+
+impl<C: ToolCaller> ProvenanceToolCaller<C> {
+    pub fn new(inner: C, enabled: bool) -> Self {
+        Self {
+            inner,
+            results: enabled.then(Arc::default),
+        }
+    }
+
+    /// The calls so far, shared with this caller, or `None` when disabled.
+    pub fn results(&self) -> Option<Arc<CallResults>> {
+        self.results.clone()
+    }
+}
+
+impl<C: ToolCaller> ToolCaller for ProvenanceToolCaller<C> {
+    fn call_tool<'a>(&'a self, name: &'a str, args: Option<Value>) -> ToolCallFuture<'a> {
+        let Some(results) = &self.results else {
+            return self.inner.call_tool(name, args);
+        };
+        Box::pin(async move {
+            let result = self.inner.call_tool(name, args).await;
+            let text = match &result {
+                Ok(result) if result.is_error != Some(true) => Some(format_call_result(result)),
+                _ => None,
+            };
+            results.record(name, text.as_deref());
+            result
+        })
+    }
+
+    fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+        self.inner.server_request(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_sources() {
+        let results = CallResults::default();
+        results.record(
+            "get_items",
+            Some(r#"{"items": [{"id": "item-1", "value": 10}, {"id": "item-2", "value": 20}]}"#),
+        );
+        results.record("get_clock", Some("12:00"));
+        results.record("delete_item", None);
+        results.record("count", Some("3"));
+
+        let value = json!({"first": {"value": 10.0, "id": "item-1"}, "time": "12:00", "n": 3});
+        assert_eq!(
+            results.sources(&value),
+            vec![
+                ProvenanceEntry {
+                    call: 0,
+                    tool: "get_items".to_string()
+                },
+                ProvenanceEntry {
+                    call: 1,
+                    tool: "get_clock".to_string()
+                },
+            ]
+        );
+
+        // Computed values, and empty ones, aren't traced
+        assert!(results.sources(&json!({"total": 30, "ids": []})).is_empty());
+        assert_eq!(results.sources(&json!("item-2"))[0].call, 0);
+    }
+}
@@ -1,106 +1,834 @@
-use crate::config::{CodeModeConfig, CodeModeExposure};
-use crate::runtime::JsRuntime;
-use crate::typescript::generate_typescript_interface;
+use crate::alias::ToolAliases;
+use crate::approval::{
+    self, ApprovalRequest, Approver, approver_from_config, guarded_tools, intended_calls,
+};
+use crate::background::{
+    BackgroundExecutions, CANCEL_TOOL, GET_TOOL, START_TOOL, cancel_execution, get_execution,
+    make_background_tools, started,
+};
+use crate::bundle::RunBundle;
+use crate::cache::ResultCache;
+use crate::circuit::CircuitBreaker;
+use crate::concurrency::ConcurrencyLimits;
+use crate::config::{
+    CodeModeConfig, CodeModeExposure, InterfaceDetail, capability_report, lazy_tools_note,
+};
+use crate::cost::{CostLedger, CostModel};
+use crate::docs::{DOCS_NOTE, DOCS_URI, HELPER_DOCS, docs_resource};
+use crate::downstream::{DownstreamServer, ProgressRoutes, ToolsChanged, UpstreamPeer};
+use crate::drain::Drain;
+use crate::engine::{ScriptRuntime, create_runtime};
+use crate::idempotent::{declares_idempotent, destructive_calls, destructive_tools};
+use crate::notes::FailureNotes;
+use crate::policy::{self, Policy, PolicyInput, client_name};
+use crate::redact::ArgRedactions;
+use crate::retry::{RetryPolicy, is_retryable};
+use crate::roots::PathArgs;
+use crate::runtime::{
+    AssertionFailure, DownstreamClient, DownstreamToolCaller, ExecutionOptions, ExecutionResult,
+    downstream_peer, random_id,
+};
+use crate::slo::{self, LatencySlos};
+use crate::stack::CallerStack;
+use crate::standby::Switchover;
+use crate::storage::{Storage, storage_from_config};
+use crate::summary::{ExecutionSummary, SUMMARY_URI, SummaryLog, summary_resource};
+use crate::telemetry::{execution_span, record_execution, traced_call};
+use crate::timeout::CallTimeouts;
+use crate::trace::TraceEntry;
+use crate::typescript::{add_examples, generate_interface, generate_tool_index};
+use crate::upstream::ClientLogLevel;
+use crate::usage::{STATS_TOOL, UsageStats, make_stats_tool, stats};
 use rmcp::ServerHandler;
 use rmcp::model::*;
 use rmcp::schemars::JsonSchema;
-use rmcp::service::{RequestContext, RoleServer};
-use serde::Deserialize;
+use rmcp::service::{NotificationContext, Peer, PeerRequestOptions, RequestContext, RoleServer};
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::sync::{Mutex, RwLock};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 
 #[derive(Debug, Deserialize, JsonSchema)]
 pub struct ExecuteCodeParams {
     #[schemars(
-        description = "JavaScript code to execute. The code has access to a `tools` object with synchronous functions for each tool. The last expression is returned. IMPORTANT: Semicolons are required after statements, and object literals must be wrapped in parentheses: ({key: value});"
+        description = "JavaScript or TypeScript code to execute. The code has access to a `tools` object with synchronous functions for each tool. The last expression is returned. IMPORTANT: Semicolons are required after statements, and object literals must be wrapped in parentheses: ({key: value});"
     )]
-    #[allow(dead_code)]
     pub code: String,
+    #[schemars(
+        description = "Optional session id. Globals declared with `var` in one execution stay available to later executions with the same session id."
+    )]
+    #[serde(default)]
+    pub session: Option<String>,
+    #[schemars(description = "Discard the session's existing state before running the code.")]
+    #[serde(default)]
+    pub reset: bool,
+    #[schemars(
+        description = "Token returned by an execution that called `yieldToAgent`. The code runs with that execution's globals."
+    )]
+    #[serde(default)]
+    pub continuation: Option<String>,
+    #[schemars(
+        description = "Declare the code safe to run again, like starting it with \"use idempotent\";. Unless set, an execution that called destructive tools is reported as not safe to retry."
+    )]
+    #[serde(default)]
+    pub idempotent: bool,
+    #[schemars(
+        description = "Also return a `trace` of every downstream tool call the code made: its arguments, duration, whether it succeeded, and the start of its result or its error."
+    )]
+    #[serde(default)]
+    pub verbose: bool,
+}
+
+/// `structuredContent` of an execute tool result, also advertised as the
+/// tool's output schema.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ExecuteCodeOutput {
+    #[schemars(description = "Value of the script's last expression, or null if it failed.")]
+    pub result: serde_json::Value,
+    #[schemars(description = "Lines written with console.log, in order.")]
+    pub logs: Vec<String>,
+    #[schemars(description = "Error message if the script failed.")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[schemars(
+        description = "Set when the script called `yieldToAgent`: pass it as `continuation` to continue with the same globals."
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub continuation: Option<String>,
+    #[schemars(
+        description = "Set when the script failed an `assert` or `expectEqual`: the assertion's message, and for `expectEqual` the expected and actual values."
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub assertion: Option<AssertionFailure>,
+    #[schemars(description = "With `verbose`, the downstream tool calls the script made.")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace: Option<Vec<TraceEntry>>,
+}
+
+impl From<&ExecutionResult> for ExecuteCodeOutput {
+    fn from(result: &ExecutionResult) -> Self {
+        Self {
+            result: result.value.clone(),
+            logs: result.logs.clone(),
+            error: result.is_error.then(|| {
+                result
+                    .error_message
+                    .clone()
+                    .unwrap_or_else(|| "Unknown error".to_string())
+            }),
+            continuation: result.continuation.clone(),
+            assertion: result.assertion.clone(),
+            trace: result.trace.clone(),
+        }
+    }
+}
+
+/// Name of the tool that returns full signatures with `lazy_tool_docs`.
+pub const TOOL_DOCS_TOOL: &str = "get_tool_docs";
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct GetToolDocsParams {
+    #[schemars(description = "Names of the tools to document, as listed in the execute tool.")]
+    pub tools: Vec<String>,
+}
+
+/// What the execute tool's description lists for `tools`, per
+/// `CodeModeConfig::interface_detail()`.
+pub(crate) fn tools_interface(config: &CodeModeConfig, tools: &[Tool], namespace: &str) -> String {
+    let camel_case = config.camel_case_aliases;
+    let lazy_tools = config.lazy_tools();
+    match config.interface_detail() {
+        InterfaceDetail::Full => {
+            generate_interface(tools, namespace, true, camel_case, &lazy_tools)
+        }
+        InterfaceDetail::Signatures => {
+            generate_interface(tools, namespace, false, camel_case, &lazy_tools)
+        }
+        InterfaceDetail::Summaries => generate_tool_index(tools, namespace, true, camel_case),
+        InterfaceDetail::Names => generate_tool_index(tools, namespace, false, camel_case),
+        InterfaceDetail::None => String::new(),
+    }
+}
+
+pub(crate) fn execute_tool_description(
+    config: &CodeModeConfig,
+    ts_interface: &str,
+    downstream: &ServerCapabilities,
+    client: Option<&ClientCapabilities>,
+) -> String {
+    if ts_interface.is_empty() {
+        return config.tool_description.clone();
+    }
+    let mut tools = match config.interface_detail() {
+        InterfaceDetail::Full | InterfaceDetail::Signatures => {
+            format!("```typescript\n{ts_interface}\n```")
+        }
+        _ => ts_interface.trim_end().to_string(),
+    };
+    if config.lazy_tool_docs {
+        tools.push_str(&format!(
+            "\nCall `{TOOL_DOCS_TOOL}` with `{{\"tools\": [...]}}` for the TypeScript signatures of the tools you need before using them."
+        ));
+    }
+    format!(
+        "{}\n\n## Available Tools (synchronous)\n\n{}\n\n## Notes\n\n- All tool calls are **synchronous** (no async/await needed)\n- Use `console.log(value)` to debug - logs are returned in the result{}{}{}{}{}{}{}{}{}",
+        config.tool_description,
+        tools,
+        DOCS_NOTE,
+        lazy_tools_note(config),
+        stats_note(config),
+        hash_note(config),
+        llm_note(config),
+        regex_note(config),
+        yield_note(config),
+        client_notes(client),
+        server_notes(downstream)
+    )
+}
+
+/// Notes on the helpers that need the client to support sampling,
+/// elicitation or roots, for the ones it declared.
+fn client_notes(client: Option<&ClientCapabilities>) -> String {
+    let mut notes = String::new();
+    let Some(client) = client else {
+        return notes;
+    };
+    if client.sampling.is_some() {
+        notes.push_str("\n- `sample(prompt, opts)` asks the client's model for a completion and returns its text");
+    }
+    if client.elicitation.is_some() {
+        notes.push_str("\n- `elicit(schema, message)` asks the user to fill in a form and returns their answer, or null if they decline, and `ask(prompt, choices)` asks a single question, taking a list of choices, a type name or a field schema (free text by default), and returns the answer itself");
+    }
+    if client.roots.is_some() {
+        notes.push_str("\n- `roots()` returns the client's roots as `[{uri, name}]`");
+    }
+    notes
+}
+
+fn server_notes(downstream: &ServerCapabilities) -> String {
+    let mut notes = String::new();
+    if downstream.resources.is_some() {
+        notes.push_str("\n- `resources.read(uri)` returns a resource's text, or `{blob, mimeType}` with base64 `blob` for binary resources");
+    }
+    if downstream.prompts.is_some() {
+        notes.push_str("\n- `prompts.list()` lists the available prompts and `prompts.get(name, args)` returns a prompt's rendered messages");
+    }
+    notes
 }
 
+fn stats_note(config: &CodeModeConfig) -> &'static str {
+    if config.stats_helpers {
+        "\n- Aggregate numbers with `stats.sum`, `stats.mean`, `stats.median`, `stats.percentile(values, p)` (p from 0 to 100) and `stats.histogram(values, bins)` rather than by hand; each takes an array of numbers, or of objects plus a property name or function as the last argument, e.g. `stats.mean(items, 'price')`"
+    } else {
+        ""
+    }
+}
+
+fn hash_note(config: &CodeModeConfig) -> &'static str {
+    if config.hash_helpers {
+        "\n- `uuid()` returns a random UUID, `sha256(value)` a hex SHA-256 and `hmac(key, value)` a hex HMAC-SHA256; values other than strings are hashed as their JSON text"
+    } else {
+        ""
+    }
+}
+
+fn llm_note(config: &CodeModeConfig) -> &'static str {
+    if config.llm_helpers {
+        "\n- `llm.complete(prompt, options)` returns a completion from the client's model, taking `sample`'s options; pass `json: true` to get the completion parsed as JSON, for classifying or extracting fields mid-script"
+    } else {
+        ""
+    }
+}
+
+fn regex_note(config: &CodeModeConfig) -> &'static str {
+    if config.safe_regex {
+        "\n- Regular expressions run on a linear-time engine without lookahead, lookbehind or backreferences"
+    } else {
+        ""
+    }
+}
+
+fn yield_note(config: &CodeModeConfig) -> &'static str {
+    if config.continuations_enabled() {
+        "\n- Call `yieldToAgent(summary)` to stop and hand `summary` back with a `continuation` token; pass that token as `continuation` with the next chunk of code to carry on with the same globals, or to `abandon_continuation` when you are done with it"
+    } else {
+        ""
+    }
+}
+
+/// Name of the tool that drops a paused `yieldToAgent` execution.
+pub const ABANDON_TOOL: &str = "abandon_continuation";
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct AbandonContinuationParams {
+    #[schemars(description = "Token returned by an execution that called `yieldToAgent`.")]
+    pub continuation: String,
+}
+
+pub(crate) fn make_abandon_tool() -> Tool {
+    use rmcp::handler::server::common::schema_for_type;
+
+    Tool {
+        name: ABANDON_TOOL.into(),
+        description: Some(
+            "Discard a paused execution that will not be continued, freeing its state.".into(),
+        ),
+        input_schema: Arc::new(schema_for_type::<AbandonContinuationParams>()),
+        title: None,
+        output_schema: None,
+        annotations: Some(ToolAnnotations::new().idempotent(true)),
+        icons: None,
+        meta: None,
+    }
+}
+
+/// The `abandon_continuation` result for `request`, against the runtime if
+/// one has been started.
+pub(crate) fn abandon_continuation(
+    request: &CallToolRequestParam,
+    runtime: Option<&dyn ScriptRuntime>,
+) -> Result<CallToolResult, ErrorData> {
+    let args = request.arguments.clone().unwrap_or_default();
+    let params: AbandonContinuationParams = serde_json::from_value(serde_json::Value::Object(args))
+        .map_err(|e| ErrorData::invalid_params(format!("Invalid parameters: {e}"), None))?;
+    let token = params.continuation;
+    if runtime.is_some_and(|runtime| runtime.abandon_continuation(&token)) {
+        Ok(CallToolResult::success(vec![Content::text(format!(
+            "Abandoned continuation `{token}`"
+        ))]))
+    } else {
+        Ok(CallToolResult::error(vec![Content::text(format!(
+            "Unknown or expired continuation `{token}`"
+        ))]))
+    }
+}
+
+pub(crate) fn make_tool_docs_tool() -> Tool {
+    use rmcp::handler::server::common::schema_for_type;
+
+    Tool {
+        name: TOOL_DOCS_TOOL.into(),
+        description: Some(
+            "Get the full TypeScript signatures, parameters and return types of the named tools for use in scripts.".into(),
+        ),
+        input_schema: Arc::new(schema_for_type::<GetToolDocsParams>()),
+        title: None,
+        output_schema: None,
+        annotations: Some(ToolAnnotations::new().read_only(true)),
+        icons: None,
+        meta: None,
+    }
+}
+
+/// The `get_tool_docs` result for `request` among the exposed `tools`.
+pub(crate) fn tool_docs(
+    request: &CallToolRequestParam,
+    tools: &[Tool],
+    namespace: &str,
+    config: &CodeModeConfig,
+) -> Result<CallToolResult, ErrorData> {
+    let args = request.arguments.clone().unwrap_or_default();
+    let params: GetToolDocsParams = serde_json::from_value(serde_json::Value::Object(args))
+        .map_err(|e| ErrorData::invalid_params(format!("Invalid parameters: {e}"), None))?;
+    let unknown: Vec<&str> = params
+        .tools
+        .iter()
+        .filter(|name| !tools.iter().any(|tool| tool.name == name.as_str()))
+        .map(String::as_str)
+        .collect();
+    if !unknown.is_empty() {
+        return Ok(CallToolResult::error(vec![Content::text(format!(
+            "Unknown tools: {}",
+            unknown.join(", ")
+        ))]));
+    }
+    let selected: Vec<Tool> = tools
+        .iter()
+        .filter(|tool| params.tools.iter().any(|name| tool.name == name.as_str()))
+        .cloned()
+        .collect();
+    Ok(CallToolResult::success(vec![Content::text(
+        generate_interface(
+            &selected,
+            namespace,
+            true,
+            config.camel_case_aliases,
+            &config.lazy_tools(),
+        ),
+    )]))
+}
+
+/// The text of an execute tool result: the script's value, with logs when
+/// there are any, or the `yieldToAgent` summary and continuation token, and
+/// the trace when asked for.
+pub(crate) fn response_value(result: &ExecutionResult) -> serde_json::Value {
+    let mut response = if let Some(continuation) = &result.continuation {
+        let mut yielded = serde_json::json!({
+            "yielded": result.value,
+            "continuation": continuation,
+        });
+        if !result.logs.is_empty() {
+            yielded["logs"] = result.logs.clone().into();
+        }
+        yielded
+    } else if result.logs.is_empty() && result.trace.is_none() {
+        return result.value.clone();
+    } else {
+        let mut response = serde_json::json!({"result": result.value});
+        if !result.logs.is_empty() {
+            response["logs"] = result.logs.clone().into();
+        }
+        response
+    };
+    if let Some(trace) = &result.trace {
+        response["trace"] = serde_json::to_value(trace).unwrap_or_default();
+    }
+    response
+}
+
+/// The text content of a failed execution: the error, the logs, and what
+/// failed if it was an assertion.
+pub(crate) fn error_response(result: &ExecutionResult) -> serde_json::Value {
+    let mut response = serde_json::json!({
+        "error": result.error_message.as_deref().unwrap_or("Unknown error"),
+        "logs": result.logs
+    });
+    if let Some(assertion) = &result.assertion {
+        response["assertion"] = serde_json::to_value(assertion).unwrap_or_default();
+    }
+    if let Some(trace) = &result.trace {
+        response["trace"] = serde_json::to_value(trace).unwrap_or_default();
+    }
+    response
+}
+
+/// Convert a JSON value to Content items.
+/// Recognizes image, audio, resource and resource_link objects in the form
+/// MCP sends them, e.g. {type: "image", data: "...", mimeType: "..."}, and
+/// converts them back to the proper Content.
+pub(crate) fn json_to_content(value: &serde_json::Value) -> Vec<Content> {
+    if let Some(content) = content_object(value) {
+        return vec![content];
+    }
+    if let Some(obj) = value.as_object() {
+        // Check if it has a "result" field (from logs wrapper)
+        if let Some(result) = obj.get("result") {
+            let mut content = json_to_content(result);
+            if let Some(logs_arr) = obj.get("logs").and_then(|v| v.as_array())
+                && !logs_arr.is_empty()
+            {
+                content.push(Content::text(format!(
+                    "Logs:\n{}",
+                    logs_arr
+                        .iter()
+                        .filter_map(|v| v.as_str())
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                )));
+            }
+            return content;
+        }
+    }
+
+    // Check if it's an array that might contain images
+    if let Some(arr) = value.as_array() {
+        let mut content = Vec::new();
+        for item in arr {
+            content.extend(json_to_content(item));
+        }
+        if !content.is_empty() {
+            return content;
+        }
+    }
+
+    // Default: convert to text
+    vec![Content::text(
+        serde_json::to_string_pretty(value).unwrap_or_else(|_| value.to_string()),
+    )]
+}
+
+/// `value` as an image, audio, resource or resource link, if it is one in
+/// the form `format_call_result` hands them to scripts.
+fn content_object(value: &serde_json::Value) -> Option<Content> {
+    let kind = value.get("type")?.as_str()?;
+    if !matches!(kind, "image" | "audio" | "resource" | "resource_link") {
+        return None;
+    }
+    let raw = serde_json::from_value::<RawContent>(value.clone()).ok()?;
+    Some(raw.no_annotation())
+}
+
+/// Whether `value` is, or is an array holding, a content object.
+fn has_content_objects(value: &serde_json::Value) -> bool {
+    match value.as_array() {
+        Some(items) => items.iter().any(has_content_objects),
+        None => content_object(value).is_some(),
+    }
+}
+
+/// The content of a successful execute tool result: the text of
+/// `response_value`, or, when the script's value holds images or other
+/// content objects, that content followed by the logs and trace as text.
+fn execute_content(result: &ExecutionResult) -> Vec<Content> {
+    if result.continuation.is_some() || !has_content_objects(&result.value) {
+        let response = response_value(result);
+        return vec![Content::text(
+            serde_json::to_string_pretty(&response).unwrap_or_else(|_| response.to_string()),
+        )];
+    }
+    let mut content = json_to_content(&result.value);
+    let mut rest = serde_json::Map::new();
+    if !result.logs.is_empty() {
+        rest.insert("logs".to_string(), result.logs.clone().into());
+    }
+    if let Some(trace) = &result.trace {
+        rest.insert(
+            "trace".to_string(),
+            serde_json::to_value(trace).unwrap_or_default(),
+        );
+    }
+    if !rest.is_empty() {
+        content.push(Content::text(
+            serde_json::to_string_pretty(&rest).unwrap_or_default(),
+        ));
+    }
+    content
+}
+
+pub(crate) fn structured_output(result: &ExecutionResult) -> serde_json::Value {
+    serde_json::to_value(ExecuteCodeOutput::from(result)).unwrap_or_default()
+}
+
+pub(crate) fn parse_execute_params(
+    request: &CallToolRequestParam,
+) -> Result<ExecuteCodeParams, ErrorData> {
+    let args = request.arguments.clone().unwrap_or_default();
+    if !args.contains_key("code") {
+        return Err(ErrorData::invalid_params("Missing 'code' parameter", None));
+    }
+    serde_json::from_value(serde_json::Value::Object(args))
+        .map_err(|e| ErrorData::invalid_params(format!("Invalid parameters: {e}"), None))
+}
+
+/// `_meta` for `tools/list`: the active mode and profile, and how each tool
+/// is exposed.
+/// Downstream tools are `passthrough` (listed and callable from scripts) or
+/// `sandbox_only` (scripts only); the execute tool itself is `virtual`.
+pub(crate) fn list_tools_meta(config: &CodeModeConfig, inner_tools: &[Tool]) -> Meta {
+    let exposure = match config.mode {
+        CodeModeExposure::ReplaceTools => "sandbox_only",
+        CodeModeExposure::Add => "passthrough",
+    };
+    let mut tools = serde_json::Map::new();
+    for tool in inner_tools {
+        tools.insert(tool.name.to_string(), exposure.into());
+    }
+    tools.insert(config.tool_name.clone(), "virtual".into());
+    if config.lazy_tool_docs {
+        tools.insert(TOOL_DOCS_TOOL.to_string(), "virtual".into());
+    }
+    if config.continuations_enabled() {
+        tools.insert(ABANDON_TOOL.to_string(), "virtual".into());
+    }
+    if config.stats_tool {
+        tools.insert(STATS_TOOL.to_string(), "virtual".into());
+    }
+    if config.background_executions {
+        for name in [START_TOOL, GET_TOOL, CANCEL_TOOL] {
+            tools.insert(name.to_string(), "virtual".into());
+        }
+    }
+
+    let mut meta = Meta::new();
+    meta.insert(
+        "codemoder".to_string(),
+        serde_json::json!({ "mode": config.mode, "profile": config.profile, "tools": tools }),
+    );
+    meta
+}
+
+/// List the downstream tools and keep `config.tool_name` from shadowing
+/// one of them; see `CodeModeConfig::resolve_tool_name`. Run before serving.
+pub async fn resolve_tool_name(
+    downstream: &DownstreamClient,
+    config: &mut CodeModeConfig,
+) -> anyhow::Result<()> {
+    let tools = downstream
+        .peer()
+        .list_all_tools()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to list the downstream tools: {e}"))?;
+    let aliases = ToolAliases::from_config(config);
+    let included = tools
+        .into_iter()
+        .filter(|t| config.includes_tool(&t.name))
+        .collect();
+    let exposed: Vec<String> = aliases
+        .upstream_tools(aliases.rename_tools(included))
+        .iter()
+        .map(|t| t.name.to_string())
+        .collect();
+    config.resolve_tool_name(&exposed)
+}
+
+/// The page of `tools` a `tools/list` request asks for, and the cursor of
+/// the page after it. Cursors are offsets into the list; without a page size
+/// every tool is on the first page.
+pub(crate) fn tools_page(
+    tools: Vec<Tool>,
+    request: Option<&PaginatedRequestParam>,
+    page_size: Option<usize>,
+) -> Result<(Vec<Tool>, Option<String>), ErrorData> {
+    let start = match request.and_then(|r| r.cursor.as_deref()) {
+        None => 0,
+        Some(cursor) => cursor
+            .parse::<usize>()
+            .ok()
+            .filter(|start| *start <= tools.len())
+            .ok_or_else(|| ErrorData::invalid_params(format!("Invalid cursor `{cursor}`"), None))?,
+    };
+    let end = page_size.map_or(tools.len(), |size| (start + size).min(tools.len()));
+    let next_cursor = (end < tools.len()).then(|| end.to_string());
+    let page = tools.into_iter().skip(start).take(end - start).collect();
+    Ok((page, next_cursor))
+}
+
+/// Clones share all state, so a background execution can run on its own
+/// task.
+#[derive(Clone)]
 pub struct CodeModeProxy {
     config: CodeModeConfig,
-    downstream: Arc<Mutex<rmcp::service::RunningService<rmcp::service::RoleClient, ()>>>,
-    cached_tools: RwLock<Vec<Tool>>,
-    cached_ts_interface: RwLock<String>,
-    runtime: Arc<Mutex<Option<JsRuntime>>>,
+    downstream: Arc<Mutex<DownstreamClient>>,
+    /// The upstream client, for requests the downstream server makes of it.
+    upstream: UpstreamPeer,
+    /// Marked when the downstream tool list changes, so the caches below
+    /// are refreshed before the next execution.
+    tools_changed: ToolsChanged,
+    /// Relays progress of passthrough tool calls to the client.
+    progress: ProgressRoutes,
+    /// The downstream server's initialize response, refreshed when a
+    /// standby takes over.
+    downstream_server: DownstreamServer,
+    cached_tools: Arc<RwLock<Vec<Tool>>>,
+    cached_ts_interface: Arc<RwLock<String>>,
+    runtime: Arc<Mutex<Option<Box<dyn ScriptRuntime>>>>,
+    limits: Arc<ConcurrencyLimits>,
+    aliases: Arc<ToolAliases>,
+    redactions: Arc<ArgRedactions>,
+    path_args: Arc<PathArgs>,
+    costs: Arc<CostModel>,
+    session_costs: Arc<CostLedger>,
+    summaries: Arc<SummaryLog>,
+    usage: Arc<UsageStats>,
+    slos: Arc<LatencySlos>,
+    timeouts: Arc<CallTimeouts>,
+    retry: Arc<RetryPolicy>,
+    circuit: Arc<CircuitBreaker>,
+    cache: Arc<ResultCache>,
+    notes: Arc<FailureNotes>,
+    background: BackgroundExecutions,
+    /// Decides whether streamed `console.log` lines reach the client.
+    log_level: ClientLogLevel,
+    approver: Option<Arc<dyn Approver>>,
+    policy: Option<Arc<dyn Policy>>,
+    /// Where executions are recorded, if anywhere.
+    storage: Option<Arc<dyn Storage>>,
+    drain: Drain,
 }
 
 impl CodeModeProxy {
-    pub fn new(
-        downstream: rmcp::service::RunningService<rmcp::service::RoleClient, ()>,
-        config: CodeModeConfig,
-    ) -> Self {
+    pub fn new(downstream: DownstreamClient, config: CodeModeConfig) -> Self {
+        let limits = Arc::new(ConcurrencyLimits::from_config(&config));
+        let aliases = Arc::new(ToolAliases::from_config(&config));
+        let redactions = Arc::new(ArgRedactions::from_config(&config, &aliases));
+        let path_args = Arc::new(PathArgs::from_config(&config, &aliases));
+        let costs = Arc::new(CostModel::from_config(&config));
+        let session_costs = Arc::new(CostLedger::new(config.session_ttl()));
+        let summaries = Arc::new(SummaryLog::new(config.summary_history));
+        let usage = Arc::new(UsageStats::new(config.stats_tool));
+        let slos = Arc::new(LatencySlos::from_config(&config, &aliases));
+        let timeouts = Arc::new(CallTimeouts::from_config(&config, slos.clone()));
+        let retry = Arc::new(RetryPolicy::from_config(&config));
+        let circuit = Arc::new(CircuitBreaker::from_config(&config));
+        let cache = Arc::new(ResultCache::from_config(&config, &aliases));
+        let notes = Arc::new(FailureNotes::new(config.failure_notes));
+        let background = BackgroundExecutions::new(config.session_ttl());
+        let approver = approver_from_config(&config);
+        let storage = storage_from_config(&config);
+        let downstream_server = DownstreamServer::default();
+        downstream_server.set(downstream.peer_info().cloned());
+        let upstream = downstream.service().upstream();
+        let tools_changed = downstream.service().tools_changed();
+        let progress = downstream.service().progress();
         Self {
             config,
             downstream: Arc::new(Mutex::new(downstream)),
-            cached_tools: RwLock::new(Vec::new()),
-            cached_ts_interface: RwLock::new(String::new()),
+            upstream,
+            tools_changed,
+            progress,
+            downstream_server,
+            cached_tools: Arc::default(),
+            cached_ts_interface: Arc::default(),
             runtime: Arc::new(Mutex::new(None)),
+            limits,
+            aliases,
+            redactions,
+            path_args,
+            costs,
+            session_costs,
+            summaries,
+            usage,
+            slos,
+            timeouts,
+            retry,
+            circuit,
+            cache,
+            notes,
+            background,
+            log_level: ClientLogLevel::default(),
+            approver,
+            policy: None,
+            storage,
+            drain: Drain::new(),
         }
     }
 
-    async fn make_execute_tools_tool(&self) -> Tool {
+    /// Decide on scripts and guarded tool calls with `approver` instead of
+    /// the one selected by the config.
+    pub fn with_approver(mut self, approver: Arc<dyn Approver>) -> Self {
+        self.approver = Some(approver);
+        self
+    }
+
+    /// Check every downstream tool call against `policy`; see
+    /// `policy::policy_from_config`.
+    pub fn with_policy(mut self, policy: Arc<dyn Policy>) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Keep recorded executions in `storage` instead of the `record_dir`
+    /// directory, recording them even without one.
+    pub fn with_storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Share a drain handle so the embedding process can stop new executions
+    /// and wait for running ones before shutting down.
+    pub fn with_drain(mut self, drain: Drain) -> Self {
+        self.drain = drain;
+        self
+    }
+
+    async fn make_execute_tools_tool(&self, client: Option<&ClientCapabilities>) -> Tool {
         use rmcp::handler::server::common::schema_for_type;
 
         let ts_interface = self.cached_ts_interface.read().await.clone();
-        let description = if ts_interface.is_empty() {
-            self.config.tool_description.clone()
-        } else {
-            format!(
-                "{}\n\n## Available Tools (synchronous)\n\n```typescript\n{}\n```\n\n## Notes\n\n- All tool calls are **synchronous** (no async/await needed)\n- Use `console.log(value)` to debug - logs are returned in the result",
-                self.config.tool_description, ts_interface
-            )
-        };
+        let description = execute_tool_description(
+            &self.config,
+            &ts_interface,
+            &self.downstream_server.capabilities(),
+            client,
+        );
 
         Tool {
             name: self.config.tool_name.clone().into(),
             description: Some(description.into()),
             input_schema: Arc::new(schema_for_type::<ExecuteCodeParams>()),
             title: None,
-            output_schema: None,
+            output_schema: Some(Arc::new(schema_for_type::<ExecuteCodeOutput>())),
             annotations: None,
-            icons: None,
+            icons: self.config.tool_icons.clone(),
             meta: None,
         }
     }
 
     fn filter_tools(&self, tools: Vec<Tool>) -> Vec<Tool> {
-        match &self.config.include_tools {
-            Some(include) => tools
-                .into_iter()
-                .filter(|t| include.iter().any(|name| name == t.name.as_ref()))
-                .collect(),
-            None => tools,
-        }
+        let included = tools
+            .into_iter()
+            .filter(|t| self.config.includes_tool(&t.name))
+            .collect();
+        add_examples(self.aliases.rename_tools(included), &self.config)
+    }
+
+    /// Marking it makes the next execution list the downstream tools again.
+    pub fn tools_changed(&self) -> ToolsChanged {
+        self.tools_changed.clone()
+    }
+
+    /// Switches the downstream server to a standby; see `crate::standby`.
+    pub async fn switchover(&self) -> Switchover {
+        let handler = self.downstream.lock().await.service().clone();
+        Switchover::new(
+            self.downstream.clone(),
+            handler,
+            self.downstream_server.clone(),
+            self.config.drain_timeout(),
+        )
     }
 
     pub async fn list_all_tools(&self) -> Result<Vec<Tool>, ErrorData> {
-        let peer = self.downstream.lock().await;
-        let inner_result = peer
-            .peer()
-            .list_tools(None)
+        let inner_tools = self.refresh_tools().await?;
+        Ok(self.exposed_tools(inner_tools, None).await)
+    }
+
+    /// List every page of the downstream tools and cache what scripts see.
+    async fn refresh_tools(&self) -> Result<Vec<Tool>, ErrorData> {
+        self.tools_changed.take();
+        let inner_tools = downstream_peer(&self.downstream)
+            .await
+            .list_all_tools()
             .await
             .map_err(|e| ErrorData::internal_error(format!("Downstream error: {e}"), None))?;
 
-        let inner_tools = self.filter_tools(inner_result.tools);
+        let inner_tools = self.filter_tools(inner_tools);
 
         {
             let mut cached = self.cached_tools.write().await;
             *cached = inner_tools.clone();
         }
+        self.tools_changed.listed();
         {
             let mut cached = self.cached_ts_interface.write().await;
-            *cached = generate_typescript_interface(&inner_tools, "tools");
+            *cached = tools_interface(
+                &self.config,
+                &self.notes.annotate(&inner_tools),
+                &self.aliases.script_namespace(),
+            );
         }
+        Ok(inner_tools)
+    }
 
+    /// The tools clients see: the downstream ones, unless replaced, and the
+    /// virtual ones. The execute tool's description mentions the helpers
+    /// that `client` supports.
+    async fn exposed_tools(
+        &self,
+        inner_tools: Vec<Tool>,
+        client: Option<&ClientCapabilities>,
+    ) -> Vec<Tool> {
         let mut result_tools = match self.config.mode {
             CodeModeExposure::ReplaceTools => vec![],
-            CodeModeExposure::Add => inner_tools,
+            CodeModeExposure::Add => self.aliases.upstream_tools(inner_tools),
         };
 
-        result_tools.push(self.make_execute_tools_tool().await);
-        Ok(result_tools)
+        result_tools.push(self.make_execute_tools_tool(client).await);
+        if self.config.lazy_tool_docs {
+            result_tools.push(make_tool_docs_tool());
+        }
+        if self.config.continuations_enabled() {
+            result_tools.push(make_abandon_tool());
+        }
+        if self.config.stats_tool {
+            result_tools.push(make_stats_tool());
+        }
+        if self.config.background_executions {
+            result_tools.extend(make_background_tools());
+        }
+        result_tools
     }
 
     pub async fn call_tool_direct(
@@ -108,15 +836,13 @@ impl CodeModeProxy {
         name: &str,
         args: serde_json::Value,
     ) -> Result<String, ErrorData> {
-        let peer = self.downstream.lock().await;
-
         let request = rmcp::model::CallToolRequestParam {
             name: name.to_string().into(),
             arguments: args.as_object().cloned(),
         };
 
-        let result = peer
-            .peer()
+        let result = downstream_peer(&self.downstream)
+            .await
             .call_tool(request)
             .await
             .map_err(|e| ErrorData::internal_error(format!("Downstream error: {e}"), None))?;
@@ -132,76 +858,249 @@ impl CodeModeProxy {
     }
 
     pub async fn execute_code_direct(&self, code: &str) -> Result<serde_json::Value, ErrorData> {
-        let result = self.execute_code(code).await?;
+        let params = ExecuteCodeParams {
+            code: code.to_string(),
+            session: None,
+            reset: false,
+            continuation: None,
+            idempotent: false,
+            verbose: false,
+        };
+        let result = self
+            .execute_code(&params, CancellationToken::new(), None, None, false)
+            .await?;
         if result.is_error {
+            let data = result
+                .assertion
+                .map(|assertion| serde_json::json!({ "assertion": assertion }));
             return Err(ErrorData::internal_error(
                 result.error_message.unwrap_or_default(),
-                None,
+                data,
             ));
         }
         Ok(result.value)
     }
 
     async fn ensure_tools_cached(&self) -> Result<(), ErrorData> {
-        let cached = self.cached_tools.read().await;
-        if !cached.is_empty() {
+        let changed =
+            self.tools_changed.take() || self.tools_changed.expired(self.config.tools_ttl());
+        if !changed && !self.cached_tools.read().await.is_empty() {
             return Ok(());
         }
-        drop(cached);
-
-        let peer = self.downstream.lock().await;
-        let inner_result = peer
-            .peer()
-            .list_tools(None)
-            .await
-            .map_err(|e| ErrorData::internal_error(format!("Downstream error: {e}"), None))?;
-
-        let inner_tools = self.filter_tools(inner_result.tools);
-
-        {
-            let mut cached = self.cached_tools.write().await;
-            *cached = inner_tools.clone();
-        }
-        {
-            let mut cached = self.cached_ts_interface.write().await;
-            *cached = generate_typescript_interface(&inner_tools, "tools");
-        }
-
+        self.refresh_tools().await?;
         Ok(())
     }
 
-    async fn execute_code(&self, code: &str) -> Result<crate::runtime::ExecutionResult, ErrorData> {
+    async fn execute_code(
+        &self,
+        params: &ExecuteCodeParams,
+        ct: CancellationToken,
+        peer: Option<Peer<RoleServer>>,
+        progress_token: Option<ProgressToken>,
+        background: bool,
+    ) -> Result<crate::runtime::ExecutionResult, ErrorData> {
         self.ensure_tools_cached().await?;
 
         let tools = self.cached_tools.read().await.clone();
         let tool_names: Vec<String> = tools.iter().map(|t| t.name.to_string()).collect();
 
-        let full_code = code.to_string();
+        let full_code = params.code.clone();
 
-        let mut runtime_guard = self.runtime.lock().await;
-        if runtime_guard.is_none() {
-            *runtime_guard = Some(
-                JsRuntime::new()
-                    .await
-                    .map_err(|e| ErrorData::internal_error(e.to_string(), None))?,
-            );
+        // Before taking the shared runtime, so executions don't wait on a
+        // human approving this one
+        if self.config.approve_scripts {
+            let request = ApprovalRequest::Script {
+                code: params.code.clone(),
+                intended_calls: intended_calls(&params.code, &tool_names),
+            };
+            if !approval::check(self.approver.as_ref(), &request).await {
+                return Ok(ExecutionResult {
+                    is_error: true,
+                    error_message: Some("Script was not approved".to_string()),
+                    ..Default::default()
+                });
+            }
         }
 
-        let runtime = runtime_guard.as_ref().unwrap();
-        let downstream = self.downstream.clone();
+        // A background execution runs on a runtime of its own so foreground
+        // ones don't wait for it, unless it needs a session or a paused
+        // execution kept in the shared one
+        let mut shared_runtime = None;
+        let own_runtime;
+        let runtime: &dyn ScriptRuntime =
+            if background && params.session.is_none() && params.continuation.is_none() {
+                own_runtime = create_runtime(&self.config)
+                    .await
+                    .map_err(|e| ErrorData::internal_error(e.to_string(), None))?;
+                own_runtime.as_ref()
+            } else {
+                let runtime_guard = shared_runtime.insert(self.runtime.lock().await);
+                if runtime_guard.is_none() {
+                    **runtime_guard = Some(
+                        create_runtime(&self.config)
+                            .await
+                            .map_err(|e| ErrorData::internal_error(e.to_string(), None))?,
+                    );
+                }
+                runtime_guard.as_deref().unwrap()
+            };
+
+        let session_spent = match &params.session {
+            Some(session) if params.reset => {
+                self.session_costs.reset(session);
+                0.0
+            }
+            Some(session) => self.session_costs.total(session),
+            None => 0.0,
+        };
+        let execution_id = random_id("exec");
+        let stack = CallerStack {
+            config: &self.config,
+            tools: &tools,
+            limits: &self.limits,
+            aliases: &self.aliases,
+            redactions: &self.redactions,
+            path_args: &self.path_args,
+            costs: &self.costs,
+            usage: &self.usage,
+            slos: &self.slos,
+            timeouts: &self.timeouts,
+            retry: &self.retry,
+            circuit: &self.circuit,
+            cache: &self.cache,
+            notes: &self.notes,
+            approver: &self.approver,
+            policy: &self.policy,
+            log_level: self.log_level.get(),
+            record: self.storage.is_some(),
+            peer: peer.clone(),
+            progress_token,
+            cancellation: ct.clone(),
+            verbose: params.verbose,
+            session_spent,
+        }
+        .build(DownstreamToolCaller::new(self.downstream.clone()).with_cancellation(ct.clone()));
 
-        runtime
-            .execute_with_tools(&full_code, &tool_names, downstream)
+        let options = ExecutionOptions {
+            timeout: self.config.timeout(),
+            cancellation: Some(ct),
+            cancel_grace: self.config.cancel_grace(),
+            lazy_tools: self.config.lazy_tools(),
+            session: params.session.clone(),
+            reset_session: params.reset,
+            typescript: self.config.typescript,
+            stats: self.config.stats_helpers,
+            hashes: self.config.hash_helpers,
+            llm: self.config.llm_helpers,
+            safe_regex: self.config.safe_regex,
+            stream_logs: self.config.stream_logs,
+            namespace: self.config.namespace.clone(),
+            camel_case: self.config.camel_case_aliases,
+            continuation: params.continuation.clone(),
+        };
+        let started = Instant::now();
+        let span = execution_span(&execution_id, params.session.as_deref());
+        let mut result = runtime
+            .execute(&full_code, &tool_names, stack.caller.clone(), options)
+            .instrument(span.clone())
             .await
-            .map_err(|e| ErrorData::internal_error(format!("Code execution failed: {e}"), None))
+            .map_err(|e| ErrorData::internal_error(format!("Code execution failed: {e}"), None))?;
+        stack.report(&mut result);
+        if !self.costs.is_empty() {
+            let cost = *stack.cost.lock().unwrap();
+            result.metrics.cost = Some(cost);
+            if let Some(session) = &params.session {
+                result.metrics.session_cost = Some(self.session_costs.add(session, cost));
+            }
+        }
+        record_execution(&span, &result);
+        result.execution_id = Some(execution_id.clone());
+        result.idempotent = params.idempotent || declares_idempotent(&params.code);
+        result.destructive_calls = destructive_calls(
+            &stack.tool_calls.lock().unwrap(),
+            &destructive_tools(&tools, &self.config),
+        )
+        .into_iter()
+        .collect();
+        if let Some(storage) = &self.storage {
+            let calls = std::mem::take(&mut *stack.recorded.lock().unwrap());
+            RunBundle::new(
+                &execution_id,
+                &full_code,
+                &self.config,
+                &tools,
+                &self.aliases,
+                calls,
+            )
+            .with_result(&result)
+            .record(storage.as_ref());
+        }
+        self.usage.record_execution(&result, started.elapsed());
+        self.summaries.record(ExecutionSummary::new(
+            &result,
+            started.elapsed(),
+            params.session.clone(),
+            stack.tool_calls.lock().unwrap().clone(),
+        ));
+        self.publish_notes(peer.as_ref()).await;
+        Ok(result)
+    }
+
+    /// Put new failure notes in the execute tool's description and tell the
+    /// client to list the tools again.
+    async fn publish_notes(&self, peer: Option<&Peer<RoleServer>>) {
+        if !self.notes.take_changed() {
+            return;
+        }
+        let tools = self.cached_tools.read().await.clone();
+        *self.cached_ts_interface.write().await = tools_interface(
+            &self.config,
+            &self.notes.annotate(&tools),
+            &self.aliases.script_namespace(),
+        );
+        if let Some(peer) = peer
+            && let Err(e) = peer.notify_tool_list_changed().await
+        {
+            tracing::debug!("Failed to notify the client of new tool notes: {e}");
+        }
     }
 }
 
 impl ServerHandler for CodeModeProxy {
     fn get_info(&self) -> ServerInfo {
+        let downstream = self.downstream_server.capabilities();
+        let mut capabilities = ServerCapabilities::builder()
+            .enable_experimental_with(
+                [(
+                    "codemoder".to_string(),
+                    capability_report(
+                        &self.config,
+                        self.downstream_server.implementation().as_ref(),
+                    ),
+                )]
+                .into(),
+            )
+            .enable_tools()
+            .build();
+        // Changes to the downstream tools are passed on
+        if let Some(tools) = &mut capabilities.tools {
+            tools.list_changed = downstream.tools.as_ref().and_then(|t| t.list_changed);
+            // So do new failure notes, to the execute tool's description
+            if self.config.failure_notes {
+                tools.list_changed = Some(true);
+            }
+        }
+        capabilities.prompts = downstream.prompts;
+        capabilities.resources = downstream.resources;
+        // For codemode://docs
+        capabilities.resources.get_or_insert_default();
+        if self.config.stream_logs {
+            capabilities.logging.get_or_insert_default();
+        }
+        capabilities.completions = downstream.completions;
         ServerInfo {
             protocol_version: Default::default(),
-            capabilities: ServerCapabilities::builder().enable_tools().build(),
+            capabilities,
             server_info: Implementation {
                 name: "code-mode-proxy".into(),
                 version: env!("CARGO_PKG_VERSION").into(),
@@ -215,97 +1114,345 @@ impl ServerHandler for CodeModeProxy {
         }
     }
 
+    async fn set_level(
+        &self,
+        request: SetLevelRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<(), ErrorData> {
+        self.log_level.set(request.level);
+        Ok(())
+    }
+
+    async fn on_initialized(&self, context: NotificationContext<RoleServer>) {
+        // Requests the downstream server makes of its client go to this one
+        self.upstream.set(context.peer);
+    }
+
     async fn list_tools(
         &self,
         request: Option<PaginatedRequestParam>,
-        _context: RequestContext<RoleServer>,
+        context: RequestContext<RoleServer>,
     ) -> Result<ListToolsResult, ErrorData> {
-        let downstream = self.downstream.lock().await;
-        let peer = downstream.peer();
+        // The first page lists the downstream tools again; later ones are
+        // cut from the same list so cursors stay valid
+        let inner_tools = if request.as_ref().is_none_or(|r| r.cursor.is_none()) {
+            self.refresh_tools().await?
+        } else {
+            self.ensure_tools_cached().await?;
+            self.cached_tools.read().await.clone()
+        };
+        let meta = list_tools_meta(&self.config, &inner_tools);
+        let client = context.peer.peer_info().map(|info| &info.capabilities);
+        let tools = self.exposed_tools(inner_tools, client).await;
+        let (tools, next_cursor) =
+            tools_page(tools, request.as_ref(), self.config.tools_page_size)?;
+
+        Ok(ListToolsResult {
+            tools,
+            next_cursor,
+            meta: Some(meta),
+        })
+    }
 
-        let inner_result = peer
-            .list_tools(request)
+    async fn list_prompts(
+        &self,
+        request: Option<PaginatedRequestParam>,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListPromptsResult, ErrorData> {
+        if self.downstream_server.capabilities().prompts.is_none() {
+            return Ok(ListPromptsResult::default());
+        }
+        let downstream = downstream_peer(&self.downstream).await;
+        downstream
+            .list_prompts(request)
             .await
-            .map_err(|e| ErrorData::internal_error(format!("Downstream error: {e}"), None))?;
+            .map_err(|e| ErrorData::internal_error(format!("Downstream error: {e}"), None))
+    }
 
-        let inner_tools = self.filter_tools(inner_result.tools);
+    async fn get_prompt(
+        &self,
+        request: GetPromptRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<GetPromptResult, ErrorData> {
+        let downstream = downstream_peer(&self.downstream).await;
+        downstream
+            .get_prompt(request)
+            .await
+            .map_err(|e| ErrorData::internal_error(format!("Downstream error: {e}"), None))
+    }
 
-        {
-            let mut cached = self.cached_tools.write().await;
-            *cached = inner_tools.clone();
-        }
-        {
-            let mut cached = self.cached_ts_interface.write().await;
-            *cached = generate_typescript_interface(&inner_tools, "tools");
+    async fn list_resources(
+        &self,
+        request: Option<PaginatedRequestParam>,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListResourcesResult, ErrorData> {
+        let first_page = request.as_ref().is_none_or(|r| r.cursor.is_none());
+        let mut result = if self.downstream_server.capabilities().resources.is_none() {
+            ListResourcesResult::default()
+        } else {
+            let downstream = downstream_peer(&self.downstream).await;
+            downstream
+                .list_resources(request)
+                .await
+                .map_err(|e| ErrorData::internal_error(format!("Downstream error: {e}"), None))?
+        };
+        if first_page {
+            if self.summaries.is_enabled() {
+                result.resources.insert(0, summary_resource());
+            }
+            result.resources.insert(0, docs_resource());
         }
+        Ok(result)
+    }
 
-        let mut result_tools = match self.config.mode {
-            CodeModeExposure::ReplaceTools => vec![],
-            CodeModeExposure::Add => inner_tools,
-        };
+    async fn list_resource_templates(
+        &self,
+        request: Option<PaginatedRequestParam>,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListResourceTemplatesResult, ErrorData> {
+        if self.downstream_server.capabilities().resources.is_none() {
+            return Ok(ListResourceTemplatesResult::default());
+        }
+        let downstream = downstream_peer(&self.downstream).await;
+        downstream
+            .list_resource_templates(request)
+            .await
+            .map_err(|e| ErrorData::internal_error(format!("Downstream error: {e}"), None))
+    }
 
-        result_tools.push(self.make_execute_tools_tool().await);
+    async fn read_resource(
+        &self,
+        request: ReadResourceRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ReadResourceResult, ErrorData> {
+        if request.uri == DOCS_URI {
+            return Ok(ReadResourceResult {
+                contents: vec![ResourceContents::text(HELPER_DOCS, DOCS_URI)],
+            });
+        }
+        if request.uri == SUMMARY_URI && self.summaries.is_enabled() {
+            return Ok(ReadResourceResult {
+                contents: vec![ResourceContents::text(self.summaries.render(), SUMMARY_URI)],
+            });
+        }
+        let downstream = downstream_peer(&self.downstream).await;
+        downstream
+            .read_resource(request)
+            .await
+            .map_err(|e| ErrorData::internal_error(format!("Downstream error: {e}"), None))
+    }
 
-        Ok(ListToolsResult {
-            tools: result_tools,
-            next_cursor: None,
-            meta: None,
-        })
+    async fn complete(
+        &self,
+        request: CompleteRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<CompleteResult, ErrorData> {
+        if self.downstream_server.capabilities().completions.is_none() {
+            return Ok(CompleteResult::default());
+        }
+        let downstream = downstream_peer(&self.downstream).await;
+        downstream
+            .complete(request)
+            .await
+            .map_err(|e| ErrorData::internal_error(format!("Downstream error: {e}"), None))
     }
 
     async fn call_tool(
         &self,
         request: CallToolRequestParam,
-        _context: RequestContext<RoleServer>,
+        context: RequestContext<RoleServer>,
     ) -> Result<CallToolResult, ErrorData> {
         if request.name.as_ref() == self.config.tool_name {
-            let code = request
-                .arguments
-                .as_ref()
-                .and_then(|args| args.get("code"))
-                .and_then(|v| v.as_str())
-                .ok_or_else(|| ErrorData::invalid_params("Missing 'code' parameter", None))?;
-
-            let result = self.execute_code(code).await?;
-
-            // Build the response content
-            let response_value = if result.logs.is_empty() {
-                result.value.clone()
-            } else {
-                serde_json::json!({
-                    "result": result.value,
-                    "logs": result.logs
-                })
+            let params = parse_execute_params(&request)?;
+            let Some(_in_flight) = self.drain.enter() else {
+                return Err(ErrorData::internal_error(
+                    "Server is draining and not accepting new executions",
+                    None,
+                ));
             };
 
+            let result = self
+                .execute_code(
+                    &params,
+                    context.ct.clone(),
+                    Some(context.peer.clone()),
+                    context.meta.get_progress_token(),
+                    false,
+                )
+                .await?;
+
             let content = if result.is_error {
                 // Include error message in the content
-                let error_response = serde_json::json!({
-                    "error": result.error_message.as_deref().unwrap_or("Unknown error"),
-                    "logs": result.logs
-                });
-                Content::text(serde_json::to_string_pretty(&error_response).unwrap_or_default())
+                vec![Content::text(
+                    serde_json::to_string_pretty(&error_response(&result)).unwrap_or_default(),
+                )]
             } else {
-                Content::text(
-                    serde_json::to_string_pretty(&response_value)
-                        .unwrap_or_else(|_| response_value.to_string()),
-                )
+                execute_content(&result)
             };
 
             return Ok(CallToolResult {
-                content: vec![content],
+                content,
                 is_error: Some(result.is_error),
-                structured_content: None,
-                meta: None,
+                structured_content: Some(structured_output(&result)),
+                meta: Some(result.meta()),
             });
         }
 
-        let downstream = self.downstream.lock().await;
-        let peer = downstream.peer();
+        if self.config.continuations_enabled() && request.name.as_ref() == ABANDON_TOOL {
+            let runtime = self.runtime.lock().await;
+            return abandon_continuation(&request, runtime.as_deref());
+        }
 
-        peer.call_tool(request)
-            .await
-            .map_err(|e| ErrorData::internal_error(format!("Downstream error: {e}"), None))
+        if self.config.background_executions {
+            match request.name.as_ref() {
+                START_TOOL => {
+                    let params = parse_execute_params(&request)?;
+                    let Some(in_flight) = self.drain.enter() else {
+                        return Err(ErrorData::internal_error(
+                            "Server is draining and not accepting new executions",
+                            None,
+                        ));
+                    };
+                    let (id, ct) = self.background.start();
+                    let proxy = self.clone();
+                    let execution = id.clone();
+                    let peer = context.peer.clone();
+                    tokio::spawn(async move {
+                        let _in_flight = in_flight;
+                        let result = proxy
+                            .execute_code(&params, ct, Some(peer), None, true)
+                            .await;
+                        proxy.background.finish(&execution, result);
+                    });
+                    return Ok(started(&id));
+                }
+                GET_TOOL => return get_execution(&request, &self.background),
+                CANCEL_TOOL => return cancel_execution(&request, &self.background),
+                _ => {}
+            }
+        }
+
+        if self.config.stats_tool && request.name.as_ref() == STATS_TOOL {
+            return Ok(stats(&self.usage, &self.slos, &self.circuit));
+        }
+
+        if self.config.lazy_tool_docs && request.name.as_ref() == TOOL_DOCS_TOOL {
+            self.ensure_tools_cached().await?;
+            let tools = self.cached_tools.read().await;
+            return tool_docs(
+                &request,
+                &self.notes.annotate(&tools),
+                &self.aliases.script_namespace(),
+                &self.config,
+            );
+        }
+
+        let name = self.aliases.strip_namespace(&request.name).to_string();
+        if !self.config.includes_tool(self.aliases.downstream(&name)) {
+            return Err(ErrorData::invalid_params(
+                format!("Unknown tool: {}", request.name),
+                None,
+            ));
+        }
+        if self.policy.is_some() {
+            self.ensure_tools_cached().await?;
+            let tools = self.cached_tools.read().await;
+            let input = PolicyInput {
+                client: client_name(&context.peer),
+                tool: self.aliases.downstream(&name).to_string(),
+                args: serde_json::Value::Object(request.arguments.clone().unwrap_or_default()),
+                annotations: tools
+                    .iter()
+                    .find(|tool| tool.name == name)
+                    .and_then(|tool| tool.annotations.clone()),
+            };
+            drop(tools);
+            if let Err(message) = policy::check(self.policy.as_ref(), &input).await {
+                return Ok(CallToolResult::error(vec![Content::text(message)]));
+            }
+        }
+        if guarded_tools(&self.config).contains(&name) {
+            let approval = ApprovalRequest::ToolCall {
+                name: name.clone(),
+                args: request.arguments.clone().map(serde_json::Value::Object),
+            };
+            if !approval::check(self.approver.as_ref(), &approval).await {
+                return Ok(CallToolResult::error(vec![Content::text(format!(
+                    "Call to {name} was not approved"
+                ))]));
+            }
+        }
+
+        let _permit = self.limits.acquire(&name).await;
+        let downstream_name = self.aliases.downstream(&name).to_string();
+        let retryable = self.retry.is_enabled() && {
+            self.ensure_tools_cached().await?;
+            self.cached_tools
+                .read()
+                .await
+                .iter()
+                .find(|tool| tool.name == name)
+                .is_some_and(|tool| is_retryable(tool, &self.config))
+        };
+        let mut arguments = request.arguments;
+        if let Some(arguments) = arguments.as_mut() {
+            self.redactions.apply(&downstream_name, arguments);
+        }
+        let request = ClientRequest::CallToolRequest(CallToolRequest {
+            method: Default::default(),
+            params: CallToolRequestParam {
+                name: downstream_name.clone().into(),
+                arguments,
+            },
+            extensions: Default::default(),
+        });
+        // The downstream server reports progress under a token of ours
+        let progress = context
+            .meta
+            .get_progress_token()
+            .map(|token| self.progress.route(context.peer.clone(), token));
+        let downstream_error =
+            |e| ErrorData::internal_error(format!("Downstream error: {e}"), None);
+        if let Err(e) = self.circuit.check() {
+            return Err(ErrorData::internal_error(e.to_string(), None));
+        }
+        let started = Instant::now();
+        let attempt = || async {
+            let options = PeerRequestOptions {
+                timeout: self.timeouts.timeout(&downstream_name),
+                meta: progress.as_ref().map(|route| route.meta()),
+            };
+            let response = downstream_peer(&self.downstream)
+                .await
+                .send_request_with_option(request.clone(), options)
+                .await
+                .map_err(downstream_error)?
+                .await_response()
+                .await
+                .map_err(downstream_error)?;
+            match response {
+                ServerResult::CallToolResult(result) => Ok(result),
+                _ => Err(ErrorData::internal_error(
+                    "Unexpected response from the downstream server",
+                    None,
+                )),
+            }
+        };
+        let result = traced_call(
+            &downstream_name,
+            "passthrough",
+            self.retry
+                .run(&downstream_name, retryable, &context.ct, attempt),
+        )
+        .await;
+        self.circuit.record(&result);
+        let ok = result.as_ref().is_ok_and(|r| r.is_error != Some(true));
+        self.usage
+            .record_call(&downstream_name, started.elapsed(), ok);
+        if let Some(alert) = self.slos.record(&downstream_name, started.elapsed()) {
+            slo::notify(&context.peer, self.log_level.get(), &alert).await;
+        }
+        result
     }
 }
 
@@ -334,18 +1481,37 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_tools_page() {
+        let tools: Vec<Tool> = ["a", "b", "c"].into_iter().map(make_test_tool).collect();
+        let page = |cursor: Option<&str>, size| {
+            let request = PaginatedRequestParam {
+                cursor: cursor.map(str::to_string),
+            };
+            tools_page(tools.clone(), Some(&request), size)
+        };
+
+        let (all, next) = tools_page(tools.clone(), None, None).unwrap();
+        assert_eq!((all.len(), next), (3, None));
+        let (first, next) = page(None, Some(2)).unwrap();
+        assert_eq!(first.len(), 2);
+        assert_eq!(next.as_deref(), Some("2"));
+        let (last, next) = page(Some("2"), Some(2)).unwrap();
+        assert_eq!(last[0].name, "c");
+        assert_eq!(next, None);
+        assert!(page(Some("4"), Some(2)).is_err());
+        assert!(page(Some("x"), Some(2)).is_err());
+    }
+
     #[test]
     fn test_filter_tools_empty_config() {
         let tools = vec![make_test_tool("tool1"), make_test_tool("tool2")];
 
         let config = CodeModeConfig::default();
-        let filtered = match &config.include_tools {
-            Some(include) => tools
-                .into_iter()
-                .filter(|t| include.iter().any(|name| name == t.name.as_ref()))
-                .collect(),
-            None => tools,
-        };
+        let filtered: Vec<Tool> = tools
+            .into_iter()
+            .filter(|t| config.includes_tool(&t.name))
+            .collect();
 
         assert_eq!(filtered.len(), 2);
     }
@@ -360,16 +1526,130 @@ mod tests {
 
         let config =
             CodeModeConfig::new().only_tools(vec!["tool1".to_string(), "tool3".to_string()]);
-        let filtered: Vec<Tool> = match &config.include_tools {
-            Some(include) => tools
-                .into_iter()
-                .filter(|t| include.iter().any(|name| name == t.name.as_ref()))
-                .collect(),
-            None => tools,
-        };
+        let filtered: Vec<Tool> = tools
+            .into_iter()
+            .filter(|t| config.includes_tool(&t.name))
+            .collect();
 
         assert_eq!(filtered.len(), 2);
         assert!(filtered.iter().any(|t| t.name == "tool1"));
         assert!(filtered.iter().any(|t| t.name == "tool3"));
     }
+
+    #[test]
+    fn test_filter_tools_with_exclusion() {
+        let tools = vec![
+            make_test_tool("tool1"),
+            make_test_tool("delete_everything"),
+            make_test_tool("tool3"),
+        ];
+
+        let config = CodeModeConfig::new()
+            .only_tools(vec!["tool1".to_string(), "delete_everything".to_string()])
+            .exclude_tools(vec!["delete_everything".to_string()]);
+        let filtered: Vec<Tool> = tools
+            .into_iter()
+            .filter(|t| config.includes_tool(&t.name))
+            .collect();
+
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered[0].name, "tool1");
+    }
+
+    #[test]
+    fn test_description_notes_follow_client_capabilities() {
+        let config = CodeModeConfig::default();
+        let server = ServerCapabilities::default();
+        let description = |client| execute_tool_description(&config, "tools", &server, client);
+
+        let bare = description(None);
+        assert!(!bare.contains("sample("));
+        assert!(!bare.contains("elicit("));
+        assert!(!bare.contains("roots()"));
+
+        let sampling = ClientCapabilities::builder().enable_sampling().build();
+        let with_sampling = description(Some(&sampling));
+        assert!(with_sampling.contains("sample("));
+        assert!(!with_sampling.contains("elicit("));
+
+        let all = ClientCapabilities::builder()
+            .enable_sampling()
+            .enable_elicitation()
+            .enable_roots()
+            .build();
+        let with_all = description(Some(&all));
+        assert!(with_all.contains("elicit("));
+        assert!(with_all.contains("ask("));
+        assert!(with_all.contains("roots()"));
+    }
+
+    #[test]
+    fn test_json_to_content_text() {
+        let value = serde_json::json!("hello world");
+        let content = json_to_content(&value);
+        assert_eq!(content.len(), 1);
+        assert!(content[0].as_text().is_some());
+    }
+
+    #[test]
+    fn test_json_to_content_image() {
+        let value = serde_json::json!({
+            "type": "image",
+            "data": "SGVsbG8=",
+            "mimeType": "image/png"
+        });
+        let content = json_to_content(&value);
+        assert_eq!(content.len(), 1);
+        let img = content[0].as_image().expect("should be image content");
+        assert_eq!(img.data, "SGVsbG8=");
+        assert_eq!(img.mime_type, "image/png");
+    }
+
+    #[test]
+    fn test_json_to_content_array_with_images() {
+        let value = serde_json::json!([
+            {"type": "image", "data": "abc123", "mimeType": "image/png"},
+            "some text"
+        ]);
+        let content = json_to_content(&value);
+        assert_eq!(content.len(), 2);
+        assert!(content[0].as_image().is_some());
+        assert!(content[1].as_text().is_some());
+    }
+
+    #[test]
+    fn test_json_to_content_resources_and_audio() {
+        let value = serde_json::json!([
+            {"type": "resource", "resource": {"uri": "file:///notes.md", "text": "# Notes"}},
+            {"type": "audio", "data": "UklGRg==", "mimeType": "audio/wav"},
+            {"type": "resource_link", "uri": "file:///board.kicad_pcb", "name": "board"},
+            {"type": "audio", "data": "no mimeType"}
+        ]);
+        let content = json_to_content(&value);
+        assert_eq!(content.len(), 4);
+        let resource = content[0].as_resource().expect("should be a resource");
+        assert!(matches!(
+            &resource.resource,
+            ResourceContents::TextResourceContents { text, .. } if text == "# Notes"
+        ));
+        assert!(matches!(content[1].raw, RawContent::Audio(_)));
+        let link = content[2].as_resource_link().expect("should be a link");
+        assert_eq!(link.uri, "file:///board.kicad_pcb");
+        // Objects that don't fit are plain JSON
+        assert!(content[3].as_text().unwrap().text.contains("no mimeType"));
+    }
+
+    #[test]
+    fn test_json_to_content_result_with_logs() {
+        let value = serde_json::json!({
+            "result": {"type": "image", "data": "abc123", "mimeType": "image/png"},
+            "logs": ["log1", "log2"]
+        });
+        let content = json_to_content(&value);
+        assert_eq!(content.len(), 2);
+        assert!(content[0].as_image().is_some());
+        let text = content[1].as_text().expect("should have logs text");
+        assert!(text.text.contains("log1"));
+        assert!(text.text.contains("log2"));
+    }
 }
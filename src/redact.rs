@@ -0,0 +1,173 @@
+//! Data minimization for tool arguments.
+//!
+//! Fields listed in `tools.<name>.strip_args` are removed and those in
+//! `tools.<name>.hash_args` are replaced by a SHA-256 digest as the very last
+//! step before a call is sent downstream. Policy and approval still see the
+//! arguments the script passed; only the downstream server gets the reduced
+//! ones.
+
+use crate::alias::ToolAliases;
+use crate::config::CodeModeConfig;
+use crate::runtime::{ServerRequest, ServerRequestFuture, ToolCallFuture, ToolCaller};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+#[derive(Debug, Default, Clone)]
+struct Rules {
+    strip: Vec<Vec<String>>,
+    hash: Vec<Vec<String>>,
+}
+
+/// Redaction rules, keyed by downstream tool name.
+#[derive(Debug, Default)]
+pub struct ArgRedactions {
+    rules: HashMap<String, Rules>,
+}
+
+impl ArgRedactions {
+    pub fn from_config(config: &CodeModeConfig, aliases: &ToolAliases) -> Self {
+        let split = |paths: &[String]| -> Vec<Vec<String>> {
+            paths
+                .iter()
+                .map(|path| path.split('.').map(str::to_string).collect())
+                .collect()
+        };
+        let rules = config
+            .tools
+            .iter()
+            .filter(|(_, settings)| {
+                !settings.strip_args.is_empty() || !settings.hash_args.is_empty()
+            })
+            .map(|(name, settings)| {
+                (
+                    aliases.downstream(name).to_string(),
+                    Rules {
+                        strip: split(&settings.strip_args),
+                        hash: split(&settings.hash_args),
+                    },
+                )
+            })
+            .collect();
+        Self { rules }
+    }
+
+    /// Apply the rules for downstream tool `name` to `args` in place.
+    pub fn apply(&self, name: &str, args: &mut Map<String, Value>) {
+        let Some(rules) = self.rules.get(name) else {
+            return;
+        };
+        for path in &rules.hash {
+            if let Some(value) = field_mut(args, path) {
+                *value = Value::String(digest(value));
+            }
+        }
+        for path in &rules.strip {
+            let (last, parents) = path.split_last().expect("paths are never empty");
+            let parent = if parents.is_empty() {
+                Some(&mut *args)
+            } else {
+                field_mut(args, parents).and_then(Value::as_object_mut)
+            };
+            if let Some(parent) = parent {
+                parent.remove(last);
+            }
+        }
+    }
+}
+
+fn field_mut<'a>(args: &'a mut Map<String, Value>, path: &[String]) -> Option<&'a mut Value> {
+    let (first, rest) = path.split_first()?;
+    rest.iter().try_fold(args.get_mut(first)?, |value, key| {
+        value.as_object_mut()?.get_mut(key)
+    })
+}
+
+/// `sha256:<hex>` of a string's contents, or of the JSON text of any other
+/// value.
+fn digest(value: &Value) -> String {
+    format!("sha256:{}", crate::hash::sha256(&crate::hash::text(value)))
+}
+
+/// A `ToolCaller` that redacts arguments by downstream tool name before
+/// passing calls on.
+pub struct RedactToolCaller<C: ToolCaller> {
+    inner: C,
+    redactions: Arc<ArgRedactions>,
+}
+
+impl<C: ToolCaller> RedactToolCaller<C> {
+    pub fn new(inner: C, redactions: Arc<ArgRedactions>) -> Self {
+        Self { inner, redactions }
+    }
+}
+
+impl<C: ToolCaller> ToolCaller for RedactToolCaller<C> {
+    fn call_tool<'a>(&'a self, name: &'a str, mut args: Option<Value>) -> ToolCallFuture<'a> {
+        if let Some(Value::Object(map)) = args.as_mut() {
+            self.redactions.apply(name, map);
+        }
+        self.inner.call_tool(name, args)
+    }
+
+    fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+        self.inner.server_request(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ToolSettings;
+    use rmcp::model::{CallToolResult, Content};
+    use serde_json::json;
+
+    struct ArgsEcho;
+
+    impl ToolCaller for ArgsEcho {
+        fn call_tool<'a>(&'a self, _name: &'a str, args: Option<Value>) -> ToolCallFuture<'a> {
+            let text = args.unwrap_or_default().to_string();
+            Box::pin(async move { Ok(CallToolResult::success(vec![Content::text(text)])) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_strip_and_hash_args() {
+        let mut config = CodeModeConfig::new().with_alias("analytics/track", "track");
+        config.tools.insert(
+            "track".to_string(),
+            ToolSettings {
+                strip_args: vec!["user_email".to_string(), "user.phone".to_string()],
+                hash_args: vec!["user.id".to_string()],
+                ..Default::default()
+            },
+        );
+        let aliases = ToolAliases::from_config(&config);
+        let redactions = Arc::new(ArgRedactions::from_config(&config, &aliases));
+        let caller = RedactToolCaller::new(ArgsEcho, redactions);
+
+        let args = json!({
+            "event": "signup",
+            "user_email": "a@example.com",
+            "user": {"id": "abc", "phone": "555"},
+        });
+        let result = caller
+            .call_tool("analytics/track", Some(args.clone()))
+            .await
+            .unwrap();
+        let sent: Value = serde_json::from_str(&result.content[0].as_text().unwrap().text).unwrap();
+        assert_eq!(
+            sent,
+            json!({
+                "event": "signup",
+                "user": {
+                    "id": "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+                },
+            })
+        );
+
+        let result = caller.call_tool("other", Some(args.clone())).await.unwrap();
+        let sent: Value = serde_json::from_str(&result.content[0].as_text().unwrap().text).unwrap();
+        assert_eq!(sent, args);
+    }
+}
@@ -0,0 +1,389 @@
+//! Retries of failed downstream tool calls.
+//!
+//! With `[retry]` set, a call that fails is made again, up to
+//! `max_attempts` times in all, after a backoff that doubles each time up to
+//! `max_backoff_ms`. Without `retry_on` patterns only calls that failed
+//! outright are retried, unless the server rejected the request itself;
+//! with them, any call whose error or error result matches one.
+//!
+//! A call that failed may still have taken effect downstream, so only tools
+//! that are safe to call twice are retried: read-only ones and ones annotated
+//! `idempotentHint`, unless they are destructive (see `idempotent`), and ones
+//! with `retry = true`. Tools with `retry = false` are never retried. Script
+//! calls go through `RetryToolCaller`, passthrough calls through
+//! `RetryPolicy::run`.
+
+use crate::alias::ToolAliases;
+use crate::config::CodeModeConfig;
+use crate::dedup::read_only_tools;
+use crate::idempotent::destructive_tools;
+use crate::runtime::{ServerRequest, ServerRequestFuture, ToolCallFuture, ToolCaller};
+use regex::Regex;
+use rmcp::model::{CallToolResult, Tool};
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// JSON-RPC errors that the same request will get again: method not found
+/// and invalid params.
+pub(crate) const PERMANENT_ERRORS: [&str; 2] = ["-32601:", "-32602:"];
+
+/// `[retry]` compiled, or nothing when it isn't set.
+#[derive(Debug, Default)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+    patterns: Vec<Regex>,
+}
+
+impl RetryPolicy {
+    pub fn from_config(config: &CodeModeConfig) -> Self {
+        let Some(retry) = &config.retry else {
+            return Self::default();
+        };
+        Self {
+            max_attempts: retry.max_attempts,
+            backoff: Duration::from_millis(retry.backoff_ms),
+            max_backoff: Duration::from_millis(retry.max_backoff_ms),
+            // Checked by `CodeModeConfig::validate`
+            patterns: retry
+                .retry_on
+                .iter()
+                .filter_map(|pattern| Regex::new(pattern).ok())
+                .collect(),
+        }
+    }
+
+    /// Whether failed calls are made again at all.
+    pub fn is_enabled(&self) -> bool {
+        self.max_attempts > 1
+    }
+
+    /// Whether a call that ended in `error`, or in an error result when
+    /// `is_result`, is worth making again.
+    fn retries(&self, error: &str, is_result: bool) -> bool {
+        if self.patterns.is_empty() {
+            return !is_result && !PERMANENT_ERRORS.iter().any(|code| error.contains(code));
+        }
+        self.patterns.iter().any(|pattern| pattern.is_match(error))
+    }
+
+    /// How long to wait before attempt `attempt` (2 for the first retry).
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(2)))
+            .min(self.max_backoff)
+    }
+
+    /// Make a call to downstream tool `tool` with `attempt`, again while it
+    /// fails in a way worth retrying and attempts are left, if `retryable`.
+    /// Stops waiting when `ct` is cancelled.
+    pub async fn run<E, F, Fut>(
+        &self,
+        tool: &str,
+        retryable: bool,
+        ct: &CancellationToken,
+        mut attempt: F,
+    ) -> Result<CallToolResult, E>
+    where
+        E: Display,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<CallToolResult, E>>,
+    {
+        if !retryable || !self.is_enabled() {
+            return attempt().await;
+        }
+        let mut attempts = 1;
+        loop {
+            let result = attempt().await;
+            let error = match &result {
+                Ok(result) if result.is_error == Some(true) => {
+                    let text = result_text(result);
+                    self.retries(&text, true).then_some(text)
+                }
+                Ok(_) => None,
+                Err(e) => {
+                    let text = e.to_string();
+                    self.retries(&text, false).then_some(text)
+                }
+            };
+            let Some(error) = error else {
+                return result;
+            };
+            if attempts >= self.max_attempts || ct.is_cancelled() {
+                return result;
+            }
+            attempts += 1;
+            let delay = self.backoff(attempts);
+            tracing::info!(
+                "Retrying {tool} in {}ms (attempt {attempts} of {}): {error}",
+                delay.as_millis(),
+                self.max_attempts
+            );
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = ct.cancelled() => return result,
+            }
+        }
+    }
+}
+
+/// Whether failed calls to `tool` may be made again: per its `retry`
+/// setting, or when it is read-only or idempotent and not destructive.
+pub fn is_retryable(tool: &Tool, config: &CodeModeConfig) -> bool {
+    let tools = std::slice::from_ref(tool);
+    let setting = config
+        .tools
+        .get(tool.name.as_ref())
+        .and_then(|settings| settings.retry);
+    setting.unwrap_or_else(|| {
+        let idempotent = tool
+            .annotations
+            .as_ref()
+            .and_then(|a| a.idempotent_hint)
+            .unwrap_or(false);
+        (idempotent || !read_only_tools(tools, config).is_empty())
+            && destructive_tools(tools, config).is_empty()
+    })
+}
+
+/// Downstream names of the `tools` whose failed calls may be made again.
+pub fn retryable_tools(
+    tools: &[Tool],
+    config: &CodeModeConfig,
+    aliases: &ToolAliases,
+) -> HashSet<String> {
+    tools
+        .iter()
+        .filter(|tool| is_retryable(tool, config))
+        .map(|tool| aliases.downstream(&tool.name).to_string())
+        .collect()
+}
+
+fn result_text(result: &CallToolResult) -> String {
+    result
+        .content
+        .iter()
+        .filter_map(|content| content.as_text())
+        .map(|text| text.text.as_str())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// A `ToolCaller` that retries failed calls to the `retryable` tools per
+/// the `RetryPolicy`. Takes downstream tool names.
+pub struct RetryToolCaller<C: ToolCaller> {
+    inner: C,
+    policy: Arc<RetryPolicy>,
+    retryable: HashSet<String>,
+    cancellation: CancellationToken,
+}
+
+impl<C: ToolCaller> RetryToolCaller<C> {
+    pub fn new(inner: C, policy: Arc<RetryPolicy>, retryable: HashSet<String>) -> Self {
+        Self {
+            inner,
+            policy,
+            retryable,
+            cancellation: CancellationToken::new(),
+        }
+    }
+
+    /// Stop retrying once `ct` fires.
+    pub fn with_cancellation(mut self, ct: CancellationToken) -> Self {
+        self.cancellation = ct;
+        self
+    }
+}
+
+impl<C: ToolCaller> ToolCaller for RetryToolCaller<C> {
+    fn call_tool<'a>(
+        &'a self,
+        name: &'a str,
+        args: Option<serde_json::Value>,
+    ) -> ToolCallFuture<'a> {
+        if !self.policy.is_enabled() || !self.retryable.contains(name) {
+            return self.inner.call_tool(name, args);
+        }
+        Box::pin(self.policy.run(name, true, &self.cancellation, move || {
+            self.inner.call_tool(name, args.clone())
+        }))
+    }
+
+    fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+        self.inner.server_request(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RetrySettings;
+    use rmcp::model::{Content, JsonObject, ToolAnnotations};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Fails with `error` until it has been called `failures` times.
+    struct Flaky {
+        calls: AtomicUsize,
+        failures: usize,
+        error: &'static str,
+        as_result: bool,
+    }
+
+    impl Flaky {
+        fn new(failures: usize, error: &'static str, as_result: bool) -> Self {
+            Self {
+                calls: AtomicUsize::new(0),
+                failures,
+                error,
+                as_result,
+            }
+        }
+    }
+
+    impl ToolCaller for Flaky {
+        fn call_tool<'a>(
+            &'a self,
+            _name: &'a str,
+            _args: Option<serde_json::Value>,
+        ) -> ToolCallFuture<'a> {
+            Box::pin(async move {
+                if self.calls.fetch_add(1, Ordering::SeqCst) >= self.failures {
+                    Ok(CallToolResult::success(vec![Content::text("ok")]))
+                } else if self.as_result {
+                    Ok(CallToolResult::error(vec![Content::text(self.error)]))
+                } else {
+                    Err(anyhow::anyhow!("{}", self.error))
+                }
+            })
+        }
+    }
+
+    fn tool(name: &str, annotations: Option<ToolAnnotations>) -> Tool {
+        let mut tool = Tool::new(name.to_string(), "", Arc::new(JsonObject::new()));
+        tool.annotations = annotations;
+        tool
+    }
+
+    fn config(retry_on: &[&str]) -> CodeModeConfig {
+        let mut config = CodeModeConfig::new().with_retry(RetrySettings {
+            max_attempts: 3,
+            backoff_ms: 1,
+            max_backoff_ms: 2,
+            retry_on: retry_on.iter().map(|p| p.to_string()).collect(),
+        });
+        config.tools.entry("get".to_string()).or_default().retry = Some(false);
+        config
+    }
+
+    /// Retries `search` (read-only), but not `get` (opted out) or `create`
+    /// (destructive).
+    fn retrying(flaky: Flaky, retry_on: &[&str]) -> RetryToolCaller<Flaky> {
+        let config = config(retry_on);
+        let read_only = Some(ToolAnnotations::new().read_only(true));
+        let tools = [
+            tool("search", read_only.clone()),
+            tool("get", read_only),
+            tool("create", None),
+        ];
+        let retryable = retryable_tools(&tools, &config, &ToolAliases::from_config(&config));
+        RetryToolCaller::new(
+            flaky,
+            Arc::new(RetryPolicy::from_config(&config)),
+            retryable,
+        )
+    }
+
+    async fn call(caller: &RetryToolCaller<Flaky>, name: &str) -> Result<bool, String> {
+        caller
+            .call_tool(name, None)
+            .await
+            .map(|r| r.is_error != Some(true))
+            .map_err(|e| e.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_retries_failed_calls() {
+        let caller = retrying(Flaky::new(2, "connection reset", false), &[]);
+        assert_eq!(call(&caller, "search").await, Ok(true));
+        assert_eq!(caller.inner.calls.load(Ordering::SeqCst), 3);
+
+        // Attempts run out
+        let caller = retrying(Flaky::new(5, "connection reset", false), &[]);
+        assert_eq!(
+            call(&caller, "search").await,
+            Err("connection reset".to_string())
+        );
+        assert_eq!(caller.inner.calls.load(Ordering::SeqCst), 3);
+
+        // Opted out
+        let caller = retrying(Flaky::new(1, "connection reset", false), &[]);
+        assert!(call(&caller, "get").await.is_err());
+        assert_eq!(caller.inner.calls.load(Ordering::SeqCst), 1);
+
+        // Not when the request itself is at fault
+        let caller = retrying(
+            Flaky::new(1, "Mcp error: -32602: Division by zero", false),
+            &[],
+        );
+        assert!(call(&caller, "search").await.is_err());
+
+        // Error results only with a pattern
+        let caller = retrying(Flaky::new(1, "rate limited", true), &[]);
+        assert_eq!(call(&caller, "search").await, Ok(false));
+        let caller = retrying(Flaky::new(1, "rate limited", true), &["rate"]);
+        assert_eq!(call(&caller, "search").await, Ok(true));
+        let caller = retrying(Flaky::new(1, "bad input", false), &["rate"]);
+        assert!(call(&caller, "search").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_destructive_tools_are_called_once() {
+        let caller = retrying(Flaky::new(1, "timed out", false), &[]);
+        assert!(call(&caller, "create").await.is_err());
+        assert_eq!(caller.inner.calls.load(Ordering::SeqCst), 1);
+
+        let caller = retrying(Flaky::new(1, "rate limited", true), &["rate"]);
+        assert_eq!(call(&caller, "create").await, Ok(false));
+        assert_eq!(caller.inner.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        let idempotent = ToolAnnotations::new().idempotent(true);
+        let tools = [
+            tool("search", Some(ToolAnnotations::new().read_only(true))),
+            tool("tag", Some(idempotent.clone().destructive(false))),
+            tool("replace", Some(idempotent)),
+            tool("create", None),
+            tool("delete", None),
+            tool("list", None),
+        ];
+        let config: CodeModeConfig =
+            toml::from_str("[tools.delete]\nretry = true\n[tools.list]\nread_only = true\n")
+                .unwrap();
+        let retryable: Vec<&str> = tools
+            .iter()
+            .filter(|tool| is_retryable(tool, &config))
+            .map(|tool| tool.name.as_ref())
+            .collect();
+        assert_eq!(retryable, ["search", "tag", "delete", "list"]);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(300),
+            ..Default::default()
+        };
+        assert_eq!(policy.backoff(2), Duration::from_millis(100));
+        assert_eq!(policy.backoff(3), Duration::from_millis(200));
+        assert_eq!(policy.backoff(4), Duration::from_millis(300));
+    }
+}
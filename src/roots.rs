@@ -0,0 +1,426 @@
+//! Keeps scripts' file paths inside the client's roots.
+//!
+//! When the client declares the `roots` capability, arguments of script
+//! tool calls that hold file paths must lie within one of its roots, so a
+//! script can't point a filesystem-capable server elsewhere on the machine.
+//! Fields listed in `tools.<name>.path_args` are always checked; with
+//! `check_paths`, so are fields whose names look like paths (`path`,
+//! `source_file`, `outputDir`, ...) and any `file://` URI.
+//!
+//! Paths are compared lexically, after resolving `.` and `..`: symlinks
+//! are not followed. Relative paths are allowed unless they climb out of
+//! the directory they are resolved against, and `~` paths are refused.
+
+use crate::alias::ToolAliases;
+use crate::config::CodeModeConfig;
+use crate::runtime::{ServerRequest, ServerRequestFuture, ToolCallFuture, ToolCaller};
+use anyhow::Result;
+use rmcp::service::{Peer, RoleServer};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::OnceCell;
+
+/// Which argument fields hold paths, keyed by downstream tool name.
+#[derive(Debug, Default)]
+pub struct PathArgs {
+    fields: HashMap<String, Vec<Vec<String>>>,
+    heuristic: bool,
+}
+
+impl PathArgs {
+    pub fn from_config(config: &CodeModeConfig, aliases: &ToolAliases) -> Self {
+        let fields = config
+            .tools
+            .iter()
+            .filter(|(_, settings)| !settings.path_args.is_empty())
+            .map(|(name, settings)| {
+                let paths = settings
+                    .path_args
+                    .iter()
+                    .map(|path| path.split('.').map(str::to_string).collect())
+                    .collect();
+                (aliases.downstream(name).to_string(), paths)
+            })
+            .collect();
+        Self {
+            fields,
+            heuristic: config.check_paths,
+        }
+    }
+
+    /// Whether calls to downstream tool `name` have paths to check.
+    fn applies_to(&self, name: &str) -> bool {
+        self.heuristic || self.fields.contains_key(name)
+    }
+
+    /// The `(field, path)` pairs in `args` to check for downstream tool
+    /// `name`.
+    fn paths(&self, name: &str, args: &Map<String, Value>) -> Vec<(String, String)> {
+        let mut found = Vec::new();
+        for path in self.fields.get(name).into_iter().flatten() {
+            if let Some(value) = field(args, path) {
+                strings(&path.join("."), value, &mut found);
+            }
+        }
+        if self.heuristic {
+            guess(None, &Value::Object(args.clone()), &mut found);
+        }
+        found.dedup();
+        found
+    }
+}
+
+fn field<'a>(args: &'a Map<String, Value>, path: &[String]) -> Option<&'a Value> {
+    let (first, rest) = path.split_first()?;
+    rest.iter()
+        .try_fold(args.get(first)?, |value, key| value.as_object()?.get(key))
+}
+
+/// The strings in `value`, itself or as an array, labelled `field`.
+fn strings(field: &str, value: &Value, found: &mut Vec<(String, String)>) {
+    match value {
+        Value::String(path) => found.push((field.to_string(), path.clone())),
+        Value::Array(items) => items
+            .iter()
+            .filter_map(Value::as_str)
+            .for_each(|path| found.push((field.to_string(), path.to_string()))),
+        _ => {}
+    }
+}
+
+/// Whether an argument named `key` probably holds a path: its last word,
+/// in snake, kebab or camel case, is `path`, `file`, `dir` and the like.
+fn looks_like_path_field(key: &str) -> bool {
+    let start = key
+        .char_indices()
+        .rfind(|&(_, c)| c == '_' || c == '-' || c.is_uppercase())
+        .map_or(0, |(i, c)| if c.is_uppercase() { i } else { i + 1 });
+    let word = key[start..].to_lowercase();
+    let word = match word.as_str() {
+        "directories" => "directory",
+        word => word.strip_suffix('s').unwrap_or(word),
+    };
+    [
+        "path",
+        "file",
+        "filename",
+        "filepath",
+        "dir",
+        "directory",
+        "dirname",
+        "folder",
+        "cwd",
+    ]
+    .contains(&word)
+}
+
+/// Path-looking fields anywhere in `value`, and `file://` URIs anywhere.
+fn guess(key: Option<&str>, value: &Value, found: &mut Vec<(String, String)>) {
+    match value {
+        Value::Object(map) => map
+            .iter()
+            .for_each(|(key, value)| guess(Some(key), value, found)),
+        Value::Array(items) => items.iter().for_each(|item| guess(key, item, found)),
+        Value::String(text) => {
+            let key = key.unwrap_or_default();
+            if looks_like_path_field(key) || text.starts_with("file://") {
+                found.push((key.to_string(), text.clone()));
+            }
+        }
+        _ => {}
+    }
+}
+
+/// `path` with `.` and `..` resolved, or `None` if a `..` climbs above its
+/// start.
+fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return None;
+                }
+            }
+            component => normalized.push(component),
+        }
+    }
+    Some(normalized)
+}
+
+/// The local path of a `file://` URI, or `text` itself.
+fn local_path(text: &str) -> String {
+    match text.strip_prefix("file://") {
+        Some(rest) => percent_decode(rest.strip_prefix("localhost").unwrap_or(rest)),
+        None => text.to_string(),
+    }
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = (bytes[i] == b'%')
+            .then(|| text.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match hex {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Whether `text` is a path within one of `roots`.
+fn within(text: &str, roots: &[PathBuf]) -> bool {
+    let path = local_path(text);
+    if path.starts_with('~') {
+        return false;
+    }
+    let path = Path::new(&path);
+    match normalize(path) {
+        Some(path) if path.is_absolute() => roots.iter().any(|root| path.starts_with(root)),
+        // Resolved against some directory it doesn't climb out of
+        Some(_) => true,
+        None => false,
+    }
+}
+
+/// The client's roots as local directories, from the `roots()` result.
+fn root_dirs(roots: &Value) -> Vec<PathBuf> {
+    roots
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|root| root["uri"].as_str())
+        .filter(|uri| uri.starts_with("file://"))
+        .filter_map(|uri| normalize(Path::new(&local_path(uri))))
+        .collect()
+}
+
+/// Whether the client behind `peer` declared the `roots` capability.
+pub fn client_has_roots(peer: &Peer<RoleServer>) -> bool {
+    peer.peer_info()
+        .is_some_and(|info| info.capabilities.roots.is_some())
+}
+
+/// A `ToolCaller` that refuses calls with paths outside the client's roots.
+/// The roots are listed once, on the first call with paths to check.
+pub struct RootsToolCaller<C: ToolCaller> {
+    inner: C,
+    path_args: Arc<PathArgs>,
+    /// Whether the client declared the `roots` capability.
+    enabled: bool,
+    roots: OnceCell<Vec<PathBuf>>,
+}
+
+impl<C: ToolCaller> RootsToolCaller<C> {
+    pub fn new(inner: C, path_args: Arc<PathArgs>, enabled: bool) -> Self {
+        Self {
+            inner,
+            path_args,
+            enabled,
+            roots: OnceCell::new(),
+        }
+    }
+
+    async fn check(&self, name: &str, args: &Map<String, Value>) -> Result<()> {
+        let paths = self.path_args.paths(name, args);
+        if paths.is_empty() {
+            return Ok(());
+        }
+        let roots = self
+            .roots
+            .get_or_try_init(|| async {
+                let roots = self
+                    .inner
+                    .server_request(ServerRequest::ListRoots)
+                    .await
+                    .map_err(|e| anyhow::anyhow!("Checking paths against roots failed: {e}"))?;
+                anyhow::Ok(root_dirs(&roots))
+            })
+            .await?;
+        if roots.is_empty() {
+            return Ok(());
+        }
+        for (field, path) in paths {
+            if !within(&path, roots) {
+                anyhow::bail!(
+                    "{name}: `{field}` path {path} is outside the directories the client shared"
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<C: ToolCaller> ToolCaller for RootsToolCaller<C> {
+    fn call_tool<'a>(&'a self, name: &'a str, args: Option<Value>) -> ToolCallFuture<'a> {
+        if !self.enabled || !self.path_args.applies_to(name) {
+            return self.inner.call_tool(name, args);
+        }
+        Box::pin(async move {
+            if let Some(Value::Object(map)) = &args {
+                self.check(name, map).await?;
+            }
+            self.inner.call_tool(name, args).await
+        })
+    }
+
+    fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+        self.inner.server_request(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ToolSettings;
+    use rmcp::model::{CallToolResult, Content};
+    use serde_json::json;
+
+    struct Workspace;
+
+    impl ToolCaller for Workspace {
+        fn call_tool<'a>(&'a self, _name: &'a str, _args: Option<Value>) -> ToolCallFuture<'a> {
+            Box::pin(async { Ok(CallToolResult::success(vec![Content::text("ok")])) })
+        }
+
+        fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+            Box::pin(async move {
+                match request {
+                    ServerRequest::ListRoots => Ok(json!([
+                        {"uri": "file:///home/me/project", "name": "project"},
+                        {"uri": "file:///tmp/my%20notes"},
+                    ])),
+                    other => anyhow::bail!("{} is not available", other.function()),
+                }
+            })
+        }
+    }
+
+    fn caller(check_paths: bool) -> RootsToolCaller<Workspace> {
+        let mut config = CodeModeConfig::new();
+        config.check_paths = check_paths;
+        config.tools.insert(
+            "copy".to_string(),
+            ToolSettings {
+                path_args: vec!["from".to_string(), "options.to".to_string()],
+                ..Default::default()
+            },
+        );
+        let aliases = ToolAliases::from_config(&config);
+        RootsToolCaller::new(
+            Workspace,
+            Arc::new(PathArgs::from_config(&config, &aliases)),
+            true,
+        )
+    }
+
+    #[test]
+    fn test_within() {
+        let roots = vec![PathBuf::from("/home/me/project")];
+        assert!(within("/home/me/project/src/main.rs", &roots));
+        assert!(within("/home/me/project", &roots));
+        assert!(within("file:///home/me/project/a%20b.txt", &roots));
+        assert!(within("src/main.rs", &roots));
+        assert!(!within("/home/me/project/../.ssh/id_rsa", &roots));
+        assert!(!within("/home/me/project-old/x", &roots));
+        assert!(!within("../outside", &roots));
+        assert!(!within("~/.ssh/id_rsa", &roots));
+        assert!(!within("/etc/passwd", &roots));
+    }
+
+    #[test]
+    fn test_looks_like_path_field() {
+        for key in [
+            "path",
+            "paths",
+            "file_path",
+            "sourceFile",
+            "outputDir",
+            "cwd",
+        ] {
+            assert!(looks_like_path_field(key), "{key}");
+        }
+        for key in ["query", "name", "profile", "content"] {
+            assert!(!looks_like_path_field(key), "{key}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_paths_outside_roots_are_refused() {
+        let annotated = caller(false);
+        annotated
+            .call_tool(
+                "copy",
+                Some(json!({"from": "/tmp/my notes/a.md", "options": {"to": "out/a.md"}})),
+            )
+            .await
+            .unwrap();
+        let err = annotated
+            .call_tool(
+                "copy",
+                Some(json!({"from": "a.md", "options": {"to": "/etc/cron.d/x"}})),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "copy: `options.to` path /etc/cron.d/x is outside the directories the client shared"
+        );
+        // Without `check_paths`, only annotated fields are checked
+        annotated
+            .call_tool("read_file", Some(json!({"path": "/etc/passwd"})))
+            .await
+            .unwrap();
+
+        let heuristic = caller(true);
+        let err = heuristic
+            .call_tool("read_file", Some(json!({"path": "/etc/passwd"})))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("`path` path /etc/passwd"));
+        heuristic
+            .call_tool(
+                "search",
+                Some(json!({"query": "/etc/passwd", "files": ["/home/me/project/a"]})),
+            )
+            .await
+            .unwrap();
+        let err = heuristic
+            .call_tool(
+                "open",
+                Some(json!({"target": {"uri": "file:///etc/hosts"}})),
+            )
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("file:///etc/hosts"));
+    }
+
+    #[tokio::test]
+    async fn test_nothing_is_checked_without_roots_capability() {
+        let config = CodeModeConfig::new().with_check_paths(true);
+        let aliases = ToolAliases::from_config(&config);
+        let caller = RootsToolCaller::new(
+            Workspace,
+            Arc::new(PathArgs::from_config(&config, &aliases)),
+            false,
+        );
+        caller
+            .call_tool("read_file", Some(json!({"path": "/etc/passwd"})))
+            .await
+            .unwrap();
+    }
+}
@@ -1,57 +1,323 @@
+use crate::alias::script_aliases;
+use crate::cache::{CallOptions, uncached};
+use crate::drain::DrainGuard;
+use crate::hash::{HASH_PRELUDE, call_hash};
+use crate::safe_regex::{SAFE_REGEX_PRELUDE, call_regex};
+use crate::stats::{STATS_PRELUDE, call_stats};
+use crate::telemetry::traced_call;
+use crate::time::{TIME_PRELUDE, call_time};
+use crate::upstream::SampleOptions;
+use crate::workflow::{WORKFLOW_PRELUDE, call_batch};
 use anyhow::{Context, Result};
+use futures::StreamExt;
 use rmcp::ServerHandler;
-use rmcp::model::{CallToolRequestParam, CallToolResult};
+use rmcp::model::{
+    CallToolRequest, CallToolRequestParam, CallToolResult, ClientRequest, GetPromptRequestParam,
+    Meta, PaginatedRequestParam, ReadResourceRequestParam, ReadResourceResult, ResourceContents,
+    ServerResult,
+};
+use rmcp::service::PeerRequestOptions;
 use rmcp::service::{RequestContext, RoleServer};
-use rquickjs::{AsyncContext, AsyncRuntime, Function, Object, Type, Value};
+use rquickjs::prelude::Opt;
+use rquickjs::{AsyncContext, AsyncRuntime, Ctx, Exception, Function, Object, Type, Value};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, mpsc, oneshot};
+use tokio_util::sync::CancellationToken;
 
-pub type DownstreamClient = rmcp::service::RunningService<rmcp::service::RoleClient, ()>;
+pub type DownstreamClient =
+    rmcp::service::RunningService<rmcp::service::RoleClient, crate::downstream::DownstreamHandler>;
+
+/// The peer of the downstream server in use, counting a request to it as in
+/// flight until dropped.
+pub struct DownstreamPeer {
+    peer: rmcp::service::Peer<rmcp::service::RoleClient>,
+    _in_flight: Option<DrainGuard>,
+}
+
+impl std::ops::Deref for DownstreamPeer {
+    type Target = rmcp::service::Peer<rmcp::service::RoleClient>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.peer
+    }
+}
+
+/// The peer to send a request to `downstream` through. The lock is only
+/// held to look it up, so requests run concurrently, and switching to a
+/// standby waits for the ones in flight before shutting the old server down.
+pub async fn downstream_peer(downstream: &Mutex<DownstreamClient>) -> DownstreamPeer {
+    let client = downstream.lock().await;
+    DownstreamPeer {
+        peer: client.peer().clone(),
+        _in_flight: client.service().requests().enter(),
+    }
+}
+
+/// Tells the downstream server to stop a request if dropped before its
+/// response arrives, as when a call timeout drops the call.
+struct AbandonedRequest {
+    peer: rmcp::service::Peer<rmcp::service::RoleClient>,
+    id: Option<rmcp::model::RequestId>,
+}
+
+impl AbandonedRequest {
+    fn new(
+        peer: &rmcp::service::Peer<rmcp::service::RoleClient>,
+        id: &rmcp::model::RequestId,
+    ) -> Self {
+        Self {
+            peer: peer.clone(),
+            id: Some(id.clone()),
+        }
+    }
+
+    fn disarm(&mut self) {
+        self.id = None;
+    }
+}
+
+impl Drop for AbandonedRequest {
+    fn drop(&mut self) {
+        let Some(request_id) = self.id.take() else {
+            return;
+        };
+        let peer = self.peer.clone();
+        tokio::spawn(async move {
+            let _ = peer
+                .notify_cancelled(rmcp::model::CancelledNotificationParam {
+                    request_id,
+                    reason: Some("call abandoned".to_string()),
+                })
+                .await;
+        });
+    }
+}
+
+pub type ToolCallFuture<'a> = Pin<Box<dyn Future<Output = Result<CallToolResult>> + Send + 'a>>;
+
+pub type ServerRequestFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<serde_json::Value>> + Send + 'a>>;
+
+/// What a script asks of the server behind its tools besides calling them,
+/// as sent by the script prelude (`{"method": ..., "params": ...}`).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "method", content = "params")]
+pub enum ServerRequest {
+    /// `resources.read(uri)`
+    #[serde(rename = "resources/read")]
+    ReadResource { uri: String },
+    /// `prompts.list()`
+    #[serde(rename = "prompts/list")]
+    ListPrompts,
+    /// `prompts.get(name, arguments)`
+    #[serde(rename = "prompts/get")]
+    GetPrompt {
+        name: String,
+        #[serde(default)]
+        arguments: Option<serde_json::Map<String, serde_json::Value>>,
+    },
+    /// `sample(prompt, opts)`, served by the upstream client
+    #[serde(rename = "sampling/createMessage")]
+    CreateMessage {
+        prompt: String,
+        #[serde(default)]
+        options: SampleOptions,
+    },
+    /// `llm.complete(prompt, options)`, served by the upstream client like
+    /// `sample`; with `json`, the completion is parsed as JSON
+    #[serde(rename = "sampling/complete")]
+    Complete {
+        prompt: String,
+        #[serde(default)]
+        options: SampleOptions,
+        #[serde(default)]
+        json: bool,
+    },
+    /// `elicit(schema, message)`, served by the upstream client
+    #[serde(rename = "elicitation/create")]
+    Elicit {
+        schema: serde_json::Value,
+        message: String,
+    },
+    /// `ask(prompt, schema)`: `elicit` taking a single question's schema,
+    /// served by the upstream client
+    #[serde(rename = "elicitation/ask")]
+    Ask {
+        prompt: String,
+        #[serde(default)]
+        schema: Option<serde_json::Value>,
+    },
+    /// `roots()`, served by the upstream client
+    #[serde(rename = "roots/list")]
+    ListRoots,
+    /// `progress(current, total, message)`, sent to the upstream client as
+    /// a progress notification on the execute request
+    #[serde(rename = "notifications/progress")]
+    Progress {
+        progress: f64,
+        #[serde(default)]
+        total: Option<f64>,
+        #[serde(default)]
+        message: Option<String>,
+    },
+    /// A `console.log` line, sent to the upstream client as a logging
+    /// notification when logs are streamed
+    #[serde(rename = "notifications/message")]
+    Log { line: String },
+}
+
+impl ServerRequest {
+    /// The script function making this request.
+    pub fn function(&self) -> &'static str {
+        match self {
+            ServerRequest::ReadResource { .. } => "resources.read",
+            ServerRequest::ListPrompts => "prompts.list",
+            ServerRequest::GetPrompt { .. } => "prompts.get",
+            ServerRequest::CreateMessage { .. } => "sample",
+            ServerRequest::Complete { .. } => "llm.complete",
+            ServerRequest::Elicit { .. } => "elicit",
+            ServerRequest::Ask { .. } => "ask",
+            ServerRequest::ListRoots => "roots",
+            ServerRequest::Progress { .. } => "progress",
+            ServerRequest::Log { .. } => "console.log",
+        }
+    }
+}
 
 pub trait ToolCaller: Send + Sync + 'static {
-    fn call_tool_blocking(
-        &self,
-        name: &str,
+    fn call_tool<'a>(
+        &'a self,
+        name: &'a str,
         args: Option<serde_json::Value>,
-    ) -> Result<CallToolResult>;
+    ) -> ToolCallFuture<'a>;
+
+    /// Serve a `ServerRequest`, returning the value the script sees. Callers
+    /// that wrap another pass this through; the default refuses.
+    fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+        Box::pin(async move { anyhow::bail!("{} is not available", request.function()) })
+    }
+}
+
+/// How `resources.read` hands a resource to a script: the text, or
+/// `{blob, mimeType}` for binary contents, and an array of those when the
+/// resource has several parts.
+pub fn resource_value(result: ReadResourceResult) -> serde_json::Value {
+    let mut values: Vec<serde_json::Value> = result
+        .contents
+        .into_iter()
+        .map(|contents| match contents {
+            ResourceContents::TextResourceContents { text, .. } => serde_json::Value::String(text),
+            ResourceContents::BlobResourceContents {
+                blob, mime_type, ..
+            } => serde_json::json!({"blob": blob, "mimeType": mime_type}),
+        })
+        .collect();
+    match values.len() {
+        1 => values.remove(0),
+        _ => serde_json::Value::Array(values),
+    }
 }
 
 pub struct DownstreamToolCaller {
     client: Arc<Mutex<DownstreamClient>>,
+    cancellation: Option<CancellationToken>,
 }
 
 impl DownstreamToolCaller {
     pub fn new(client: Arc<Mutex<DownstreamClient>>) -> Self {
-        Self { client }
+        Self {
+            client,
+            cancellation: None,
+        }
+    }
+
+    /// Abort in-flight downstream calls (and tell downstream) once `ct` fires.
+    pub fn with_cancellation(mut self, ct: CancellationToken) -> Self {
+        self.cancellation = Some(ct);
+        self
     }
 }
 
 impl ToolCaller for DownstreamToolCaller {
-    fn call_tool_blocking(
-        &self,
-        tool_name: &str,
+    fn call_tool<'a>(
+        &'a self,
+        tool_name: &'a str,
         args: Option<serde_json::Value>,
-    ) -> Result<CallToolResult> {
-        use tokio::runtime::Handle;
-
-        let tool_name = tool_name.to_string();
-        let arguments = args.and_then(|v| v.as_object().cloned());
-        let client = self.client.clone();
+    ) -> ToolCallFuture<'a> {
+        Box::pin(traced_call(tool_name, "script", async move {
+            let arguments = args.and_then(|v| v.as_object().cloned());
+            let ct = self.cancellation.clone().unwrap_or_default();
 
-        tokio::task::block_in_place(|| {
-            Handle::current().block_on(async {
-                let client = client.lock().await;
-                let peer = client.peer();
+            let peer = downstream_peer(&self.client).await;
 
-                let request = CallToolRequestParam {
-                    name: tool_name.into(),
+            let request = ClientRequest::CallToolRequest(CallToolRequest {
+                method: Default::default(),
+                params: CallToolRequestParam {
+                    name: tool_name.to_string().into(),
                     arguments,
-                };
+                },
+                extensions: Default::default(),
+            });
 
-                peer.call_tool(request)
-                    .await
-                    .map_err(|e| anyhow::anyhow!("Tool call failed: {e}"))
-            })
+            let mut handle = peer
+                .send_cancellable_request(request, PeerRequestOptions::no_options())
+                .await
+                .map_err(|e| anyhow::anyhow!("Tool call failed: {e}"))?;
+
+            let mut abandoned = AbandonedRequest::new(&peer, &handle.id);
+            let response = tokio::select! {
+                response = &mut handle.rx => response,
+                _ = ct.cancelled() => {
+                    abandoned.disarm();
+                    let _ = handle.cancel(Some("execution cancelled".to_string())).await;
+                    anyhow::bail!("Tool call cancelled");
+                }
+            };
+            abandoned.disarm();
+
+            match response.map_err(|_| anyhow::anyhow!("Tool call failed: transport closed"))? {
+                Ok(ServerResult::CallToolResult(result)) => Ok(result),
+                Ok(_) => Err(anyhow::anyhow!("Tool call failed: unexpected response")),
+                Err(e) => Err(anyhow::anyhow!("Tool call failed: {e}")),
+            }
+        }))
+    }
+
+    fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+        Box::pin(async move {
+            match request {
+                ServerRequest::ReadResource { uri } => {
+                    let result = downstream_peer(&self.client)
+                        .await
+                        .read_resource(ReadResourceRequestParam { uri })
+                        .await
+                        .map_err(|e| anyhow::anyhow!("Reading resource failed: {e}"))?;
+                    Ok(resource_value(result))
+                }
+                ServerRequest::ListPrompts => {
+                    let prompts = downstream_peer(&self.client)
+                        .await
+                        .list_all_prompts()
+                        .await
+                        .map_err(|e| anyhow::anyhow!("Listing prompts failed: {e}"))?;
+                    Ok(serde_json::to_value(prompts)?)
+                }
+                ServerRequest::GetPrompt { name, arguments } => {
+                    let result = downstream_peer(&self.client)
+                        .await
+                        .get_prompt(GetPromptRequestParam { name, arguments })
+                        .await
+                        .map_err(|e| anyhow::anyhow!("Getting prompt failed: {e}"))?;
+                    Ok(serde_json::to_value(result.messages)?)
+                }
+                other => anyhow::bail!("{} is not available", other.function()),
+            }
         })
     }
 }
@@ -68,50 +334,574 @@ impl<H: ServerHandler + Send + Sync + 'static> HandlerToolCaller<H> {
 }
 
 impl<H: ServerHandler + Send + Sync + 'static> ToolCaller for HandlerToolCaller<H> {
-    fn call_tool_blocking(
+    fn call_tool<'a>(
+        &'a self,
+        tool_name: &'a str,
+        args: Option<serde_json::Value>,
+    ) -> ToolCallFuture<'a> {
+        Box::pin(traced_call(tool_name, "script", async move {
+            let request = CallToolRequestParam {
+                name: tool_name.to_string().into(),
+                arguments: args.and_then(|v| v.as_object().cloned()),
+            };
+
+            let ct = self.context.ct.clone();
+            tokio::select! {
+                result = self.handler.call_tool(request, self.context.clone()) => {
+                    result.map_err(|e| anyhow::anyhow!("Tool call failed: {e:?}"))
+                }
+                _ = ct.cancelled() => Err(anyhow::anyhow!("Tool call cancelled")),
+            }
+        }))
+    }
+
+    fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+        Box::pin(async move {
+            match request {
+                ServerRequest::ReadResource { uri } => {
+                    let result = self
+                        .handler
+                        .read_resource(ReadResourceRequestParam { uri }, self.context.clone())
+                        .await
+                        .map_err(|e| anyhow::anyhow!("Reading resource failed: {}", e.message))?;
+                    Ok(resource_value(result))
+                }
+                ServerRequest::ListPrompts => {
+                    let mut prompts = Vec::new();
+                    let mut cursor = None;
+                    loop {
+                        let page = self
+                            .handler
+                            .list_prompts(
+                                Some(PaginatedRequestParam { cursor }),
+                                self.context.clone(),
+                            )
+                            .await
+                            .map_err(|e| {
+                                anyhow::anyhow!("Listing prompts failed: {}", e.message)
+                            })?;
+                        prompts.extend(page.prompts);
+                        cursor = page.next_cursor;
+                        if cursor.is_none() {
+                            break;
+                        }
+                    }
+                    Ok(serde_json::to_value(prompts)?)
+                }
+                ServerRequest::GetPrompt { name, arguments } => {
+                    let result = self
+                        .handler
+                        .get_prompt(
+                            GetPromptRequestParam { name, arguments },
+                            self.context.clone(),
+                        )
+                        .await
+                        .map_err(|e| anyhow::anyhow!("Getting prompt failed: {}", e.message))?;
+                    Ok(serde_json::to_value(result.messages)?)
+                }
+                other => anyhow::bail!("{} is not available", other.function()),
+            }
+        })
+    }
+}
+
+enum BridgeRequest {
+    Tool {
+        name: String,
+        args: Option<serde_json::Value>,
+        options: CallOptions,
+        reply: oneshot::Sender<Result<CallToolResult>>,
+    },
+    Server {
+        request: ServerRequest,
+        reply: oneshot::Sender<Result<serde_json::Value>>,
+    },
+    Tools {
+        calls: Vec<BatchedCall>,
+        concurrency: usize,
+        reply: oneshot::Sender<Vec<Result<CallToolResult>>>,
+    },
+}
+
+/// One of the calls `ToolBridge::call_tools` makes: after `delay`, and
+/// failing if it takes longer than `timeout`.
+pub(crate) struct BatchedCall {
+    pub name: String,
+    pub args: Option<serde_json::Value>,
+    pub delay: Duration,
+    pub timeout: Option<Duration>,
+}
+
+/// How a script running on a blocking thread calls tools: each call is sent
+/// to the task in `run_blocking_script`, which awaits the real `ToolCaller`.
+#[derive(Clone)]
+pub(crate) struct ToolBridge {
+    requests: mpsc::UnboundedSender<BridgeRequest>,
+}
+
+impl ToolBridge {
+    pub(crate) fn call_tool(
         &self,
-        tool_name: &str,
+        name: &str,
         args: Option<serde_json::Value>,
+        options: CallOptions,
     ) -> Result<CallToolResult> {
-        use tokio::runtime::Handle;
+        let (reply, response) = oneshot::channel();
+        let request = BridgeRequest::Tool {
+            name: name.to_string(),
+            args,
+            options,
+            reply,
+        };
+        if self.requests.send(request).is_err() {
+            anyhow::bail!("Tool call failed: execution was abandoned");
+        }
+        response
+            .blocking_recv()
+            .map_err(|_| anyhow::anyhow!("Tool call failed: execution was abandoned"))?
+    }
 
-        let tool_name = tool_name.to_string();
-        let arguments = args.and_then(|v| v.as_object().cloned());
-        let handler = self.handler.clone();
-        let context = self.context.clone();
+    /// Make `calls` with up to `concurrency` in flight at once, returning
+    /// their results in the order of `calls`.
+    pub(crate) fn call_tools(
+        &self,
+        calls: Vec<BatchedCall>,
+        concurrency: usize,
+    ) -> Result<Vec<Result<CallToolResult>>> {
+        let (reply, response) = oneshot::channel();
+        let request = BridgeRequest::Tools {
+            calls,
+            concurrency,
+            reply,
+        };
+        if self.requests.send(request).is_err() {
+            anyhow::bail!("Tool calls failed: execution was abandoned");
+        }
+        response
+            .blocking_recv()
+            .map_err(|_| anyhow::anyhow!("Tool calls failed: execution was abandoned"))
+    }
 
-        tokio::task::block_in_place(|| {
-            Handle::current().block_on(async {
-                let request = CallToolRequestParam {
-                    name: tool_name.into(),
-                    arguments,
-                };
+    /// Send `request` without waiting for it to be served, for
+    /// notifications the script doesn't need an answer to.
+    pub(crate) fn notify(&self, request: ServerRequest) {
+        let (reply, _) = oneshot::channel();
+        let _ = self.requests.send(BridgeRequest::Server { request, reply });
+    }
 
-                handler
-                    .call_tool(request, context)
-                    .await
-                    .map_err(|e| anyhow::anyhow!("Tool call failed: {e:?}"))
-            })
-        })
+    pub(crate) fn server_request(&self, request: ServerRequest) -> Result<serde_json::Value> {
+        let (reply, response) = oneshot::channel();
+        let function = request.function();
+        // Progress is best effort: scripts don't fail for want of a client
+        // to report to
+        let best_effort = matches!(request, ServerRequest::Progress { .. });
+        if self
+            .requests
+            .send(BridgeRequest::Server { request, reply })
+            .is_err()
+        {
+            anyhow::bail!("{function} failed: execution was abandoned");
+        }
+        let result = response
+            .blocking_recv()
+            .map_err(|_| anyhow::anyhow!("{function} failed: execution was abandoned"))?;
+        match result {
+            Err(_) if best_effort => Ok(serde_json::Value::Null),
+            result => result,
+        }
+    }
+}
+
+/// Run `script` on the blocking pool and serve its tool calls from the current
+/// task. Script engines are synchronous, so this keeps them off runtime worker
+/// threads and works on both the multi-thread and current-thread runtimes.
+pub(crate) async fn run_blocking_script<C, T, F>(caller: Arc<C>, script: F) -> Result<T>
+where
+    C: ToolCaller + ?Sized,
+    T: Send + 'static,
+    F: FnOnce(ToolBridge) -> T + Send + 'static,
+{
+    let (requests, mut pending) = mpsc::unbounded_channel();
+    let mut script = tokio::task::spawn_blocking(move || script(ToolBridge { requests }));
+    loop {
+        tokio::select! {
+            // Serve what the script sent before it finished, such as
+            // streamed log lines, before returning
+            biased;
+            Some(request) = pending.recv() => match request {
+                BridgeRequest::Tool { name, args, options, reply } => {
+                    let call = caller.call_tool(&name, args);
+                    let result = if options.cache {
+                        call.await
+                    } else {
+                        uncached(call).await
+                    };
+                    let _ = reply.send(result);
+                }
+                BridgeRequest::Server { request, reply } => {
+                    let _ = reply.send(caller.server_request(request).await);
+                }
+                BridgeRequest::Tools { calls, concurrency, reply } => {
+                    let caller = &caller;
+                    let results = futures::stream::iter(calls)
+                        .map(|call| async move {
+                            tokio::time::sleep(call.delay).await;
+                            let result = caller.call_tool(&call.name, call.args);
+                            match call.timeout {
+                                Some(limit) => tokio::time::timeout(limit, result)
+                                    .await
+                                    .unwrap_or_else(|_| {
+                                        Err(anyhow::anyhow!(
+                                            "timed out after {}ms",
+                                            limit.as_millis()
+                                        ))
+                                    }),
+                                None => result.await,
+                            }
+                        })
+                        .buffered(concurrency.max(1))
+                        .collect()
+                        .await;
+                    let _ = reply.send(results);
+                }
+            },
+            result = &mut script => return result.context("Script execution panicked"),
+        }
     }
 }
 
+/// Counters collected while running a single script.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExecutionMetrics {
+    /// Tool calls made by the script, including deduplicated ones.
+    pub tool_calls: usize,
+    /// Calls answered from an earlier identical read-only call.
+    pub dedup_hits: usize,
+    /// Calls answered from results kept by an earlier execution.
+    pub cache_hits: usize,
+    /// Tools whose results didn't match their output schema, with
+    /// `schema_drift = "quarantine"`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub schema_drift: Vec<String>,
+    /// Cost of the script's tool calls, when tool costs are configured.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>,
+    /// Cost of all executions in the script's session so far, including
+    /// this one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_cost: Option<f64>,
+    /// Bytes of tool results the script consumed, which would otherwise
+    /// have passed through the agent's context.
+    pub consumed_bytes: usize,
+    /// Bytes of the value the script returned.
+    pub returned_bytes: usize,
+    /// `consumed_bytes` minus `returned_bytes`, in estimated tokens.
+    pub estimated_tokens_saved: usize,
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct ExecutionResult {
     pub value: serde_json::Value,
     pub logs: Vec<String>,
     pub is_error: bool,
     pub error_message: Option<String>,
+    pub metrics: ExecutionMetrics,
+    /// Set when the script called `yieldToAgent`; `value` is then the
+    /// summary it passed.
+    pub continuation: Option<String>,
+    /// Names this execution in summaries and recorded bundles.
+    pub execution_id: Option<String>,
+    /// Set when the script ended with a failed `assert` or `expectEqual`.
+    pub assertion: Option<AssertionFailure>,
+    /// Whether the script declared itself safe to retry.
+    pub idempotent: bool,
+    /// Destructive tools the script called.
+    pub destructive_calls: Vec<String>,
+    /// Every downstream call the script made, when asked for with `verbose`.
+    pub trace: Option<Vec<crate::trace::TraceEntry>>,
+    /// The downstream calls `value` came from, when `provenance` is set.
+    pub provenance: Option<Vec<crate::provenance::ProvenanceEntry>>,
+}
+
+/// A failed `assert(cond, msg)` or `expectEqual(actual, expected, msg)`,
+/// reported apart from other script errors so failed self-checks can be
+/// told from crashes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct AssertionFailure {
+    pub message: String,
+    /// For `expectEqual`, the values compared.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub expected: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub actual: Option<serde_json::Value>,
+}
+
+impl AssertionFailure {
+    /// Parse the `assertion` property the assert prelude sets on the errors
+    /// it throws.
+    pub(crate) fn from_json(json: &str) -> Option<Self> {
+        serde_json::from_str(json).ok()
+    }
+}
+
+impl ExecutionResult {
+    /// Whether running the script again can't repeat side effects it
+    /// shouldn't; see `crate::idempotent`.
+    pub fn retry_safe(&self) -> bool {
+        self.idempotent || self.destructive_calls.is_empty()
+    }
+
+    /// `_meta` attached to the `execute_tools` result.
+    pub fn meta(&self) -> Meta {
+        let mut meta = Meta::new();
+        if let Some(id) = &self.execution_id {
+            meta.insert("executionId".to_string(), id.clone().into());
+        }
+        if self.idempotent {
+            meta.insert("idempotent".to_string(), true.into());
+        }
+        meta.insert("retrySafe".to_string(), self.retry_safe().into());
+        meta.insert(
+            "metrics".to_string(),
+            serde_json::to_value(&self.metrics).unwrap_or_default(),
+        );
+        if let Some(provenance) = &self.provenance {
+            meta.insert(
+                "provenance".to_string(),
+                serde_json::to_value(provenance).unwrap_or_default(),
+            );
+        }
+        meta
+    }
+}
+
+/// Per-execution limits passed to `JsRuntime::execute_with_options`.
+#[derive(Debug, Clone, Default)]
+pub struct ExecutionOptions {
+    /// Wall-clock limit after which the script is interrupted.
+    pub timeout: Option<Duration>,
+    /// Interrupts the script when cancelled, e.g. by the client cancelling the
+    /// `execute_tools` request.
+    pub cancellation: Option<CancellationToken>,
+    /// How long a cancelled script may keep running, with `cancelled()`
+    /// returning true, to return what it has before it is interrupted.
+    pub cancel_grace: Duration,
+    /// Run in this session's persistent context instead of a fresh one.
+    pub session: Option<String>,
+    /// Discard the session's existing context before running.
+    pub reset_session: bool,
+    /// Accept TypeScript by stripping type syntax before evaluation.
+    pub typescript: bool,
+    /// Define the `stats` global; see `crate::stats`.
+    pub stats: bool,
+    /// Define `uuid`, `sha256` and `hmac`; see `crate::hash`.
+    pub hashes: bool,
+    /// Define `llm.complete`; see `crate::upstream`.
+    pub llm: bool,
+    /// Match regular expressions in linear time; see `crate::safe_regex`.
+    pub safe_regex: bool,
+    /// Send each `console.log` line to the caller as a `ServerRequest::Log`
+    /// as it is written, as well as returning it in the result.
+    pub stream_logs: bool,
+    /// Tools whose results are kept on the Rust side and handed to the script
+    /// as a reader (`result.length`, `result.read(offset, len)`, `result.text()`).
+    pub lazy_tools: HashSet<String>,
+    /// Nest the tools under `tools.<namespace>` instead of `tools`.
+    pub namespace: Option<String>,
+    /// Also define snake_case tools under their camelCase names; see
+    /// `crate::alias::camel_case_aliases`. Tools whose names aren't
+    /// identifiers are always defined under one too; see
+    /// `crate::alias::identifier_aliases`.
+    pub camel_case: bool,
+    /// Continue from the execution that yielded this token, with its globals.
+    /// Takes the place of `session`.
+    pub continuation: Option<String>,
+}
+
+/// When a script is told to wind down and when it is interrupted, shared
+/// by the engines. `cancelled()` turns true once the execution is cancelled
+/// or the last tenth of its timeout has begun; a cancelled script is
+/// interrupted once it has had `cancel_grace` to return.
+#[derive(Debug, Clone)]
+pub(crate) struct Interrupt {
+    deadline: Option<Instant>,
+    wind_down: Option<Instant>,
+    cancellation: Option<CancellationToken>,
+    grace: Duration,
+    /// When the script first noticed the cancellation.
+    cancelled_at: Arc<std::sync::OnceLock<Instant>>,
+}
+
+impl Interrupt {
+    pub(crate) fn new(options: &ExecutionOptions) -> Self {
+        let now = Instant::now();
+        Self {
+            deadline: options.timeout.map(|t| now + t),
+            wind_down: options.timeout.map(|t| now + t - t / 10),
+            cancellation: options.cancellation.clone(),
+            grace: options.cancel_grace,
+            cancelled_at: Arc::default(),
+        }
+    }
+
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// What `deadline.remainingMs()` returns: whole milliseconds until the
+    /// timeout, or infinity without one.
+    pub(crate) fn remaining_ms(&self) -> f64 {
+        self.deadline.map_or(f64::INFINITY, |d| {
+            d.saturating_duration_since(Instant::now()).as_millis() as f64
+        })
+    }
+
+    /// Whether the timeout has passed.
+    pub(crate) fn expired(&self) -> bool {
+        self.deadline.is_some_and(|d| Instant::now() >= d)
+    }
+
+    /// Whether the execution was cancelled and its grace period is over.
+    pub(crate) fn cancelled(&self) -> bool {
+        if !self
+            .cancellation
+            .as_ref()
+            .is_some_and(|ct| ct.is_cancelled())
+        {
+            return false;
+        }
+        self.cancelled_at.get_or_init(Instant::now).elapsed() >= self.grace
+    }
+
+    /// What the script's `cancelled()` returns.
+    pub(crate) fn winding_down(&self) -> bool {
+        self.cancellation
+            .as_ref()
+            .is_some_and(|ct| ct.is_cancelled())
+            || self.wind_down.is_some_and(|w| Instant::now() >= w)
+    }
+}
+
+struct Session {
+    context: AsyncContext,
+    last_used: Instant,
 }
 
 pub struct JsRuntime {
     runtime: AsyncRuntime,
+    sessions: std::sync::Mutex<HashMap<String, Session>>,
+    session_ttl: Duration,
+    /// Contexts of executions that called `yieldToAgent`, by token.
+    continuations: std::sync::Mutex<HashMap<String, Session>>,
+    max_continuations: usize,
 }
 
 impl JsRuntime {
     pub async fn new() -> Result<Self> {
         let runtime = AsyncRuntime::new()?;
-        Ok(Self { runtime })
+        Ok(Self {
+            runtime,
+            sessions: std::sync::Mutex::new(HashMap::new()),
+            session_ttl: Duration::from_secs(30 * 60),
+            continuations: std::sync::Mutex::new(HashMap::new()),
+            max_continuations: 0,
+        })
+    }
+
+    /// Discard sessions and continuations that have been idle for longer
+    /// than `ttl`.
+    pub fn with_session_ttl(mut self, ttl: Duration) -> Self {
+        self.session_ttl = ttl;
+        self
+    }
+
+    /// Let scripts call `yieldToAgent`, keeping at most `max` paused
+    /// contexts. Beyond that the least recently used one is dropped.
+    pub fn with_max_continuations(mut self, max: usize) -> Self {
+        self.max_continuations = max;
+        self
+    }
+
+    /// Number of live sessions, after expiring idle ones.
+    pub fn session_count(&self) -> usize {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, s| s.last_used.elapsed() < self.session_ttl);
+        sessions.len()
+    }
+
+    /// Number of outstanding continuations, after expiring idle ones.
+    pub fn continuation_count(&self) -> usize {
+        let mut continuations = self.continuations.lock().unwrap();
+        continuations.retain(|_, c| c.last_used.elapsed() < self.session_ttl);
+        continuations.len()
+    }
+
+    /// Drop the context kept for `token`. False if there was none.
+    pub fn abandon_continuation(&self, token: &str) -> bool {
+        self.continuations.lock().unwrap().remove(token).is_some()
+    }
+
+    fn take_continuation(&self, token: &str) -> Option<AsyncContext> {
+        let mut continuations = self.continuations.lock().unwrap();
+        continuations.retain(|_, c| c.last_used.elapsed() < self.session_ttl);
+        continuations.remove(token).map(|c| c.context)
+    }
+
+    /// Keep `context` under `token`, or a new token, evicting the least
+    /// recently used continuation when over the limit.
+    fn keep_continuation(&self, token: Option<String>, context: AsyncContext) -> String {
+        let token = token.unwrap_or_else(continuation_token);
+        let mut continuations = self.continuations.lock().unwrap();
+        continuations.insert(
+            token.clone(),
+            Session {
+                context,
+                last_used: Instant::now(),
+            },
+        );
+        while continuations.len() > self.max_continuations {
+            let Some(oldest) = continuations
+                .iter()
+                .min_by_key(|(_, c)| c.last_used)
+                .map(|(token, _)| token.clone())
+            else {
+                break;
+            };
+            tracing::warn!("Dropping continuation {oldest}: over max_continuations");
+            continuations.remove(&oldest);
+        }
+        token
+    }
+
+    /// The context a script runs in: a fresh one, or the session's
+    /// persistent context so globals carry over between executions.
+    async fn context_for(&self, session: Option<&str>, reset: bool) -> Result<AsyncContext> {
+        let Some(id) = session else {
+            return Ok(AsyncContext::full(&self.runtime).await?);
+        };
+
+        {
+            let mut sessions = self.sessions.lock().unwrap();
+            sessions.retain(|_, s| s.last_used.elapsed() < self.session_ttl);
+            if reset {
+                sessions.remove(id);
+            }
+            if let Some(session) = sessions.get_mut(id) {
+                session.last_used = Instant::now();
+                return Ok(session.context.clone());
+            }
+        }
+
+        let context = AsyncContext::full(&self.runtime).await?;
+        self.sessions.lock().unwrap().insert(
+            id.to_string(),
+            Session {
+                context: context.clone(),
+                last_used: Instant::now(),
+            },
+        );
+        Ok(context)
     }
 
     pub async fn execute(&self, code: &str) -> Result<serde_json::Value> {
@@ -153,21 +943,118 @@ impl JsRuntime {
         tool_names: &[String],
         caller: Arc<C>,
     ) -> Result<ExecutionResult> {
+        self.execute_with_options(code, tool_names, caller, ExecutionOptions::default())
+            .await
+    }
+
+    pub async fn execute_with_options<C: ToolCaller + ?Sized>(
+        &self,
+        code: &str,
+        tool_names: &[String],
+        caller: Arc<C>,
+        options: ExecutionOptions,
+    ) -> Result<ExecutionResult> {
+        let code = if options.typescript {
+            match crate::transpile::strip_types(code) {
+                Ok(code) => code,
+                Err(message) => {
+                    return Ok(ExecutionResult {
+                        is_error: true,
+                        error_message: Some(message),
+                        ..Default::default()
+                    });
+                }
+            }
+        } else {
+            code.to_string()
+        };
+
+        // QuickJS polls the interrupt handler while running bytecode, so this
+        // stops runaway loops but not a tool call that is blocked downstream.
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let handler_timed_out = timed_out.clone();
+        let interrupt = Interrupt::new(&options);
+        let handler_interrupt = interrupt.clone();
+        self.runtime
+            .set_interrupt_handler(Some(Box::new(move || {
+                let expired = handler_interrupt.expired();
+                if expired {
+                    handler_timed_out.store(true, Ordering::Relaxed);
+                }
+                expired || handler_interrupt.cancelled()
+            })))
+            .await;
+
+        let result = self
+            .run_script(&code, tool_names, caller, options, interrupt, timed_out)
+            .await;
+        self.runtime.set_interrupt_handler(None).await;
+        result
+    }
+
+    async fn run_script<C: ToolCaller + ?Sized>(
+        &self,
+        code: &str,
+        tool_names: &[String],
+        caller: Arc<C>,
+        options: ExecutionOptions,
+        interrupt: Interrupt,
+        timed_out: Arc<AtomicBool>,
+    ) -> Result<ExecutionResult> {
+        let deadline = interrupt.deadline();
         let code = code.to_string();
         let tool_names = tool_names.to_vec();
         let logs: Arc<std::sync::Mutex<Vec<String>>> = Arc::new(std::sync::Mutex::new(Vec::new()));
         let logs_clone = logs.clone();
+        let tool_calls = Arc::new(AtomicUsize::new(0));
+        let tool_calls_clone = tool_calls.clone();
+        let lazy_tools: Vec<String> = tool_names
+            .iter()
+            .filter(|name| options.lazy_tools.contains(*name))
+            .cloned()
+            .collect();
+        let lazy_results = Arc::new(std::sync::Mutex::new(Vec::<String>::new()));
+        let namespace = options.namespace.clone();
+        let aliases = script_aliases(&tool_names, options.camel_case);
+        let yielded: Arc<std::sync::Mutex<Option<serde_json::Value>>> = Arc::default();
+        let yielded_clone = yielded.clone();
+        let can_yield = self.max_continuations > 0;
+        let with_stats = options.stats;
+        let with_hashes = options.hashes;
+        let with_llm = options.llm;
+        let with_safe_regex = options.safe_regex;
+        let stream_logs = options.stream_logs;
 
-        let context = AsyncContext::full(&self.runtime).await?;
+        let context = match &options.continuation {
+            Some(token) => match self.take_continuation(token) {
+                Some(context) => context,
+                None => {
+                    return Ok(ExecutionResult {
+                        is_error: true,
+                        error_message: Some(format!("Unknown or expired continuation `{token}`")),
+                        ..Default::default()
+                    });
+                }
+            },
+            None => {
+                self.context_for(options.session.as_deref(), options.reset_session)
+                    .await?
+            }
+        };
+        let kept_context = context.clone();
 
-        context
-            .with(move |ctx| {
+        let script = move |bridge: ToolBridge| {
+            futures::executor::block_on(context.with(move |ctx| {
                 let globals = ctx.globals();
 
                 // Set up console.log
                 let console = Object::new(ctx.clone())?;
                 let logs_for_closure = logs_clone.clone();
+                let log_bridge = stream_logs.then(|| bridge.clone());
                 let log_fn = Function::new(ctx.clone(), move |args: String| {
+                    if let Some(bridge) = &log_bridge {
+                        bridge.notify(ServerRequest::Log { line: args.clone() });
+                    }
                     if let Ok(mut logs) = logs_for_closure.lock() {
                         logs.push(args);
                     }
@@ -175,154 +1062,549 @@ impl JsRuntime {
                 console.set("log", log_fn)?;
                 globals.set("console", console)?;
 
-                // Set up __stringify helper for console.log
-                let stringify_setup = r#"
-                    var __original_console_log = console.log;
-                    console.log = function() {
-                        var parts = [];
-                        for (var i = 0; i < arguments.length; i++) {
-                            var arg = arguments[i];
-                            if (typeof arg === 'object') {
-                                parts.push(JSON.stringify(arg));
-                            } else {
-                                parts.push(String(arg));
-                            }
-                        }
-                        __original_console_log(parts.join(' '));
-                    };
-                "#;
-                let _: Value = ctx.eval(stringify_setup.as_bytes().to_vec())?;
+                let _: Value = ctx.eval(CONSOLE_PRELUDE.as_bytes().to_vec())?;
+                let _: Value = ctx.eval(ASSERT_PRELUDE.as_bytes().to_vec())?;
+
+                if can_yield {
+                    let yield_fn = Function::new(
+                        ctx.clone(),
+                        move |ctx: Ctx<'_>, summary: String| -> rquickjs::Result<()> {
+                            let summary = serde_json::from_str(&summary).unwrap_or_default();
+                            *yielded_clone.lock().unwrap() = Some(summary);
+                            Err(throw_uncatchable(&ctx, "yieldToAgent"))
+                        },
+                    )?;
+                    globals.set("__yield_to_agent", yield_fn)?;
+                    let _: Value = ctx.eval(YIELD_PRELUDE.as_bytes().to_vec())?;
+                }
+
+                // Lazy results live here for the duration of the execution; the
+                // script only ever holds the slices it reads.
+                let results_for_read = lazy_results.clone();
+                let read_fn = Function::new(
+                    ctx.clone(),
+                    move |handle: usize, offset: usize, len: usize| {
+                        read_lazy_result(&results_for_read, handle, offset, len)
+                    },
+                )?;
+                globals.set("__read_result", read_fn)?;
 
                 let raw_tools = Object::new(ctx.clone())?;
 
                 for tool_name in &tool_names {
                     let name = tool_name.clone();
-                    let caller_clone = caller.clone();
-
-                    let func = Function::new(ctx.clone(), move |args: String| {
-                        let tool_name = name.clone();
-                        let caller = caller_clone.clone();
+                    let bridge = bridge.clone();
+                    let tool_calls = tool_calls_clone.clone();
+                    let lazy_store = lazy_tools.contains(tool_name).then(|| lazy_results.clone());
 
-                        let args_value: Option<serde_json::Value> = serde_json::from_str(&args).ok();
-                        let result = caller.call_tool_blocking(&tool_name, args_value);
-
-                        match result {
-                            Ok(call_result) => format_call_result(&call_result),
-                            Err(e) => format!("{{\"error\": \"{e}\"}}"),
-                        }
-                    })?;
+                    let func =
+                        Function::new(ctx.clone(), move |args: String, options: Opt<String>| {
+                            tool_calls.fetch_add(1, Ordering::Relaxed);
+                            call_raw_tool(
+                                &bridge,
+                                &name,
+                                &args,
+                                options.0.as_deref(),
+                                lazy_store.as_deref(),
+                            )
+                        })?;
 
                     raw_tools.set(tool_name.as_str(), func)?;
                 }
 
                 globals.set("__raw_tools", raw_tools)?;
 
-                let tool_names_json = serde_json::to_string(&tool_names).unwrap_or("[]".to_string());
-                let tool_wrapper_code = format!(r#"
-                    var tools = {{}};
-                    var __tool_names = {tool_names_json};
-                    for (var i = 0; i < __tool_names.length; i++) {{
-                        (function(toolName) {{
-                            tools[toolName] = function(args) {{
-                                var jsonArgs = JSON.stringify(args || {{}});
-                                var resultStr = __raw_tools[toolName](jsonArgs);
-                                var result;
-                                try {{
-                                    result = JSON.parse(resultStr);
-                                }} catch (e) {{
-                                    result = resultStr;
-                                }}
-                                // If result contains an error field, throw it as an exception
-                                if (result && typeof result === 'object' && result.error) {{
-                                    throw new Error('Tool ' + toolName + ' failed: ' + result.error);
-                                }}
-                                return result;
-                            }};
-                        }})(__tool_names[i]);
-                    }}
-                "#);
-                let wrapper_result: Result<Value, _> = ctx.eval(tool_wrapper_code.as_bytes().to_vec());
+                let batch_bridge = bridge.clone();
+                let batch_tools = tool_names.clone();
+                let batch_lazy_tools = lazy_tools.clone();
+                let batch_results = lazy_results.clone();
+                let batch_calls = tool_calls_clone.clone();
+                let batch_fn = Function::new(ctx.clone(), move |request: String| {
+                    call_batch(
+                        &batch_bridge,
+                        &request,
+                        deadline,
+                        &batch_tools,
+                        &batch_lazy_tools,
+                        &batch_results,
+                        &batch_calls,
+                    )
+                })?;
+                globals.set("__raw_batch", batch_fn)?;
+
+                let server_bridge = bridge.clone();
+                let server_fn = Function::new(ctx.clone(), move |request: String| {
+                    call_server(&server_bridge, &request)
+                })?;
+                globals.set("__server_request", server_fn)?;
+                let _: Value = ctx.eval(SERVER_PRELUDE.as_bytes().to_vec())?;
+
+                let remaining_interrupt = interrupt.clone();
+                let cancelled_fn = Function::new(ctx.clone(), move || interrupt.winding_down())?;
+                globals.set("cancelled", cancelled_fn)?;
+                let remaining_fn =
+                    Function::new(ctx.clone(), move || remaining_interrupt.remaining_ms())?;
+                globals.set("__remaining_ms", remaining_fn)?;
+                let _: Value = ctx.eval(DEADLINE_PRELUDE.as_bytes().to_vec())?;
+
+                let time_fn = Function::new(ctx.clone(), |request: String| call_time(&request))?;
+                globals.set("__time", time_fn)?;
+                let _: Value = ctx.eval(TIME_PRELUDE.as_bytes().to_vec())?;
+
+                if with_stats {
+                    let stats_fn =
+                        Function::new(ctx.clone(), |request: String| call_stats(&request))?;
+                    globals.set("__stats", stats_fn)?;
+                    let _: Value = ctx.eval(STATS_PRELUDE.as_bytes().to_vec())?;
+                }
+
+                if with_llm {
+                    let _: Value = ctx.eval(LLM_PRELUDE.as_bytes().to_vec())?;
+                }
+
+                if with_hashes {
+                    let hash_fn =
+                        Function::new(ctx.clone(), |request: String| call_hash(&request))?;
+                    globals.set("__hash", hash_fn)?;
+                    let _: Value = ctx.eval(HASH_PRELUDE.as_bytes().to_vec())?;
+                }
+
+                if with_safe_regex {
+                    let regex_fn =
+                        Function::new(ctx.clone(), |request: String| call_regex(&request))?;
+                    globals.set("__regex", regex_fn)?;
+                    let _: Value = ctx.eval(SAFE_REGEX_PRELUDE.as_bytes().to_vec())?;
+                }
+
+                let tool_wrapper_code =
+                    tools_prelude(&tool_names, &lazy_tools, &aliases, namespace.as_deref());
+                let wrapper_result: Result<Value, _> =
+                    ctx.eval(tool_wrapper_code.as_bytes().to_vec());
                 if let Err(e) = wrapper_result {
                     return Err(anyhow::anyhow!("Tool wrapper setup failed: {e:?}"));
                 }
+                let _: Value = ctx.eval(WORKFLOW_PRELUDE.as_bytes().to_vec())?;
 
                 let code_result: Result<Value, _> = ctx.eval(code.as_bytes().to_vec());
                 match code_result {
-                    Ok(result) => Ok((value_to_json(&result)?, None)),
+                    Ok(result) => Ok((value_to_json(&result)?, None, None)),
                     Err(_e) => {
-                        let error_msg = if let Some(exc) = ctx.catch().as_exception() {
+                        let exception = ctx.catch();
+                        let assertion = exception
+                            .as_exception()
+                            .and_then(|exc| exc.get::<_, Option<String>>("assertion").ok())
+                            .flatten()
+                            .and_then(|json| AssertionFailure::from_json(&json));
+                        let error_msg = if timed_out.load(Ordering::Relaxed) {
+                            format!(
+                                "Execution timed out after {}ms",
+                                options.timeout.unwrap_or_default().as_millis()
+                            )
+                        } else if options
+                            .cancellation
+                            .as_ref()
+                            .is_some_and(|ct| ct.is_cancelled())
+                        {
+                            "Execution cancelled".to_string()
+                        } else if let Some(exc) = exception.as_exception() {
                             exc.message().unwrap_or_default().to_string()
                         } else {
                             "Unknown JavaScript error".to_string()
                         };
                         // Return the error as a successful result with is_error=true
-                        Ok((serde_json::Value::Null, Some(error_msg)))
+                        Ok((serde_json::Value::Null, Some(error_msg), assertion))
                     }
                 }
-            })
-            .await
-            .map(|(value, error)| {
-                let captured_logs = logs.lock().map(|l| l.clone()).unwrap_or_default();
-                ExecutionResult {
-                    value,
-                    logs: captured_logs,
-                    is_error: error.is_some(),
-                    error_message: error,
-                }
-            })
+            }))
+        };
+
+        let (mut value, mut error, assertion) = run_blocking_script(caller, script).await??;
+        let mut continuation = None;
+        if let Some(summary) = yielded.lock().unwrap().take() {
+            value = summary;
+            error = None;
+            continuation = Some(self.keep_continuation(options.continuation, kept_context));
+        }
+        let captured_logs = logs.lock().map(|l| l.clone()).unwrap_or_default();
+        Ok(ExecutionResult {
+            value,
+            logs: captured_logs,
+            is_error: error.is_some(),
+            error_message: error,
+            metrics: ExecutionMetrics {
+                tool_calls: tool_calls.load(Ordering::Relaxed),
+                ..Default::default()
+            },
+            continuation,
+            assertion,
+            ..Default::default()
+        })
     }
 }
 
-fn format_call_result(result: &CallToolResult) -> String {
-    let contents: Vec<serde_json::Value> = result
-        .content
-        .iter()
-        .map(|c| {
-            if let Some(text) = c.as_text() {
-                serde_json::Value::String(text.text.clone())
-            } else if let Some(image) = c.as_image() {
-                serde_json::json!({
-                    "type": "image",
-                    "data": image.data,
-                    "mimeType": image.mime_type
-                })
+/// Replaces the native `console.log` (which takes one string) with one that
+/// formats its arguments like a browser console.
+pub(crate) const CONSOLE_PRELUDE: &str = r#"
+    var __original_console_log = console.log;
+    console.log = function() {
+        var parts = [];
+        for (var i = 0; i < arguments.length; i++) {
+            var arg = arguments[i];
+            if (typeof arg === 'object') {
+                parts.push(JSON.stringify(arg));
             } else {
-                serde_json::Value::Null
+                parts.push(String(arg));
             }
-        })
-        .collect();
-
-    if contents.len() == 1
-        && let Some(s) = contents[0].as_str()
-    {
-        return s.to_string();
-    }
-
-    serde_json::to_string(&contents).unwrap_or_else(|_| "[]".to_string())
-}
+        }
+        __original_console_log(parts.join(' '));
+    };
+"#;
 
-fn value_to_json(value: &Value) -> Result<serde_json::Value> {
-    let type_of = value.type_of();
+/// `deadline.remainingMs()`, on top of `__remaining_ms`. Set as a property
+/// rather than declared, so scripts can still declare their own `deadline`.
+pub(crate) const DEADLINE_PRELUDE: &str = r#"
+    globalThis.deadline = {
+        remainingMs: function() {
+            return __remaining_ms();
+        }
+    };
+"#;
 
-    match type_of {
-        Type::Undefined | Type::Null => Ok(serde_json::Value::Null),
-        Type::Bool => {
-            let b = value.as_bool().unwrap_or(false);
-            Ok(serde_json::Value::Bool(b))
+/// Script functions served by the server behind the tools, on top of
+/// `__server_request` (JSON string in, JSON string out).
+pub(crate) const SERVER_PRELUDE: &str = r#"
+    function __server(method, params) {
+        var response = JSON.parse(__server_request(JSON.stringify({method: method, params: params})));
+        if (response.error) {
+            throw new Error(response.error);
         }
-        Type::Int => {
-            let i = value.as_int().unwrap_or(0);
-            Ok(serde_json::Value::Number(i.into()))
+        return response.result;
+    }
+    var resources = {
+        read: function(uri) {
+            return __server('resources/read', {uri: String(uri)});
         }
-        Type::Float => {
-            let f = value.as_float().unwrap_or(0.0);
-            Ok(serde_json::json!(f))
+    };
+    var prompts = {
+        list: function() {
+            return __server('prompts/list');
+        },
+        get: function(name, args) {
+            return __server('prompts/get', {name: String(name), arguments: args || null});
         }
-        Type::String => {
-            let s = value
-                .as_string()
-                .context("Expected string")?
-                .to_string()
+    };
+    function sample(prompt, opts) {
+        if (typeof prompt !== 'string') {
+            prompt = JSON.stringify(prompt);
+        }
+        return __server('sampling/createMessage', {prompt: prompt, options: opts || {}});
+    }
+    function elicit(schema, message) {
+        return __server('elicitation/create', {schema: schema, message: String(message)});
+    }
+    function ask(prompt, schema) {
+        return __server('elicitation/ask', {prompt: String(prompt), schema: schema == null ? null : schema});
+    }
+    function roots() {
+        return __server('roots/list');
+    }
+    function progress(current, total, message) {
+        __server('notifications/progress', {
+            progress: Number(current),
+            total: total == null ? null : Number(total),
+            message: message == null ? null : String(message)
+        });
+    }
+"#;
+
+/// `llm.complete(prompt, options)`, on top of `SERVER_PRELUDE`. `json` is
+/// taken out of `options`, which are otherwise those of `sample`.
+pub(crate) const LLM_PRELUDE: &str = r#"
+    var llm = {
+        complete: function(prompt, options) {
+            if (typeof prompt !== 'string') {
+                prompt = JSON.stringify(prompt);
+            }
+            options = Object.assign({}, options);
+            var json = !!options.json;
+            delete options.json;
+            return __server('sampling/complete', {prompt: prompt, options: options, json: json});
+        }
+    };
+"#;
+
+/// `assert(cond, msg)` and `expectEqual(actual, expected, msg)`. Failures
+/// throw an `AssertionError` whose `assertion` property carries the details
+/// as JSON; values are compared as JSON with object keys in any order.
+pub(crate) const ASSERT_PRELUDE: &str = r#"
+    function __canonical(value) {
+        if (Array.isArray(value)) {
+            return '[' + value.map(__canonical).join(',') + ']';
+        }
+        if (value !== null && typeof value === 'object') {
+            return '{' + Object.keys(value).sort().map(function(key) {
+                return JSON.stringify(key) + ':' + __canonical(value[key]);
+            }).join(',') + '}';
+        }
+        var json = JSON.stringify(value);
+        return json === undefined ? 'undefined' : json;
+    }
+    function __assertion_error(details) {
+        var error = new Error(details.message);
+        error.name = 'AssertionError';
+        error.assertion = JSON.stringify(details);
+        return error;
+    }
+    function assert(condition, message) {
+        if (!condition) {
+            throw __assertion_error({
+                message: message === undefined ? 'Assertion failed' : String(message)
+            });
+        }
+    }
+    function expectEqual(actual, expected, message) {
+        var a = __canonical(actual);
+        var e = __canonical(expected);
+        if (a !== e) {
+            throw __assertion_error({
+                message: message === undefined ? 'Expected ' + e + ', got ' + a : String(message),
+                expected: expected === undefined ? null : expected,
+                actual: actual === undefined ? null : actual
+            });
+        }
+    }
+"#;
+
+/// `yieldToAgent(summary)` records the summary and unwinds the script, which
+/// then ends with the summary as its result and a continuation token.
+/// `__yield_to_agent` does the unwinding, past any `catch` or `finally`.
+const YIELD_PRELUDE: &str = r#"
+    function yieldToAgent(summary) {
+        __yield_to_agent(JSON.stringify(summary === undefined ? null : summary));
+    }
+"#;
+
+/// Throw an error with `message` that scripts can't catch, the way QuickJS
+/// stops a script when interrupted.
+fn throw_uncatchable(ctx: &Ctx<'_>, message: &str) -> rquickjs::Error {
+    let error = match Exception::from_message(ctx.clone(), message) {
+        Ok(error) => error,
+        Err(e) => return e,
+    };
+    // SAFETY: both pointers come from live rquickjs handles, and the flag
+    // only changes how the error unwinds.
+    unsafe {
+        rquickjs::qjs::JS_SetUncatchableError(ctx.as_raw().as_ptr(), error.as_value().as_raw());
+    }
+    ctx.throw(error.into_value())
+}
+
+/// An unguessable token naming a context kept for `yieldToAgent`.
+fn continuation_token() -> String {
+    random_id("cont")
+}
+
+/// A unique, unguessable id: `<prefix>-` and 122 random bits as 32 hex
+/// digits.
+pub fn random_id(prefix: &str) -> String {
+    format!("{prefix}-{}", uuid::Uuid::new_v4().simple())
+}
+
+/// Builds the `tools` object on top of `__raw_tools` (JSON string in, JSON
+/// string out) and `__read_result` for lazy results.
+pub(crate) fn tools_prelude(
+    tool_names: &[String],
+    lazy_tools: &[String],
+    aliases: &BTreeMap<String, String>,
+    namespace: Option<&str>,
+) -> String {
+    let namespace_json = serde_json::to_string(&namespace).unwrap_or("null".to_string());
+    let aliases_json = serde_json::to_string(aliases).unwrap_or("{}".to_string());
+    let tool_names_json = serde_json::to_string(tool_names).unwrap_or("[]".to_string());
+    let lazy_tools_json = serde_json::to_string(lazy_tools).unwrap_or("[]".to_string());
+    format!(
+        r#"
+        var tools = {{}};
+        var __tool_names = {tool_names_json};
+        var __lazy_tools = {lazy_tools_json};
+        function __make_reader(handle, length) {{
+            return {{
+                length: length,
+                read: function(offset, len) {{
+                    offset = offset || 0;
+                    return __read_result(handle, offset, len === undefined ? length - offset : len);
+                }},
+                text: function() {{
+                    return __read_result(handle, 0, length);
+                }}
+            }};
+        }}
+        for (var i = 0; i < __tool_names.length; i++) {{
+            (function(toolName) {{
+                tools[toolName] = function(args, options) {{
+                    var jsonArgs = JSON.stringify(args || {{}});
+                    var resultStr = options === undefined
+                        ? __raw_tools[toolName](jsonArgs)
+                        : __raw_tools[toolName](jsonArgs, JSON.stringify(options));
+                    var result;
+                    try {{
+                        result = JSON.parse(resultStr);
+                    }} catch (e) {{
+                        result = resultStr;
+                    }}
+                    // If result contains an error field, throw it as an exception
+                    if (result && typeof result === 'object' && result.error) {{
+                        var error = new Error('Tool ' + toolName + ' failed: ' + result.error);
+                        if (result.code) {{
+                            error.code = result.code;
+                        }}
+                        throw error;
+                    }}
+                    if (__lazy_tools.indexOf(toolName) >= 0 && result && result.__handle !== undefined) {{
+                        return __make_reader(result.__handle, result.length);
+                    }}
+                    return result;
+                }};
+            }})(__tool_names[i]);
+        }}
+        var __aliases = {aliases_json};
+        for (var alias in __aliases) {{
+            tools[alias] = tools[__aliases[alias]];
+        }}
+        var __namespace = {namespace_json};
+        if (__namespace !== null) {{
+            var __namespaced = {{}};
+            __namespaced[__namespace] = tools;
+            tools = __namespaced;
+        }}
+    "#
+    )
+}
+
+/// Call `name` with JSON-encoded `args` and return the result text that
+/// `__raw_tools` hands back to the script. Results of lazy tools are stored
+/// in `lazy_store` and replaced by a `{"__handle", "length"}` stub.
+pub(crate) fn call_raw_tool(
+    bridge: &ToolBridge,
+    name: &str,
+    args: &str,
+    options: Option<&str>,
+    lazy_store: Option<&std::sync::Mutex<Vec<String>>>,
+) -> String {
+    let args_value: Option<serde_json::Value> = serde_json::from_str(args).ok();
+    let options = match options.map(serde_json::from_str::<CallOptions>) {
+        Some(Ok(options)) => options,
+        Some(Err(e)) => {
+            return serde_json::json!({"error": format!("Invalid call options: {e}")}).to_string();
+        }
+        None => CallOptions::default(),
+    };
+    raw_tool_result(bridge.call_tool(name, args_value, options), lazy_store)
+}
+
+/// The text `__raw_tools` hands back to the script for a call's `result`.
+/// A result the tool marked as an error is handed back as `{"error"}` with
+/// its text, like a call that failed, so the script's call throws.
+pub(crate) fn raw_tool_result(
+    result: Result<CallToolResult>,
+    lazy_store: Option<&std::sync::Mutex<Vec<String>>>,
+) -> String {
+    match (result, lazy_store) {
+        (Ok(call_result), _) if call_result.is_error == Some(true) => {
+            serde_json::json!({"error": format_call_result(&call_result)}).to_string()
+        }
+        (Ok(call_result), Some(store)) => {
+            let text = format_call_result(&call_result);
+            let length = text.chars().count();
+            let mut store = store.lock().unwrap();
+            store.push(text);
+            serde_json::json!({"__handle": store.len() - 1, "length": length}).to_string()
+        }
+        (Ok(call_result), None) => format_call_result(&call_result),
+        (Err(e), _) if e.is::<crate::cost::BudgetExceeded>() => {
+            serde_json::json!({"error": e.to_string(), "code": crate::cost::BudgetExceeded::CODE})
+                .to_string()
+        }
+        (Err(e), _) if e.is::<crate::circuit::CircuitOpen>() => {
+            serde_json::json!({"error": e.to_string(), "code": crate::circuit::CircuitOpen::CODE})
+                .to_string()
+        }
+        (Err(e), _) => serde_json::json!({"error": e.to_string()}).to_string(),
+    }
+}
+
+/// Serve `__server_request(json)`: parse the request the prelude built and
+/// return `{"result": ...}` or `{"error": ...}` as JSON text.
+pub(crate) fn call_server(bridge: &ToolBridge, request: &str) -> String {
+    let response = serde_json::from_str::<ServerRequest>(request)
+        .map_err(|e| anyhow::anyhow!("Invalid request: {e}"))
+        .and_then(|request| bridge.server_request(request));
+    match response {
+        Ok(result) => serde_json::json!({ "result": result }).to_string(),
+        Err(e) => serde_json::json!({ "error": e.to_string() }).to_string(),
+    }
+}
+
+/// Text of `__read_result(handle, offset, len)`, counted in characters.
+pub(crate) fn read_lazy_result(
+    store: &std::sync::Mutex<Vec<String>>,
+    handle: usize,
+    offset: usize,
+    len: usize,
+) -> String {
+    let results = store.lock().unwrap();
+    results
+        .get(handle)
+        .map(|text| text.chars().skip(offset).take(len).collect::<String>())
+        .unwrap_or_default()
+}
+
+/// The text a script gets for `result`: its single text content, or a JSON
+/// array of its contents. Text is a string and anything else an object as
+/// MCP sends it: `{type: "image", data, mimeType}`, `{type: "audio", data,
+/// mimeType}`, `{type: "resource", resource: {uri, text | blob, mimeType}}`
+/// or `{type: "resource_link", uri, name, ...}`.
+pub(crate) fn format_call_result(result: &CallToolResult) -> String {
+    let contents: Vec<serde_json::Value> = result
+        .content
+        .iter()
+        .map(|c| match c.as_text() {
+            Some(text) => serde_json::Value::String(text.text.clone()),
+            None => serde_json::to_value(&c.raw).unwrap_or_default(),
+        })
+        .collect();
+
+    if contents.len() == 1
+        && let Some(s) = contents[0].as_str()
+    {
+        return s.to_string();
+    }
+
+    serde_json::to_string(&contents).unwrap_or_else(|_| "[]".to_string())
+}
+
+fn value_to_json(value: &Value) -> Result<serde_json::Value> {
+    let type_of = value.type_of();
+
+    match type_of {
+        Type::Undefined | Type::Null => Ok(serde_json::Value::Null),
+        Type::Bool => {
+            let b = value.as_bool().unwrap_or(false);
+            Ok(serde_json::Value::Bool(b))
+        }
+        Type::Int => {
+            let i = value.as_int().unwrap_or(0);
+            Ok(serde_json::Value::Number(i.into()))
+        }
+        Type::Float => {
+            let f = value.as_float().unwrap_or(0.0);
+            Ok(serde_json::json!(f))
+        }
+        Type::String => {
+            let s = value
+                .as_string()
+                .context("Expected string")?
+                .to_string()
                 .context("Failed to convert JS string")?;
             Ok(serde_json::Value::String(s))
         }
@@ -394,6 +1676,1198 @@ mod tests {
         assert_eq!(result, serde_json::json!("hello world"));
     }
 
+    struct NoTools;
+
+    impl ToolCaller for NoTools {
+        fn call_tool<'a>(
+            &'a self,
+            name: &'a str,
+            _args: Option<serde_json::Value>,
+        ) -> ToolCallFuture<'a> {
+            Box::pin(async move { anyhow::bail!("unexpected call to {name}") })
+        }
+    }
+
+    /// Serves `resources.read` for `mem://` URIs.
+    struct Resources;
+
+    impl ToolCaller for Resources {
+        fn call_tool<'a>(
+            &'a self,
+            name: &'a str,
+            _args: Option<serde_json::Value>,
+        ) -> ToolCallFuture<'a> {
+            Box::pin(async move { anyhow::bail!("unexpected call to {name}") })
+        }
+
+        fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+            Box::pin(async move {
+                let ServerRequest::ReadResource { uri } = request else {
+                    anyhow::bail!("unexpected {}", request.function());
+                };
+                let contents = match uri.as_str() {
+                    "mem://text" => ResourceContents::text("hello", &uri),
+                    "mem://blob" => ResourceContents::BlobResourceContents {
+                        uri,
+                        mime_type: Some("image/png".to_string()),
+                        blob: "aGk=".to_string(),
+                        meta: None,
+                    },
+                    _ => anyhow::bail!("Unknown resource {uri}"),
+                };
+                Ok(resource_value(ReadResourceResult {
+                    contents: vec![contents],
+                }))
+            })
+        }
+    }
+
+    /// Records streamed `console.log` lines.
+    #[derive(Default)]
+    struct LogLines(std::sync::Mutex<Vec<String>>);
+
+    impl ToolCaller for LogLines {
+        fn call_tool<'a>(
+            &'a self,
+            name: &'a str,
+            _args: Option<serde_json::Value>,
+        ) -> ToolCallFuture<'a> {
+            Box::pin(async move { anyhow::bail!("unexpected call to {name}") })
+        }
+
+        fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+            Box::pin(async move {
+                let ServerRequest::Log { line } = request else {
+                    anyhow::bail!("unexpected {}", request.function());
+                };
+                self.0.lock().unwrap().push(line);
+                Ok(serde_json::Value::Null)
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_logs() {
+        let runtime = JsRuntime::new().await.unwrap();
+        let code = "for (var i = 0; i < 20; i++) { console.log('line', i); } 'done'";
+        let caller = Arc::new(LogLines::default());
+        let options = ExecutionOptions {
+            stream_logs: true,
+            ..Default::default()
+        };
+        let result = runtime
+            .execute_with_options(code, &[], caller.clone(), options)
+            .await
+            .unwrap();
+        assert!(!result.is_error, "{:?}", result.error_message);
+        // Every line is sent, in order, before the execution returns
+        assert_eq!(*caller.0.lock().unwrap(), result.logs);
+        assert_eq!(result.logs.len(), 20);
+
+        let caller = Arc::new(LogLines::default());
+        runtime
+            .execute_with_caller(code, &[], caller.clone())
+            .await
+            .unwrap();
+        assert!(caller.0.lock().unwrap().is_empty());
+    }
+
+    struct Completions;
+
+    impl ToolCaller for Completions {
+        fn call_tool<'a>(
+            &'a self,
+            name: &'a str,
+            _args: Option<serde_json::Value>,
+        ) -> ToolCallFuture<'a> {
+            Box::pin(async move { anyhow::bail!("unexpected call to {name}") })
+        }
+
+        fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+            Box::pin(async move {
+                let ServerRequest::Complete {
+                    prompt,
+                    options,
+                    json,
+                } = request
+                else {
+                    anyhow::bail!("unexpected {}", request.function());
+                };
+                Ok(serde_json::json!({
+                    "prompt": prompt,
+                    "maxTokens": options.max_tokens,
+                    "json": json,
+                }))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_llm_complete() {
+        let runtime = JsRuntime::new().await.unwrap();
+        let code = r#"
+            typeof llm === 'undefined' ? 'no llm' : [
+                llm.complete('Classify: crash on start'),
+                llm.complete({text: 'crash'}, {maxTokens: 5, json: true})
+            ]
+        "#;
+        let options = ExecutionOptions {
+            llm: true,
+            ..Default::default()
+        };
+        let result = runtime
+            .execute_with_options(code, &[], Arc::new(Completions), options)
+            .await
+            .unwrap();
+        assert!(!result.is_error, "{:?}", result.error_message);
+        assert_eq!(
+            result.value,
+            serde_json::json!([
+                {"prompt": "Classify: crash on start", "maxTokens": null, "json": false},
+                {"prompt": "{\"text\":\"crash\"}", "maxTokens": 5.0, "json": true},
+            ])
+        );
+
+        let result = runtime
+            .execute_with_caller(code, &[], Arc::new(Completions))
+            .await
+            .unwrap();
+        assert_eq!(result.value, "no llm");
+    }
+
+    #[tokio::test]
+    async fn test_read_resource() {
+        let runtime = JsRuntime::new().await.unwrap();
+        let code = r#"
+            var missing;
+            try { resources.read("mem://missing"); } catch (e) { missing = e.message; }
+            ({text: resources.read("mem://text"), blob: resources.read("mem://blob"), missing: missing})
+        "#;
+        let result = runtime
+            .execute_with_caller(code, &[], Arc::new(Resources))
+            .await
+            .unwrap();
+        assert!(!result.is_error, "{:?}", result.error_message);
+        assert_eq!(
+            result.value,
+            serde_json::json!({
+                "text": "hello",
+                "blob": {"blob": "aGk=", "mimeType": "image/png"},
+                "missing": "Unknown resource mem://missing",
+            })
+        );
+
+        let result = runtime
+            .execute_with_caller("resources.read('mem://text')", &[], Arc::new(NoTools))
+            .await
+            .unwrap();
+        assert_eq!(
+            result.error_message.as_deref(),
+            Some("resources.read is not available")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_timeout_interrupts_infinite_loop() {
+        let runtime = JsRuntime::new().await.unwrap();
+        let options = ExecutionOptions {
+            timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let result = runtime
+            .execute_with_options(
+                r#"console.log("before"); while (true) {}"#,
+                &[],
+                Arc::new(NoTools),
+                options,
+            )
+            .await
+            .unwrap();
+
+        assert!(result.is_error);
+        assert_eq!(
+            result.error_message.as_deref(),
+            Some("Execution timed out after 100ms")
+        );
+        assert_eq!(result.logs, vec!["before".to_string()]);
+
+        // The runtime stays usable after an interrupted script
+        let result = runtime
+            .execute_with_caller("1 + 1", &[], Arc::new(NoTools))
+            .await
+            .unwrap();
+        assert_eq!(result.value, serde_json::json!(2));
+    }
+
+    #[tokio::test]
+    async fn test_cancellation_interrupts_script() {
+        let runtime = JsRuntime::new().await.unwrap();
+        let ct = CancellationToken::new();
+
+        // The script blocks this thread, so cancel from another one
+        let canceller = ct.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            canceller.cancel();
+        });
+
+        let options = ExecutionOptions {
+            cancellation: Some(ct),
+            ..Default::default()
+        };
+        let result = runtime
+            .execute_with_options("while (true) {}", &[], Arc::new(NoTools), options)
+            .await
+            .unwrap();
+
+        assert!(result.is_error);
+        assert_eq!(result.error_message.as_deref(), Some("Execution cancelled"));
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_script_winds_down() {
+        let runtime = JsRuntime::new().await.unwrap();
+        let ct = CancellationToken::new();
+        let canceller = ct.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            canceller.cancel();
+        });
+
+        let options = ExecutionOptions {
+            cancellation: Some(ct),
+            cancel_grace: Duration::from_secs(5),
+            ..Default::default()
+        };
+        let result = runtime
+            .execute_with_options(
+                "var done = 0; while (!cancelled()) { done++; } ({partial: done > 0})",
+                &[],
+                Arc::new(NoTools),
+                options,
+            )
+            .await
+            .unwrap();
+        assert!(!result.is_error, "{:?}", result.error_message);
+        assert_eq!(result.value, serde_json::json!({"partial": true}));
+
+        // Near the deadline, before it is interrupted
+        let options = ExecutionOptions {
+            timeout: Some(Duration::from_millis(500)),
+            ..Default::default()
+        };
+        let result = runtime
+            .execute_with_options(
+                "while (!cancelled()) {} 'wound down'",
+                &[],
+                Arc::new(NoTools),
+                options,
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.value, "wound down");
+    }
+
+    #[tokio::test]
+    async fn test_session_keeps_globals() {
+        let runtime = JsRuntime::new().await.unwrap();
+        let in_session = |session: &str, reset: bool| ExecutionOptions {
+            session: Some(session.to_string()),
+            reset_session: reset,
+            ..Default::default()
+        };
+
+        let run = |code: &'static str, options: ExecutionOptions| {
+            runtime.execute_with_options(code, &[], Arc::new(NoTools), options)
+        };
+
+        run("var counter = 41;", in_session("a", false))
+            .await
+            .unwrap();
+        let result = run("counter + 1", in_session("a", false)).await.unwrap();
+        assert_eq!(result.value, serde_json::json!(42));
+
+        let result = run("typeof counter", in_session("b", false)).await.unwrap();
+        assert_eq!(result.value, "undefined");
+
+        let result = run("typeof counter", in_session("a", true)).await.unwrap();
+        assert_eq!(result.value, "undefined");
+
+        let result = run("typeof counter", ExecutionOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(result.value, "undefined");
+        assert_eq!(runtime.session_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_yield_to_agent_continues_with_globals() {
+        let runtime = JsRuntime::new().await.unwrap().with_max_continuations(4);
+        let run = |code: &'static str, options: ExecutionOptions| {
+            runtime.execute_with_options(code, &[], Arc::new(NoTools), options)
+        };
+
+        let result = run(
+            "var done = 3; yieldToAgent({done: done}); done = 100;",
+            ExecutionOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert!(!result.is_error);
+        assert_eq!(result.value, serde_json::json!({"done": 3}));
+        let token = result.continuation.unwrap();
+
+        let resumed = ExecutionOptions {
+            continuation: Some(token.clone()),
+            ..Default::default()
+        };
+        let result = run("done + 1", resumed).await.unwrap();
+        assert_eq!(result.value, serde_json::json!(4));
+        assert_eq!(result.continuation, None);
+        assert_eq!(runtime.continuation_count(), 0);
+
+        let unknown = ExecutionOptions {
+            continuation: Some("cont-nope".to_string()),
+            ..Default::default()
+        };
+        let result = run("1", unknown).await.unwrap();
+        assert_eq!(
+            result.error_message.as_deref(),
+            Some("Unknown or expired continuation `cont-nope`")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_yield_to_agent_cannot_be_caught() {
+        let runtime = JsRuntime::new().await.unwrap().with_max_continuations(4);
+        let result = runtime
+            .execute_with_options(
+                r#"
+                var done = 3;
+                try { yieldToAgent({done: done}); } catch (e) {}
+                try { yieldToAgent(0); } finally { done = 50; }
+                done = 100;
+                "#,
+                &[],
+                Arc::new(NoTools),
+                ExecutionOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert!(!result.is_error, "{:?}", result.error_message);
+        assert_eq!(result.value, serde_json::json!({"done": 3}));
+
+        let resumed = ExecutionOptions {
+            continuation: result.continuation,
+            ..Default::default()
+        };
+        let result = runtime
+            .execute_with_options("done", &[], Arc::new(NoTools), resumed)
+            .await
+            .unwrap();
+        assert_eq!(result.value, serde_json::json!(3));
+    }
+
+    async fn yield_once(runtime: &JsRuntime) -> Result<ExecutionResult> {
+        let options = ExecutionOptions::default();
+        runtime
+            .execute_with_options("yieldToAgent(1)", &[], Arc::new(NoTools), options)
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_assertion_failures() {
+        let runtime = JsRuntime::new().await.unwrap();
+        let run = |code: &'static str| {
+            runtime.execute_with_options(code, &[], Arc::new(NoTools), ExecutionOptions::default())
+        };
+
+        let result =
+            run("expectEqual({b: [1, 2], a: 'x'}, {a: 'x', b: [1, 2]}); assert(1, 'ok'); 7")
+                .await
+                .unwrap();
+        assert!(!result.is_error, "{:?}", result.error_message);
+        assert_eq!(result.value, 7);
+
+        let result = run("expectEqual({count: 2}, {count: 3})").await.unwrap();
+        assert!(result.is_error);
+        assert_eq!(
+            result.assertion,
+            Some(AssertionFailure {
+                message: r#"Expected {"count":3}, got {"count":2}"#.to_string(),
+                expected: Some(serde_json::json!({"count": 3})),
+                actual: Some(serde_json::json!({"count": 2})),
+            })
+        );
+
+        let result = run("assert([].length > 0, 'no parts found')")
+            .await
+            .unwrap();
+        assert!(result.error_message.unwrap().contains("no parts found"));
+        assert_eq!(result.assertion.unwrap().message, "no parts found");
+
+        // Only failed assertions are reported as such
+        let result = run("throw new Error('boom')").await.unwrap();
+        assert!(result.is_error);
+        assert_eq!(result.assertion, None);
+        let result = run("try { assert(false) } catch (e) {} null.x")
+            .await
+            .unwrap();
+        assert_eq!(result.assertion, None);
+    }
+
+    #[tokio::test]
+    async fn test_time_helpers() {
+        let runtime = JsRuntime::new().await.unwrap();
+        let run = |code: &'static str| {
+            runtime.execute_with_options(code, &[], Arc::new(NoTools), ExecutionOptions::default())
+        };
+        let code = r#"
+            var start = parseDate("2026-03-28 09:00", {timezone: "Europe/Berlin"});
+            var later = new Date(new Date(start).getTime() + 24 * 3600 * 1000);
+            ({
+                start: start,
+                later: convertTimezone(later, "Europe/Berlin"),
+                day: formatDate(later, "%a %H:%M", "Europe/Berlin"),
+                fresh: typeof now() === "string"
+            })
+        "#;
+        let result = run(code).await.unwrap();
+        assert!(!result.is_error, "{:?}", result.error_message);
+        assert_eq!(
+            result.value,
+            serde_json::json!({
+                "start": "2026-03-28T08:00:00Z",
+                "later": "2026-03-29T10:00:00+02:00",
+                "day": "Sun 10:00",
+                "fresh": true
+            })
+        );
+
+        // Scripts can still declare their own `now`
+        let result = run("const now = 5; now + 1").await.unwrap();
+        assert_eq!(result.value, 6);
+        let result = run("formatDate(0, '%Y', 'Nowhere')").await.unwrap();
+        let error = result.error_message.unwrap();
+        assert!(error.contains("Unknown time zone `Nowhere`"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn test_stats_helpers() {
+        let runtime = JsRuntime::new().await.unwrap();
+        let run = |code: &'static str, stats: bool| {
+            let options = ExecutionOptions {
+                stats,
+                ..Default::default()
+            };
+            runtime.execute_with_options(code, &[], Arc::new(NoTools), options)
+        };
+        let code = r#"
+            var items = [{price: 4}, {price: 1}, {price: 10}, {price: 5}];
+            ({
+                sum: stats.sum(items, "price"),
+                mean: stats.mean(items, function(item) { return item.price * 2; }),
+                median: stats.median([4, 1, 10, 5]),
+                p75: stats.percentile([1, 2, 3, 4, 5], 75),
+                empty: stats.mean([]),
+                bins: stats.histogram(items, 2, "price").map(function(b) { return b.count; })
+            })
+        "#;
+        let result = run(code, true).await.unwrap();
+        assert!(!result.is_error, "{:?}", result.error_message);
+        assert_eq!(
+            result.value,
+            serde_json::json!({
+                "sum": 20.0,
+                "mean": 10.0,
+                "median": 4.5,
+                "p75": 4.0,
+                "empty": null,
+                "bins": [3.0, 1.0]
+            })
+        );
+
+        let result = run("stats.sum([{price: 1}, {cost: 2}], 'price')", true)
+            .await
+            .unwrap();
+        assert_eq!(
+            result.error_message.as_deref(),
+            Some("stats.sum: value at index 1 is not a number: null")
+        );
+
+        let result = run("typeof stats", false).await.unwrap();
+        assert_eq!(result.value, "undefined");
+    }
+
+    #[tokio::test]
+    async fn test_safe_regex() {
+        let runtime = JsRuntime::new().await.unwrap();
+        let options = ExecutionOptions {
+            safe_regex: true,
+            timeout: Some(Duration::from_secs(5)),
+            ..Default::default()
+        };
+        let code = r#"
+            var text = "R1 10k, R2 4.7k, C1 100n";
+            var parts = [];
+            for (var m of text.matchAll(/(?<ref>[A-Z]\d+) ([\d.]+)(\w)/g)) {
+                parts.push(m.groups.ref + "=" + m[2]);
+            }
+            var re = /\d+/g;
+            var first = re.exec(text);
+            ({
+                parts: parts,
+                replaced: text.replace(/(\w)(\d)/g, "$2$1"),
+                replacedFn: text.replace(/k\b/, function(m, i) { return "K@" + i; }),
+                split: "a1b22c".split(/\d+/),
+                splitCaptures: "a,b".split(/(,)/),
+                matched: text.match(/[A-Z]\d/g),
+                tested: /^r\d/i.test(text),
+                search: text.search(/C/),
+                global: [first[0], first.index, re.lastIndex]
+            })
+        "#;
+        let result = runtime
+            .execute_with_options(code, &[], Arc::new(NoTools), options.clone())
+            .await
+            .unwrap();
+        assert!(!result.is_error, "{:?}", result.error_message);
+        assert_eq!(
+            result.value,
+            serde_json::json!({
+                "parts": ["R1=10", "R2=4.7", "C1=100"],
+                "replaced": "1R 01k, 2R 4.7k, 1C 010n",
+                "replacedFn": "R1 10K@5, R2 4.7k, C1 100n",
+                "split": ["a", "b", "c"],
+                "splitCaptures": ["a", ",", "b"],
+                "matched": ["R1", "R2", "C1"],
+                "tested": true,
+                "search": 17.0,
+                "global": ["1", 1.0, 2.0]
+            })
+        );
+        // The same matches as QuickJS's own engine (which keeps integers as
+        // integers)
+        let native = runtime
+            .execute_with_options(code, &[], Arc::new(NoTools), ExecutionOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            native.value.to_string(),
+            result.value.to_string().replace(".0", "")
+        );
+
+        let result = runtime
+            .execute_with_options(
+                r#"/(a+)+$/.test("a".repeat(50000) + "!")"#,
+                &[],
+                Arc::new(NoTools),
+                options.clone(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.value, false);
+
+        let result = runtime
+            .execute_with_options("/a(?=b)/.test('ab')", &[], Arc::new(NoTools), options)
+            .await
+            .unwrap();
+        let error = result.error_message.unwrap();
+        assert!(
+            error.contains("lookahead and lookbehind assertions are not supported"),
+            "{error}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hash_helpers() {
+        let runtime = JsRuntime::new().await.unwrap();
+        let options = ExecutionOptions {
+            hashes: true,
+            ..Default::default()
+        };
+        let code = r#"
+            var a = uuid(), b = uuid();
+            ({
+                unique: a !== b && /^[0-9a-f-]{36}$/.test(a),
+                sha: sha256("abc"),
+                object: sha256({a: 1}) === sha256('{"a":1}'),
+                hmac: hmac("Jefe", "what do ya want for nothing?")
+            })
+        "#;
+        let result = runtime
+            .execute_with_options(code, &[], Arc::new(NoTools), options)
+            .await
+            .unwrap();
+        assert!(!result.is_error, "{:?}", result.error_message);
+        assert_eq!(
+            result.value,
+            serde_json::json!({
+                "unique": true,
+                "sha": "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+                "object": true,
+                "hmac": "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_continuation_limits() {
+        let runtime = JsRuntime::new().await.unwrap();
+
+        let result = yield_once(&runtime).await.unwrap();
+        assert!(result.is_error, "yieldToAgent is off by default");
+
+        let runtime = runtime.with_max_continuations(2);
+        let first = yield_once(&runtime).await.unwrap().continuation.unwrap();
+        let second = yield_once(&runtime).await.unwrap().continuation.unwrap();
+        let third = yield_once(&runtime).await.unwrap().continuation.unwrap();
+        assert_eq!(runtime.continuation_count(), 2);
+        assert!(!runtime.abandon_continuation(&first), "evicted as oldest");
+        assert!(runtime.abandon_continuation(&second));
+        assert!(!runtime.abandon_continuation(&second));
+        assert!(runtime.abandon_continuation(&third));
+        assert_eq!(runtime.continuation_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_idle_sessions_expire() {
+        let runtime = JsRuntime::new()
+            .await
+            .unwrap()
+            .with_session_ttl(Duration::ZERO);
+        let options = ExecutionOptions {
+            session: Some("a".to_string()),
+            ..Default::default()
+        };
+        runtime
+            .execute_with_options("var x = 1;", &[], Arc::new(NoTools), options)
+            .await
+            .unwrap();
+        assert_eq!(runtime.session_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_typescript_source() {
+        let runtime = JsRuntime::new().await.unwrap();
+        let options = ExecutionOptions {
+            typescript: true,
+            ..Default::default()
+        };
+        let result = runtime
+            .execute_with_options(
+                "function double(x: number): number { return x * 2; }\ndouble(21 as number);",
+                &[],
+                Arc::new(NoTools),
+                options.clone(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.value, serde_json::json!(42));
+
+        let result = runtime
+            .execute_with_options("var x: = 1;", &[], Arc::new(NoTools), options)
+            .await
+            .unwrap();
+        assert!(result.is_error);
+        assert!(
+            result
+                .error_message
+                .unwrap()
+                .starts_with("TypeScript error at line 1")
+        );
+    }
+
+    struct SlowEcho;
+
+    impl ToolCaller for SlowEcho {
+        fn call_tool<'a>(
+            &'a self,
+            name: &'a str,
+            _args: Option<serde_json::Value>,
+        ) -> ToolCallFuture<'a> {
+            use rmcp::model::Content;
+            Box::pin(async move {
+                // Needs the runtime's timer, so this only completes if the
+                // script isn't blocking the (only) runtime thread.
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                Ok(CallToolResult::success(vec![Content::text(
+                    name.to_string(),
+                )]))
+            })
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_async_tool_calls_on_current_thread_runtime() {
+        let runtime = JsRuntime::new().await.unwrap();
+        let result = runtime
+            .execute_with_caller(
+                "tools.first({}) + ',' + tools.second({})",
+                &["first".to_string(), "second".to_string()],
+                Arc::new(SlowEcho),
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.value, "first,second");
+        assert_eq!(result.metrics.tool_calls, 2);
+    }
+
+    /// Answers every call with an error result.
+    struct Refuses;
+
+    impl ToolCaller for Refuses {
+        fn call_tool<'a>(
+            &'a self,
+            name: &'a str,
+            _args: Option<serde_json::Value>,
+        ) -> ToolCallFuture<'a> {
+            use rmcp::model::Content;
+            Box::pin(async move {
+                Ok(CallToolResult::error(vec![Content::text(format!(
+                    "{name} is out of order"
+                ))]))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_error_results_throw() {
+        let runtime = JsRuntime::new().await.unwrap();
+        let result = runtime
+            .execute_with_caller(
+                "try { tools.lookup({}); 'returned' } catch (e) { e.message }",
+                &["lookup".to_string()],
+                Arc::new(Refuses),
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.value, "Tool lookup failed: lookup is out of order");
+    }
+
+    #[tokio::test]
+    async fn test_tools_named_like_non_identifiers() {
+        let runtime = JsRuntime::new().await.unwrap();
+        let result = runtime
+            .execute_with_caller(
+                "[tools.repos_list({}), tools['repos.list']({}), tools.search_issues({})]",
+                &["repos.list".to_string(), "search-issues".to_string()],
+                Arc::new(SlowEcho),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            result.value,
+            serde_json::json!(["repos.list", "repos.list", "search-issues"])
+        );
+    }
+
+    /// `square({n})` and `add({a, b})`, slowly, recording the most calls in
+    /// flight at once; `flaky({n})` fails `failures` times before returning
+    /// `n`, and `sleep({ms})` takes that long.
+    #[derive(Default)]
+    struct Arithmetic {
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+        failures: AtomicUsize,
+    }
+
+    impl ToolCaller for Arithmetic {
+        fn call_tool<'a>(
+            &'a self,
+            name: &'a str,
+            args: Option<serde_json::Value>,
+        ) -> ToolCallFuture<'a> {
+            use rmcp::model::Content;
+            Box::pin(async move {
+                let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                self.in_flight.fetch_sub(1, Ordering::SeqCst);
+                let args = args.unwrap_or_default();
+                let arg = |key: &str| args[key].as_i64().unwrap_or_default();
+                let value = match name {
+                    "flaky" => {
+                        let failing = self
+                            .failures
+                            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                            .is_ok();
+                        if failing {
+                            anyhow::bail!("unavailable");
+                        }
+                        arg("n")
+                    }
+                    "sleep" => {
+                        tokio::time::sleep(Duration::from_millis(arg("ms") as u64)).await;
+                        0
+                    }
+                    "square" if arg("n") < 0 => anyhow::bail!("negative"),
+                    "square" => arg("n") * arg("n"),
+                    _ => arg("a") + arg("b"),
+                };
+                Ok(CallToolResult::success(vec![Content::text(
+                    value.to_string(),
+                )]))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_workflow_map() {
+        let runtime = JsRuntime::new().await.unwrap();
+        let tools = ["square".to_string(), "add".to_string()];
+        let caller = Arc::new(Arithmetic::default());
+        let code = r#"
+            var progress = [];
+            var results = workflow.map([1, 2, 3, 4, 5, 6], function(n, i) {
+                console.log('item', i);
+                return tools.add({a: tools.square({n: n}), b: i});
+            }, {
+                concurrency: 3,
+                onProgress: function(done, total) { progress.push(done + '/' + total); }
+            });
+            ({results: results, progress: progress})
+        "#;
+        let result = runtime
+            .execute_with_caller(code, &tools, caller.clone())
+            .await
+            .unwrap();
+        assert!(!result.is_error, "{:?}", result.error_message);
+        assert_eq!(
+            result.value,
+            serde_json::json!({
+                "results": [1.0, 5.0, 11.0, 19.0, 29.0, 41.0],
+                "progress": ["1/6", "2/6", "3/6", "4/6", "5/6", "6/6"]
+            })
+        );
+        // Each item logs once, from the run that finished it
+        assert_eq!(result.logs.len(), 6);
+        assert_eq!(result.metrics.tool_calls, 12);
+        assert_eq!(caller.max_in_flight.load(Ordering::SeqCst), 3);
+
+        let code = r#"
+            workflow.map([2, -1, 3], function(n) {
+                return tools.square({n: n});
+            }, {continueOnError: true})
+        "#;
+        let result = runtime
+            .execute_with_caller(code, &tools, caller.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            result.value,
+            serde_json::json!({
+                "results": [4.0, null, 9.0],
+                "errors": [{"index": 1, "item": -1, "error": "Tool square failed: negative"}]
+            })
+        );
+
+        let code = "workflow.map([2, -1, 3], function(n) { return tools.square({n: n}); })";
+        let result = runtime
+            .execute_with_caller(code, &tools, caller.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            result.error_message.as_deref(),
+            Some("Tool square failed: negative")
+        );
+
+        let code = r#"
+            var calls = 0;
+            workflow.map([1], function(n) {
+                calls++;
+                return calls === 1 ? tools.square({n: n}) : tools.add({a: n, b: n});
+            })
+        "#;
+        let result = runtime
+            .execute_with_caller(code, &tools, caller)
+            .await
+            .unwrap();
+        assert_eq!(
+            result.error_message.as_deref(),
+            Some(
+                "workflow.map: functions must make the same tool calls each time they run, but item 0 called add where it called square before"
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_workflow_dag() {
+        let runtime = JsRuntime::new().await.unwrap();
+        let tools = ["square".to_string(), "add".to_string()];
+        let caller = Arc::new(Arithmetic::default());
+        let code = r#"
+            var dag = workflow.dag({
+                steps: {
+                    left: function() { return tools.square({n: 2}); },
+                    right: function() { return tools.square({n: 3}); },
+                    sum: function(deps) { return tools.add({a: deps.left, b: deps.right}); },
+                    twice: function(deps) { return tools.add({a: deps.sum, b: deps.sum}); }
+                },
+                dependsOn: {sum: ['left', 'right'], twice: ['sum']}
+            });
+            var t = dag.timings;
+            ({
+                results: dag.results,
+                ordered: t.sum.startMs >= t.left.endMs && t.twice.startMs >= t.sum.endMs,
+                durations: Object.keys(t).every(function(name) {
+                    return t[name].durationMs === t[name].endMs - t[name].startMs;
+                })
+            })
+        "#;
+        let result = runtime
+            .execute_with_caller(code, &tools, caller.clone())
+            .await
+            .unwrap();
+        assert!(!result.is_error, "{:?}", result.error_message);
+        assert_eq!(
+            result.value,
+            serde_json::json!({
+                "results": {"left": 4.0, "right": 9.0, "sum": 13.0, "twice": 26.0},
+                "ordered": true,
+                "durations": true
+            })
+        );
+        assert_eq!(result.metrics.tool_calls, 4);
+        // Only the independent steps overlap
+        assert_eq!(caller.max_in_flight.load(Ordering::SeqCst), 2);
+
+        let code = r#"
+            var dag = workflow.dag({
+                steps: {
+                    bad: function() { return tools.square({n: -1}); },
+                    good: function() { return tools.square({n: 4}); },
+                    after: function(deps) { return deps.bad; },
+                    last: function(deps) { return deps.after; }
+                },
+                dependsOn: {after: ['bad', 'good'], last: ['after']}
+            }, {continueOnError: true});
+            ({results: dag.results, errors: dag.errors, skipped: dag.skipped})
+        "#;
+        let result = runtime
+            .execute_with_caller(code, &tools, caller.clone())
+            .await
+            .unwrap();
+        assert!(!result.is_error, "{:?}", result.error_message);
+        assert_eq!(
+            result.value,
+            serde_json::json!({
+                "results": {"good": 16.0},
+                "errors": {"bad": "Tool square failed: negative"},
+                "skipped": ["after", "last"]
+            })
+        );
+
+        let code = r#"
+            workflow.dag({
+                steps: {a: function() {}, b: function() {}, c: function() {}},
+                dependsOn: {a: ['b'], b: ['a']}
+            })
+        "#;
+        let result = runtime
+            .execute_with_caller(code, &tools, caller.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            result.error_message.as_deref(),
+            Some("workflow.dag: steps depend on each other in a cycle: a, b")
+        );
+
+        let code = "workflow.dag({steps: {a: function() {}}, dependsOn: {a: ['z']}})";
+        let result = runtime
+            .execute_with_caller(code, &tools, caller)
+            .await
+            .unwrap();
+        assert_eq!(
+            result.error_message.as_deref(),
+            Some("workflow.dag: step a depends on unknown step z")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_workflow_retries_and_timeouts() {
+        let runtime = JsRuntime::new().await.unwrap();
+        let tools = ["flaky".to_string(), "sleep".to_string()];
+        let caller = Arc::new(Arithmetic::default());
+        caller.failures.store(2, Ordering::SeqCst);
+        let code = "workflow.map([7], function(n) { return tools.flaky({n: n}); }, {retries: 2, backoff: 5})";
+        let result = runtime
+            .execute_with_caller(code, &tools, caller.clone())
+            .await
+            .unwrap();
+        assert!(!result.is_error, "{:?}", result.error_message);
+        assert_eq!(result.value, serde_json::json!([7.0]));
+        assert_eq!(result.metrics.tool_calls, 3);
+
+        caller.failures.store(2, Ordering::SeqCst);
+        let code = "workflow.map([7], function(n) { return tools.flaky({n: n}); }, {retries: 1})";
+        let result = runtime
+            .execute_with_caller(code, &tools, caller.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            result.error_message.as_deref(),
+            Some("Tool flaky failed: unavailable")
+        );
+
+        let code = r#"
+            var dag = workflow.dag({
+                steps: {
+                    slow: function() { return tools.sleep({ms: 2000}); },
+                    quick: function() { return tools.sleep({ms: 1}); }
+                },
+                options: {slow: {timeout: 30, retries: 1}}
+            }, {continueOnError: true});
+            ({
+                error: dag.errors.slow.indexOf('Tool sleep failed: timed out after') === 0,
+                attempts: dag.timings.slow.attempts,
+                quick: dag.results.quick
+            })
+        "#;
+        let started = Instant::now();
+        let result = runtime
+            .execute_with_caller(code, &tools, caller.clone())
+            .await
+            .unwrap();
+        assert!(!result.is_error, "{:?}", result.error_message);
+        assert_eq!(
+            result.value,
+            serde_json::json!({"error": true, "attempts": 2, "quick": 0.0})
+        );
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // Calls stop at the execution's timeout even without a step timeout
+        let options = ExecutionOptions {
+            timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let started = Instant::now();
+        let result = runtime
+            .execute_with_options(
+                "workflow.map([1], function() { return tools.sleep({ms: 5000}); })",
+                &tools,
+                caller.clone(),
+                options,
+            )
+            .await
+            .unwrap();
+        assert!(result.is_error);
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        let code = "workflow.map([1], function() {}, {retries: -1})";
+        let result = runtime
+            .execute_with_caller(code, &tools, caller)
+            .await
+            .unwrap();
+        assert_eq!(
+            result.error_message.as_deref(),
+            Some("workflow.map: retries must be a non-negative integer")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deadline_remaining() {
+        let runtime = JsRuntime::new().await.unwrap();
+        let tools = ["square".to_string(), "add".to_string()];
+        let caller = Arc::new(Arithmetic::default());
+        let result = runtime
+            .execute_with_caller(
+                "deadline.remainingMs() === Infinity",
+                &tools,
+                caller.clone(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.value, true);
+
+        let options = ExecutionOptions {
+            timeout: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let code = r#"
+            var left = deadline.remainingMs();
+            var mapped = workflow.map([1, 2], function(n) { return tools.square({n: n}); }, {reserveMs: 5000});
+            var reserved = workflow.map([1, 2], function(n) { return tools.square({n: n}); }, {reserveMs: 20000});
+            var dag = workflow.dag({
+                steps: {a: function() { return tools.square({n: 3}); }, b: function(deps) { return deps.a; }},
+                dependsOn: {b: ['a']}
+            }, {reserveMs: 20000});
+            ({left: left > 9000 && left <= 10000, mapped: mapped, reserved: reserved, dag: dag})
+        "#;
+        let result = runtime
+            .execute_with_options(code, &tools, caller, options)
+            .await
+            .unwrap();
+        assert!(!result.is_error, "{:?}", result.error_message);
+        assert_eq!(result.value["left"], true);
+        assert_eq!(
+            result.value["mapped"],
+            serde_json::json!({"results": [1.0, 4.0], "errors": [], "skipped": []})
+        );
+        assert_eq!(
+            result.value["reserved"],
+            serde_json::json!({"results": [null, null], "errors": [], "skipped": [0, 1]})
+        );
+        assert_eq!(
+            result.value["dag"]["skipped"],
+            serde_json::json!(["a", "b"])
+        );
+        assert_eq!(result.value["dag"]["results"], serde_json::json!({}));
+    }
+
+    struct BigText;
+
+    impl ToolCaller for BigText {
+        fn call_tool<'a>(
+            &'a self,
+            _name: &'a str,
+            _args: Option<serde_json::Value>,
+        ) -> ToolCallFuture<'a> {
+            use rmcp::model::Content;
+            Box::pin(async move {
+                Ok(CallToolResult::success(vec![Content::text(
+                    "0123456789".repeat(1000),
+                )]))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lazy_tool_result_reader() {
+        let runtime = JsRuntime::new().await.unwrap();
+        let options = ExecutionOptions {
+            lazy_tools: HashSet::from(["dump".to_string()]),
+            ..Default::default()
+        };
+        let result = runtime
+            .execute_with_options(
+                r#"
+                var r = tools.dump({});
+                ({length: r.length, head: r.read(0, 5), tail: r.read(9995), full: r.text().length})
+                "#,
+                &["dump".to_string()],
+                Arc::new(BigText),
+                options,
+            )
+            .await
+            .unwrap();
+
+        assert!(!result.is_error, "{:?}", result.error_message);
+        assert_eq!(result.value["length"].as_f64(), Some(10000.0));
+        assert_eq!(result.value["head"], "01234");
+        assert_eq!(result.value["tail"], "56789");
+        assert_eq!(result.value["full"].as_f64(), Some(10000.0));
+    }
+
+    #[test]
+    fn test_random_id() {
+        let id = random_id("exec");
+        let digits = id.strip_prefix("exec-").unwrap();
+        assert_eq!(digits.len(), 32);
+        assert!(digits.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(id, random_id("exec"));
+    }
+
     #[test]
     fn test_format_call_result_with_text() {
         use rmcp::model::{CallToolResult, Content};
@@ -438,4 +2912,36 @@ mod tests {
         assert_eq!(arr[1]["data"], "SGVsbG8=");
         assert_eq!(arr[1]["mimeType"], "image/png");
     }
+
+    #[test]
+    fn test_format_call_result_with_resources_and_audio() {
+        use rmcp::model::{AnnotateAble, Content, RawAudioContent, RawContent, RawResource};
+
+        let result = CallToolResult::success(vec![
+            Content::resource(ResourceContents::TextResourceContents {
+                uri: "file:///notes.md".to_string(),
+                mime_type: Some("text/markdown".to_string()),
+                text: "# Notes".to_string(),
+                meta: None,
+            }),
+            RawContent::Audio(RawAudioContent {
+                data: "UklGRg==".to_string(),
+                mime_type: "audio/wav".to_string(),
+            })
+            .no_annotation(),
+            Content::resource_link(RawResource::new("file:///board.kicad_pcb", "board")),
+        ]);
+        let parsed: serde_json::Value = serde_json::from_str(&format_call_result(&result)).unwrap();
+
+        assert_eq!(parsed[0]["type"], "resource");
+        assert_eq!(parsed[0]["resource"]["uri"], "file:///notes.md");
+        assert_eq!(parsed[0]["resource"]["text"], "# Notes");
+        assert_eq!(
+            parsed[1],
+            serde_json::json!({"type": "audio", "data": "UklGRg==", "mimeType": "audio/wav"})
+        );
+        assert_eq!(parsed[2]["type"], "resource_link");
+        assert_eq!(parsed[2]["uri"], "file:///board.kicad_pcb");
+        assert_eq!(parsed[2]["name"], "board");
+    }
 }
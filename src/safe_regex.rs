@@ -0,0 +1,647 @@
+//! Linear-time regular expressions for scripts, enabled with the
+//! `safe_regex` setting.
+//!
+//! The JavaScript engines match regular expressions by backtracking, and
+//! neither checks the interrupt handler while doing so: a pattern like
+//! `/(a+)+$/` against a long run of `a`s holds the script thread far past
+//! any timeout. With `safe_regex`, `RegExp.prototype.exec` and the
+//! `Symbol.match`, `Symbol.matchAll`, `Symbol.replace` and `Symbol.split`
+//! methods that `String.prototype` delegates to are replaced by ones that
+//! match with the `regex` crate, which runs in time linear in the input.
+//!
+//! Patterns are translated from JavaScript syntax. Lookahead, lookbehind
+//! and backreferences have no linear-time implementation, so patterns that
+//! use them fail with a `SyntaxError` when they are run.
+
+use anyhow::Result;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, LazyLock, Mutex};
+
+/// Replaces the regex methods with ones that call `__regex(json)` (JSON
+/// string in, JSON string out).
+pub(crate) const SAFE_REGEX_PRELUDE: &str = r#"
+    (function() {
+        function run(re, input, start, all, flags) {
+            var response = JSON.parse(__regex(JSON.stringify({
+                source: re.source,
+                flags: flags === undefined ? re.flags : flags,
+                input: input,
+                start: start,
+                all: all
+            })));
+            if (response.error) {
+                throw new SyntaxError(response.error);
+            }
+            return response.result;
+        }
+        function undefinedForNull(value) {
+            return value === null ? undefined : value;
+        }
+        function groupsOf(m) {
+            if (!m.groups) {
+                return undefined;
+            }
+            var groups = Object.create(null);
+            Object.keys(m.groups).forEach(function(name) {
+                groups[name] = undefinedForNull(m.groups[name]);
+            });
+            return groups;
+        }
+        function toResult(m, input) {
+            var result = m.captures.map(undefinedForNull);
+            result.index = m.index;
+            result.input = input;
+            result.groups = groupsOf(m);
+            return result;
+        }
+        // The first match from `lastIndex` for global and sticky patterns,
+        // updating it as `exec` does
+        function first(re, input) {
+            var advance = re.global || re.sticky;
+            var start = advance ? Math.max(0, Math.floor(Number(re.lastIndex)) || 0) : 0;
+            var found = start > input.length ? [] : run(re, input, start, false);
+            if (found.length === 0) {
+                if (advance) {
+                    re.lastIndex = 0;
+                }
+                return null;
+            }
+            if (advance) {
+                re.lastIndex = found[0].end;
+            }
+            return found[0];
+        }
+        function substitute(template, m, input) {
+            var captures = m.captures.map(undefinedForNull);
+            var groups = groupsOf(m);
+            var result = '';
+            for (var i = 0; i < template.length; i++) {
+                var c = template[i];
+                var next = template[i + 1];
+                if (c !== '$' || next === undefined) {
+                    result += c;
+                } else if (next === '$') {
+                    result += '$';
+                    i++;
+                } else if (next === '&') {
+                    result += captures[0];
+                    i++;
+                } else if (next === '`') {
+                    result += input.slice(0, m.index);
+                    i++;
+                } else if (next === "'") {
+                    result += input.slice(m.end);
+                    i++;
+                } else if (next >= '0' && next <= '9') {
+                    var digits = next;
+                    var after = template[i + 2];
+                    if (after >= '0' && after <= '9' && Number(next + after) < captures.length) {
+                        digits = next + after;
+                    }
+                    var n = Number(digits);
+                    if (n >= 1 && n < captures.length) {
+                        result += captures[n] === undefined ? '' : captures[n];
+                        i += digits.length;
+                    } else {
+                        result += c;
+                    }
+                } else if (next === '<' && groups !== undefined && template.indexOf('>', i) > 0) {
+                    var close = template.indexOf('>', i);
+                    var value = groups[template.slice(i + 2, close)];
+                    result += value === undefined ? '' : value;
+                    i = close;
+                } else {
+                    result += c;
+                }
+            }
+            return result;
+        }
+        function define(name, fn) {
+            Object.defineProperty(RegExp.prototype, name, {
+                value: fn,
+                writable: true,
+                configurable: true
+            });
+        }
+        define('exec', function(input) {
+            input = String(input);
+            var m = first(this, input);
+            return m === null ? null : toResult(m, input);
+        });
+        define(Symbol.match, function(input) {
+            input = String(input);
+            if (!this.global) {
+                return this.exec(input);
+            }
+            this.lastIndex = 0;
+            var found = run(this, input, 0, true);
+            return found.length === 0 ? null : found.map(function(m) { return m.captures[0]; });
+        });
+        define(Symbol.matchAll, function(input) {
+            input = String(input);
+            var found;
+            if (this.global) {
+                found = run(this, input, Math.max(0, Number(this.lastIndex) || 0), true);
+            } else {
+                var m = first(this, input);
+                found = m === null ? [] : [m];
+            }
+            var i = 0;
+            var iterator = {
+                next: function() {
+                    return i < found.length
+                        ? {value: toResult(found[i++], input), done: false}
+                        : {value: undefined, done: true};
+                }
+            };
+            iterator[Symbol.iterator] = function() { return this; };
+            return iterator;
+        });
+        define(Symbol.replace, function(input, replacement) {
+            input = String(input);
+            var found;
+            if (this.global) {
+                this.lastIndex = 0;
+                found = run(this, input, 0, true);
+            } else {
+                var m = first(this, input);
+                found = m === null ? [] : [m];
+            }
+            var functional = typeof replacement === 'function';
+            if (!functional) {
+                replacement = String(replacement);
+            }
+            var result = '';
+            var position = 0;
+            found.forEach(function(m) {
+                var replaced;
+                if (functional) {
+                    var args = m.captures.map(undefinedForNull).concat([m.index, input]);
+                    var groups = groupsOf(m);
+                    if (groups !== undefined) {
+                        args.push(groups);
+                    }
+                    replaced = String(replacement.apply(undefined, args));
+                } else {
+                    replaced = substitute(replacement, m, input);
+                }
+                result += input.slice(position, m.index) + replaced;
+                position = m.end;
+            });
+            return result + input.slice(position);
+        });
+        define(Symbol.split, function(input, limit) {
+            input = String(input);
+            var max = limit === undefined ? 4294967295 : limit >>> 0;
+            var result = [];
+            if (max === 0) {
+                return result;
+            }
+            var flags = this.flags.replace('y', '').replace('g', '');
+            if (input.length === 0) {
+                return run(this, input, 0, false, flags).length === 0 ? [input] : result;
+            }
+            var position = 0;
+            var found = run(this, input, 0, true, flags);
+            for (var i = 0; i < found.length; i++) {
+                var m = found[i];
+                // Empty matches where the last piece ended, or at the end
+                if (m.end === position || m.index >= input.length) {
+                    continue;
+                }
+                result.push(input.slice(position, m.index));
+                if (result.length === max) {
+                    return result;
+                }
+                for (var g = 1; g < m.captures.length; g++) {
+                    result.push(undefinedForNull(m.captures[g]));
+                    if (result.length === max) {
+                        return result;
+                    }
+                }
+                position = m.end;
+            }
+            result.push(input.slice(position));
+            return result;
+        });
+    })();
+"#;
+
+/// A call from the prelude: run `source` with `flags` on `input` from the
+/// UTF-16 offset `start`, for the first match or, with `all`, every match.
+#[derive(Debug, Deserialize)]
+struct RegexRequest {
+    source: String,
+    flags: String,
+    input: String,
+    start: usize,
+    all: bool,
+}
+
+/// A match, with offsets in UTF-16 code units as JavaScript counts them.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RegexMatch {
+    pub index: usize,
+    pub end: usize,
+    /// The whole match followed by each group, null where a group did not
+    /// take part
+    pub captures: Vec<Option<String>>,
+    /// Named groups, if the pattern has any
+    pub groups: Option<BTreeMap<String, Option<String>>>,
+}
+
+/// Serve `__regex(json)`, returning `{"result": [...]}` or `{"error": ...}`
+/// as JSON text.
+pub(crate) fn call_regex(request: &str) -> String {
+    let result = serde_json::from_str::<RegexRequest>(request)
+        .map_err(|e| anyhow::anyhow!("Invalid regex request: {e}"))
+        .and_then(|r| exec(&r.source, &r.flags, &r.input, r.start, r.all));
+    match result {
+        Ok(result) => serde_json::json!({ "result": result }).to_string(),
+        Err(e) => serde_json::json!({ "error": e.to_string() }).to_string(),
+    }
+}
+
+type Cache = HashMap<(String, String), Arc<Regex>>;
+
+/// Compiled patterns by source and flags, since scripts tend to run the same
+/// few over and over.
+static CACHE: LazyLock<Mutex<Cache>> = LazyLock::new(Default::default);
+
+const CACHE_SIZE: usize = 128;
+
+fn compile(source: &str, flags: &str) -> Result<Arc<Regex>> {
+    let key = (source.to_string(), flags.to_string());
+    if let Some(regex) = CACHE.lock().unwrap().get(&key) {
+        return Ok(regex.clone());
+    }
+    let pattern = translate(source, flags.contains('s'))?;
+    let regex = RegexBuilder::new(&pattern)
+        .case_insensitive(flags.contains('i'))
+        .multi_line(flags.contains('m'))
+        .crlf(flags.contains('m'))
+        .build()
+        .map_err(|e| anyhow::anyhow!("Invalid regular expression /{source}/: {e}"))?;
+    let regex = Arc::new(regex);
+    let mut cache = CACHE.lock().unwrap();
+    if cache.len() >= CACHE_SIZE {
+        cache.clear();
+    }
+    cache.insert(key, regex.clone());
+    Ok(regex)
+}
+
+/// Matches of the JavaScript pattern `source` with `flags` in `input`,
+/// starting at the UTF-16 offset `start`: the first one, or with `all` each
+/// one after the last as a global regex finds them. With the sticky flag
+/// `y`, matches must start where the search does.
+pub fn exec(
+    source: &str,
+    flags: &str,
+    input: &str,
+    start: usize,
+    all: bool,
+) -> Result<Vec<RegexMatch>> {
+    let regex = compile(source, flags)?;
+    let sticky = flags.contains('y');
+    let mut offsets = Utf16Offsets::new(input);
+    let mut position = offsets.byte_offset(start);
+    let mut matches = Vec::new();
+    while position <= input.len() {
+        let Some(captures) = regex.captures_at(input, position) else {
+            break;
+        };
+        let whole = captures.get(0).expect("group 0 is the whole match");
+        if sticky && whole.start() != position {
+            break;
+        }
+        let groups = regex.capture_names().any(|name| name.is_some()).then(|| {
+            regex
+                .capture_names()
+                .enumerate()
+                .filter_map(|(i, name)| {
+                    name.map(|name| {
+                        (
+                            name.to_string(),
+                            captures.get(i).map(|c| c.as_str().to_string()),
+                        )
+                    })
+                })
+                .collect()
+        });
+        matches.push(RegexMatch {
+            index: offsets.units(whole.start()),
+            end: offsets.units(whole.end()),
+            captures: captures
+                .iter()
+                .map(|c| c.map(|c| c.as_str().to_string()))
+                .collect(),
+            groups,
+        });
+        if !all {
+            break;
+        }
+        // Step over empty matches, as `String.prototype.replace` does
+        position = if whole.is_empty() {
+            input[whole.end()..]
+                .chars()
+                .next()
+                .map_or(input.len() + 1, |c| whole.end() + c.len_utf8())
+        } else {
+            whole.end()
+        };
+    }
+    Ok(matches)
+}
+
+/// Converts between byte offsets into a string and the UTF-16 offsets
+/// JavaScript uses, for offsets that only move forward.
+struct Utf16Offsets<'a> {
+    text: &'a str,
+    byte: usize,
+    unit: usize,
+}
+
+impl<'a> Utf16Offsets<'a> {
+    fn new(text: &'a str) -> Self {
+        Self {
+            text,
+            byte: 0,
+            unit: 0,
+        }
+    }
+
+    /// The byte offset of the UTF-16 offset `units`, rounded up to a whole
+    /// character; past the end for offsets beyond it.
+    fn byte_offset(&self, units: usize) -> usize {
+        let mut unit = 0;
+        for (byte, c) in self.text.char_indices() {
+            if unit >= units {
+                return byte;
+            }
+            unit += c.len_utf16();
+        }
+        if unit >= units {
+            self.text.len()
+        } else {
+            self.text.len() + 1
+        }
+    }
+
+    fn units(&mut self, byte: usize) -> usize {
+        if byte < self.byte {
+            self.byte = 0;
+            self.unit = 0;
+        }
+        self.unit += self.text[self.byte..byte].encode_utf16().count();
+        self.byte = byte;
+        self.unit
+    }
+}
+
+/// `source`, a JavaScript pattern, in the `regex` crate's syntax.
+pub fn translate(source: &str, dot_all: bool) -> Result<String> {
+    let unsupported = |what: &str| {
+        anyhow::anyhow!(
+            "Invalid regular expression /{source}/: {what} are not supported by the linear-time regex engine"
+        )
+    };
+    let chars: Vec<char> = source.chars().collect();
+    let mut out = String::with_capacity(source.len());
+    let mut in_class = false;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '\\' => {
+                let Some(&next) = chars.get(i + 1) else {
+                    anyhow::bail!("Invalid regular expression /{source}/: \\ at end of pattern");
+                };
+                i += 2;
+                match next {
+                    '1'..='9' => return Err(unsupported("backreferences")),
+                    'k' if chars.get(i) == Some(&'<') => return Err(unsupported("backreferences")),
+                    '0' => out.push_str("\\x00"),
+                    'd' => out.push_str(if in_class { "0-9" } else { "[0-9]" }),
+                    'D' => out.push_str("[^0-9]"),
+                    'w' => out.push_str(if in_class {
+                        "0-9A-Za-z_"
+                    } else {
+                        "[0-9A-Za-z_]"
+                    }),
+                    'W' => out.push_str("[^0-9A-Za-z_]"),
+                    'b' if in_class => out.push_str("\\x08"),
+                    'b' => out.push_str("(?-u:\\b)"),
+                    'B' => out.push_str("(?-u:\\B)"),
+                    's' | 'S' | 't' | 'n' | 'r' | 'f' | 'v' => {
+                        out.push('\\');
+                        out.push(next);
+                    }
+                    'p' | 'P' if chars.get(i) == Some(&'{') => {
+                        let close = chars[i..].iter().position(|c| *c == '}').ok_or_else(|| {
+                            anyhow::anyhow!(
+                                "Invalid regular expression /{source}/: unterminated \\{next}{{"
+                            )
+                        })?;
+                        out.push('\\');
+                        out.push(next);
+                        out.extend(&chars[i..i + close + 1]);
+                        i += close + 1;
+                    }
+                    'c' if chars.get(i).is_some_and(|c| c.is_ascii_alphabetic()) => {
+                        out.push_str(&format!("\\x{{{:x}}}", chars[i] as u32 % 32));
+                        i += 1;
+                    }
+                    'x' if chars
+                        .get(i..i + 2)
+                        .is_some_and(|h| h.iter().all(char::is_ascii_hexdigit)) =>
+                    {
+                        out.push_str("\\x");
+                        out.extend(&chars[i..i + 2]);
+                        i += 2;
+                    }
+                    'u' => {
+                        let (code, len) = unicode_escape(&chars[i..]).ok_or_else(|| {
+                            anyhow::anyhow!(
+                                "Invalid regular expression /{source}/: invalid \\u escape"
+                            )
+                        })?;
+                        i += len;
+                        let code = if (0xD800..0xDC00).contains(&code)
+                            && chars.get(i) == Some(&'\\')
+                            && chars.get(i + 1) == Some(&'u')
+                        {
+                            match unicode_escape(&chars[i + 2..]) {
+                                Some((low, len)) if (0xDC00..0xE000).contains(&low) => {
+                                    i += len + 2;
+                                    0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00)
+                                }
+                                _ => code,
+                            }
+                        } else {
+                            code
+                        };
+                        if char::from_u32(code).is_none() {
+                            return Err(unsupported("unpaired surrogates"));
+                        }
+                        out.push_str(&format!("\\x{{{code:x}}}"));
+                    }
+                    other => out.push_str(&regex::escape(&other.to_string())),
+                }
+                continue;
+            }
+            '[' if !in_class => {
+                match (chars.get(i + 1), chars.get(i + 2)) {
+                    // `[^]` matches anything and `[]` nothing
+                    (Some('^'), Some(']')) => {
+                        out.push_str("(?s:.)");
+                        i += 3;
+                        continue;
+                    }
+                    (Some(']'), _) => {
+                        out.push_str("[^\\x00-\\x{10FFFF}]");
+                        i += 2;
+                        continue;
+                    }
+                    _ => {}
+                }
+                in_class = true;
+                out.push('[');
+                if chars.get(i + 1) == Some(&'^') {
+                    out.push('^');
+                    i += 1;
+                }
+            }
+            ']' if in_class => {
+                in_class = false;
+                out.push(']');
+            }
+            // Literal in a JavaScript class, set syntax in a `regex` one
+            '[' | '&' | '~' if in_class => {
+                out.push('\\');
+                out.push(c);
+            }
+            '(' if chars.get(i + 1) == Some(&'?') => {
+                let rest: String = chars[i + 2..chars.len().min(i + 4)].iter().collect();
+                if rest.starts_with('=')
+                    || rest.starts_with('!')
+                    || rest.starts_with("<=")
+                    || rest.starts_with("<!")
+                {
+                    return Err(unsupported("lookahead and lookbehind assertions"));
+                }
+                out.push(c);
+            }
+            '.' if !in_class => {
+                out.push_str(if dot_all {
+                    "(?s:.)"
+                } else {
+                    "[^\\n\\r\\x{2028}\\x{2029}]"
+                });
+            }
+            '{' if !in_class && !is_quantifier(&chars[i..]) => out.push_str("\\{"),
+            // Quantifiers are copied whole below, so this brace is literal
+            '}' if !in_class => out.push_str("\\}"),
+            '{' if !in_class => {
+                let close = chars[i..].iter().position(|c| *c == '}').expect("checked");
+                out.extend(&chars[i..i + close + 1]);
+                i += close + 1;
+                continue;
+            }
+            _ => out.push(c),
+        }
+        i += 1;
+    }
+    Ok(out)
+}
+
+/// Whether `chars`, starting at `{`, is a `{n}`, `{n,}` or `{n,m}` quantifier.
+fn is_quantifier(chars: &[char]) -> bool {
+    let Some(close) = chars.iter().position(|c| *c == '}') else {
+        return false;
+    };
+    let inner: String = chars[1..close].iter().collect();
+    let (min, max) = inner.split_once(',').unwrap_or((&inner, "0"));
+    !min.is_empty()
+        && min.chars().all(|c| c.is_ascii_digit())
+        && max.chars().all(|c| c.is_ascii_digit())
+}
+
+/// The code point of `XXXX` or `{X...}` after `\u`, and how many characters
+/// it took.
+fn unicode_escape(chars: &[char]) -> Option<(u32, usize)> {
+    if chars.first() == Some(&'{') {
+        let close = chars.iter().position(|c| *c == '}')?;
+        let hex: String = chars[1..close].iter().collect();
+        return Some((u32::from_str_radix(&hex, 16).ok()?, close + 1));
+    }
+    let hex: String = chars.get(..4)?.iter().collect();
+    Some((u32::from_str_radix(&hex, 16).ok()?, 4))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spans(source: &str, flags: &str, input: &str) -> Vec<(usize, usize)> {
+        exec(source, flags, input, 0, true)
+            .unwrap()
+            .iter()
+            .map(|m| (m.index, m.end))
+            .collect()
+    }
+
+    #[test]
+    fn test_translate() {
+        assert_eq!(translate(r"\d+\.\d*", false).unwrap(), r"[0-9]+\.[0-9]*");
+        assert_eq!(translate(r"[\w-]", false).unwrap(), r"[0-9A-Za-z_-]");
+        assert_eq!(translate(r"a{2,}b{", false).unwrap(), r"a{2,}b\{");
+        assert_eq!(
+            translate(r"\u00e9\u{1F600}", false).unwrap(),
+            r"\x{e9}\x{1f600}"
+        );
+        assert_eq!(translate(r"\ud83d\ude00", false).unwrap(), r"\x{1f600}");
+        assert_eq!(translate(r"[^][a&&b]", false).unwrap(), r"(?s:.)[a\&\&b]");
+        assert!(translate(r"(a)\1", false).is_err());
+        assert!(translate(r"(?<=a)b", false).is_err());
+        assert!(translate(r"a(?!b)", false).is_err());
+        assert!(translate(r"(?<year>\d{4})", false).is_ok());
+    }
+
+    #[test]
+    fn test_exec() {
+        assert_eq!(spans("a+", "g", "baaab aa"), vec![(1, 4), (6, 8)]);
+        assert_eq!(spans("x*", "g", "ab"), vec![(0, 0), (1, 1), (2, 2)]);
+        // UTF-16 offsets, as JavaScript counts them
+        assert_eq!(spans("b", "g", "😀b"), vec![(2, 3)]);
+        assert_eq!(spans(".", "g", "a\nb"), vec![(0, 1), (2, 3)]);
+        assert_eq!(spans(".", "gs", "a\n"), vec![(0, 1), (1, 2)]);
+        assert_eq!(spans("^b", "gm", "a\r\nb"), vec![(3, 4)]);
+        assert_eq!(spans("a", "gy", "aaba"), vec![(0, 1), (1, 2)]);
+        assert_eq!(spans("A", "gi", "a"), vec![(0, 1)]);
+
+        let m = &exec(r"(?<year>\d{4})-(\d\d)?", "", "on 2026-", 0, false).unwrap()[0];
+        assert_eq!(
+            m.captures,
+            vec![Some("2026-".to_string()), Some("2026".to_string()), None]
+        );
+        assert_eq!(
+            m.groups,
+            Some(BTreeMap::from([(
+                "year".to_string(),
+                Some("2026".to_string())
+            )]))
+        );
+        assert!(exec("a", "", "bab", 2, false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_no_catastrophic_backtracking() {
+        let input = format!("{}!", "a".repeat(100_000));
+        let started = std::time::Instant::now();
+        assert!(exec("(a+)+$", "", &input, 0, false).unwrap().is_empty());
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,112 @@
+//! Estimating how much context a script saved the agent.
+//!
+//! Without code mode, every intermediate tool result would pass through the
+//! model's context. With it, only the script's return value does. Each
+//! execution's metrics report the bytes of downstream results the script
+//! consumed, the bytes it returned, and the difference as an estimated
+//! number of tokens, at `BYTES_PER_TOKEN`.
+
+use crate::runtime::{
+    ExecutionMetrics, ServerRequest, ServerRequestFuture, ToolCallFuture, ToolCaller,
+};
+use rmcp::model::CallToolResult;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The usual rule of thumb for English text and JSON.
+pub const BYTES_PER_TOKEN: usize = 4;
+
+/// The serialized size of a tool result: its content plus any structured
+/// content.
+pub fn result_bytes(result: &CallToolResult) -> usize {
+    serde_json::to_vec(&result.content).map_or(0, |v| v.len())
+        + result
+            .structured_content
+            .as_ref()
+            .map_or(0, |v| v.to_string().len())
+}
+
+/// Fill in the savings metrics of an execution that consumed `consumed`
+/// bytes of results and returned `value`.
+pub fn record_savings(metrics: &mut ExecutionMetrics, consumed: usize, value: &serde_json::Value) {
+    let returned = match value {
+        serde_json::Value::Null => 0,
+        value => value.to_string().len(),
+    };
+    metrics.consumed_bytes = consumed;
+    metrics.returned_bytes = returned;
+    metrics.estimated_tokens_saved = consumed.saturating_sub(returned) / BYTES_PER_TOKEN;
+}
+
+/// A `ToolCaller` that adds up the size of the results a script receives,
+/// including those answered by deduplication.
+pub struct SavingsToolCaller<C: ToolCaller> {
+    inner: C,
+    consumed: Arc<AtomicUsize>,
+}
+
+impl<C: ToolCaller> SavingsToolCaller<C> {
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            consumed: Arc::default(),
+        }
+    }
+
+    /// The running total in bytes, shared with this caller.
+    pub fn consumed(&self) -> Arc<AtomicUsize> {
+        self.consumed.clone()
+    }
+}
+
+impl<C: ToolCaller> ToolCaller for SavingsToolCaller<C> {
+    fn call_tool<'a>(
+        &'a self,
+        name: &'a str,
+        args: Option<serde_json::Value>,
+    ) -> ToolCallFuture<'a> {
+        Box::pin(async move {
+            let result = self.inner.call_tool(name, args).await?;
+            self.consumed
+                .fetch_add(result_bytes(&result), Ordering::Relaxed);
+            Ok(result)
+        })
+    }
+
+    fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+        self.inner.server_request(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rmcp::model::Content;
+
+    #[test]
+    fn test_record_savings() {
+        let mut metrics = ExecutionMetrics::default();
+        record_savings(&mut metrics, 4000, &serde_json::json!({"total": 42}));
+        assert_eq!(metrics.consumed_bytes, 4000);
+        assert_eq!(metrics.returned_bytes, 12);
+        assert_eq!(metrics.estimated_tokens_saved, 997);
+
+        // Returning more than was read saves nothing
+        record_savings(&mut metrics, 0, &serde_json::json!("hello"));
+        assert_eq!(metrics.returned_bytes, 7);
+        assert_eq!(metrics.estimated_tokens_saved, 0);
+
+        record_savings(&mut metrics, 10, &serde_json::Value::Null);
+        assert_eq!(metrics.returned_bytes, 0);
+    }
+
+    #[test]
+    fn test_result_bytes() {
+        let mut result = CallToolResult::success(vec![Content::text("abc")]);
+        let content = serde_json::to_vec(&result.content).unwrap().len();
+        assert_eq!(result_bytes(&result), content);
+
+        result.structured_content = Some(serde_json::json!({"a": 1}));
+        assert_eq!(result_bytes(&result), content + 7);
+    }
+}
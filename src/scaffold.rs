@@ -0,0 +1,104 @@
+//! Generates the `main.rs` glue for serving an rmcp server through
+//! `CodeModeWrapper`, used by `codemoder scaffold-wrapper`.
+
+use anyhow::Result;
+
+const MAIN_TEMPLATE: &str = r#"//! Generated by `codemoder scaffold-wrapper`. Serves `{SERVER_TYPE}` over stdio
+//! with an `execute_tools` tool that lets models script its tools.
+//!
+//! Set `CODEMODER_CONFIG` (and optionally `CODEMODER_PROFILE`) to load a
+//! codemoder config file instead of the defaults below.
+
+use anyhow::Result;
+use codemoder::{CodeModeConfig, CodeModeWrapper, Drain};
+use rmcp::ServiceExt;
+use std::time::Duration;
+use {SERVER_CRATE}::{SERVER_TYPE};
+
+fn config() -> Result<CodeModeConfig> {
+    if let Some(path) = std::env::var_os("CODEMODER_CONFIG") {
+        let profile = std::env::var("CODEMODER_PROFILE").ok();
+        return CodeModeConfig::from_file_with_profile(path, profile.as_deref());
+    }
+    Ok(CodeModeConfig::new()
+        .add()
+        .dedupe_read_only_calls(true)
+        .with_timeout(Duration::from_secs(30)))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
+        .init();
+
+    let config = config()?;
+    let drain = Drain::new();
+    let drain_timeout = config.drain_timeout();
+    let server =
+        CodeModeWrapper::new({SERVER_TYPE}::{CONSTRUCTOR}, config).with_drain(drain.clone());
+
+    let service = server.serve(rmcp::transport::stdio()).await?;
+    let ct = service.cancellation_token();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            drain.start();
+            drain.wait_idle(drain_timeout).await;
+            ct.cancel();
+        }
+    });
+    service.waiting().await?;
+    Ok(())
+}
+"#;
+
+/// Dependencies the generated `main.rs` needs in the server's Cargo.toml.
+pub fn dependencies() -> String {
+    r#"codemoder = "{VERSION}"
+rmcp = { version = "0.11", features = ["server", "transport-io"] }
+tokio = { version = "1", features = ["full"] }
+tracing-subscriber = { version = "0.3", features = ["env-filter"] }
+anyhow = "1"
+"#
+    .replace("{VERSION}", env!("CARGO_PKG_VERSION"))
+}
+
+/// Render `main.rs` for `server_type` exported from `server_crate`.
+/// `constructor` is the expression after `ServerType::`, e.g. `new()`.
+pub fn wrapper_main(server_crate: &str, server_type: &str, constructor: &str) -> Result<String> {
+    let crate_ident = server_crate.replace('-', "_");
+    for (what, value) in [
+        ("crate name", crate_ident.as_str()),
+        ("server type", server_type),
+    ] {
+        let valid = value
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            anyhow::bail!("Invalid {what} `{value}`");
+        }
+    }
+    Ok(MAIN_TEMPLATE
+        .replace("{SERVER_CRATE}", &crate_ident)
+        .replace("{SERVER_TYPE}", server_type)
+        .replace("{CONSTRUCTOR}", constructor))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrapper_main() {
+        let code = wrapper_main("my-server", "BoardServer", "new()").unwrap();
+        assert!(code.contains("use my_server::BoardServer;"));
+        assert!(code.contains("CodeModeWrapper::new(BoardServer::new(), config)"));
+        assert!(!code.contains("{SERVER"));
+
+        let err = wrapper_main("my server", "BoardServer", "new()").unwrap_err();
+        assert_eq!(err.to_string(), "Invalid crate name `my server`");
+    }
+}
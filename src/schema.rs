@@ -0,0 +1,185 @@
+//! Validation of downstream results against declared output schemas.
+//!
+//! With `schema_drift` set, every successful result handed to a script is
+//! checked against its tool's `outputSchema`, so a downstream regression
+//! surfaces where it happens instead of as a confusing failure later in the
+//! script. The structured content is validated, or the text content parsed
+//! as JSON when a tool only returns text.
+
+use crate::config::SchemaDriftAction;
+use crate::runtime::{ServerRequest, ServerRequestFuture, ToolCallFuture, ToolCaller};
+use jsonschema::Validator;
+use rmcp::model::{CallToolResult, Tool};
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+
+/// Compiled output schemas, keyed by exposed tool name.
+#[derive(Default)]
+pub struct OutputSchemas {
+    validators: HashMap<String, Validator>,
+}
+
+impl OutputSchemas {
+    /// Compile the output schemas of `tools`. Tools whose schema doesn't
+    /// compile are skipped with a warning.
+    pub fn from_tools(tools: &[Tool]) -> Self {
+        let validators = tools
+            .iter()
+            .filter_map(|tool| {
+                let schema = Value::Object(tool.output_schema.as_deref()?.clone());
+                match jsonschema::validator_for(&schema) {
+                    Ok(validator) => Some((tool.name.to_string(), validator)),
+                    Err(e) => {
+                        tracing::warn!("Ignoring invalid output schema of {}: {e}", tool.name);
+                        None
+                    }
+                }
+            })
+            .collect();
+        Self { validators }
+    }
+
+    /// Why `result` of `name` doesn't match its output schema, if it doesn't.
+    pub fn check(&self, name: &str, result: &CallToolResult) -> Option<String> {
+        let validator = self.validators.get(name)?;
+        if result.is_error == Some(true) {
+            return None;
+        }
+        let Some(value) = result_value(result) else {
+            return Some("result has no structured content".to_string());
+        };
+        let errors: Vec<String> = validator
+            .iter_errors(&value)
+            .map(|e| {
+                let path = e.instance_path.to_string();
+                if path.is_empty() {
+                    e.to_string()
+                } else {
+                    format!("{path}: {e}")
+                }
+            })
+            .collect();
+        (!errors.is_empty()).then(|| errors.join("; "))
+    }
+}
+
+fn result_value(result: &CallToolResult) -> Option<Value> {
+    if let Some(structured) = &result.structured_content {
+        return Some(structured.clone());
+    }
+    match result.content.as_slice() {
+        [content] => serde_json::from_str(&content.as_text()?.text).ok(),
+        _ => None,
+    }
+}
+
+/// A `ToolCaller` that checks results against their output schema and
+/// either fails the call or records the tool as quarantined.
+pub struct SchemaToolCaller<C: ToolCaller> {
+    inner: C,
+    schemas: Arc<OutputSchemas>,
+    action: SchemaDriftAction,
+    quarantined: Arc<Mutex<BTreeSet<String>>>,
+}
+
+impl<C: ToolCaller> SchemaToolCaller<C> {
+    pub fn new(inner: C, schemas: Arc<OutputSchemas>, action: SchemaDriftAction) -> Self {
+        Self {
+            inner,
+            schemas,
+            action,
+            quarantined: Arc::default(),
+        }
+    }
+
+    /// Tools whose results drifted from their schema, filled in as calls are
+    /// made under `SchemaDriftAction::Quarantine`.
+    pub fn quarantined(&self) -> Arc<Mutex<BTreeSet<String>>> {
+        self.quarantined.clone()
+    }
+}
+
+impl<C: ToolCaller> ToolCaller for SchemaToolCaller<C> {
+    fn call_tool<'a>(&'a self, name: &'a str, args: Option<Value>) -> ToolCallFuture<'a> {
+        Box::pin(async move {
+            let result = self.inner.call_tool(name, args).await?;
+            if self.action == SchemaDriftAction::Ignore {
+                return Ok(result);
+            }
+            if let Some(reason) = self.schemas.check(name, &result) {
+                if self.action == SchemaDriftAction::Error {
+                    anyhow::bail!("Schema drift in {name} result: {reason}");
+                }
+                tracing::warn!("Schema drift in {name} result: {reason}");
+                self.quarantined.lock().unwrap().insert(name.to_string());
+            }
+            Ok(result)
+        })
+    }
+
+    fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+        self.inner.server_request(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rmcp::model::Content;
+    use serde_json::json;
+
+    /// Returns `args.result` as text content.
+    struct Returns;
+
+    impl ToolCaller for Returns {
+        fn call_tool<'a>(&'a self, _name: &'a str, args: Option<Value>) -> ToolCallFuture<'a> {
+            let text = args.unwrap_or_default()["result"].to_string();
+            Box::pin(async move { Ok(CallToolResult::success(vec![Content::text(text)])) })
+        }
+    }
+
+    fn schemas() -> Arc<OutputSchemas> {
+        let mut tool = Tool::new("add", "Add", Arc::new(Default::default()));
+        tool.output_schema = Some(Arc::new(
+            json!({
+                "type": "object",
+                "properties": {"result": {"type": "number"}},
+                "required": ["result"],
+            })
+            .as_object()
+            .unwrap()
+            .clone(),
+        ));
+        Arc::new(OutputSchemas::from_tools(&[tool]))
+    }
+
+    #[tokio::test]
+    async fn test_schema_drift_error() {
+        let caller = SchemaToolCaller::new(Returns, schemas(), SchemaDriftAction::Error);
+
+        let ok = json!({"result": {"result": 3}});
+        assert!(caller.call_tool("add", Some(ok)).await.is_ok());
+        let drifted = json!({"result": {"result": "3"}});
+        let err = caller.call_tool("add", Some(drifted)).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"Schema drift in add result: /result: "3" is not of type "number""#
+        );
+        let other = json!({"result": "anything"});
+        assert!(caller.call_tool("other", Some(other)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_schema_drift_quarantine() {
+        let caller = SchemaToolCaller::new(Returns, schemas(), SchemaDriftAction::Quarantine);
+        let quarantined = caller.quarantined();
+
+        let drifted = json!({"result": {"sum": 3}});
+        assert!(caller.call_tool("add", Some(drifted)).await.is_ok());
+        assert_eq!(
+            quarantined.lock().unwrap().iter().collect::<Vec<_>>(),
+            vec!["add"]
+        );
+    }
+}
@@ -0,0 +1,438 @@
+//! MCP conformance checks against an assembled proxy, for
+//! `codemoder selftest`.
+//!
+//! The proxy is served in-process and an MCP client connected to it runs a
+//! fixed suite: the initialize handshake, listing tools, walking the tool
+//! list page by page, calling the execute tool, the errors of bad requests,
+//! progress and log notifications, and cancelling a running execution.
+//! Each check passes, or fails with what went wrong. The scripts it runs
+//! call no downstream tools, so the suite is safe to point at a real server.
+
+use crate::config::{CodeModeConfig, ScriptEngine};
+use crate::proxy::CodeModeProxy;
+use anyhow::{Context, ensure};
+use rmcp::model::{
+    CallToolRequest, CallToolRequestParam, CallToolResult, ClientRequest, ErrorCode, LoggingLevel,
+    LoggingMessageNotificationParam, PaginatedRequestParam, ProgressNotificationParam,
+    SetLevelRequestParam,
+};
+use rmcp::service::{NotificationContext, PeerRequestOptions, RunningService, ServiceError};
+use rmcp::{ClientHandler, RoleClient, ServiceExt};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a single check may take before it fails.
+pub const CHECK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait for a notification sent alongside a response.
+const NOTIFICATION_WAIT: Duration = Duration::from_secs(2);
+
+/// The outcome of one check.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Check {
+    pub name: String,
+    pub ok: bool,
+    /// What was seen, or what went wrong.
+    pub detail: String,
+}
+
+/// The outcome of the suite: `ok` when every check passed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Report {
+    pub ok: bool,
+    pub checks: Vec<Check>,
+}
+
+/// The scripts the checks run, in the configured engine's language.
+struct Scripts {
+    sum: &'static str,
+    throw: &'static str,
+    progress: &'static str,
+    log: &'static str,
+    spin: &'static str,
+}
+
+impl Scripts {
+    fn for_engine(engine: ScriptEngine) -> Self {
+        match engine {
+            ScriptEngine::Lua => Self {
+                sum: "return 1 + 2",
+                throw: "error('selftest failure')",
+                progress: "progress(1, 2, 'selftest') return 1",
+                log: "print('selftest') return 1",
+                spin: "while true do end",
+            },
+            ScriptEngine::Javascript | ScriptEngine::Boa => Self {
+                sum: "1 + 2",
+                throw: "throw new Error('selftest failure')",
+                progress: "progress(1, 2, 'selftest'); 1",
+                log: "console.log('selftest'); 1",
+                spin: "while (true) {}",
+            },
+        }
+    }
+}
+
+/// The client side of the suite, keeping the notifications it gets.
+#[derive(Clone, Default)]
+struct Observer {
+    progress: Arc<Mutex<Vec<ProgressNotificationParam>>>,
+    logs: Arc<Mutex<Vec<LoggingMessageNotificationParam>>>,
+}
+
+impl ClientHandler for Observer {
+    async fn on_progress(
+        &self,
+        params: ProgressNotificationParam,
+        _context: NotificationContext<RoleClient>,
+    ) {
+        self.progress.lock().unwrap().push(params);
+    }
+
+    async fn on_logging_message(
+        &self,
+        params: LoggingMessageNotificationParam,
+        _context: NotificationContext<RoleClient>,
+    ) {
+        self.logs.lock().unwrap().push(params);
+    }
+}
+
+type Client = RunningService<RoleClient, Observer>;
+
+/// Serve `proxy`, built from `config`, in-process and run the suite
+/// against it.
+pub async fn run(proxy: CodeModeProxy, config: &CodeModeConfig) -> Report {
+    let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+    tokio::spawn(async move {
+        if let Ok(service) = proxy.serve(tokio::io::split(server_io)).await {
+            let _ = service.waiting().await;
+        }
+    });
+    let observer = Observer::default();
+    let connect = async {
+        let client = observer
+            .clone()
+            .serve(tokio::io::split(client_io))
+            .await
+            .context("Handshake failed")?;
+        let detail = check_initialize(&client)?;
+        Ok((client, detail))
+    };
+    let (client, detail) = match within_timeout(connect).await {
+        Ok(connected) => connected,
+        Err(e) => {
+            return Report {
+                ok: false,
+                checks: vec![check_result("initialize", Err(e))],
+            };
+        }
+    };
+
+    let scripts = Scripts::for_engine(config.engine);
+    let checks = vec![
+        check_result("initialize", Ok(detail)),
+        check("list_tools", check_list_tools(&client, config)).await,
+        check("pagination", check_pagination(&client, config)).await,
+        check("call_tool", check_call_tool(&client, config, &scripts)).await,
+        check("errors", check_errors(&client, config, &scripts)).await,
+        check(
+            "notifications",
+            check_notifications(&client, &observer, config, &scripts),
+        )
+        .await,
+        check(
+            "cancellation",
+            check_cancellation(&client, config, &scripts),
+        )
+        .await,
+    ];
+    let _ = client.cancel().await;
+    Report {
+        ok: checks.iter().all(|check| check.ok),
+        checks,
+    }
+}
+
+async fn within_timeout<T>(run: impl Future<Output = anyhow::Result<T>>) -> anyhow::Result<T> {
+    tokio::time::timeout(CHECK_TIMEOUT, run)
+        .await
+        .unwrap_or_else(|_| anyhow::bail!("Timed out after {}s", CHECK_TIMEOUT.as_secs()))
+}
+
+async fn check(name: &str, run: impl Future<Output = anyhow::Result<String>>) -> Check {
+    check_result(name, within_timeout(run).await)
+}
+
+fn check_result(name: &str, result: anyhow::Result<String>) -> Check {
+    let (ok, detail) = match result {
+        Ok(detail) => (true, detail),
+        Err(e) => (false, format!("{e:#}")),
+    };
+    Check {
+        name: name.to_string(),
+        ok,
+        detail,
+    }
+}
+
+fn execute_request(config: &CodeModeConfig, code: &str) -> CallToolRequestParam {
+    CallToolRequestParam {
+        name: config.tool_name.clone().into(),
+        arguments: serde_json::json!({"code": code}).as_object().cloned(),
+    }
+}
+
+async fn execute(
+    client: &Client,
+    config: &CodeModeConfig,
+    code: &str,
+) -> anyhow::Result<CallToolResult> {
+    client
+        .call_tool(execute_request(config, code))
+        .await
+        .with_context(|| format!("Executing `{code}` failed"))
+}
+
+/// The text of `result`'s first content item.
+fn text(result: &CallToolResult) -> &str {
+    result
+        .content
+        .first()
+        .and_then(|content| content.as_text())
+        .map_or("", |text| text.text.as_str())
+}
+
+/// Wait a little for `seen` to hold, for notifications that may arrive just
+/// after the response they came with.
+async fn eventually(seen: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + NOTIFICATION_WAIT;
+    while !seen() {
+        if Instant::now() > deadline {
+            return false;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    true
+}
+
+fn check_initialize(client: &Client) -> anyhow::Result<String> {
+    let info = client
+        .peer_info()
+        .context("The server sent no initialize result")?;
+    ensure!(
+        info.capabilities.tools.is_some(),
+        "The server doesn't declare the tools capability"
+    );
+    ensure!(!info.server_info.name.is_empty(), "The server has no name");
+    Ok(format!(
+        "{} {}, protocol {}",
+        info.server_info.name, info.server_info.version, info.protocol_version
+    ))
+}
+
+async fn check_list_tools(client: &Client, config: &CodeModeConfig) -> anyhow::Result<String> {
+    let tools = client.list_all_tools().await?;
+    let mut names = HashSet::new();
+    for tool in &tools {
+        ensure!(names.insert(&tool.name), "{} is listed twice", tool.name);
+        ensure!(
+            tool.input_schema.get("type") == Some(&"object".into()),
+            "The input schema of {} isn't an object schema",
+            tool.name
+        );
+    }
+    ensure!(
+        names.iter().any(|name| **name == config.tool_name),
+        "{} isn't listed",
+        config.tool_name
+    );
+    Ok(format!("{} tools", tools.len()))
+}
+
+async fn check_pagination(client: &Client, config: &CodeModeConfig) -> anyhow::Result<String> {
+    let mut cursor = None;
+    let mut pages = 0;
+    let mut names = Vec::new();
+    loop {
+        let page = client
+            .list_tools(Some(PaginatedRequestParam { cursor }))
+            .await?;
+        pages += 1;
+        if let Some(size) = config.tools_page_size {
+            ensure!(
+                page.tools.len() <= size,
+                "Page {pages} has {} tools, more than tools_page_size ({size})",
+                page.tools.len()
+            );
+        }
+        names.extend(page.tools.into_iter().map(|tool| tool.name));
+        cursor = page.next_cursor;
+        if cursor.is_none() {
+            break;
+        }
+        ensure!(
+            pages <= names.len(),
+            "The cursors don't end after {pages} pages"
+        );
+    }
+    let unique: HashSet<_> = names.iter().collect();
+    ensure!(unique.len() == names.len(), "Pages list some tools twice");
+
+    match client
+        .list_tools(Some(PaginatedRequestParam {
+            cursor: Some("selftest-invalid-cursor".to_string()),
+        }))
+        .await
+    {
+        Err(ServiceError::McpError(e)) if e.code == ErrorCode::INVALID_PARAMS => {}
+        Err(e) => anyhow::bail!("An invalid cursor failed with {e}, not invalid params"),
+        Ok(_) => anyhow::bail!("An invalid cursor was accepted"),
+    }
+    Ok(format!("{} tools in {pages} pages", names.len()))
+}
+
+async fn check_call_tool(
+    client: &Client,
+    config: &CodeModeConfig,
+    scripts: &Scripts,
+) -> anyhow::Result<String> {
+    let result = execute(client, config, scripts.sum).await?;
+    ensure!(
+        result.is_error != Some(true),
+        "`{}` failed: {}",
+        scripts.sum,
+        text(&result)
+    );
+    ensure!(
+        text(&result).parse::<f64>().ok() == Some(3.0),
+        "`{}` returned {:?}, not 3",
+        scripts.sum,
+        text(&result)
+    );
+    let structured = result
+        .structured_content
+        .context("The result has no structured content")?;
+    ensure!(
+        structured["result"].as_f64() == Some(3.0),
+        "The structured result is {}, not 3",
+        structured["result"]
+    );
+    Ok(format!("`{}` returned 3", scripts.sum))
+}
+
+async fn check_errors(
+    client: &Client,
+    config: &CodeModeConfig,
+    scripts: &Scripts,
+) -> anyhow::Result<String> {
+    let unknown = client
+        .call_tool(CallToolRequestParam {
+            name: "codemoder_selftest_no_such_tool".into(),
+            arguments: None,
+        })
+        .await;
+    match unknown {
+        Err(ServiceError::McpError(_)) => {}
+        Ok(result) if result.is_error == Some(true) => {}
+        Ok(_) => anyhow::bail!("Calling an unknown tool succeeded"),
+        Err(e) => return Err(e).context("Calling an unknown tool failed outside MCP"),
+    }
+
+    let no_code = client
+        .call_tool(CallToolRequestParam {
+            name: config.tool_name.clone().into(),
+            arguments: Some(Default::default()),
+        })
+        .await;
+    match no_code {
+        Err(ServiceError::McpError(e)) if e.code == ErrorCode::INVALID_PARAMS => {}
+        Err(e) => anyhow::bail!("Executing without code failed with {e}, not invalid params"),
+        Ok(_) => anyhow::bail!("Executing without code was accepted"),
+    }
+
+    let thrown = execute(client, config, scripts.throw).await?;
+    ensure!(
+        thrown.is_error == Some(true),
+        "A script that throws didn't return an error result"
+    );
+    ensure!(
+        text(&thrown).contains("selftest failure"),
+        "The error result doesn't carry the script's error: {}",
+        text(&thrown)
+    );
+    Ok("unknown tool, missing code and a thrown error".to_string())
+}
+
+async fn check_notifications(
+    client: &Client,
+    observer: &Observer,
+    config: &CodeModeConfig,
+    scripts: &Scripts,
+) -> anyhow::Result<String> {
+    // Requests from rmcp clients carry a progress token
+    execute(client, config, scripts.progress).await?;
+    let progressed = eventually(|| {
+        observer.progress.lock().unwrap().iter().any(|progress| {
+            progress.progress == 1.0 && progress.message.as_deref() == Some("selftest")
+        })
+    })
+    .await;
+    ensure!(progressed, "`progress()` sent no progress notification");
+
+    if !config.stream_logs {
+        return Ok("progress; logs not checked, stream_logs is off".to_string());
+    }
+    client
+        .set_level(SetLevelRequestParam {
+            level: LoggingLevel::Debug,
+        })
+        .await?;
+    execute(client, config, scripts.log).await?;
+    let logged = eventually(|| {
+        observer
+            .logs
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|log| log.data.to_string().contains("selftest"))
+    })
+    .await;
+    ensure!(logged, "A script's log line sent no log notification");
+    Ok("progress and logs".to_string())
+}
+
+async fn check_cancellation(
+    client: &Client,
+    config: &CodeModeConfig,
+    scripts: &Scripts,
+) -> anyhow::Result<String> {
+    let request = ClientRequest::CallToolRequest(CallToolRequest {
+        method: Default::default(),
+        params: execute_request(config, scripts.spin),
+        extensions: Default::default(),
+    });
+    let handle = client
+        .send_cancellable_request(request, PeerRequestOptions::no_options())
+        .await?;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let cancelled = Instant::now();
+    handle.cancel(Some("selftest".to_string())).await?;
+
+    // The endless script holds the runtime until it is stopped
+    let result = execute(client, config, scripts.sum)
+        .await
+        .context("Executing after a cancellation failed")?;
+    ensure!(
+        result.is_error != Some(true),
+        "Executing after a cancellation failed: {}",
+        text(&result)
+    );
+    Ok(format!(
+        "The next execution finished {}ms after cancelling an endless script",
+        cancelled.elapsed().as_millis()
+    ))
+}
@@ -0,0 +1,322 @@
+//! Latency percentiles of downstream tools, checked against objectives.
+//!
+//! The durations of the last `WINDOW` calls to each downstream tool, from
+//! scripts or passed through, are kept while `stats_tool` is on, any tool
+//! sets `latency_slo_ms` under `[tools.<name>]` or `[call_timeout]` is
+//! adaptive, which derives timeouts from them. `codemode_stats` reports
+//! their p50, p95 and p99. Once a tool with an objective has `MIN_SAMPLES`
+//! calls in its window, a p95 above `latency_slo_ms` is a breach: it is
+//! logged as a warning with `tool`, `p95_ms` and `slo_ms` fields, which the
+//! `otel` feature exports with the call's span, and sent to the client as a
+//! `notifications/message` from logger `codemoder.slo`. Recovery is
+//! reported the same way, at `notice` level.
+
+use crate::alias::ToolAliases;
+use crate::config::CodeModeConfig;
+use crate::runtime::{ServerRequest, ServerRequestFuture, ToolCallFuture, ToolCaller};
+use rmcp::model::{LoggingLevel, LoggingMessageNotificationParam};
+use rmcp::service::{Peer, RoleServer};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Calls per tool that percentiles are computed over.
+pub const WINDOW: usize = 100;
+
+/// Calls a tool needs in its window before its objective is checked.
+pub const MIN_SAMPLES: usize = 20;
+
+#[derive(Debug, Default)]
+struct Window {
+    durations: VecDeque<Duration>,
+    breached: bool,
+    breaches: u64,
+}
+
+impl Window {
+    fn push(&mut self, duration: Duration) {
+        if self.durations.len() == WINDOW {
+            self.durations.pop_front();
+        }
+        self.durations.push_back(duration);
+    }
+
+    /// The `p`th percentile by nearest rank, in milliseconds.
+    fn percentile_ms(&self, p: f64) -> f64 {
+        let mut sorted: Vec<Duration> = self.durations.iter().copied().collect();
+        sorted.sort();
+        let Some(rank) = ((p / 100.0 * sorted.len() as f64).ceil() as usize).checked_sub(1) else {
+            return 0.0;
+        };
+        let ms = sorted[rank.min(sorted.len() - 1)].as_secs_f64() * 1000.0;
+        (ms * 10.0).round() / 10.0
+    }
+}
+
+/// A tool's p95 crossing its objective, in either direction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SloAlert {
+    /// The tool's downstream name.
+    pub tool: String,
+    pub p95_ms: f64,
+    pub slo_ms: u64,
+    /// True when the objective was breached, false when it recovered.
+    pub breached: bool,
+}
+
+impl SloAlert {
+    fn message(&self) -> String {
+        match self.breached {
+            true => format!(
+                "Latency objective breached: {} p95 is {}ms, above {}ms",
+                self.tool, self.p95_ms, self.slo_ms
+            ),
+            false => format!(
+                "Latency objective met again: {} p95 is {}ms, within {}ms",
+                self.tool, self.p95_ms, self.slo_ms
+            ),
+        }
+    }
+
+    fn level(&self) -> LoggingLevel {
+        match self.breached {
+            true => LoggingLevel::Warning,
+            false => LoggingLevel::Notice,
+        }
+    }
+}
+
+/// The latency windows of one server, shared by its executions.
+#[derive(Debug, Default)]
+pub struct LatencySlos {
+    enabled: bool,
+    /// Downstream tool name to its p95 objective.
+    objectives: HashMap<String, Duration>,
+    windows: Mutex<BTreeMap<String, Window>>,
+}
+
+impl LatencySlos {
+    pub fn from_config(config: &CodeModeConfig, aliases: &ToolAliases) -> Self {
+        let objectives: HashMap<String, Duration> = config
+            .tools
+            .iter()
+            .filter_map(|(name, settings)| {
+                let slo = settings.latency_slo_ms?;
+                Some((
+                    aliases.downstream(name).to_string(),
+                    Duration::from_millis(slo),
+                ))
+            })
+            .collect();
+        Self {
+            enabled: config.stats_tool
+                || !objectives.is_empty()
+                || config.call_timeout.as_ref().is_some_and(|t| t.adaptive),
+            objectives,
+            windows: Mutex::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Add a call to downstream tool `tool` to its window, returning an
+    /// alert, already logged, if its objective was just breached or met
+    /// again.
+    pub fn record(&self, tool: &str, duration: Duration) -> Option<SloAlert> {
+        if !self.enabled {
+            return None;
+        }
+        let mut windows = self.windows.lock().unwrap();
+        let window = windows.entry(tool.to_string()).or_default();
+        window.push(duration);
+        let slo = self.objectives.get(tool)?;
+        if window.durations.len() < MIN_SAMPLES {
+            return None;
+        }
+        let p95_ms = window.percentile_ms(95.0);
+        let breached = p95_ms > slo.as_secs_f64() * 1000.0;
+        if breached == window.breached {
+            return None;
+        }
+        window.breached = breached;
+        window.breaches += u64::from(breached);
+        let alert = SloAlert {
+            tool: tool.to_string(),
+            p95_ms,
+            slo_ms: slo.as_millis() as u64,
+            breached,
+        };
+        match breached {
+            true => tracing::warn!(tool, p95_ms, slo_ms = alert.slo_ms, "{}", alert.message()),
+            false => tracing::info!(tool, p95_ms, slo_ms = alert.slo_ms, "{}", alert.message()),
+        }
+        Some(alert)
+    }
+
+    /// The `p`th percentile latency of downstream tool `tool`, once it has
+    /// `MIN_SAMPLES` calls in its window.
+    pub fn percentile(&self, tool: &str, p: f64) -> Option<Duration> {
+        let windows = self.windows.lock().unwrap();
+        let window = windows
+            .get(tool)
+            .filter(|window| window.durations.len() >= MIN_SAMPLES)?;
+        Some(Duration::from_secs_f64(window.percentile_ms(p) / 1000.0))
+    }
+
+    /// Percentiles of each tool, and its objective if it has one, as
+    /// `codemode_stats` reports them.
+    pub fn report(&self) -> serde_json::Value {
+        let windows = self.windows.lock().unwrap();
+        let tools: serde_json::Map<String, serde_json::Value> = windows
+            .iter()
+            .map(|(name, window)| {
+                let mut report = serde_json::json!({
+                    "samples": window.durations.len(),
+                    "p50Ms": window.percentile_ms(50.0),
+                    "p95Ms": window.percentile_ms(95.0),
+                    "p99Ms": window.percentile_ms(99.0),
+                });
+                if let Some(slo) = self.objectives.get(name) {
+                    report["sloMs"] = (slo.as_millis() as u64).into();
+                    report["breached"] = window.breached.into();
+                    report["breaches"] = window.breaches.into();
+                }
+                (name.clone(), report)
+            })
+            .collect();
+        serde_json::Value::Object(tools)
+    }
+}
+
+/// Tell the client about `alert` if it asked for messages of that level.
+pub async fn notify(peer: &Peer<RoleServer>, client_level: LoggingLevel, alert: &SloAlert) {
+    if (alert.level() as u8) < (client_level as u8) {
+        return;
+    }
+    let _ = peer
+        .notify_logging_message(LoggingMessageNotificationParam {
+            level: alert.level(),
+            logger: Some("codemoder.slo".to_string()),
+            data: serde_json::json!({
+                "message": alert.message(),
+                "tool": alert.tool,
+                "p95Ms": alert.p95_ms,
+                "sloMs": alert.slo_ms,
+                "breached": alert.breached,
+            }),
+        })
+        .await;
+}
+
+/// A `ToolCaller` that times calls reaching downstream tools.
+pub struct SloToolCaller<C: ToolCaller> {
+    inner: C,
+    slos: Arc<LatencySlos>,
+    peer: Option<Peer<RoleServer>>,
+    client_level: LoggingLevel,
+}
+
+impl<C: ToolCaller> SloToolCaller<C> {
+    pub fn new(inner: C, slos: Arc<LatencySlos>) -> Self {
+        Self {
+            inner,
+            slos,
+            peer: None,
+            client_level: LoggingLevel::Info,
+        }
+    }
+
+    /// Send alerts to `peer`, which asked for messages from `level` up.
+    pub fn with_client(mut self, peer: Option<Peer<RoleServer>>, level: LoggingLevel) -> Self {
+        self.peer = peer;
+        self.client_level = level;
+        self
+    }
+}
+
+impl<C: ToolCaller> ToolCaller for SloToolCaller<C> {
+    fn call_tool<'a>(
+        &'a self,
+        name: &'a str,
+        args: Option<serde_json::Value>,
+    ) -> ToolCallFuture<'a> {
+        if !self.slos.is_enabled() {
+            return self.inner.call_tool(name, args);
+        }
+        Box::pin(async move {
+            let started = Instant::now();
+            let result = self.inner.call_tool(name, args).await;
+            if let Some(alert) = self.slos.record(name, started.elapsed())
+                && let Some(peer) = &self.peer
+            {
+                notify(peer, self.client_level, &alert).await;
+            }
+            result
+        })
+    }
+
+    fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+        self.inner.server_request(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slos(slo_ms: u64) -> LatencySlos {
+        let mut config = CodeModeConfig::new();
+        config
+            .tools
+            .entry("search".to_string())
+            .or_default()
+            .latency_slo_ms = Some(slo_ms);
+        LatencySlos::from_config(&config, &ToolAliases::from_config(&config))
+    }
+
+    #[test]
+    fn test_breach_and_recovery() {
+        let slos = slos(100);
+        for _ in 0..MIN_SAMPLES - 1 {
+            assert_eq!(slos.record("search", Duration::from_millis(500)), None);
+        }
+        let alert = slos.record("search", Duration::from_millis(500)).unwrap();
+        assert!(alert.breached);
+        assert_eq!((alert.p95_ms, alert.slo_ms), (500.0, 100));
+        // Reported once, not on every slow call
+        assert_eq!(slos.record("search", Duration::from_millis(500)), None);
+
+        let mut recovered = None;
+        for _ in 0..WINDOW {
+            recovered = recovered.or(slos.record("search", Duration::from_millis(10)));
+        }
+        assert!(!recovered.unwrap().breached);
+
+        let report = slos.report();
+        assert_eq!(report["search"]["samples"], WINDOW);
+        assert_eq!(report["search"]["p50Ms"], 10.0);
+        assert_eq!(report["search"]["breaches"], 1);
+        assert_eq!(report["search"]["breached"], false);
+    }
+
+    #[test]
+    fn test_percentiles_without_objective() {
+        let slos = LatencySlos::from_config(
+            &CodeModeConfig::new().with_stats_tool(true),
+            &ToolAliases::default(),
+        );
+        for ms in 1..=100 {
+            assert_eq!(slos.record("list", Duration::from_millis(ms)), None);
+        }
+        assert_eq!(
+            slos.report()["list"],
+            serde_json::json!({"samples": 100, "p50Ms": 50.0, "p95Ms": 95.0, "p99Ms": 99.0})
+        );
+
+        let off = LatencySlos::from_config(&CodeModeConfig::new(), &ToolAliases::default());
+        off.record("list", Duration::from_millis(1));
+        assert_eq!(off.report(), serde_json::json!({}));
+    }
+}
@@ -0,0 +1,223 @@
+//! The chain of `ToolCaller`s every script tool call goes through, built
+//! the same way by `CodeModeProxy` and `CodeModeWrapper` around whatever
+//! reaches their tools.
+//!
+//! Outermost first: dedup, savings, counting, approval, concurrency limits,
+//! schema drift, failure notes, coercion, strict arguments, cost, aliases,
+//! policy, roots, redaction, the upstream helpers, provenance, trace,
+//! recording, cache, circuit breaker, retries, usage, latency SLOs and
+//! timeouts. Layers that are switched off pass calls straight through.
+
+use crate::alias::{AliasToolCaller, ToolAliases};
+use crate::approval::{ApprovalToolCaller, Approver, guarded_tools};
+use crate::bundle::{RecordedCall, RecordingToolCaller};
+use crate::cache::{CacheToolCaller, ResultCache};
+use crate::circuit::{CircuitBreaker, CircuitToolCaller};
+use crate::coerce::{ArgCoercion, CoerceToolCaller};
+use crate::concurrency::{ConcurrencyLimits, LimitedToolCaller};
+use crate::config::{CodeModeConfig, SchemaDriftAction};
+use crate::cost::{CostModel, CostToolCaller};
+use crate::dedup::{DedupToolCaller, memoized_tools, read_only_tools};
+use crate::notes::{FailureNoteToolCaller, FailureNotes};
+use crate::policy::{Policy, PolicyToolCaller, client_name, tool_annotations};
+use crate::provenance::{CallResults, ProvenanceToolCaller};
+use crate::redact::{ArgRedactions, RedactToolCaller};
+use crate::retry::{RetryPolicy, RetryToolCaller, retryable_tools};
+use crate::roots::{PathArgs, RootsToolCaller, client_has_roots};
+use crate::runtime::{ExecutionResult, ToolCaller};
+use crate::savings::{SavingsToolCaller, record_savings};
+use crate::schema::{OutputSchemas, SchemaToolCaller};
+use crate::slo::{LatencySlos, SloToolCaller};
+use crate::strict::{StrictArgs, StrictArgsToolCaller};
+use crate::summary::CountingToolCaller;
+use crate::timeout::{CallTimeouts, TimeoutToolCaller};
+use crate::trace::{TraceEntry, TraceToolCaller};
+use crate::upstream::UpstreamToolCaller;
+use crate::usage::{UsageStats, UsageToolCaller};
+use rmcp::model::{LoggingLevel, ProgressToken, Tool};
+use rmcp::service::{Peer, RoleServer};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio_util::sync::CancellationToken;
+
+/// What one execution's stack is built from: the server's shared state and
+/// the execution's own settings.
+pub(crate) struct CallerStack<'a> {
+    pub config: &'a CodeModeConfig,
+    /// The tools scripts see, as cached for this execution.
+    pub tools: &'a [Tool],
+    pub limits: &'a Arc<ConcurrencyLimits>,
+    pub aliases: &'a Arc<ToolAliases>,
+    pub redactions: &'a Arc<ArgRedactions>,
+    pub path_args: &'a Arc<PathArgs>,
+    pub costs: &'a Arc<CostModel>,
+    pub usage: &'a Arc<UsageStats>,
+    pub slos: &'a Arc<LatencySlos>,
+    pub timeouts: &'a Arc<CallTimeouts>,
+    pub retry: &'a Arc<RetryPolicy>,
+    pub circuit: &'a Arc<CircuitBreaker>,
+    pub cache: &'a Arc<ResultCache>,
+    pub notes: &'a Arc<FailureNotes>,
+    pub approver: &'a Option<Arc<dyn Approver>>,
+    pub policy: &'a Option<Arc<dyn Policy>>,
+    /// The level the client asked to be logged at.
+    pub log_level: LoggingLevel,
+    /// Keep every call for a repro bundle.
+    pub record: bool,
+    /// The client that made the request, for the helpers that ask it
+    /// things and the layers that tell it things.
+    pub peer: Option<Peer<RoleServer>>,
+    pub progress_token: Option<ProgressToken>,
+    /// Stops retrying once the execution is cancelled.
+    pub cancellation: CancellationToken,
+    /// Trace every call for the result.
+    pub verbose: bool,
+    /// What the execution's session has already spent.
+    pub session_spent: f64,
+}
+
+impl CallerStack<'_> {
+    /// Wrap `downstream`, which calls the tools themselves, in every layer.
+    pub fn build(self, downstream: impl ToolCaller) -> ExecutionCaller {
+        let config = self.config;
+        let tools = self.tools;
+        let read_only = read_only_tools(tools, config)
+            .iter()
+            .map(|name| self.aliases.downstream(name).to_string())
+            .collect();
+        let cache_caller = CacheToolCaller::new(
+            CircuitToolCaller::new(
+                RetryToolCaller::new(
+                    UsageToolCaller::new(
+                        SloToolCaller::new(
+                            TimeoutToolCaller::new(downstream, self.timeouts.clone()),
+                            self.slos.clone(),
+                        )
+                        .with_client(self.peer.clone(), self.log_level),
+                        self.usage.clone(),
+                    ),
+                    self.retry.clone(),
+                    retryable_tools(tools, config, self.aliases),
+                )
+                .with_cancellation(self.cancellation),
+                self.circuit.clone(),
+            ),
+            self.cache.clone(),
+            read_only,
+        );
+        let cache_hits = cache_caller.hits();
+        let recording_caller = RecordingToolCaller::new(cache_caller, self.record);
+        let recorded = recording_caller.calls();
+        let trace_caller = TraceToolCaller::new(recording_caller, self.verbose);
+        let trace = trace_caller.entries();
+        let provenance_caller = ProvenanceToolCaller::new(trace_caller, config.provenance);
+        let provenance = provenance_caller.results();
+        let cost_caller = CostToolCaller::new(
+            AliasToolCaller::new(
+                PolicyToolCaller::new(
+                    RootsToolCaller::new(
+                        RedactToolCaller::new(
+                            UpstreamToolCaller::new(provenance_caller, self.peer.clone(), config)
+                                .with_progress_token(self.progress_token)
+                                .with_log_level(config.stream_logs.then_some(self.log_level)),
+                            self.redactions.clone(),
+                        ),
+                        self.path_args.clone(),
+                        self.peer.as_ref().is_some_and(client_has_roots),
+                    ),
+                    self.policy.clone(),
+                )
+                .with_client(self.peer.as_ref().and_then(client_name))
+                .with_annotations(tool_annotations(tools, self.aliases)),
+                self.aliases.clone(),
+            ),
+            self.costs.clone(),
+        )
+        .with_execution_budget(config.max_execution_cost)
+        .with_session_budget(config.max_session_cost, self.session_spent);
+        let cost = cost_caller.total();
+        let schemas = if config.schema_drift == SchemaDriftAction::Ignore {
+            Arc::default()
+        } else {
+            Arc::new(OutputSchemas::from_tools(tools))
+        };
+        let schema_caller = SchemaToolCaller::new(
+            FailureNoteToolCaller::new(
+                CoerceToolCaller::new(
+                    StrictArgsToolCaller::new(cost_caller, StrictArgs::from_config(config, tools)),
+                    ArgCoercion::from_config(config, tools),
+                ),
+                self.notes.clone(),
+                tools,
+            ),
+            schemas,
+            config.schema_drift,
+        );
+        let quarantined = schema_caller.quarantined();
+        let counting_caller = CountingToolCaller::new(ApprovalToolCaller::new(
+            LimitedToolCaller::new(schema_caller, self.limits.clone()),
+            self.approver.clone(),
+            guarded_tools(config),
+        ));
+        let tool_calls = counting_caller.counts();
+        let savings_caller = SavingsToolCaller::new(counting_caller);
+        let consumed = savings_caller.consumed();
+        let dedup_caller = DedupToolCaller::new(savings_caller, memoized_tools(tools, config));
+        let dedup_hits = dedup_caller.hits();
+
+        ExecutionCaller {
+            caller: Arc::new(dedup_caller),
+            dedup_hits,
+            cache_hits,
+            recorded,
+            trace,
+            provenance,
+            cost,
+            quarantined,
+            tool_calls,
+            consumed,
+        }
+    }
+}
+
+/// The outermost caller of an execution's stack, and what its layers
+/// report once the execution is done.
+pub(crate) struct ExecutionCaller {
+    pub caller: Arc<dyn ToolCaller>,
+    pub dedup_hits: Arc<AtomicUsize>,
+    pub cache_hits: Arc<AtomicUsize>,
+    /// Every call, when recording.
+    pub recorded: Arc<Mutex<Vec<RecordedCall>>>,
+    pub trace: Option<Arc<Mutex<Vec<TraceEntry>>>>,
+    pub provenance: Option<Arc<CallResults>>,
+    /// What the execution has cost so far.
+    pub cost: Arc<Mutex<f64>>,
+    pub quarantined: Arc<Mutex<BTreeSet<String>>>,
+    /// Calls that reached the tool, by tool name.
+    pub tool_calls: Arc<Mutex<BTreeMap<String, usize>>>,
+    /// Bytes of tool results handed to the script.
+    pub consumed: Arc<AtomicUsize>,
+}
+
+impl ExecutionCaller {
+    /// Put the layers' metrics, trace and provenance in `result`.
+    pub fn report(&self, result: &mut ExecutionResult) {
+        result.metrics.dedup_hits = self.dedup_hits.load(Ordering::Relaxed);
+        result.metrics.cache_hits = self.cache_hits.load(Ordering::Relaxed);
+        record_savings(
+            &mut result.metrics,
+            self.consumed.load(Ordering::Relaxed),
+            &result.value,
+        );
+        result.metrics.schema_drift = self.quarantined.lock().unwrap().iter().cloned().collect();
+        result.trace = self
+            .trace
+            .as_ref()
+            .map(|trace| std::mem::take(&mut *trace.lock().unwrap()));
+        result.provenance = self
+            .provenance
+            .as_ref()
+            .map(|calls| calls.sources(&result.value));
+    }
+}
@@ -0,0 +1,88 @@
+//! Switching traffic to a standby downstream server.
+//!
+//! With `--standby-command`, a second downstream server is started and
+//! initialized next to the first, sharing its connection to the client.
+//! `SIGUSR2` switches to it: later calls go to the standby, calls already
+//! sent to the old server get up to `drain_timeout_secs` to finish, the old
+//! server is shut down, the tools are listed again and the client is told
+//! they changed. The standby's identity and capabilities replace the old
+//! server's in the capability report and in what is passed through. A fresh standby is then started from the same command, so
+//! an upgraded server can be put in place without restarting the client's
+//! session.
+
+use crate::downstream::{DownstreamHandler, DownstreamServer};
+use crate::runtime::DownstreamClient;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+/// The downstream server in use, and the standby ready to replace it.
+#[derive(Clone)]
+pub struct Switchover {
+    downstream: Arc<Mutex<DownstreamClient>>,
+    handler: DownstreamHandler,
+    /// Where the server in use is described, updated on each switch.
+    server: DownstreamServer,
+    standby: Arc<Mutex<Option<DownstreamClient>>>,
+    /// How long requests to the old server get to finish.
+    grace: Duration,
+}
+
+impl Switchover {
+    /// Switch `downstream`, served by `handler` and described in `server`,
+    /// to standbys served by handlers sharing its state, giving requests to
+    /// the old server up to `grace` to finish.
+    pub fn new(
+        downstream: Arc<Mutex<DownstreamClient>>,
+        handler: DownstreamHandler,
+        server: DownstreamServer,
+        grace: Duration,
+    ) -> Self {
+        Self {
+            downstream,
+            handler,
+            server,
+            standby: Arc::default(),
+            grace,
+        }
+    }
+
+    /// The handler to serve a standby with, sharing the upstream client,
+    /// tool list changes and progress routes of the server in use.
+    pub fn handler(&self) -> DownstreamHandler {
+        self.handler.for_another_server()
+    }
+
+    /// Keep `client`, already initialized, as the standby, shutting down
+    /// any standby it replaces.
+    pub async fn set_standby(&self, client: DownstreamClient) {
+        let replaced = self.standby.lock().await.replace(client);
+        if let Some(replaced) = replaced {
+            let _ = replaced.cancel().await;
+        }
+    }
+
+    /// Put the standby in place of the server in use and shut the old one
+    /// down. Fails when no standby is ready.
+    pub async fn switch(&self) -> anyhow::Result<()> {
+        let Some(standby) = self.standby.lock().await.take() else {
+            anyhow::bail!("No standby downstream server is ready");
+        };
+        let mut downstream = self.downstream.lock().await;
+        self.server.set(standby.peer_info().cloned());
+        let old = std::mem::replace(&mut *downstream, standby);
+        drop(downstream);
+        self.handler.tools_changed().mark();
+        let requests = old.service().requests();
+        if !requests.wait_idle(self.grace).await {
+            tracing::warn!(
+                "Shutting down the old downstream server with {} requests still running",
+                requests.in_flight()
+            );
+        }
+        if let Err(e) = old.cancel().await {
+            tracing::debug!("Failed to shut down the old downstream server: {e}");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,268 @@
+//! The optional `stats` global, for scripts that aggregate numbers across
+//! tool results. Enabled with the `stats_helpers` setting.
+//!
+//! - `stats.sum(values, key)`
+//! - `stats.mean(values, key)`
+//! - `stats.median(values, key)`
+//! - `stats.percentile(values, p, key)`: the `p`th percentile (0-100), linearly
+//!   interpolated between the closest ranks
+//! - `stats.histogram(values, bins, key)`: `bins` (default 10) equal-width
+//!   buckets from the smallest to the largest value, as `[{min, max, count}]`
+//!
+//! `key` is optional: a property name or, from JavaScript, a function that
+//! picks the number out of each item, so `stats.mean(items, 'price')` works
+//! on tool results directly. Every value must be a number; a missing field
+//! is an error naming its index rather than a silent `NaN`. `mean`, `median`
+//! and `percentile` of no values are null.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+/// Wraps `__stats(json)` (JSON string in, JSON string out) into the `stats`
+/// object, picking values out with `key` before they are sent.
+pub(crate) const STATS_PRELUDE: &str = r#"
+    (function() {
+        function request(fn, values, key, args) {
+            if (!Array.isArray(values)) {
+                throw new Error('stats.' + fn + ': values must be an array');
+            }
+            if (key !== undefined && key !== null) {
+                var pick = typeof key === 'function' ? key : function(item) {
+                    return item == null ? undefined : item[key];
+                };
+                values = values.map(function(item) { return pick(item); });
+            }
+            args.fn = fn;
+            args.values = values.map(function(v) { return v === undefined ? null : v; });
+            var response = JSON.parse(__stats(JSON.stringify(args)));
+            if (response.error) {
+                throw new Error(response.error);
+            }
+            return response.result;
+        }
+        globalThis.stats = {
+            sum: function(values, key) { return request('sum', values, key, {}); },
+            mean: function(values, key) { return request('mean', values, key, {}); },
+            median: function(values, key) { return request('median', values, key, {}); },
+            percentile: function(values, p, key) {
+                return request('percentile', values, key, {p: p});
+            },
+            histogram: function(values, bins, key) {
+                return request('histogram', values, key, {bins: bins == null ? null : bins});
+            }
+        };
+    })();
+"#;
+
+/// Buckets `histogram` uses when the script doesn't say.
+pub const DEFAULT_BINS: usize = 10;
+
+/// One bucket of a histogram: values in `[min, max)`, or `[min, max]` for the
+/// last bucket.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Bin {
+    pub min: f64,
+    pub max: f64,
+    pub count: usize,
+}
+
+/// A call from the stats prelude, as `{"fn": ..., "values": [...], ...}`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "fn", rename_all = "camelCase")]
+enum StatsRequest {
+    Sum {
+        values: Vec<serde_json::Value>,
+    },
+    Mean {
+        values: Vec<serde_json::Value>,
+    },
+    Median {
+        values: Vec<serde_json::Value>,
+    },
+    Percentile {
+        values: Vec<serde_json::Value>,
+        p: serde_json::Value,
+    },
+    Histogram {
+        values: Vec<serde_json::Value>,
+        bins: Option<serde_json::Value>,
+    },
+}
+
+/// Serve `__stats(json)`, returning `{"result": ...}` or `{"error": ...}` as
+/// JSON text.
+pub(crate) fn call_stats(request: &str) -> String {
+    let result = serde_json::from_str::<StatsRequest>(request)
+        .map_err(|e| anyhow::anyhow!("Invalid stats request: {e}"))
+        .and_then(|request| match request {
+            StatsRequest::Sum { values } => Ok(serde_json::json!(sum(&numbers("sum", &values)?))),
+            StatsRequest::Mean { values } => {
+                Ok(serde_json::json!(mean(&numbers("mean", &values)?)))
+            }
+            StatsRequest::Median { values } => {
+                Ok(serde_json::json!(median(&numbers("median", &values)?)))
+            }
+            StatsRequest::Percentile { values, p } => {
+                let p = p
+                    .as_f64()
+                    .ok_or_else(|| anyhow::anyhow!("stats.percentile: p must be a number"))?;
+                let values = numbers("percentile", &values)?;
+                Ok(serde_json::json!(percentile(&values, p)?))
+            }
+            StatsRequest::Histogram { values, bins } => {
+                let bins = match bins {
+                    None | Some(serde_json::Value::Null) => DEFAULT_BINS,
+                    Some(bins) => bins.as_u64().filter(|b| *b > 0).ok_or_else(|| {
+                        anyhow::anyhow!("stats.histogram: bins must be a positive integer")
+                    })? as usize,
+                };
+                let values = numbers("histogram", &values)?;
+                Ok(serde_json::json!(histogram(&values, bins)))
+            }
+        });
+    match result {
+        Ok(result) => serde_json::json!({ "result": result }).to_string(),
+        Err(e) => serde_json::json!({ "error": e.to_string() }).to_string(),
+    }
+}
+
+/// `values` as numbers, or an error naming the first one that isn't.
+pub fn numbers(function: &str, values: &[serde_json::Value]) -> Result<Vec<f64>> {
+    values
+        .iter()
+        .enumerate()
+        .map(|(i, value)| {
+            value.as_f64().ok_or_else(|| {
+                anyhow::anyhow!("stats.{function}: value at index {i} is not a number: {value}")
+            })
+        })
+        .collect()
+}
+
+pub fn sum(values: &[f64]) -> f64 {
+    values.iter().sum()
+}
+
+pub fn mean(values: &[f64]) -> Option<f64> {
+    (!values.is_empty()).then(|| sum(values) / values.len() as f64)
+}
+
+pub fn median(values: &[f64]) -> Option<f64> {
+    percentile(values, 50.0).ok().flatten()
+}
+
+/// The `p`th percentile of `values`, interpolating linearly between the two
+/// closest ranks (as spreadsheets' `PERCENTILE.INC` does).
+pub fn percentile(values: &[f64], p: f64) -> Result<Option<f64>> {
+    if !(0.0..=100.0).contains(&p) {
+        anyhow::bail!("stats.percentile: p must be between 0 and 100, got {p}");
+    }
+    if values.is_empty() {
+        return Ok(None);
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let rank = p / 100.0 * (sorted.len() - 1) as f64;
+    let (lower, upper) = (rank.floor() as usize, rank.ceil() as usize);
+    let fraction = rank - lower as f64;
+    Ok(Some(
+        sorted[lower] + (sorted[upper] - sorted[lower]) * fraction,
+    ))
+}
+
+/// `bins` equal-width buckets spanning `values`.
+pub fn histogram(values: &[f64], bins: usize) -> Vec<Bin> {
+    let Some(min) = values.iter().copied().reduce(f64::min) else {
+        return Vec::new();
+    };
+    let max = values.iter().copied().fold(min, f64::max);
+    // All values equal: one bucket holds them all
+    let bins = if min == max { 1 } else { bins };
+    let width = (max - min) / bins as f64;
+    let mut histogram: Vec<Bin> = (0..bins)
+        .map(|i| Bin {
+            min: min + width * i as f64,
+            max: if i + 1 == bins {
+                max
+            } else {
+                min + width * (i + 1) as f64
+            },
+            count: 0,
+        })
+        .collect();
+    for value in values {
+        let index = if width == 0.0 {
+            0
+        } else {
+            (((value - min) / width) as usize).min(bins - 1)
+        };
+        histogram[index].count += 1;
+    }
+    histogram
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summaries() {
+        let values = [3.0, 1.0, 4.0, 1.0, 5.0];
+        assert_eq!(sum(&values), 14.0);
+        assert_eq!(mean(&values), Some(2.8));
+        assert_eq!(median(&values), Some(3.0));
+        assert_eq!(median(&[1.0, 2.0, 3.0, 4.0]), Some(2.5));
+        assert_eq!(percentile(&values, 0.0).unwrap(), Some(1.0));
+        assert_eq!(percentile(&values, 100.0).unwrap(), Some(5.0));
+        assert_eq!(percentile(&values, 90.0).unwrap(), Some(4.6));
+        assert!(percentile(&values, 101.0).is_err());
+        assert_eq!(sum(&[]), 0.0);
+        assert_eq!(mean(&[]), None);
+        assert_eq!(percentile(&[], 50.0).unwrap(), None);
+    }
+
+    #[test]
+    fn test_histogram() {
+        let bins = histogram(&[0.0, 1.0, 2.5, 5.0, 9.0, 10.0], 2);
+        assert_eq!(
+            bins,
+            vec![
+                Bin {
+                    min: 0.0,
+                    max: 5.0,
+                    count: 3
+                },
+                Bin {
+                    min: 5.0,
+                    max: 10.0,
+                    count: 3
+                },
+            ]
+        );
+        assert_eq!(
+            histogram(&[7.0, 7.0], 4),
+            vec![Bin {
+                min: 7.0,
+                max: 7.0,
+                count: 2
+            }]
+        );
+        assert!(histogram(&[], 4).is_empty());
+    }
+
+    #[test]
+    fn test_call_stats() {
+        let response = call_stats(r#"{"fn": "percentile", "values": [1, 2, 3], "p": 50}"#);
+        assert_eq!(response, r#"{"result":2.0}"#);
+        let response = call_stats(r#"{"fn": "mean", "values": [1, null]}"#);
+        assert_eq!(
+            response,
+            r#"{"error":"stats.mean: value at index 1 is not a number: null"}"#
+        );
+        let response = call_stats(r#"{"fn": "histogram", "values": [1], "bins": 0}"#);
+        assert_eq!(
+            response,
+            r#"{"error":"stats.histogram: bins must be a positive integer"}"#
+        );
+    }
+}
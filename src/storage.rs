@@ -0,0 +1,208 @@
+//! Where state that outlives an execution is kept.
+//!
+//! Persistence goes through a `Storage`: values stored under a key within a
+//! namespace, one namespace per feature. Recorded executions are kept in
+//! `runs`. `FsStorage` keeps each namespace as a directory of files under a
+//! root and is what `record_dir` selects; `MemoryStorage` keeps everything
+//! in process. Embedders that want state in their own database implement
+//! `Storage` and pass it to `CodeModeProxy::with_storage` or
+//! `CodeModeWrapper::with_storage`.
+
+use crate::config::CodeModeConfig;
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// Keeps byte values by namespace and key. Names are made of ASCII letters,
+/// digits, `-`, `_` and `.`, and don't start with `.`.
+pub trait Storage: Send + Sync {
+    fn get(&self, namespace: &str, key: &str) -> Result<Option<Vec<u8>>>;
+    fn put(&self, namespace: &str, key: &str, value: &[u8]) -> Result<()>;
+    /// The keys in `namespace`, sorted.
+    fn list(&self, namespace: &str) -> Result<Vec<String>>;
+    /// Remove `key`, returning whether it was there.
+    fn delete(&self, namespace: &str, key: &str) -> Result<bool>;
+}
+
+/// Fail unless `name` is a valid namespace or key.
+pub fn check_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || name.starts_with('.')
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        anyhow::bail!("Invalid storage name `{name}`");
+    }
+    Ok(())
+}
+
+/// Keeps each namespace as a directory under `root`, and each key as a file
+/// in it.
+#[derive(Debug, Clone)]
+pub struct FsStorage {
+    root: PathBuf,
+}
+
+impl FsStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path(&self, namespace: &str, key: &str) -> Result<PathBuf> {
+        check_name(namespace)?;
+        check_name(key)?;
+        Ok(self.root.join(namespace).join(key))
+    }
+}
+
+impl Storage for FsStorage {
+    fn get(&self, namespace: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.path(namespace, key)?;
+        match std::fs::read(&path) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    fn put(&self, namespace: &str, key: &str, value: &[u8]) -> Result<()> {
+        let path = self.path(namespace, key)?;
+        let dir = self.root.join(namespace);
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+        std::fs::write(&path, value).with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    fn list(&self, namespace: &str) -> Result<Vec<String>> {
+        check_name(namespace)?;
+        let dir = self.root.join(namespace);
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to list {}", dir.display())),
+        };
+        let mut keys = Vec::new();
+        for entry in entries {
+            let entry = entry.with_context(|| format!("Failed to list {}", dir.display()))?;
+            if let Some(key) = entry.file_name().to_str()
+                && check_name(key).is_ok()
+                && entry.file_type().is_ok_and(|t| t.is_file())
+            {
+                keys.push(key.to_string());
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn delete(&self, namespace: &str, key: &str) -> Result<bool> {
+        let path = self.path(namespace, key)?;
+        match std::fs::remove_file(&path) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e).with_context(|| format!("Failed to delete {}", path.display())),
+        }
+    }
+}
+
+/// Keeps everything in process, for tests and short-lived embedders.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    values: Mutex<BTreeMap<(String, String), Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, namespace: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        check_name(namespace)?;
+        check_name(key)?;
+        let values = self.values.lock().unwrap();
+        Ok(values
+            .get(&(namespace.to_string(), key.to_string()))
+            .cloned())
+    }
+
+    fn put(&self, namespace: &str, key: &str, value: &[u8]) -> Result<()> {
+        check_name(namespace)?;
+        check_name(key)?;
+        self.values
+            .lock()
+            .unwrap()
+            .insert((namespace.to_string(), key.to_string()), value.to_vec());
+        Ok(())
+    }
+
+    fn list(&self, namespace: &str) -> Result<Vec<String>> {
+        check_name(namespace)?;
+        let values = self.values.lock().unwrap();
+        Ok(values
+            .keys()
+            .filter(|(ns, _)| ns == namespace)
+            .map(|(_, key)| key.clone())
+            .collect())
+    }
+
+    fn delete(&self, namespace: &str, key: &str) -> Result<bool> {
+        check_name(namespace)?;
+        check_name(key)?;
+        let mut values = self.values.lock().unwrap();
+        Ok(values
+            .remove(&(namespace.to_string(), key.to_string()))
+            .is_some())
+    }
+}
+
+/// The storage selected by `config`: the `record_dir` directory, if set.
+pub fn storage_from_config(config: &CodeModeConfig) -> Option<Arc<dyn Storage>> {
+    config
+        .record_dir
+        .as_ref()
+        .map(|dir| Arc::new(FsStorage::new(dir)) as Arc<dyn Storage>)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exercise(storage: &dyn Storage) {
+        assert_eq!(storage.get("runs", "a.json").unwrap(), None);
+        storage.put("runs", "b.json", b"two").unwrap();
+        storage.put("runs", "a.json", b"one").unwrap();
+        storage.put("other", "c", b"three").unwrap();
+        assert_eq!(storage.get("runs", "a.json").unwrap().unwrap(), b"one");
+        assert_eq!(storage.list("runs").unwrap(), ["a.json", "b.json"]);
+        assert!(storage.list("empty").unwrap().is_empty());
+
+        storage.put("runs", "a.json", b"replaced").unwrap();
+        assert_eq!(storage.get("runs", "a.json").unwrap().unwrap(), b"replaced");
+        assert!(storage.delete("runs", "a.json").unwrap());
+        assert!(!storage.delete("runs", "a.json").unwrap());
+        assert_eq!(storage.list("runs").unwrap(), ["b.json"]);
+
+        // Names can't reach outside their namespace
+        for name in ["", "..", "../x", "a/b", ".hidden"] {
+            assert!(storage.put("runs", name, b"").is_err(), "{name}");
+            assert!(storage.get(name, "a").is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_memory_storage() {
+        exercise(&MemoryStorage::new());
+    }
+
+    #[test]
+    fn test_fs_storage() {
+        let dir = std::env::temp_dir().join(format!("codemoder-storage-{}", std::process::id()));
+        exercise(&FsStorage::new(&dir));
+        assert!(dir.join("other").join("c").is_file());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
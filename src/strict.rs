@@ -0,0 +1,245 @@
+//! Refusing script tool calls with arguments the tool doesn't declare.
+//!
+//! Downstream servers often ignore fields they don't know, so a misspelled
+//! parameter silently falls back to its default. With `strict_args` set,
+//! globally or under `[tools.<name>]`, a call whose arguments include fields
+//! the tool's input schema doesn't declare fails instead, naming them and
+//! the declared parameters in alphabetical order.
+//! Objects whose schema allows additional properties are left alone, as are
+//! those without declared properties.
+
+use crate::config::CodeModeConfig;
+use crate::runtime::{ServerRequest, ServerRequestFuture, ToolCallFuture, ToolCaller};
+use rmcp::model::Tool;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// The input schemas of the tools held to `strict_args`, keyed by exposed
+/// tool name.
+#[derive(Debug, Default)]
+pub struct StrictArgs {
+    schemas: HashMap<String, Value>,
+}
+
+impl StrictArgs {
+    pub fn from_config(config: &CodeModeConfig, tools: &[Tool]) -> Self {
+        let schemas = tools
+            .iter()
+            .filter(|tool| {
+                config
+                    .tools
+                    .get(tool.name.as_ref())
+                    .and_then(|settings| settings.strict_args)
+                    .unwrap_or(config.strict_args)
+            })
+            .map(|tool| {
+                (
+                    tool.name.to_string(),
+                    Value::Object(tool.input_schema.as_ref().clone()),
+                )
+            })
+            .collect();
+        Self { schemas }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.schemas.is_empty()
+    }
+
+    /// Fail if `args` of a call to `tool` has fields its schema doesn't
+    /// declare.
+    pub fn check(&self, tool: &str, args: Option<&Value>) -> anyhow::Result<()> {
+        let (Some(schema), Some(args)) = (self.schemas.get(tool), args) else {
+            return Ok(());
+        };
+        let mut found = Vec::new();
+        unexpected_fields(schema, args, "", &mut found);
+        if found.is_empty() {
+            return Ok(());
+        }
+        // Sorted, rather than in whatever order serde_json keeps maps in
+        found.sort();
+        let fields: Vec<String> = found.iter().map(|field| format!("`{field}`")).collect();
+        let mut declared: Vec<&String> = schema
+            .get("properties")
+            .and_then(Value::as_object)
+            .map(|properties| properties.keys().collect())
+            .unwrap_or_default();
+        declared.sort();
+        let declared: Vec<String> = declared.iter().map(|name| format!("`{name}`")).collect();
+        let noun = match fields.len() {
+            1 => "argument",
+            _ => "arguments",
+        };
+        anyhow::bail!(
+            "Unexpected {noun} {} for {tool}; its parameters are {}",
+            fields.join(", "),
+            declared.join(", ")
+        )
+    }
+}
+
+/// Collect the dotted paths of fields in `value` that `schema` doesn't
+/// declare, under `path`.
+fn unexpected_fields(schema: &Value, value: &Value, path: &str, found: &mut Vec<String>) {
+    let (Some(properties), Some(object)) = (
+        schema.get("properties").and_then(Value::as_object),
+        value.as_object(),
+    ) else {
+        return;
+    };
+    let closed = matches!(
+        schema.get("additionalProperties"),
+        None | Some(Value::Bool(false))
+    );
+    for (name, field) in object {
+        let field_path = match path {
+            "" => name.clone(),
+            parent => format!("{parent}.{name}"),
+        };
+        match properties.get(name) {
+            Some(field_schema) => unexpected_fields(field_schema, field, &field_path, found),
+            None if closed => found.push(field_path),
+            None => {}
+        }
+    }
+}
+
+/// A `ToolCaller` that refuses calls with undeclared argument fields.
+pub struct StrictArgsToolCaller<C: ToolCaller> {
+    inner: C,
+    strict: StrictArgs,
+}
+
+impl<C: ToolCaller> StrictArgsToolCaller<C> {
+    pub fn new(inner: C, strict: StrictArgs) -> Self {
+        Self { inner, strict }
+    }
+}
+
+impl<C: ToolCaller> ToolCaller for StrictArgsToolCaller<C> {
+    fn call_tool<'a>(&'a self, name: &'a str, args: Option<Value>) -> ToolCallFuture<'a> {
+        if self.strict.is_empty() {
+            return self.inner.call_tool(name, args);
+        }
+        if let Err(e) = self.strict.check(name, args.as_ref()) {
+            return Box::pin(async move { Err(e) });
+        }
+        self.inner.call_tool(name, args)
+    }
+
+    fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+        self.inner.server_request(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn tool(name: &str, schema: Value) -> Tool {
+        Tool::new(
+            name.to_string(),
+            "",
+            Arc::new(schema.as_object().unwrap().clone()),
+        )
+    }
+
+    fn tools() -> Vec<Tool> {
+        vec![
+            tool(
+                "move_footprint",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "id": {"type": "string"},
+                        "x_mm": {"type": "number"},
+                        "options": {
+                            "type": "object",
+                            "properties": {"snap": {"type": "boolean"}}
+                        },
+                        "labels": {
+                            "type": "object",
+                            "properties": {"name": {"type": "string"}},
+                            "additionalProperties": {"type": "string"}
+                        }
+                    }
+                }),
+            ),
+            tool(
+                "search",
+                json!({
+                    "type": "object",
+                    "properties": {"query": {"type": "string"}},
+                    "additionalProperties": true
+                }),
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_check() {
+        let strict =
+            StrictArgs::from_config(&CodeModeConfig::new().with_strict_args(true), &tools());
+        assert!(
+            strict
+                .check(
+                    "move_footprint",
+                    Some(&json!({"id": "R1", "x_mm": 1, "labels": {"side": "top"}}))
+                )
+                .is_ok()
+        );
+        assert_eq!(
+            strict
+                .check("move_footprint", Some(&json!({"id": "R1", "xMm": 1})))
+                .unwrap_err()
+                .to_string(),
+            "Unexpected argument `xMm` for move_footprint; its parameters are `id`, `labels`, `options`, `x_mm`"
+        );
+        let error = strict
+            .check(
+                "move_footprint",
+                Some(&json!({"id": "R1", "y": 1, "options": {"grid": 1}})),
+            )
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            "Unexpected arguments `options.grid`, `y` for move_footprint; its parameters are `id`, `labels`, `options`, `x_mm`"
+        );
+
+        // Open schemas take anything
+        assert!(
+            strict
+                .check("search", Some(&json!({"query": "a", "limit": 5})))
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_per_tool_setting() {
+        let mut config = CodeModeConfig::new();
+        config
+            .tools
+            .entry("move_footprint".to_string())
+            .or_default()
+            .strict_args = Some(true);
+        let strict = StrictArgs::from_config(&config, &tools());
+        assert!(
+            strict
+                .check("move_footprint", Some(&json!({"z": 1})))
+                .is_err()
+        );
+
+        config.strict_args = true;
+        config.tools.get_mut("move_footprint").unwrap().strict_args = Some(false);
+        let strict = StrictArgs::from_config(&config, &tools());
+        assert!(
+            strict
+                .check("move_footprint", Some(&json!({"z": 1})))
+                .is_ok()
+        );
+    }
+}
@@ -0,0 +1,236 @@
+//! Compact, human-readable summaries of recent executions.
+//!
+//! The latest `summary_history` executions are kept in memory and served as
+//! the `codemode://summary` resource, one line each: when it ran, how long it
+//! took, which tools it called and how it ended.
+
+use crate::runtime::{
+    ExecutionResult, ServerRequest, ServerRequestFuture, ToolCallFuture, ToolCaller,
+};
+use rmcp::model::{AnnotateAble, RawResource, Resource};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// URI of the resource listing recent execution summaries.
+pub const SUMMARY_URI: &str = "codemode://summary";
+
+/// The `codemode://summary` resource as listed in `resources/list`.
+pub fn summary_resource() -> Resource {
+    let mut resource = RawResource::new(SUMMARY_URI, "codemode-summary");
+    resource.description = Some("Summaries of the most recent executions".to_string());
+    resource.mime_type = Some("text/plain".to_string());
+    resource.no_annotation()
+}
+
+/// Errors are cut to this many characters in a summary line.
+const MAX_ERROR_CHARS: usize = 120;
+
+/// What happened in one execution.
+#[derive(Debug, Clone)]
+pub struct ExecutionSummary {
+    pub execution_id: Option<String>,
+    pub finished: Instant,
+    pub duration: Duration,
+    pub session: Option<String>,
+    /// Calls that reached the tool, by tool name.
+    pub tool_calls: BTreeMap<String, usize>,
+    pub dedup_hits: usize,
+    pub error: Option<String>,
+    pub yielded: bool,
+}
+
+impl ExecutionSummary {
+    pub fn new(
+        result: &ExecutionResult,
+        duration: Duration,
+        session: Option<String>,
+        tool_calls: BTreeMap<String, usize>,
+    ) -> Self {
+        Self {
+            execution_id: result.execution_id.clone(),
+            finished: Instant::now(),
+            duration,
+            session,
+            tool_calls,
+            dedup_hits: result.metrics.dedup_hits,
+            error: result
+                .is_error
+                .then(|| result.error_message.clone().unwrap_or_default()),
+            yielded: result.continuation.is_some(),
+        }
+    }
+}
+
+impl fmt::Display for ExecutionSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(id) = &self.execution_id {
+            write!(f, "{id}: ")?;
+        }
+        write!(
+            f,
+            "{}s ago, {} ms",
+            self.finished.elapsed().as_secs(),
+            self.duration.as_millis()
+        )?;
+        if let Some(session) = &self.session {
+            write!(f, ", session {session}")?;
+        }
+        let calls: usize = self.tool_calls.values().sum();
+        match calls {
+            0 => write!(f, ", no tool calls")?,
+            1 => write!(f, ", 1 call")?,
+            n => write!(f, ", {n} calls")?,
+        }
+        if calls > 0 {
+            let tools: Vec<String> = self
+                .tool_calls
+                .iter()
+                .map(|(name, &n)| match n {
+                    1 => name.clone(),
+                    n => format!("{name} x{n}"),
+                })
+                .collect();
+            write!(f, " ({})", tools.join(", "))?;
+        }
+        if self.dedup_hits > 0 {
+            write!(f, ", {} deduplicated", self.dedup_hits)?;
+        }
+        match &self.error {
+            Some(error) => {
+                let line = error.lines().next().unwrap_or_default();
+                let short: String = line.chars().take(MAX_ERROR_CHARS).collect();
+                let ellipsis = if short.len() < error.len() { "..." } else { "" };
+                write!(f, ", failed: {short}{ellipsis}")
+            }
+            None if self.yielded => write!(f, ", yielded"),
+            None => write!(f, ", ok"),
+        }
+    }
+}
+
+/// The most recent execution summaries, oldest first.
+pub struct SummaryLog {
+    entries: Mutex<VecDeque<ExecutionSummary>>,
+    capacity: usize,
+}
+
+impl SummaryLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    /// Whether summaries are kept at all.
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn record(&self, summary: ExecutionSummary) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(summary);
+    }
+
+    /// The summaries so far, most recent first.
+    pub fn recent(&self) -> Vec<ExecutionSummary> {
+        self.entries.lock().unwrap().iter().rev().cloned().collect()
+    }
+
+    /// The text of the `codemode://summary` resource.
+    pub fn render(&self) -> String {
+        let recent = self.recent();
+        let mut output = format!("Last {} executions, most recent first:\n", recent.len());
+        for summary in recent {
+            writeln!(output, "- {summary}").unwrap();
+        }
+        output
+    }
+}
+
+/// A `ToolCaller` that counts calls per tool for the execution summary.
+pub struct CountingToolCaller<C: ToolCaller> {
+    inner: C,
+    counts: Arc<Mutex<BTreeMap<String, usize>>>,
+}
+
+impl<C: ToolCaller> CountingToolCaller<C> {
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            counts: Arc::default(),
+        }
+    }
+
+    /// The counts, shared with this caller.
+    pub fn counts(&self) -> Arc<Mutex<BTreeMap<String, usize>>> {
+        self.counts.clone()
+    }
+}
+
+impl<C: ToolCaller> ToolCaller for CountingToolCaller<C> {
+    fn call_tool<'a>(
+        &'a self,
+        name: &'a str,
+        args: Option<serde_json::Value>,
+    ) -> ToolCallFuture<'a> {
+        *self
+            .counts
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default() += 1;
+        self.inner.call_tool(name, args)
+    }
+
+    fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+        self.inner.server_request(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(error: Option<&str>, calls: &[(&str, usize)]) -> ExecutionSummary {
+        ExecutionSummary {
+            execution_id: None,
+            finished: Instant::now(),
+            duration: Duration::from_millis(42),
+            session: None,
+            tool_calls: calls.iter().map(|(n, c)| (n.to_string(), *c)).collect(),
+            dedup_hits: 0,
+            error: error.map(str::to_string),
+            yielded: false,
+        }
+    }
+
+    #[test]
+    fn test_summary_log() {
+        let log = SummaryLog::new(2);
+        log.record(summary(None, &[]));
+        log.record(ExecutionSummary {
+            execution_id: Some("exec-0a1b".to_string()),
+            ..summary(None, &[("add", 2), ("echo", 1)])
+        });
+        log.record(summary(
+            Some("Tool add failed: boom\nat line 3"),
+            &[("add", 1)],
+        ));
+
+        assert_eq!(
+            log.render(),
+            "Last 2 executions, most recent first:\n\
+             - 0s ago, 42 ms, 1 call (add), failed: Tool add failed: boom...\n\
+             - exec-0a1b: 0s ago, 42 ms, 3 calls (add x2, echo), ok\n"
+        );
+    }
+}
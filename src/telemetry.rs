@@ -0,0 +1,150 @@
+//! Logging, and OpenTelemetry export of the proxy's spans (`otel` feature).
+//!
+//! The proxy records `tracing` spans whether or not they are exported:
+//! `execute_tools` for each execution, with a `tools/call` child for each
+//! downstream call its script makes, and `tools/call` for each passthrough
+//! call. Calls carry `origin = "script"` or `"passthrough"`. Embedders of
+//! `CodeModeWrapper` see the same spans in their own subscriber.
+//!
+//! With `--otlp-endpoint` (or `OTEL_EXPORTER_OTLP_ENDPOINT`), the binary
+//! also sends them over OTLP/HTTP to a collector, as service `codemoder`.
+
+use crate::runtime::ExecutionResult;
+use anyhow::Result;
+use rmcp::model::CallToolResult;
+use std::future::Future;
+use tracing::Instrument;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// The standard OpenTelemetry variable for the collector's address, used
+/// when `--otlp-endpoint` isn't given.
+pub const ENDPOINT_VAR: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+
+/// Keeps span export running; spans still buffered are flushed when it is
+/// dropped.
+pub struct Telemetry {
+    #[cfg(feature = "otel")]
+    provider: opentelemetry_sdk::trace::SdkTracerProvider,
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Err(e) = self.provider.shutdown() {
+            eprintln!("Flushing spans failed: {e}");
+        }
+    }
+}
+
+/// The span for the execution `id`, whose downstream calls are its
+/// children.
+pub fn execution_span(id: &str, session: Option<&str>) -> tracing::Span {
+    tracing::info_span!(
+        "execute_tools",
+        execution.id = id,
+        session,
+        tool_calls = tracing::field::Empty,
+        is_error = tracing::field::Empty
+    )
+}
+
+/// Record how the execution in `span` went.
+pub fn record_execution(span: &tracing::Span, result: &ExecutionResult) {
+    span.record("tool_calls", result.metrics.tool_calls);
+    span.record("is_error", result.is_error);
+}
+
+/// Run the downstream call `call` to `tool` in a `tools/call` span, marked
+/// with where it came from and whether it failed.
+pub async fn traced_call<E>(
+    tool: &str,
+    origin: &'static str,
+    call: impl Future<Output = Result<CallToolResult, E>>,
+) -> Result<CallToolResult, E> {
+    let span = tracing::info_span!("tools/call", tool, origin, is_error = tracing::field::Empty);
+    let result = call.instrument(span.clone()).await;
+    span.record(
+        "is_error",
+        result.as_ref().map_or(true, |r| r.is_error == Some(true)),
+    );
+    result
+}
+
+/// The OTLP/HTTP traces URL for a collector at `endpoint`, which may be
+/// given with or without the `/v1/traces` path.
+pub fn traces_url(endpoint: &str) -> String {
+    let endpoint = endpoint.trim_end_matches('/');
+    if endpoint.ends_with("/v1/traces") {
+        endpoint.to_string()
+    } else {
+        format!("{endpoint}/v1/traces")
+    }
+}
+
+/// Log to stderr and, given a collector `endpoint`, export spans to it.
+/// Returns the guard that keeps the export going, if there is one.
+pub fn init(endpoint: Option<String>) -> Result<Option<Telemetry>> {
+    let endpoint = endpoint.or_else(|| std::env::var(ENDPOINT_VAR).ok().filter(|e| !e.is_empty()));
+    let filter = tracing_subscriber::EnvFilter::from_default_env()
+        .add_directive(tracing::Level::INFO.into());
+    let fmt = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+    let registry = tracing_subscriber::registry().with(filter).with(fmt);
+
+    let Some(endpoint) = endpoint else {
+        registry.init();
+        return Ok(None);
+    };
+    #[cfg(feature = "otel")]
+    {
+        use opentelemetry::trace::TracerProvider;
+        use opentelemetry_otlp::WithExportConfig;
+
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .with_endpoint(traces_url(&endpoint))
+            .build()?;
+        let provider = opentelemetry_sdk::trace::SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(
+                opentelemetry_sdk::Resource::builder()
+                    .with_service_name("codemoder")
+                    .build(),
+            )
+            .build();
+        let tracer = provider.tracer("codemoder");
+        registry
+            .with(tracing_opentelemetry::layer().with_tracer(tracer))
+            .init();
+        tracing::info!("Exporting spans to {}", traces_url(&endpoint));
+        Ok(Some(Telemetry { provider }))
+    }
+    #[cfg(not(feature = "otel"))]
+    {
+        registry.init();
+        anyhow::bail!(
+            "Exporting spans to {endpoint} requires building codemoder with `--features otel`"
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traces_url() {
+        assert_eq!(
+            traces_url("http://localhost:4318"),
+            "http://localhost:4318/v1/traces"
+        );
+        assert_eq!(
+            traces_url("http://collector:4318/"),
+            "http://collector:4318/v1/traces"
+        );
+        assert_eq!(
+            traces_url("http://collector:4318/v1/traces"),
+            "http://collector:4318/v1/traces"
+        );
+    }
+}
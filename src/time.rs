@@ -0,0 +1,294 @@
+//! Date and time helpers for scripts, implemented with chrono so scripts
+//! don't depend on the engine's `Date` parsing and have real time zones.
+//!
+//! - `now()`: the current time
+//! - `parseDate(text, {format, timezone})`: a date from RFC 3339, RFC 2822,
+//!   `YYYY-MM-DD[ HH:MM[:SS]]` or a strftime `format`; times without an
+//!   offset are taken in `timezone` (default UTC)
+//! - `formatDate(date, format, timezone)`: `date` formatted with strftime
+//!   `format` in `timezone` (default UTC)
+//! - `convertTimezone(date, timezone)`: `date` as RFC 3339 with the offset of
+//!   `timezone` at that moment
+//!
+//! Dates are passed as ISO 8601 strings, epoch milliseconds or, from
+//! JavaScript, `Date` objects, and returned as ISO 8601 strings in UTC
+//! (`2026-03-01T09:30:00Z`), which `new Date(...)` reads back. Time zones
+//! are IANA names such as `Europe/Berlin`, or `UTC`.
+
+use anyhow::{Context, Result};
+use chrono::format::StrftimeItems;
+use chrono::offset::LocalResult;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::Deserialize;
+
+/// Wraps `__time(json)` (JSON string in, JSON string out) into the script
+/// functions. They are assigned to the global object rather than declared,
+/// so a script can still declare `const now = ...` of its own.
+pub(crate) const TIME_PRELUDE: &str = r#"
+    function __time_request(fn, args) {
+        var response = JSON.parse(__time(JSON.stringify({fn: fn, args: args})));
+        if (response.error) {
+            throw new Error(response.error);
+        }
+        return response.result;
+    }
+    function __time_date(date) {
+        return date instanceof Date ? date.getTime() : date;
+    }
+    globalThis.now = function() {
+        return __time_request('now', {});
+    };
+    globalThis.parseDate = function(text, opts) {
+        opts = opts || {};
+        return __time_request('parseDate', {
+            text: String(text),
+            format: opts.format || null,
+            timezone: opts.timezone || null
+        });
+    };
+    globalThis.formatDate = function(date, format, timezone) {
+        return __time_request('formatDate', {
+            date: __time_date(date),
+            format: String(format),
+            timezone: timezone || null
+        });
+    };
+    globalThis.convertTimezone = function(date, timezone) {
+        return __time_request('convertTimezone', {
+            date: __time_date(date),
+            timezone: String(timezone)
+        });
+    };
+"#;
+
+/// A date given by a script.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum DateArg {
+    /// Milliseconds since the Unix epoch
+    Millis(f64),
+    Text(String),
+}
+
+impl DateArg {
+    pub fn to_datetime(&self) -> Result<DateTime<Utc>> {
+        match self {
+            DateArg::Millis(ms) => DateTime::from_timestamp_millis(*ms as i64)
+                .with_context(|| format!("Date out of range: {ms}")),
+            DateArg::Text(text) => parse_date(text, None, None),
+        }
+    }
+}
+
+/// A call from the time prelude, as `{"fn": ..., "args": ...}`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "fn", content = "args", rename_all = "camelCase")]
+enum TimeRequest {
+    Now {},
+    ParseDate {
+        text: String,
+        format: Option<String>,
+        timezone: Option<String>,
+    },
+    FormatDate {
+        date: DateArg,
+        format: String,
+        timezone: Option<String>,
+    },
+    ConvertTimezone {
+        date: DateArg,
+        timezone: String,
+    },
+}
+
+/// Serve `__time(json)`, returning `{"result": ...}` or `{"error": ...}` as
+/// JSON text.
+pub(crate) fn call_time(request: &str) -> String {
+    let result = serde_json::from_str::<TimeRequest>(request)
+        .map_err(|e| anyhow::anyhow!("Invalid time request: {e}"))
+        .and_then(|request| match request {
+            TimeRequest::Now {} => Ok(now()),
+            TimeRequest::ParseDate {
+                text,
+                format,
+                timezone,
+            } => parse_date(&text, format.as_deref(), timezone.as_deref()).map(iso),
+            TimeRequest::FormatDate {
+                date,
+                format,
+                timezone,
+            } => format_date(date.to_datetime()?, &format, timezone.as_deref()),
+            TimeRequest::ConvertTimezone { date, timezone } => {
+                convert_timezone(date.to_datetime()?, &timezone)
+            }
+        });
+    match result {
+        Ok(result) => serde_json::json!({ "result": result }).to_string(),
+        Err(e) => serde_json::json!({ "error": e.to_string() }).to_string(),
+    }
+}
+
+/// `date` as an ISO 8601 string in UTC, with milliseconds when it has them.
+pub fn iso(date: DateTime<Utc>) -> String {
+    date.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+pub fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+pub fn timezone(name: &str) -> Result<Tz> {
+    name.parse()
+        .map_err(|_| anyhow::anyhow!("Unknown time zone `{name}`"))
+}
+
+/// Formats tried, in order, for dates without an offset.
+const NAIVE_FORMATS: &[&str] = &[
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%Y-%m-%dT%H:%M",
+    "%Y-%m-%d %H:%M",
+];
+
+pub fn parse_date(
+    text: &str,
+    format: Option<&str>,
+    timezone_name: Option<&str>,
+) -> Result<DateTime<Utc>> {
+    let text = text.trim();
+    let tz = timezone_name.map(timezone).transpose()?.unwrap_or(Tz::UTC);
+    let naive = match format {
+        Some(format) => {
+            check_format(format)?;
+            if let Ok(date) = DateTime::parse_from_str(text, format) {
+                return Ok(date.to_utc());
+            }
+            NaiveDateTime::parse_from_str(text, format)
+                .or_else(|_| {
+                    NaiveDate::parse_from_str(text, format).map(|d| d.and_time(Default::default()))
+                })
+                .map_err(|e| anyhow::anyhow!("Cannot parse `{text}` as `{format}`: {e}"))?
+        }
+        None => {
+            if let Ok(date) = DateTime::parse_from_rfc3339(text) {
+                return Ok(date.to_utc());
+            }
+            if let Ok(date) = DateTime::parse_from_rfc2822(text) {
+                return Ok(date.to_utc());
+            }
+            NAIVE_FORMATS
+                .iter()
+                .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+                .or_else(|| {
+                    NaiveDate::parse_from_str(text, "%Y-%m-%d")
+                        .ok()
+                        .map(|d| d.and_time(Default::default()))
+                })
+                .with_context(|| format!("Cannot parse `{text}` as a date"))?
+        }
+    };
+    match tz.from_local_datetime(&naive) {
+        LocalResult::Single(date) => Ok(date.to_utc()),
+        // The repeated hour when clocks go back: take the first
+        LocalResult::Ambiguous(earliest, _) => Ok(earliest.to_utc()),
+        LocalResult::None => anyhow::bail!("{naive} does not exist in {tz}"),
+    }
+}
+
+pub fn format_date(
+    date: DateTime<Utc>,
+    format: &str,
+    timezone_name: Option<&str>,
+) -> Result<String> {
+    let tz = timezone_name.map(timezone).transpose()?.unwrap_or(Tz::UTC);
+    let items = check_format(format)?;
+    Ok(date
+        .with_timezone(&tz)
+        .format_with_items(items.iter())
+        .to_string())
+}
+
+pub fn convert_timezone(date: DateTime<Utc>, timezone_name: &str) -> Result<String> {
+    let tz = timezone(timezone_name)?;
+    Ok(date
+        .with_timezone(&tz)
+        .to_rfc3339_opts(SecondsFormat::AutoSi, true))
+}
+
+fn check_format(format: &str) -> Result<Vec<chrono::format::Item<'_>>> {
+    StrftimeItems::new(format)
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid date format `{format}`"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_date() {
+        let parse = |text, format, tz| iso(parse_date(text, format, tz).unwrap());
+        assert_eq!(
+            parse("2026-03-01T09:30:00+01:00", None, None),
+            "2026-03-01T08:30:00Z"
+        );
+        assert_eq!(
+            parse("Sun, 1 Mar 2026 09:30:00 GMT", None, None),
+            "2026-03-01T09:30:00Z"
+        );
+        assert_eq!(parse("2026-03-01", None, None), "2026-03-01T00:00:00Z");
+        assert_eq!(
+            parse("2026-07-01 09:30", None, Some("Europe/Berlin")),
+            "2026-07-01T07:30:00Z"
+        );
+        assert_eq!(
+            parse(
+                "01/03/2026 9:30",
+                Some("%d/%m/%Y %H:%M"),
+                Some("America/New_York")
+            ),
+            "2026-03-01T14:30:00Z"
+        );
+        // Clocks go forward at 02:00 on 2026-03-29 in Berlin
+        let err = parse_date("2026-03-29 02:30", None, Some("Europe/Berlin")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "2026-03-29 02:30:00 does not exist in Europe/Berlin"
+        );
+        assert!(parse_date("yesterday", None, None).is_err());
+        assert_eq!(
+            parse_date("2026-03-01", None, Some("Mars/Olympus"))
+                .unwrap_err()
+                .to_string(),
+            "Unknown time zone `Mars/Olympus`"
+        );
+    }
+
+    #[test]
+    fn test_format_and_convert() {
+        let date = parse_date("2026-01-15T23:30:00Z", None, None).unwrap();
+        assert_eq!(
+            format_date(date, "%Y-%m-%d %H:%M", None).unwrap(),
+            "2026-01-15 23:30"
+        );
+        assert_eq!(
+            format_date(date, "%A %d %B", Some("Asia/Tokyo")).unwrap(),
+            "Friday 16 January"
+        );
+        assert!(format_date(date, "%Q", None).is_err());
+        assert_eq!(
+            convert_timezone(date, "America/Los_Angeles").unwrap(),
+            "2026-01-15T15:30:00-08:00"
+        );
+    }
+
+    #[test]
+    fn test_call_time() {
+        let response = call_time(r#"{"fn": "formatDate", "args": {"date": 0, "format": "%Y"}}"#);
+        assert_eq!(response, r#"{"result":"1970"}"#);
+        let response =
+            call_time(r#"{"fn": "convertTimezone", "args": {"date": "x", "timezone": "UTC"}}"#);
+        assert_eq!(response, r#"{"error":"Cannot parse `x` as a date"}"#);
+    }
+}
@@ -0,0 +1,206 @@
+//! Timeouts of single downstream tool calls.
+//!
+//! With `[call_timeout]` set, a downstream call, from a script or passed
+//! through, fails once it has taken `max_ms`. With `adaptive = true`, each
+//! tool instead gets `p99_multiplier` times the p99 latency of its recent
+//! calls, kept between `min_ms` and `max_ms`, so a normally fast tool that
+//! hangs fails fast while a slow but healthy one isn't cut off. A tool gets
+//! `max_ms` until it has `slo::MIN_SAMPLES` calls. A call that timed out
+//! failed outright: it is retried per `[retry]` if the tool is safe to call
+//! twice, since it may have finished downstream, and counts toward the
+//! circuit breaker.
+
+use crate::config::CodeModeConfig;
+use crate::runtime::{ServerRequest, ServerRequestFuture, ToolCallFuture, ToolCaller};
+use crate::slo::LatencySlos;
+use rmcp::model::CallToolResult;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// `[call_timeout]`, or nothing when it isn't set.
+#[derive(Debug, Default)]
+pub struct CallTimeouts {
+    max: Option<Duration>,
+    min: Duration,
+    adaptive: bool,
+    multiplier: f64,
+    latencies: Arc<LatencySlos>,
+}
+
+impl CallTimeouts {
+    /// Timeouts adapting to the latencies kept in `latencies`, which
+    /// `LatencySlos::from_config` enables for adaptive timeouts.
+    pub fn from_config(config: &CodeModeConfig, latencies: Arc<LatencySlos>) -> Self {
+        let Some(settings) = &config.call_timeout else {
+            return Self::default();
+        };
+        Self {
+            max: Some(Duration::from_millis(settings.max_ms)),
+            min: Duration::from_millis(settings.min_ms),
+            adaptive: settings.adaptive,
+            multiplier: settings.p99_multiplier,
+            latencies,
+        }
+    }
+
+    /// How long a call to downstream tool `tool` may take.
+    pub fn timeout(&self, tool: &str) -> Option<Duration> {
+        let max = self.max?;
+        if !self.adaptive {
+            return Some(max);
+        }
+        let Some(p99) = self.latencies.percentile(tool, 99.0) else {
+            return Some(max);
+        };
+        Some(p99.mul_f64(self.multiplier).clamp(self.min, max))
+    }
+
+    /// Make `call` to downstream tool `tool`, failing with the error
+    /// `timed_out` makes from a message once it takes longer than the tool's
+    /// timeout.
+    pub async fn run<E, Fut>(
+        &self,
+        tool: &str,
+        call: Fut,
+        timed_out: impl FnOnce(String) -> E,
+    ) -> Result<CallToolResult, E>
+    where
+        Fut: Future<Output = Result<CallToolResult, E>>,
+    {
+        let Some(timeout) = self.timeout(tool) else {
+            return call.await;
+        };
+        match tokio::time::timeout(timeout, call).await {
+            Ok(result) => result,
+            Err(_) => {
+                let message = format!("Call to {tool} timed out after {}ms", timeout.as_millis());
+                tracing::warn!(tool, timeout_ms = timeout.as_millis() as u64, "{message}");
+                Err(timed_out(message))
+            }
+        }
+    }
+}
+
+/// A `ToolCaller` that fails calls taking longer than their tool's timeout.
+/// Takes downstream tool names.
+pub struct TimeoutToolCaller<C: ToolCaller> {
+    inner: C,
+    timeouts: Arc<CallTimeouts>,
+}
+
+impl<C: ToolCaller> TimeoutToolCaller<C> {
+    pub fn new(inner: C, timeouts: Arc<CallTimeouts>) -> Self {
+        Self { inner, timeouts }
+    }
+}
+
+impl<C: ToolCaller> ToolCaller for TimeoutToolCaller<C> {
+    fn call_tool<'a>(
+        &'a self,
+        name: &'a str,
+        args: Option<serde_json::Value>,
+    ) -> ToolCallFuture<'a> {
+        if self.timeouts.max.is_none() {
+            return self.inner.call_tool(name, args);
+        }
+        Box::pin(
+            self.timeouts
+                .run(name, self.inner.call_tool(name, args), anyhow::Error::msg),
+        )
+    }
+
+    fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+        self.inner.server_request(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alias::ToolAliases;
+    use crate::config::CallTimeoutSettings;
+    use crate::slo::MIN_SAMPLES;
+    use rmcp::model::Content;
+
+    fn call_timeouts(adaptive: bool) -> Arc<CallTimeouts> {
+        let config = CodeModeConfig::new().with_call_timeout(CallTimeoutSettings {
+            max_ms: 1000,
+            adaptive,
+            min_ms: 50,
+            p99_multiplier: 3.0,
+        });
+        let latencies = Arc::new(LatencySlos::from_config(
+            &config,
+            &ToolAliases::from_config(&config),
+        ));
+        Arc::new(CallTimeouts::from_config(&config, latencies))
+    }
+
+    /// Answers `sleep` after `args.ms` milliseconds.
+    struct Sleep;
+
+    impl ToolCaller for Sleep {
+        fn call_tool<'a>(
+            &'a self,
+            _name: &'a str,
+            args: Option<serde_json::Value>,
+        ) -> ToolCallFuture<'a> {
+            let ms = args
+                .and_then(|args| args["ms"].as_u64())
+                .unwrap_or_default();
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_millis(ms)).await;
+                Ok(CallToolResult::success(vec![Content::text("done")]))
+            })
+        }
+    }
+
+    #[test]
+    fn test_adaptive_timeouts() {
+        let timeouts = call_timeouts(true);
+        // Too few calls to go by
+        assert_eq!(timeouts.timeout("fast"), Some(Duration::from_millis(1000)));
+
+        for _ in 0..MIN_SAMPLES {
+            timeouts.latencies.record("fast", Duration::from_millis(5));
+            timeouts
+                .latencies
+                .record("usual", Duration::from_millis(100));
+            timeouts
+                .latencies
+                .record("slow", Duration::from_millis(800));
+        }
+        // Bounded by min_ms and max_ms
+        assert_eq!(timeouts.timeout("fast"), Some(Duration::from_millis(50)));
+        assert_eq!(timeouts.timeout("usual"), Some(Duration::from_millis(300)));
+        assert_eq!(timeouts.timeout("slow"), Some(Duration::from_millis(1000)));
+
+        // A static timeout ignores latencies, and none is set by default
+        assert_eq!(
+            call_timeouts(false).timeout("fast"),
+            Some(Duration::from_millis(1000))
+        );
+        assert_eq!(CallTimeouts::default().timeout("fast"), None);
+    }
+
+    #[tokio::test]
+    async fn test_calls_time_out() {
+        let timeouts = call_timeouts(true);
+        for _ in 0..MIN_SAMPLES {
+            timeouts.latencies.record("sleep", Duration::from_millis(1));
+        }
+        let caller = TimeoutToolCaller::new(Sleep, timeouts);
+        let err = caller
+            .call_tool("sleep", Some(serde_json::json!({"ms": 500})))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Call to sleep timed out after 50ms");
+        assert!(
+            caller
+                .call_tool("sleep", Some(serde_json::json!({"ms": 1})))
+                .await
+                .is_ok()
+        );
+    }
+}
@@ -0,0 +1,164 @@
+//! Per-execution traces of downstream tool calls, returned with the result
+//! when the execute tool is called with `verbose: true`.
+//!
+//! Each entry has the tool's downstream name, the arguments it was sent,
+//! how long the call took, and whether it succeeded, with the start of its
+//! result or its error. Entries are in the order the calls finished, which
+//! for concurrent `workflow` calls need not be the order they were made in.
+
+use crate::runtime::{
+    ServerRequest, ServerRequestFuture, ToolCallFuture, ToolCaller, format_call_result,
+};
+use rmcp::schemars::JsonSchema;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Characters of each result kept in a trace.
+pub const MAX_RESULT_CHARS: usize = 500;
+
+/// One downstream tool call.
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TraceEntry {
+    pub tool: String,
+    pub args: Option<serde_json::Value>,
+    pub duration_ms: u64,
+    /// False if the call failed or the tool reported an error.
+    pub ok: bool,
+    /// The result's text, cut to `MAX_RESULT_CHARS` characters.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// `text` cut to `MAX_RESULT_CHARS` characters, saying how long it was.
+fn truncate(text: String) -> String {
+    let length = text.chars().count();
+    if length <= MAX_RESULT_CHARS {
+        return text;
+    }
+    let short: String = text.chars().take(MAX_RESULT_CHARS).collect();
+    format!("{short}... ({length} characters)")
+}
+
+/// A `ToolCaller` that records a `TraceEntry` for each call when enabled.
+pub struct TraceToolCaller<C: ToolCaller> {
+    inner: C,
+    entries: Option<Arc<Mutex<Vec<TraceEntry>>>>,
+}
+
+impl<C: ToolCaller> TraceToolCaller<C> {
+    pub fn new(inner: C, enabled: bool) -> Self {
+        Self {
+            inner,
+            entries: enabled.then(Arc::default),
+        }
+    }
+
+    /// The trace so far, shared with this caller, or `None` when disabled.
+    pub fn entries(&self) -> Option<Arc<Mutex<Vec<TraceEntry>>>> {
+        self.entries.clone()
+    }
+}
+
+impl<C: ToolCaller> ToolCaller for TraceToolCaller<C> {
+    fn call_tool<'a>(
+        &'a self,
+        name: &'a str,
+        args: Option<serde_json::Value>,
+    ) -> ToolCallFuture<'a> {
+        let Some(entries) = &self.entries else {
+            return self.inner.call_tool(name, args);
+        };
+        Box::pin(async move {
+            let traced_args = args.clone();
+            let started = Instant::now();
+            let result = self.inner.call_tool(name, args).await;
+            let (ok, text, error) = match &result {
+                Ok(result) if result.is_error == Some(true) => {
+                    (false, None, Some(truncate(format_call_result(result))))
+                }
+                Ok(result) => (true, Some(truncate(format_call_result(result))), None),
+                Err(e) => (false, None, Some(e.to_string())),
+            };
+            entries.lock().unwrap().push(TraceEntry {
+                tool: name.to_string(),
+                args: traced_args,
+                duration_ms: started.elapsed().as_millis() as u64,
+                ok,
+                result: text,
+                error,
+            });
+            result
+        })
+    }
+
+    fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+        self.inner.server_request(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rmcp::model::{CallToolResult, Content};
+
+    struct Echo;
+
+    impl ToolCaller for Echo {
+        fn call_tool<'a>(
+            &'a self,
+            name: &'a str,
+            args: Option<serde_json::Value>,
+        ) -> ToolCallFuture<'a> {
+            Box::pin(async move {
+                match name {
+                    "echo" => Ok(CallToolResult::success(vec![Content::text(
+                        args.unwrap_or_default()["text"]
+                            .as_str()
+                            .unwrap_or_default()
+                            .to_string(),
+                    )])),
+                    "refuse" => Ok(CallToolResult::error(vec![Content::text("no")])),
+                    _ => anyhow::bail!("Tool call failed: boom"),
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_trace() {
+        let caller = TraceToolCaller::new(Echo, true);
+        let entries = caller.entries().unwrap();
+        let long = "x".repeat(MAX_RESULT_CHARS + 10);
+        caller
+            .call_tool("echo", Some(serde_json::json!({"text": "hi"})))
+            .await
+            .unwrap();
+        caller
+            .call_tool("echo", Some(serde_json::json!({"text": long})))
+            .await
+            .unwrap();
+        caller.call_tool("refuse", None).await.unwrap();
+        caller.call_tool("fail", None).await.unwrap_err();
+
+        let entries = entries.lock().unwrap().clone();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].tool, "echo");
+        assert_eq!(entries[0].args, Some(serde_json::json!({"text": "hi"})));
+        assert!(entries[0].ok);
+        assert_eq!(entries[0].result.as_deref(), Some("hi"));
+        assert_eq!(
+            entries[1].result.as_deref(),
+            Some(format!("{}... (510 characters)", "x".repeat(MAX_RESULT_CHARS)).as_str())
+        );
+        assert!(!entries[2].ok);
+        assert_eq!(entries[2].error.as_deref(), Some("no"));
+        assert_eq!(entries[3].error.as_deref(), Some("Tool call failed: boom"));
+
+        let untraced = TraceToolCaller::new(Echo, false);
+        assert!(untraced.entries().is_none());
+    }
+}
@@ -0,0 +1,338 @@
+//! Strip TypeScript type syntax from scripts before they reach QuickJS.
+//!
+//! Types are erased by overwriting them with spaces rather than by
+//! re-generating the code, so line and column numbers in runtime errors still
+//! point at the script the model wrote.
+
+use oxc_allocator::Allocator;
+use oxc_ast::ast::*;
+use oxc_ast_visit::{Visit, walk};
+use oxc_parser::Parser;
+use oxc_span::{GetSpan, SourceType, Span};
+use oxc_syntax::scope::ScopeFlags;
+
+/// Convert TypeScript source to JavaScript. Plain JavaScript is returned
+/// unchanged. Errors carry 1-based line and column numbers.
+pub fn strip_types(source: &str) -> Result<String, String> {
+    let allocator = Allocator::default();
+    let source_type = SourceType::ts().with_script(true);
+    let parsed = Parser::new(&allocator, source, source_type).parse();
+
+    if let Some(error) = parsed.errors.first() {
+        let offset = error
+            .labels
+            .as_ref()
+            .and_then(|labels| labels.first())
+            .map(|label| label.offset());
+        return Err(match offset {
+            Some(offset) => {
+                let (line, column) = line_column(source, offset);
+                format!("TypeScript error at line {line}, column {column}: {error}")
+            }
+            None => format!("TypeScript error: {error}"),
+        });
+    }
+
+    let mut stripper = Stripper {
+        source,
+        blanks: Vec::new(),
+        unsupported: None,
+    };
+    stripper.visit_program(&parsed.program);
+
+    if let Some((feature, span)) = stripper.unsupported {
+        let (line, column) = line_column(source, span.start as usize);
+        return Err(format!(
+            "TypeScript error at line {line}, column {column}: {feature} are not supported, use plain JavaScript instead"
+        ));
+    }
+
+    if stripper.blanks.is_empty() {
+        return Ok(source.to_string());
+    }
+
+    let mut output = source.as_bytes().to_vec();
+    for (start, end) in stripper.blanks {
+        for byte in &mut output[start..end] {
+            // Keep line breaks so positions survive; everything else becomes a
+            // space. Multi-byte characters are blanked byte by byte, which
+            // still yields valid UTF-8.
+            if *byte != b'\n' && *byte != b'\r' {
+                *byte = b' ';
+            }
+        }
+    }
+    Ok(String::from_utf8(output).expect("blanking preserves UTF-8"))
+}
+
+fn line_column(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.len() - before.rfind('\n').map(|i| i + 1).unwrap_or(0) + 1;
+    (line, column)
+}
+
+struct Stripper<'s> {
+    source: &'s str,
+    blanks: Vec<(usize, usize)>,
+    unsupported: Option<(&'static str, Span)>,
+}
+
+impl Stripper<'_> {
+    fn blank(&mut self, start: u32, end: u32) {
+        if start < end {
+            self.blanks.push((start as usize, end as usize));
+        }
+    }
+
+    fn blank_span(&mut self, span: Span) {
+        self.blank(span.start, span.end);
+    }
+
+    /// Blank the first occurrence of `ch` in `[start, end)`.
+    fn blank_char(&mut self, ch: char, start: u32, end: u32) {
+        if let Some(pos) = self.source[start as usize..end as usize].find(ch) {
+            let pos = start + pos as u32;
+            self.blank(pos, pos + 1);
+        }
+    }
+
+    /// Blank TypeScript-only modifier keywords in `[start, end)`.
+    fn blank_modifiers(&mut self, start: u32, end: u32) {
+        const MODIFIERS: [&str; 6] = [
+            "public",
+            "private",
+            "protected",
+            "readonly",
+            "override",
+            "declare",
+        ];
+        let text = &self.source[start as usize..end as usize];
+        let mut offset = 0;
+        for word in text.split(|c: char| c.is_whitespace()) {
+            if MODIFIERS.contains(&word) {
+                let word_start = start + offset as u32;
+                self.blank(word_start, word_start + word.len() as u32);
+            }
+            offset += word.len() + 1;
+        }
+    }
+
+    fn unsupported(&mut self, feature: &'static str, span: Span) {
+        self.unsupported.get_or_insert((feature, span));
+    }
+}
+
+impl<'a> Visit<'a> for Stripper<'_> {
+    fn visit_ts_type_annotation(&mut self, it: &TSTypeAnnotation<'a>) {
+        self.blank_span(it.span);
+    }
+
+    fn visit_ts_type_parameter_declaration(&mut self, it: &TSTypeParameterDeclaration<'a>) {
+        self.blank_span(it.span);
+    }
+
+    fn visit_ts_type_parameter_instantiation(&mut self, it: &TSTypeParameterInstantiation<'a>) {
+        self.blank_span(it.span);
+    }
+
+    fn visit_ts_interface_declaration(&mut self, it: &TSInterfaceDeclaration<'a>) {
+        self.blank_span(it.span);
+    }
+
+    fn visit_ts_type_alias_declaration(&mut self, it: &TSTypeAliasDeclaration<'a>) {
+        self.blank_span(it.span);
+    }
+
+    fn visit_ts_as_expression(&mut self, it: &TSAsExpression<'a>) {
+        self.blank(it.expression.span().end, it.span.end);
+        self.visit_expression(&it.expression);
+    }
+
+    fn visit_ts_satisfies_expression(&mut self, it: &TSSatisfiesExpression<'a>) {
+        self.blank(it.expression.span().end, it.span.end);
+        self.visit_expression(&it.expression);
+    }
+
+    fn visit_ts_non_null_expression(&mut self, it: &TSNonNullExpression<'a>) {
+        self.blank(it.expression.span().end, it.span.end);
+        self.visit_expression(&it.expression);
+    }
+
+    fn visit_ts_type_assertion(&mut self, it: &TSTypeAssertion<'a>) {
+        self.blank(it.span.start, it.expression.span().start);
+        self.visit_expression(&it.expression);
+    }
+
+    fn visit_ts_enum_declaration(&mut self, it: &TSEnumDeclaration<'a>) {
+        self.unsupported("enums", it.span);
+    }
+
+    fn visit_ts_module_declaration(&mut self, it: &TSModuleDeclaration<'a>) {
+        if it.declare {
+            self.blank_span(it.span);
+        } else {
+            self.unsupported("namespaces", it.span);
+        }
+    }
+
+    fn visit_decorator(&mut self, it: &Decorator<'a>) {
+        self.unsupported("decorators", it.span);
+    }
+
+    fn visit_variable_declaration(&mut self, it: &VariableDeclaration<'a>) {
+        if it.declare {
+            self.blank_span(it.span);
+            return;
+        }
+        walk::walk_variable_declaration(self, it);
+    }
+
+    fn visit_variable_declarator(&mut self, it: &VariableDeclarator<'a>) {
+        if it.definite
+            && let Some(annotation) = &it.type_annotation
+        {
+            self.blank_char('!', it.id.span().end, annotation.span.start);
+        }
+        walk::walk_variable_declarator(self, it);
+    }
+
+    fn visit_function(&mut self, it: &Function<'a>, flags: ScopeFlags) {
+        // Ambient declarations and overload signatures have no body
+        if it.declare || it.body.is_none() {
+            self.blank_span(it.span);
+            return;
+        }
+        if let Some(this_param) = &it.this_param {
+            let end = self.source[this_param.span.end as usize..]
+                .find([',', ')'])
+                .map(|i| this_param.span.end + i as u32)
+                .unwrap_or(this_param.span.end);
+            let end = if self.source[end as usize..].starts_with(',') {
+                end + 1
+            } else {
+                end
+            };
+            self.blank(this_param.span.start, end);
+        }
+        walk::walk_function(self, it, flags);
+    }
+
+    fn visit_formal_parameter(&mut self, it: &FormalParameter<'a>) {
+        if it.accessibility.is_some() || it.readonly || it.r#override {
+            self.unsupported("parameter properties", it.span);
+        }
+        if it.optional {
+            let end = it
+                .type_annotation
+                .as_ref()
+                .map(|t| t.span.start)
+                .or_else(|| it.initializer.as_ref().map(|i| i.span().start))
+                .unwrap_or(it.span.end);
+            self.blank_char('?', it.pattern.span().end, end);
+        }
+        walk::walk_formal_parameter(self, it);
+    }
+
+    fn visit_class(&mut self, it: &Class<'a>) {
+        if it.declare {
+            self.blank_span(it.span);
+            return;
+        }
+        if it.r#abstract {
+            self.unsupported("abstract classes", it.span);
+        }
+        if let (Some(first), Some(last)) = (it.implements.first(), it.implements.last()) {
+            let head = &self.source[it.span.start as usize..first.span.start as usize];
+            if let Some(keyword) = head.rfind("implements") {
+                self.blank(it.span.start + keyword as u32, last.span.end);
+            }
+        }
+        walk::walk_class(self, it);
+    }
+
+    fn visit_property_definition(&mut self, it: &PropertyDefinition<'a>) {
+        if it.declare || it.r#type == PropertyDefinitionType::TSAbstractPropertyDefinition {
+            self.blank_span(it.span);
+            return;
+        }
+        self.blank_modifiers(it.span.start, it.key.span().start);
+        if it.optional || it.definite {
+            let end = it
+                .type_annotation
+                .as_ref()
+                .map(|t| t.span.start)
+                .or_else(|| it.value.as_ref().map(|v| v.span().start))
+                .unwrap_or(it.span.end);
+            let marker = if it.optional { '?' } else { '!' };
+            self.blank_char(marker, it.key.span().end, end);
+        }
+        walk::walk_property_definition(self, it);
+    }
+
+    fn visit_method_definition(&mut self, it: &MethodDefinition<'a>) {
+        if it.value.body.is_none() || it.r#type == MethodDefinitionType::TSAbstractMethodDefinition
+        {
+            self.blank_span(it.span);
+            return;
+        }
+        self.blank_modifiers(it.span.start, it.key.span().start);
+        walk::walk_method_definition(self, it);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_javascript_is_unchanged() {
+        let code = "var items = tools.get_items({});\nitems.length;";
+        assert_eq!(strip_types(code).unwrap(), code);
+    }
+
+    #[test]
+    fn test_strips_annotations_and_declarations() {
+        let code = r#"
+interface Item { id: string; value: number }
+type Id = string;
+function total(items: Item[], scale?: number): number {
+    var sum: number = 0;
+    for (const item of items as Item[]) { sum += item!.value; }
+    return sum * (scale ?? 1);
+}
+const ids = new Set<Id>();
+total([{id: "a", value: 2}], 3);
+"#;
+        let js = strip_types(code).unwrap();
+        assert!(!js.contains("interface"));
+        assert!(!js.contains(": number"));
+        assert!(!js.contains(" as "));
+        assert!(!js.contains("<Id>"));
+        assert!(!js.contains("scale?"));
+        assert_eq!(js.lines().count(), code.lines().count());
+    }
+
+    #[test]
+    fn test_strips_class_modifiers() {
+        let code = "class A implements B { private readonly x: number = 1; public get(): number { return this.x; } }";
+        let js = strip_types(code).unwrap();
+        assert!(!js.contains("implements"));
+        assert!(!js.contains("private"));
+        assert!(!js.contains("readonly"));
+        assert!(!js.contains("public"));
+        assert!(js.contains("x   "));
+    }
+
+    #[test]
+    fn test_reports_syntax_error_position() {
+        let err = strip_types("var a = 1;\nvar b: = 2;").unwrap_err();
+        assert!(err.starts_with("TypeScript error at line 2"), "{err}");
+    }
+
+    #[test]
+    fn test_rejects_enums() {
+        let err = strip_types("enum Color { Red }").unwrap_err();
+        assert!(err.contains("enums are not supported"), "{err}");
+    }
+}
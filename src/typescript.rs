@@ -1,8 +1,126 @@
-use rmcp::model::Tool;
+use crate::alias::{identifier_aliases, script_aliases};
+use crate::config::CodeModeConfig;
+use rmcp::model::{Meta, Tool};
 use serde_json::Value;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Write;
 
+/// `_meta` key of a tool's example calls, rendered as `@example` tags.
+pub const EXAMPLES_META: &str = "examples";
+
+/// Example calls from the tool's `_meta.examples`.
+fn tool_examples(tool: &Tool) -> Vec<&str> {
+    tool.meta
+        .as_ref()
+        .and_then(|meta| meta.get(EXAMPLES_META))
+        .and_then(Value::as_array)
+        .map(|examples| examples.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default()
+}
+
+/// Put `tools.<name>.examples` from the config in the tools' `_meta`, in
+/// place of any examples the downstream server gave.
+pub fn add_examples(mut tools: Vec<Tool>, config: &CodeModeConfig) -> Vec<Tool> {
+    for tool in &mut tools {
+        let Some(settings) = config
+            .tools
+            .get(tool.name.as_ref())
+            .filter(|s| !s.examples.is_empty())
+        else {
+            continue;
+        };
+        tool.meta
+            .get_or_insert_with(Meta::new)
+            .insert(EXAMPLES_META.to_string(), settings.examples.clone().into());
+    }
+    tools
+}
+
+/// The JSDoc comment above a function: its description and examples.
+fn write_tool_doc(output: &mut String, description: Option<&str>, examples: &[&str]) {
+    if examples.is_empty() && !description.is_some_and(|desc| desc.contains('\n')) {
+        if let Some(desc) = description {
+            writeln!(output, "  /** {desc} */").unwrap();
+        }
+        return;
+    }
+    writeln!(output, "  /**").unwrap();
+    if let Some(desc) = description {
+        for line in desc.lines() {
+            writeln!(output, "   * {line}").unwrap();
+        }
+    }
+    for example in examples {
+        writeln!(output, "   * @example").unwrap();
+        for line in example.trim().lines() {
+            writeln!(output, "   * {line}").unwrap();
+        }
+    }
+    writeln!(output, "   */").unwrap();
+}
+
 pub fn generate_typescript_interface(tools: &[Tool], namespace: &str) -> String {
+    generate_interface(tools, namespace, true, false, &HashSet::new())
+}
+
+/// Like `generate_typescript_interface`, without JSDoc comments.
+pub fn generate_typescript_signatures(tools: &[Tool], namespace: &str) -> String {
+    generate_interface(tools, namespace, false, false, &HashSet::new())
+}
+
+/// What tools configured with `lazy_result` return to scripts.
+const LAZY_RESULT_INTERFACE: &str = "  /** A large tool result, read a slice at a time */
+  interface LazyResult {
+    /** Length of the result text */
+    length: number;
+    /** `len` characters of the text from `offset`, or the rest of it */
+    read(offset?: number, len?: number): string;
+    /** The whole text */
+    text(): string;
+  }
+";
+
+/// Tool name to the identifier scripts call it by, for tools whose names
+/// aren't identifiers.
+fn identifiers_by_tool(tools: &[Tool]) -> HashMap<String, String> {
+    identifier_aliases(&tool_names(tools))
+        .into_iter()
+        .map(|(identifier, name)| (name, identifier))
+        .collect()
+}
+
+/// Tool name to its camelCase alias, when `camel_case` is set.
+fn aliases_by_tool(tools: &[Tool], camel_case: bool) -> HashMap<String, String> {
+    if !camel_case {
+        return HashMap::new();
+    }
+    let names = tool_names(tools);
+    let identifiers = identifier_aliases(&names);
+    script_aliases(&names, true)
+        .into_iter()
+        .filter(|(alias, _)| !identifiers.contains_key(alias))
+        .map(|(alias, name)| (name, alias))
+        .collect()
+}
+
+fn tool_names(tools: &[Tool]) -> Vec<String> {
+    tools.iter().map(|tool| tool.name.to_string()).collect()
+}
+
+/// The interface, with JSDoc comments when `docs` is set and each camelCase
+/// alias declared after its tool when `camel_case` is. A tool whose name
+/// isn't an identifier is declared under the identifier scripts call it by,
+/// with a comment naming the tool. Tools in `lazy_tools` are declared as
+/// returning a `LazyResult` reader.
+pub(crate) fn generate_interface(
+    tools: &[Tool],
+    namespace: &str,
+    docs: bool,
+    camel_case: bool,
+    lazy_tools: &HashSet<String>,
+) -> String {
+    let identifiers = identifiers_by_tool(tools);
+    let aliases = aliases_by_tool(tools, camel_case);
     let mut output = String::new();
 
     writeln!(
@@ -12,44 +130,119 @@ pub fn generate_typescript_interface(tools: &[Tool], namespace: &str) -> String
     .unwrap();
     writeln!(output, "// Do not edit manually\n").unwrap();
     writeln!(output, "declare namespace {namespace} {{").unwrap();
+    if tools
+        .iter()
+        .any(|tool| lazy_tools.contains(tool.name.as_ref()))
+    {
+        output.push_str(LAZY_RESULT_INTERFACE);
+        output.push('\n');
+    }
 
     for tool in tools {
-        let interface_name = to_pascal_case(&tool.name);
-        let fn_name = tool.name.replace('-', "_");
+        let fn_name = identifiers
+            .get(tool.name.as_ref())
+            .map(String::as_str)
+            .unwrap_or(&tool.name);
+        let interface_name = to_pascal_case(fn_name);
 
-        if let Some(desc) = &tool.description {
-            writeln!(output, "  /** {desc} */").unwrap();
+        if fn_name != tool.name {
+            writeln!(
+                output,
+                "  // {namespace}.{fn_name} calls the tool {:?}",
+                tool.name
+            )
+            .unwrap();
+        }
+        if docs {
+            write_tool_doc(
+                &mut output,
+                tool.description.as_deref(),
+                &tool_examples(tool),
+            );
         }
 
-        let params_type = generate_params_interface(&tool.input_schema, &interface_name, 1);
-        let return_type = tool
-            .output_schema
-            .as_ref()
-            .map(|schema| {
-                json_schema_to_typescript(&serde_json::Value::Object(schema.as_ref().clone()))
-            })
-            .unwrap_or_else(|| "unknown".to_string());
+        let params_type = generate_params_interface(&tool.input_schema, &interface_name, 1, docs);
+        let return_type = if lazy_tools.contains(tool.name.as_ref()) {
+            "LazyResult".to_string()
+        } else {
+            tool.output_schema
+                .as_ref()
+                .map(|schema| {
+                    json_schema_to_typescript(&serde_json::Value::Object(schema.as_ref().clone()))
+                })
+                .unwrap_or_else(|| "unknown".to_string())
+        };
 
         if !params_type.is_empty() {
             output.push_str(&params_type);
             writeln!(
                 output,
-                "  function {fn_name}(params: {interface_name}Params): {return_type};\n"
+                "  function {fn_name}(params: {interface_name}Params): {return_type};"
             )
             .unwrap();
         } else {
-            writeln!(output, "  function {fn_name}(): {return_type};\n").unwrap();
+            writeln!(output, "  function {fn_name}(): {return_type};").unwrap();
         }
+        if let Some(alias) = aliases.get(tool.name.as_ref()) {
+            writeln!(output, "  const {alias}: typeof {fn_name};").unwrap();
+        }
+        output.push('\n');
     }
 
     writeln!(output, "}}").unwrap();
     output
 }
 
+/// One line per tool, with the first sentence of its description when
+/// `summaries` is set and its camelCase alias when `camel_case` is. A tool
+/// whose name isn't an identifier is listed under the identifier scripts
+/// call it by.
+pub fn generate_tool_index(
+    tools: &[Tool],
+    namespace: &str,
+    summaries: bool,
+    camel_case: bool,
+) -> String {
+    let identifiers = identifiers_by_tool(tools);
+    let aliases = aliases_by_tool(tools, camel_case);
+    let mut output = String::new();
+    for tool in tools {
+        let fn_name = identifiers
+            .get(tool.name.as_ref())
+            .map(String::as_str)
+            .unwrap_or(&tool.name);
+        let name = match aliases.get(tool.name.as_ref()) {
+            Some(alias) => format!("{namespace}.{fn_name} (or {namespace}.{alias})"),
+            None => format!("{namespace}.{fn_name}"),
+        };
+        match tool
+            .description
+            .as_deref()
+            .filter(|_| summaries)
+            .map(summary_line)
+        {
+            Some(summary) if !summary.is_empty() => {
+                writeln!(output, "- {name}: {summary}").unwrap()
+            }
+            _ => writeln!(output, "- {name}").unwrap(),
+        }
+    }
+    output
+}
+
+fn summary_line(description: &str) -> &str {
+    let line = description.trim().lines().next().unwrap_or_default();
+    match line.find(". ") {
+        Some(end) => &line[..=end],
+        None => line,
+    }
+}
+
 fn generate_params_interface(
     schema: &serde_json::Map<String, Value>,
     base_name: &str,
     indent: usize,
+    docs: bool,
 ) -> String {
     let mut output = String::new();
     let indent_str = "  ".repeat(indent);
@@ -76,7 +269,11 @@ fn generate_params_interface(
             let ts_type = json_schema_to_typescript_with_defs(prop_schema, defs);
             let optional = if is_required { "" } else { "?" };
 
-            if let Some(desc) = prop_schema.get("description").and_then(|d| d.as_str()) {
+            if let Some(desc) = prop_schema
+                .get("description")
+                .and_then(|d| d.as_str())
+                .filter(|_| docs)
+            {
                 writeln!(output, "{indent_str}  /** {desc} */").unwrap();
             }
 
@@ -184,7 +381,8 @@ fn json_schema_to_typescript_with_defs(schema: &Value, defs: Option<&Value>) ->
 }
 
 fn to_pascal_case(s: &str) -> String {
-    s.split(['_', '-'])
+    let pascal: String = s
+        .split(|c: char| !c.is_ascii_alphanumeric())
         .map(|part| {
             let mut chars = part.chars();
             match chars.next() {
@@ -192,7 +390,11 @@ fn to_pascal_case(s: &str) -> String {
                 Some(first) => first.to_uppercase().chain(chars).collect(),
             }
         })
-        .collect()
+        .collect();
+    match pascal.starts_with(|c: char| c.is_ascii_digit()) {
+        true => format!("_{pascal}"),
+        false => pascal,
+    }
 }
 
 #[cfg(test)]
@@ -262,7 +464,7 @@ mod tests {
             }),
         );
 
-        let ts = generate_typescript_interface(&[tool], "kicad");
+        let ts = generate_typescript_interface(std::slice::from_ref(&tool), "kicad");
 
         assert!(ts.contains("declare namespace kicad"));
         assert!(ts.contains("interface MoveFootprintParams"));
@@ -270,6 +472,105 @@ mod tests {
         assert!(ts.contains("x_mm: number"));
         assert!(ts.contains("rotation?: number")); // optional
         assert!(ts.contains("function move_footprint(params: MoveFootprintParams): unknown"));
+
+        let signatures = generate_typescript_signatures(&[tool], "kicad");
+        assert!(signatures.contains("x_mm: number"));
+        assert!(!signatures.contains("/**"));
+    }
+
+    #[test]
+    fn test_examples() {
+        let mut tool = make_tool(
+            "search",
+            "Search issues",
+            json!({
+                "type": "object",
+                "properties": {"query": {"type": "string"}}
+            }),
+        );
+        let mut meta = Meta::new();
+        meta.insert(
+            EXAMPLES_META.to_string(),
+            json!(["tools.search({query: \"is:open\"})"]),
+        );
+        tool.meta = Some(meta);
+
+        let ts = generate_typescript_interface(std::slice::from_ref(&tool), "tools");
+        assert!(ts.contains(
+            "  /**\n   * Search issues\n   * @example\n   * tools.search({query: \"is:open\"})\n   */\n"
+        ));
+        let signatures = generate_typescript_signatures(std::slice::from_ref(&tool), "tools");
+        assert!(!signatures.contains("@example"));
+
+        // Examples from the config replace the downstream server's
+        let config: CodeModeConfig = toml::from_str(
+            r#"
+            [tools.search]
+            examples = ["const open = tools.search({\n  query: \"is:open\",\n});"]
+            "#,
+        )
+        .unwrap();
+        let tools = add_examples(vec![tool], &config);
+        let ts = generate_typescript_interface(&tools, "tools");
+        assert!(!ts.contains("is:open\"})"));
+        assert!(ts.contains(
+            "   * @example\n   * const open = tools.search({\n   *   query: \"is:open\",\n   * });\n"
+        ));
+    }
+
+    #[test]
+    fn test_camel_case_aliases() {
+        let tools = [
+            make_tool("move_footprint", "Move", json!({"type": "object"})),
+            make_tool("list", "List", json!({"type": "object"})),
+        ];
+        let ts = generate_interface(&tools, "tools", false, true, &HashSet::new());
+        assert!(ts.contains(
+            "  function move_footprint(): unknown;\n  const moveFootprint: typeof move_footprint;\n\n"
+        ));
+        assert!(!ts.contains("typeof list"));
+        assert!(!generate_typescript_interface(&tools, "tools").contains("moveFootprint"));
+
+        assert_eq!(
+            generate_tool_index(&tools, "tools", false, true),
+            "- tools.move_footprint (or tools.moveFootprint)\n- tools.list\n"
+        );
+    }
+
+    #[test]
+    fn test_lazy_tools_return_readers() {
+        let tools = [
+            make_tool("dump", "Dump", json!({"type": "object"})),
+            make_tool("list", "List", json!({"type": "object"})),
+        ];
+        let lazy = HashSet::from(["dump".to_string()]);
+        let ts = generate_interface(&tools, "tools", true, false, &lazy);
+        assert!(ts.contains("  interface LazyResult {\n"));
+        assert!(ts.contains("    read(offset?: number, len?: number): string;\n"));
+        assert!(ts.contains("  function dump(): LazyResult;\n"));
+        assert!(ts.contains("  function list(): unknown;\n"));
+
+        let ts = generate_interface(&tools[1..], "tools", true, false, &lazy);
+        assert!(!ts.contains("LazyResult"));
+    }
+
+    #[test]
+    fn test_tool_names_that_are_not_identifiers() {
+        let tools = [
+            make_tool("repos.list", "List repos", json!({"type": "object"})),
+            make_tool("search-issues", "Search", json!({"type": "object"})),
+        ];
+        let ts = generate_interface(&tools, "tools", false, true, &HashSet::new());
+        assert!(ts.contains(
+            "  // tools.repos_list calls the tool \"repos.list\"\n  function repos_list(): unknown;\n  const reposList: typeof repos_list;\n"
+        ));
+        assert!(ts.contains("  function search_issues(): unknown;\n"));
+        assert!(!ts.contains("search-issues("));
+
+        assert_eq!(
+            generate_tool_index(&tools, "tools", false, false),
+            "- tools.repos_list\n- tools.search_issues\n"
+        );
     }
 
     #[test]
@@ -299,6 +600,8 @@ mod tests {
         assert_eq!(to_pascal_case("get_items"), "GetItems");
         assert_eq!(to_pascal_case("move-footprint"), "MoveFootprint");
         assert_eq!(to_pascal_case("simple"), "Simple");
+        assert_eq!(to_pascal_case("repos.list"), "ReposList");
+        assert_eq!(to_pascal_case("_2fa_verify"), "_2faVerify");
     }
 
     #[test]
@@ -336,4 +639,24 @@ mod tests {
         assert!(ts.contains("string"));
         assert!(ts.contains("null"));
     }
+
+    #[test]
+    fn test_tool_index() {
+        let tools = vec![
+            make_tool(
+                "get-items",
+                "Get all items. Supports paging.\nMore details.",
+                json!({}),
+            ),
+            make_tool("add", "", json!({})),
+        ];
+        assert_eq!(
+            generate_tool_index(&tools, "tools", true, false),
+            "- tools.get_items: Get all items.\n- tools.add\n"
+        );
+        assert_eq!(
+            generate_tool_index(&tools, "tools", false, false),
+            "- tools.get_items\n- tools.add\n"
+        );
+    }
 }
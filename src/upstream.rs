@@ -0,0 +1,523 @@
+//! Requests from scripts to the upstream client, the agent's host, rather
+//! than to the server behind the tools.
+//!
+//! `sample(prompt, opts)` sends `sampling/createMessage` to the client, so a
+//! script can have the model summarize or classify intermediate results
+//! without a round trip through the agent. It is only available when the
+//! client declared the `sampling` capability, and gives up after
+//! `sampling_timeout_secs`.
+//!
+//! With `llm_helpers`, `llm.complete(prompt, options)` does the same under a
+//! name that reads as a step in a pipeline. Its `json: true` option parses
+//! the completion as JSON, allowing for a Markdown code fence around it, for
+//! extraction and classification prompts that ask for structured output.
+//!
+//! `elicit(schema, message)` sends `elicitation/create`, asking the user to
+//! fill in a small form, and returns their answer, or `null` if they declined
+//! or dismissed it. It needs the client's `elicitation` capability and gives
+//! up after `elicitation_timeout_secs`.
+//!
+//! `ask(prompt, schema)` is `elicit` for a single question: `schema` is a
+//! type name, a list of choices or one field's schema (free text if left
+//! out), and it returns the answer itself rather than a form's object. A
+//! full form schema, with `properties`, works as it does with `elicit`.
+//!
+//! `roots()` returns the client's roots, `[{uri, name?}]`, so a script
+//! driving filesystem-like tools can keep to the directories the user shared.
+//! It needs the client's `roots` capability.
+//!
+//! `progress(current, total, message)` sends a progress notification on the
+//! execute request's progress token, so a long script doesn't look stuck.
+//! It does nothing when the client didn't ask for progress, and drops
+//! reports that don't move forward, as MCP requires progress to increase.
+//!
+//! With `stream_logs`, each `console.log` line is also sent as it is written,
+//! as an `info` logging notification from the `console` logger, unless the
+//! client has set a higher level with `logging/setLevel`.
+
+use crate::config::CodeModeConfig;
+use crate::runtime::{ServerRequest, ServerRequestFuture, ToolCallFuture, ToolCaller};
+use anyhow::{Context, Result};
+use rmcp::model::{
+    ClientCapabilities, Content, CreateElicitationRequestParam, CreateMessageRequestParam,
+    ElicitationAction, ElicitationSchema, LoggingLevel, LoggingMessageNotificationParam, ModelHint,
+    ModelPreferences, PrimitiveSchema, ProgressNotificationParam, ProgressToken, Role,
+    SamplingMessage,
+};
+use rmcp::service::{Peer, RoleServer};
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// `maxTokens` of a `sample()` call that doesn't set one.
+pub const DEFAULT_MAX_TOKENS: u32 = 1024;
+
+/// How long `roots()` waits for the client, which answers from its own
+/// state without asking the model or the user.
+const ROOTS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The `opts` of `sample(prompt, opts)`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SampleOptions {
+    pub system_prompt: Option<String>,
+    pub max_tokens: Option<u32>,
+    pub temperature: Option<f32>,
+    pub stop_sequences: Option<Vec<String>>,
+    /// Model names or families to prefer, passed to the client as hints.
+    pub models: Option<Vec<String>>,
+}
+
+impl SampleOptions {
+    /// The `sampling/createMessage` parameters asking for a reply to `prompt`.
+    pub fn into_params(self, prompt: String) -> CreateMessageRequestParam {
+        CreateMessageRequestParam {
+            messages: vec![SamplingMessage {
+                role: Role::User,
+                content: Content::text(prompt),
+            }],
+            model_preferences: self.models.map(|models| ModelPreferences {
+                hints: Some(
+                    models
+                        .into_iter()
+                        .map(|name| ModelHint { name: Some(name) })
+                        .collect(),
+                ),
+                cost_priority: None,
+                speed_priority: None,
+                intelligence_priority: None,
+            }),
+            system_prompt: self.system_prompt,
+            include_context: None,
+            temperature: self.temperature,
+            max_tokens: self.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            stop_sequences: self.stop_sequences,
+            metadata: None,
+        }
+    }
+}
+
+/// The `elicitation/create` parameters for `elicit(schema, message)`.
+/// `type: "object"` may be left out of `schema`.
+pub fn elicitation_params(
+    mut schema: serde_json::Value,
+    message: String,
+) -> Result<CreateElicitationRequestParam> {
+    let object = schema.as_object_mut().context("Invalid elicit schema")?;
+    object.entry("type").or_insert("object".into());
+    // `PrimitiveSchema` is untagged and would read `{type: "string", enum}`
+    // as a plain string, losing the choices, so enums are picked out here.
+    let properties = object.remove("properties").unwrap_or_default();
+    let properties = serde_json::from_value::<serde_json::Map<_, _>>(properties)
+        .context("Invalid elicit schema")?
+        .into_iter()
+        .map(|(name, property)| {
+            let property = if property.get("enum").is_some() {
+                serde_json::from_value(property).map(PrimitiveSchema::Enum)
+            } else {
+                serde_json::from_value(property)
+            };
+            Ok((name, property.context("Invalid elicit schema")?))
+        })
+        .collect::<Result<_>>()?;
+    object.insert(
+        "properties".to_string(),
+        serde_json::Value::Object(Default::default()),
+    );
+    let mut requested_schema: ElicitationSchema =
+        serde_json::from_value(schema).context("Invalid elicit schema")?;
+    requested_schema.properties = properties;
+    Ok(CreateElicitationRequestParam {
+        message,
+        requested_schema,
+    })
+}
+
+/// A completion asked to be JSON, parsed, without the Markdown code fence
+/// models often wrap it in.
+pub fn completion_json(text: &str) -> Result<serde_json::Value> {
+    let trimmed = text.trim();
+    let unfenced = trimmed
+        .strip_prefix("```")
+        .and_then(|rest| rest.strip_suffix("```"))
+        .map(|body| body.split_once('\n').map_or("", |(_, body)| body))
+        .unwrap_or(trimmed);
+    serde_json::from_str(unfenced).map_err(|e| {
+        let start: String = trimmed.chars().take(200).collect();
+        anyhow::anyhow!("llm.complete: the completion is not JSON ({e}): {start}")
+    })
+}
+
+/// Name of the one field `ask()` puts a single question in.
+const ASK_FIELD: &str = "answer";
+
+/// The `elicit` schema for `ask(prompt, schema)`, and whether it wraps a
+/// single question in `ASK_FIELD` rather than being a full form.
+pub fn ask_schema(schema: Option<serde_json::Value>) -> Result<(serde_json::Value, bool)> {
+    let field = match schema {
+        None | Some(serde_json::Value::Null) => serde_json::json!({"type": "string"}),
+        Some(serde_json::Value::String(kind)) => serde_json::json!({"type": kind}),
+        Some(serde_json::Value::Array(choices)) => {
+            let choices: Vec<String> = choices
+                .into_iter()
+                .map(|choice| match choice {
+                    serde_json::Value::String(choice) => choice,
+                    other => other.to_string(),
+                })
+                .collect();
+            if choices.is_empty() {
+                anyhow::bail!("ask needs at least one choice");
+            }
+            serde_json::json!({"type": "string", "enum": choices})
+        }
+        Some(schema) if schema.get("properties").is_some() => return Ok((schema, false)),
+        Some(schema @ serde_json::Value::Object(_)) => schema,
+        Some(_) => anyhow::bail!("Invalid ask schema"),
+    };
+    Ok((
+        serde_json::json!({"properties": {ASK_FIELD: field}, "required": [ASK_FIELD]}),
+        true,
+    ))
+}
+
+/// A `ToolCaller` that serves client-bound requests like `sample()` through
+/// the upstream `peer` and passes everything else to `inner`.
+/// The level the client last asked for with `logging/setLevel`, shared by
+/// the executions it starts.
+#[derive(Clone)]
+pub struct ClientLogLevel(Arc<Mutex<LoggingLevel>>);
+
+impl Default for ClientLogLevel {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(LoggingLevel::Info)))
+    }
+}
+
+impl ClientLogLevel {
+    pub fn set(&self, level: LoggingLevel) {
+        *self.0.lock().unwrap() = level;
+    }
+
+    pub fn get(&self) -> LoggingLevel {
+        *self.0.lock().unwrap()
+    }
+}
+
+pub struct UpstreamToolCaller<C: ToolCaller> {
+    inner: C,
+    peer: Option<Peer<RoleServer>>,
+    sampling_timeout: Duration,
+    elicitation_timeout: Duration,
+    progress_token: Option<ProgressToken>,
+    last_progress: Mutex<Option<f64>>,
+    log_level: Option<LoggingLevel>,
+}
+
+impl<C: ToolCaller> UpstreamToolCaller<C> {
+    /// Without a `peer`, e.g. for executions not started by a client,
+    /// client-bound requests fail.
+    pub fn new(inner: C, peer: Option<Peer<RoleServer>>, config: &CodeModeConfig) -> Self {
+        Self {
+            inner,
+            peer,
+            sampling_timeout: config.sampling_timeout(),
+            elicitation_timeout: config.elicitation_timeout(),
+            progress_token: None,
+            last_progress: Mutex::new(None),
+            log_level: None,
+        }
+    }
+
+    /// Report `progress()` under `token`, the execute request's.
+    pub fn with_progress_token(mut self, token: Option<ProgressToken>) -> Self {
+        self.progress_token = token;
+        self
+    }
+
+    /// Send streamed `console.log` lines to a client whose logging level is
+    /// `level`; without one they are dropped.
+    pub fn with_log_level(mut self, level: Option<LoggingLevel>) -> Self {
+        self.log_level = level;
+        self
+    }
+
+    /// The client, if it declared the capability `function` needs.
+    fn client(
+        &self,
+        function: &str,
+        capability: &str,
+        supports: impl Fn(&ClientCapabilities) -> bool,
+    ) -> Result<&Peer<RoleServer>> {
+        self.peer
+            .as_ref()
+            .filter(|peer| {
+                peer.peer_info()
+                    .is_some_and(|info| supports(&info.capabilities))
+            })
+            .with_context(|| {
+                format!("{function} is not available: the client does not support {capability}")
+            })
+    }
+
+    async fn sample(
+        &self,
+        function: &str,
+        prompt: String,
+        options: SampleOptions,
+    ) -> Result<serde_json::Value> {
+        let peer = self.client(function, "sampling", |c| c.sampling.is_some())?;
+        let result = tokio::time::timeout(
+            self.sampling_timeout,
+            peer.create_message(options.into_params(prompt)),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("{function} timed out after {:?}", self.sampling_timeout))?
+        .map_err(|e| anyhow::anyhow!("Sampling failed: {e}"))?;
+        match result.message.content.as_text() {
+            Some(text) => Ok(serde_json::Value::String(text.text.clone())),
+            None => Ok(serde_json::to_value(&result.message.content)?),
+        }
+    }
+
+    async fn complete(
+        &self,
+        prompt: String,
+        options: SampleOptions,
+        json: bool,
+    ) -> Result<serde_json::Value> {
+        let completion = self.sample("llm.complete", prompt, options).await?;
+        match completion {
+            serde_json::Value::String(text) if json => completion_json(&text),
+            completion => Ok(completion),
+        }
+    }
+
+    async fn elicit(
+        &self,
+        function: &str,
+        schema: serde_json::Value,
+        message: String,
+    ) -> Result<serde_json::Value> {
+        let peer = self.client(function, "elicitation", |c| c.elicitation.is_some())?;
+        let params = elicitation_params(schema, message)?;
+        let result =
+            tokio::time::timeout(self.elicitation_timeout, peer.create_elicitation(params))
+                .await
+                .map_err(|_| {
+                    anyhow::anyhow!("{function} timed out after {:?}", self.elicitation_timeout)
+                })?
+                .map_err(|e| anyhow::anyhow!("Elicitation failed: {e}"))?;
+        match result.action {
+            ElicitationAction::Accept => Ok(result.content.unwrap_or_default()),
+            ElicitationAction::Decline | ElicitationAction::Cancel => Ok(serde_json::Value::Null),
+        }
+    }
+
+    async fn ask(
+        &self,
+        prompt: String,
+        schema: Option<serde_json::Value>,
+    ) -> Result<serde_json::Value> {
+        let (schema, single) = ask_schema(schema)?;
+        let mut answer = self.elicit("ask", schema, prompt).await?;
+        if single && let Some(value) = answer.get_mut(ASK_FIELD) {
+            return Ok(value.take());
+        }
+        Ok(answer)
+    }
+
+    async fn progress(
+        &self,
+        progress: f64,
+        total: Option<f64>,
+        message: Option<String>,
+    ) -> Result<serde_json::Value> {
+        let (Some(peer), Some(token)) = (&self.peer, &self.progress_token) else {
+            return Ok(serde_json::Value::Null);
+        };
+        {
+            let mut last = self.last_progress.lock().unwrap();
+            if last.is_some_and(|last| progress <= last) || !progress.is_finite() {
+                return Ok(serde_json::Value::Null);
+            }
+            *last = Some(progress);
+        }
+        peer.notify_progress(ProgressNotificationParam {
+            progress_token: token.clone(),
+            progress,
+            total,
+            message,
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!("Reporting progress failed: {e}"))?;
+        Ok(serde_json::Value::Null)
+    }
+
+    async fn log(&self, line: String) -> Result<serde_json::Value> {
+        let (Some(peer), Some(LoggingLevel::Debug | LoggingLevel::Info)) =
+            (&self.peer, self.log_level)
+        else {
+            return Ok(serde_json::Value::Null);
+        };
+        peer.notify_logging_message(LoggingMessageNotificationParam {
+            level: LoggingLevel::Info,
+            logger: Some("console".to_string()),
+            data: serde_json::Value::String(line),
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!("Streaming log failed: {e}"))?;
+        Ok(serde_json::Value::Null)
+    }
+
+    async fn roots(&self) -> Result<serde_json::Value> {
+        let peer = self.client("roots", "roots", |c| c.roots.is_some())?;
+        let result = tokio::time::timeout(ROOTS_TIMEOUT, peer.list_roots())
+            .await
+            .map_err(|_| anyhow::anyhow!("roots timed out after {ROOTS_TIMEOUT:?}"))?
+            .map_err(|e| anyhow::anyhow!("Listing roots failed: {e}"))?;
+        Ok(serde_json::to_value(result.roots)?)
+    }
+}
+
+impl<C: ToolCaller> ToolCaller for UpstreamToolCaller<C> {
+    fn call_tool<'a>(
+        &'a self,
+        name: &'a str,
+        args: Option<serde_json::Value>,
+    ) -> ToolCallFuture<'a> {
+        self.inner.call_tool(name, args)
+    }
+
+    fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+        match request {
+            ServerRequest::CreateMessage { prompt, options } => {
+                Box::pin(self.sample("sample", prompt, options))
+            }
+            ServerRequest::Complete {
+                prompt,
+                options,
+                json,
+            } => Box::pin(self.complete(prompt, options, json)),
+            ServerRequest::Elicit { schema, message } => {
+                Box::pin(self.elicit("elicit", schema, message))
+            }
+            ServerRequest::Ask { prompt, schema } => Box::pin(self.ask(prompt, schema)),
+            ServerRequest::ListRoots => Box::pin(self.roots()),
+            ServerRequest::Progress {
+                progress,
+                total,
+                message,
+            } => Box::pin(self.progress(progress, total, message)),
+            ServerRequest::Log { line } => Box::pin(self.log(line)),
+            request => self.inner.server_request(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_options() {
+        let options: SampleOptions = serde_json::from_value(serde_json::json!({
+            "systemPrompt": "Answer yes or no",
+            "temperature": 0.5,
+            "models": ["claude"]
+        }))
+        .unwrap();
+        let params = options.into_params("Is 7 prime?".to_string());
+        assert_eq!(params.max_tokens, DEFAULT_MAX_TOKENS);
+        assert_eq!(params.system_prompt.as_deref(), Some("Answer yes or no"));
+        assert_eq!(
+            params.messages[0].content.as_text().unwrap().text,
+            "Is 7 prime?"
+        );
+        let hints = params.model_preferences.unwrap().hints.unwrap();
+        assert_eq!(hints[0].name.as_deref(), Some("claude"));
+
+        let unknown = serde_json::from_value::<SampleOptions>(serde_json::json!({"maxToken": 5}));
+        assert!(unknown.is_err());
+    }
+
+    #[test]
+    fn test_elicitation_params() {
+        let schema = serde_json::json!({
+            "properties": {"item": {"type": "string", "enum": ["R1", "R2", "R3"]}},
+            "required": ["item"]
+        });
+        let params = elicitation_params(schema, "Which one?".to_string()).unwrap();
+        assert_eq!(
+            params.requested_schema.required,
+            Some(vec!["item".to_string()])
+        );
+        assert!(matches!(
+            params.requested_schema.properties["item"],
+            PrimitiveSchema::Enum(_)
+        ));
+
+        let nested = serde_json::json!({"properties": {"a": {"type": "object"}}});
+        let err = elicitation_params(nested, "?".to_string()).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid elicit schema");
+    }
+
+    #[test]
+    fn test_completion_json() {
+        assert_eq!(
+            completion_json(" {\"label\": \"bug\"}\n").unwrap(),
+            serde_json::json!({"label": "bug"})
+        );
+        assert_eq!(
+            completion_json("```json\n[1, 2]\n```").unwrap(),
+            serde_json::json!([1, 2])
+        );
+        assert_eq!(
+            completion_json("```\n\"yes\"\n```").unwrap(),
+            serde_json::json!("yes")
+        );
+        let err = completion_json("It is a bug.").unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("llm.complete: the completion is not JSON"),
+            "{err}"
+        );
+        assert!(err.to_string().ends_with(": It is a bug."), "{err}");
+    }
+
+    #[test]
+    fn test_ask_schema() {
+        let text = serde_json::json!({
+            "properties": {"answer": {"type": "string"}},
+            "required": ["answer"]
+        });
+        assert_eq!(ask_schema(None).unwrap(), (text.clone(), true));
+        assert_eq!(
+            ask_schema(Some(serde_json::json!("string"))).unwrap(),
+            (text, true)
+        );
+
+        let (schema, single) = ask_schema(Some(serde_json::json!(["C1", "C2", 3]))).unwrap();
+        assert!(single);
+        assert_eq!(
+            schema["properties"]["answer"],
+            serde_json::json!({"type": "string", "enum": ["C1", "C2", "3"]})
+        );
+        let params = elicitation_params(schema, "Which one?".to_string()).unwrap();
+        assert!(matches!(
+            params.requested_schema.properties["answer"],
+            PrimitiveSchema::Enum(_)
+        ));
+
+        let (schema, _) =
+            ask_schema(Some(serde_json::json!({"type": "integer", "minimum": 1}))).unwrap();
+        assert_eq!(
+            schema["properties"]["answer"],
+            serde_json::json!({"type": "integer", "minimum": 1})
+        );
+
+        let form = serde_json::json!({"properties": {"ref": {"type": "string"}}});
+        assert_eq!(ask_schema(Some(form.clone())).unwrap(), (form, false));
+        assert!(ask_schema(Some(serde_json::json!([]))).is_err());
+        assert!(ask_schema(Some(serde_json::json!(5))).is_err());
+    }
+}
@@ -0,0 +1,254 @@
+//! Usage counters since startup, served by the optional `codemode_stats`
+//! tool (`stats_tool` setting).
+//!
+//! For each downstream tool: how many calls reached it, from scripts or
+//! passed through, how many failed, and how long they took on average. For
+//! executions: how many ran, how many failed and their average duration.
+//! Deduplication's hit rate is the share of script calls answered from an
+//! earlier identical call. Latency percentiles of recent calls come from
+//! `slo::LatencySlos`. With `[circuit_breaker]` set, the circuit's state
+//! too.
+
+use crate::circuit::CircuitBreaker;
+use crate::runtime::{
+    ExecutionResult, ServerRequest, ServerRequestFuture, ToolCallFuture, ToolCaller,
+};
+use crate::slo::LatencySlos;
+use rmcp::model::{CallToolResult, Content, EmptyObject, Tool, ToolAnnotations};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Name of the tool that reports usage counters.
+pub const STATS_TOOL: &str = "codemode_stats";
+
+#[derive(Debug, Default, Clone)]
+struct Counter {
+    count: u64,
+    errors: u64,
+    total: Duration,
+}
+
+impl Counter {
+    fn record(&mut self, duration: Duration, ok: bool) {
+        self.count += 1;
+        self.errors += u64::from(!ok);
+        self.total += duration;
+    }
+
+    fn average_ms(&self) -> f64 {
+        match self.count {
+            0 => 0.0,
+            n => (self.total.as_secs_f64() * 1000.0 / n as f64 * 10.0).round() / 10.0,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    tools: BTreeMap<String, Counter>,
+    executions: Counter,
+    script_calls: u64,
+    dedup_hits: u64,
+}
+
+/// The counters of one server, or nothing when the stats tool is off.
+pub struct UsageStats {
+    enabled: bool,
+    started: Instant,
+    counters: Mutex<Counters>,
+}
+
+impl UsageStats {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            started: Instant::now(),
+            counters: Mutex::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Count a call that reached downstream tool `tool`.
+    pub fn record_call(&self, tool: &str, duration: Duration, ok: bool) {
+        if !self.enabled {
+            return;
+        }
+        let mut counters = self.counters.lock().unwrap();
+        counters
+            .tools
+            .entry(tool.to_string())
+            .or_default()
+            .record(duration, ok);
+    }
+
+    /// Count a finished execution.
+    pub fn record_execution(&self, result: &ExecutionResult, duration: Duration) {
+        if !self.enabled {
+            return;
+        }
+        let mut counters = self.counters.lock().unwrap();
+        counters.executions.record(duration, !result.is_error);
+        counters.script_calls += result.metrics.tool_calls as u64;
+        counters.dedup_hits += result.metrics.dedup_hits as u64;
+    }
+
+    /// What `codemode_stats` reports.
+    pub fn report(&self) -> serde_json::Value {
+        let counters = self.counters.lock().unwrap();
+        let tools: serde_json::Map<String, serde_json::Value> = counters
+            .tools
+            .iter()
+            .map(|(name, counter)| {
+                (
+                    name.clone(),
+                    serde_json::json!({
+                        "calls": counter.count,
+                        "errors": counter.errors,
+                        "averageMs": counter.average_ms(),
+                    }),
+                )
+            })
+            .collect();
+        let hit_rate = match counters.script_calls {
+            0 => 0.0,
+            n => (counters.dedup_hits as f64 / n as f64 * 1000.0).round() / 1000.0,
+        };
+        serde_json::json!({
+            "uptimeSecs": self.started.elapsed().as_secs(),
+            "executions": {
+                "count": counters.executions.count,
+                "errors": counters.executions.errors,
+                "averageMs": counters.executions.average_ms(),
+            },
+            "tools": tools,
+            "dedup": {
+                "scriptCalls": counters.script_calls,
+                "hits": counters.dedup_hits,
+                "hitRate": hit_rate,
+            },
+        })
+    }
+}
+
+/// The `codemode_stats` tool.
+pub fn make_stats_tool() -> Tool {
+    use rmcp::handler::server::common::schema_for_type;
+
+    Tool {
+        name: STATS_TOOL.into(),
+        description: Some(
+            "Usage since the server started: calls, errors and average duration per downstream tool, latency percentiles of recent calls and any latency objectives breached, executions, and how often identical read-only calls were deduplicated.".into(),
+        ),
+        input_schema: Arc::new(schema_for_type::<EmptyObject>()),
+        title: None,
+        output_schema: None,
+        annotations: Some(ToolAnnotations::new().read_only(true)),
+        icons: None,
+        meta: None,
+    }
+}
+
+/// The `codemode_stats` result, with latency percentiles and the circuit's
+/// state when there is one.
+pub fn stats(usage: &UsageStats, slos: &LatencySlos, circuit: &CircuitBreaker) -> CallToolResult {
+    let mut report = usage.report();
+    report["latency"] = slos.report();
+    if circuit.is_enabled() {
+        report["circuit"] = circuit.report();
+    }
+    CallToolResult::success(vec![Content::text(
+        serde_json::to_string_pretty(&report).unwrap_or_default(),
+    )])
+}
+
+/// A `ToolCaller` that counts the calls reaching downstream tools.
+pub struct UsageToolCaller<C: ToolCaller> {
+    inner: C,
+    usage: Arc<UsageStats>,
+}
+
+impl<C: ToolCaller> UsageToolCaller<C> {
+    pub fn new(inner: C, usage: Arc<UsageStats>) -> Self {
+        Self { inner, usage }
+    }
+}
+
+impl<C: ToolCaller> ToolCaller for UsageToolCaller<C> {
+    fn call_tool<'a>(
+        &'a self,
+        name: &'a str,
+        args: Option<serde_json::Value>,
+    ) -> ToolCallFuture<'a> {
+        if !self.usage.is_enabled() {
+            return self.inner.call_tool(name, args);
+        }
+        Box::pin(async move {
+            let started = Instant::now();
+            let result = self.inner.call_tool(name, args).await;
+            let ok = result.as_ref().is_ok_and(|r| r.is_error != Some(true));
+            self.usage.record_call(name, started.elapsed(), ok);
+            result
+        })
+    }
+
+    fn server_request<'a>(&'a self, request: ServerRequest) -> ServerRequestFuture<'a> {
+        self.inner.server_request(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::ExecutionMetrics;
+
+    #[test]
+    fn test_report() {
+        let usage = UsageStats::new(true);
+        usage.record_call("search", Duration::from_millis(10), true);
+        usage.record_call("search", Duration::from_millis(20), false);
+        usage.record_call("get_item", Duration::from_millis(5), true);
+        usage.record_execution(
+            &ExecutionResult {
+                metrics: ExecutionMetrics {
+                    tool_calls: 4,
+                    dedup_hits: 1,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            Duration::from_millis(40),
+        );
+        usage.record_execution(
+            &ExecutionResult {
+                is_error: true,
+                ..Default::default()
+            },
+            Duration::from_millis(20),
+        );
+
+        let report = usage.report();
+        assert_eq!(
+            report["tools"],
+            serde_json::json!({
+                "get_item": {"calls": 1, "errors": 0, "averageMs": 5.0},
+                "search": {"calls": 2, "errors": 1, "averageMs": 15.0},
+            })
+        );
+        assert_eq!(
+            report["executions"],
+            serde_json::json!({"count": 2, "errors": 1, "averageMs": 30.0})
+        );
+        assert_eq!(
+            report["dedup"],
+            serde_json::json!({"scriptCalls": 4, "hits": 1, "hitRate": 0.25})
+        );
+
+        let disabled = UsageStats::new(false);
+        disabled.record_call("search", Duration::from_millis(10), true);
+        assert_eq!(disabled.report()["tools"], serde_json::json!({}));
+    }
+}
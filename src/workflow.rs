@@ -0,0 +1,508 @@
+//! Structured concurrency for scripts, the bulk patterns they otherwise
+//! write by hand:
+//!
+//! - `workflow.map(items, fn, opts)`: call `fn(item, index)` for every item
+//! - `workflow.dag({steps, dependsOn}, opts)`: run each of the functions in
+//!   `steps` once the steps it depends on have finished, passing it their
+//!   results by name
+//!
+//! with up to `opts.concurrency` (default 4) tool calls in flight at once.
+//!
+//! Scripts are synchronous, so the functions take turns: each one runs until
+//! it makes a tool call it doesn't have the result of yet, the calls
+//! collected that way are made together over the bridge, and each function
+//! then runs again from the start with its earlier calls answered from what
+//! was recorded. A function therefore has to make the same calls in the same
+//! order each time, and `console.log` lines are only kept from its last run.
+//! A step starts in the same round as the last of its dependencies finishes,
+//! so its first call goes out with everything else that is ready.
+//!
+//! `map` results come back in the order of `items`. By default the first item
+//! to throw stops the map; with `continueOnError: true` it returns
+//! `{results, errors}`, with `null` results for the items that failed and
+//! `{index, item, error}` for each of them. `onProgress(done, total)` is
+//! called as items finish; without it they are reported with `progress()`.
+//!
+//! `dag` returns `{results, timings}`, both by step name, with each step's
+//! `{startMs, endMs, durationMs}` counted from the start of the `dag` call.
+//! A failing step stops it too; with `continueOnError: true` it also returns
+//! `errors` by step name and the names of the steps that were `skipped`
+//! because something they depend on failed.
+//!
+//! Both take `{timeout, retries, backoff}`, for every item and as the
+//! default for every step, which `dag` lets a step override in
+//! `options.<name>`. A failed attempt at a step is repeated from the start up
+//! to `retries` times, `backoff` milliseconds after the first failure and
+//! twice as long after each one after that; spent budgets and replay errors
+//! aren't retried. `timeout` limits each attempt, so the step's tool calls
+//! get what is left of it. No call or backoff outlasts the execution's own
+//! timeout.
+//!
+//! With `reserveMs`, items and steps that haven't started once no more than
+//! that is left of the execution's timeout are not started at all, so the
+//! script has time to return what was done. `map` then returns `{results,
+//! errors, skipped}` with the indexes of the items left out, and `dag`
+//! returns `errors` and `skipped` as with `continueOnError`.
+
+use crate::runtime::{BatchedCall, ToolBridge, raw_tool_result};
+use serde::Deserialize;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Tool calls in flight at once when the script doesn't say.
+pub const DEFAULT_CONCURRENCY: usize = 4;
+
+/// Defines the `workflow` global on top of `__raw_tools`, `__raw_batch`
+/// (JSON string in, JSON string out) and `__remaining_ms`, so it has to run
+/// after the tools prelude.
+pub(crate) const WORKFLOW_PRELUDE: &str = r#"
+    (function() {
+        var WAIT = {};
+        var running = false;
+        function errorMessage(error) {
+            return error && error.message !== undefined ? String(error.message) : String(error);
+        }
+        // One run of a task: its value or error, or the tool call it is
+        // waiting for.
+        function run(task, rawTools, log) {
+            var replayed = 0;
+            var wanted = null;
+            var lines = [];
+            var recorder = {};
+            Object.keys(rawTools).forEach(function(name) {
+                recorder[name] = function(args) {
+                    if (wanted === null && replayed < task.calls.length) {
+                        var call = task.calls[replayed++];
+                        if (call.name !== name || call.args !== args) {
+                            var error = new Error(task.fn + ': functions must make the same tool calls each time they run, but ' +
+                                task.label + ' called ' + name + ' where it called ' + call.name + ' before');
+                            error.retryable = false;
+                            throw error;
+                        }
+                        return call.result;
+                    }
+                    if (wanted === null) {
+                        wanted = {name: name, args: args};
+                    }
+                    throw WAIT;
+                };
+            });
+            __raw_tools = recorder;
+            console.log = function() {
+                lines.push(arguments);
+            };
+            var outcome;
+            try {
+                outcome = {value: task.run()};
+            } catch (e) {
+                outcome = {error: e};
+            } finally {
+                __raw_tools = rawTools;
+                console.log = log;
+            }
+            // Waiting even if the task caught the WAIT and carried on
+            if (wanted !== null) {
+                return {wanted: wanted};
+            }
+            lines.forEach(function(args) {
+                log.apply(console, args);
+            });
+            return outcome;
+        }
+        // Whether a failed attempt is worth repeating: not when the budget
+        // is spent or the function itself is at fault
+        function retryable(error) {
+            return !(error && (error.retryable === false || error.code === 'budget_exceeded'));
+        }
+        // Start the next attempt at `task` after a failure, if it has any
+        // left, with its backoff delaying its first tool call.
+        function retry(task, error) {
+            if (task.attempts > task.limits.retries || !retryable(error)) {
+                return false;
+            }
+            task.delay = task.limits.backoff * Math.pow(2, task.attempts - 1);
+            task.attempts++;
+            task.calls = [];
+            task.startedAt = Date.now() + task.delay;
+            return true;
+        }
+        // Run `ready` tasks round by round, making each round's tool calls
+        // together. `settle(task, outcome)` is called as each one finishes
+        // and returns the tasks that are ready to start because of it. Once
+        // no more than `reserve` ms of the execution are left, tasks that
+        // haven't started settle as `{skipped: true}` instead.
+        function schedule(fn, ready, concurrency, reserve, settle) {
+            if (running) {
+                throw new Error(fn + ': workflow calls cannot be nested');
+            }
+            var rawTools = __raw_tools;
+            var log = console.log;
+            running = true;
+            try {
+                var pending = ready;
+                while (pending.length > 0) {
+                    var waiting = [];
+                    for (var i = 0; i < pending.length; i++) {
+                        var task = pending[i];
+                        if (task.startedAt === undefined) {
+                            if (reserve !== null && __remaining_ms() <= reserve) {
+                                pending = pending.concat(settle(task, {skipped: true}));
+                                continue;
+                            }
+                            task.startedAt = Date.now();
+                        }
+                        var outcome = run(task, rawTools, log);
+                        var timeout = task.limits.timeout;
+                        if (outcome.wanted && timeout !== null) {
+                            // What is left of the attempt's time is the
+                            // call's limit
+                            var left = timeout - (Date.now() - task.startedAt);
+                            if (left > 0) {
+                                outcome.wanted.timeoutMs = Math.ceil(left);
+                            } else {
+                                outcome = {error: new Error(task.fn + ': ' + task.label + ' timed out after ' + timeout + 'ms')};
+                            }
+                        }
+                        if (outcome.wanted) {
+                            outcome.wanted.delayMs = task.delay;
+                            task.delay = 0;
+                            waiting.push({task: task, call: outcome.wanted});
+                        } else if ('error' in outcome && retry(task, outcome.error)) {
+                            pending.push(task);
+                        } else {
+                            pending = pending.concat(settle(task, outcome));
+                        }
+                    }
+                    if (waiting.length === 0) {
+                        break;
+                    }
+                    var response = JSON.parse(__raw_batch(JSON.stringify({
+                        calls: waiting.map(function(w) { return w.call; }),
+                        concurrency: concurrency
+                    })));
+                    if (!Array.isArray(response)) {
+                        throw new Error(response.error);
+                    }
+                    waiting.forEach(function(w, i) {
+                        w.call.result = response[i];
+                        w.task.calls.push(w.call);
+                    });
+                    pending = waiting.map(function(w) { return w.task; });
+                }
+            } finally {
+                running = false;
+            }
+        }
+        function concurrencyOf(fn, opts) {
+            var concurrency = opts.concurrency == null ? null : opts.concurrency;
+            if (concurrency !== null && (typeof concurrency !== 'number' || concurrency < 1 ||
+                    Math.floor(concurrency) !== concurrency)) {
+                throw new TypeError(fn + ': concurrency must be a positive integer');
+            }
+            return concurrency;
+        }
+        function reserveOf(fn, opts) {
+            var reserve = opts.reserveMs == null ? null : opts.reserveMs;
+            if (reserve !== null && (typeof reserve !== 'number' || !(reserve >= 0))) {
+                throw new TypeError(fn + ': reserveMs must be a non-negative number of milliseconds');
+            }
+            return reserve;
+        }
+        // `{timeout, retries, backoff}` for a step, from `opts` and then
+        // `defaults`
+        function limitsOf(fn, opts, defaults) {
+            function option(name) {
+                var value = opts[name] == null ? defaults[name] : opts[name];
+                return value == null ? null : value;
+            }
+            var limits = {timeout: option('timeout'), retries: option('retries') || 0, backoff: option('backoff') || 0};
+            if (limits.timeout !== null && (typeof limits.timeout !== 'number' || !(limits.timeout > 0))) {
+                throw new TypeError(fn + ': timeout must be a positive number of milliseconds');
+            }
+            if (typeof limits.retries !== 'number' || limits.retries < 0 || Math.floor(limits.retries) !== limits.retries) {
+                throw new TypeError(fn + ': retries must be a non-negative integer');
+            }
+            if (typeof limits.backoff !== 'number' || !(limits.backoff >= 0)) {
+                throw new TypeError(fn + ': backoff must be a non-negative number of milliseconds');
+            }
+            return limits;
+        }
+        function map(items, fn, opts) {
+            if (!Array.isArray(items)) {
+                throw new TypeError('workflow.map: items must be an array');
+            }
+            if (typeof fn !== 'function') {
+                throw new TypeError('workflow.map: fn must be a function');
+            }
+            opts = opts || {};
+            var concurrency = concurrencyOf('workflow.map', opts);
+            var reserve = reserveOf('workflow.map', opts);
+            var limits = limitsOf('workflow.map', opts, {});
+            // Reported as the execution's progress unless the script
+            // takes it itself
+            var onProgress = typeof opts.onProgress === 'function' ? opts.onProgress : function(done, total) {
+                progress(done, total, 'workflow.map: ' + done + ' of ' + total + ' items done');
+            };
+            var tasks = items.map(function(item, index) {
+                return {
+                    fn: 'workflow.map',
+                    label: 'item ' + index,
+                    calls: [],
+                    limits: limits,
+                    attempts: 1,
+                    delay: 0,
+                    run: function() { return fn(item, index); },
+                    item: item,
+                    index: index
+                };
+            });
+            var results = items.map(function() { return null; });
+            var errors = [];
+            var skipped = [];
+            var done = 0;
+            schedule('workflow.map', tasks, concurrency, reserve, function(task, outcome) {
+                if (outcome.skipped) {
+                    skipped.push(task.index);
+                } else if ('error' in outcome) {
+                    if (!opts.continueOnError) {
+                        throw outcome.error;
+                    }
+                    errors.push({index: task.index, item: task.item, error: errorMessage(outcome.error)});
+                } else {
+                    results[task.index] = outcome.value === undefined ? null : outcome.value;
+                }
+                done++;
+                onProgress(done, items.length);
+                return [];
+            });
+            if (!opts.continueOnError && reserve === null) {
+                return results;
+            }
+            var report = {results: results, errors: errors};
+            if (reserve !== null) {
+                report.skipped = skipped;
+            }
+            return report;
+        }
+        function dag(graph, opts) {
+            graph = graph || {};
+            opts = opts || {};
+            var steps = graph.steps;
+            var dependsOn = graph.dependsOn || {};
+            if (steps === null || typeof steps !== 'object' || Array.isArray(steps)) {
+                throw new TypeError('workflow.dag: steps must be an object of functions by name');
+            }
+            var concurrency = concurrencyOf('workflow.dag', opts);
+            var reserve = reserveOf('workflow.dag', opts);
+            var stepOptions = graph.options || {};
+            var names = Object.keys(steps);
+            names.forEach(function(name) {
+                if (typeof steps[name] !== 'function') {
+                    throw new TypeError('workflow.dag: step ' + name + ' must be a function');
+                }
+            });
+            Object.keys(stepOptions).forEach(function(name) {
+                if (!(name in steps)) {
+                    throw new TypeError('workflow.dag: options names unknown step ' + name);
+                }
+            });
+            Object.keys(dependsOn).forEach(function(name) {
+                if (!(name in steps)) {
+                    throw new TypeError('workflow.dag: dependsOn names unknown step ' + name);
+                }
+                var deps = dependsOn[name];
+                if (!Array.isArray(deps)) {
+                    throw new TypeError('workflow.dag: dependsOn.' + name + ' must be an array of step names');
+                }
+                deps.forEach(function(dep) {
+                    if (!(dep in steps)) {
+                        throw new TypeError('workflow.dag: step ' + name + ' depends on unknown step ' + dep);
+                    }
+                });
+            });
+            function depsOf(name) {
+                return dependsOn[name] || [];
+            }
+            // Steps that can never start: those on or after a cycle
+            var ordered = {};
+            var progressed = true;
+            while (progressed) {
+                progressed = false;
+                names.forEach(function(name) {
+                    if (!ordered[name] && depsOf(name).every(function(dep) { return ordered[dep]; })) {
+                        ordered[name] = true;
+                        progressed = true;
+                    }
+                });
+            }
+            var cyclic = names.filter(function(name) { return !ordered[name]; });
+            if (cyclic.length > 0) {
+                throw new TypeError('workflow.dag: steps depend on each other in a cycle: ' + cyclic.join(', '));
+            }
+
+            var started = Date.now();
+            var results = {};
+            var timings = {};
+            var errors = {};
+            var skipped = [];
+            var finished = {};
+            var tasks = {};
+            names.forEach(function(name) {
+                tasks[name] = {
+                    fn: 'workflow.dag',
+                    label: 'step ' + name,
+                    calls: [],
+                    limits: limitsOf('workflow.dag', stepOptions[name] || {}, opts),
+                    attempts: 1,
+                    delay: 0,
+                    name: name,
+                    run: function() {
+                        if (this.startMs === undefined) {
+                            this.startMs = Date.now() - started;
+                        }
+                        var inputs = {};
+                        depsOf(name).forEach(function(dep) { inputs[dep] = results[dep]; });
+                        return steps[name](inputs);
+                    }
+                };
+            });
+            function readyAfter(name) {
+                return names.filter(function(next) {
+                    var deps = depsOf(next);
+                    return !finished[next] && deps.indexOf(name) >= 0 &&
+                        deps.every(function(dep) { return finished[dep]; });
+                });
+            }
+            function skip(name) {
+                names.forEach(function(next) {
+                    if (!finished[next] && depsOf(next).indexOf(name) >= 0) {
+                        finished[next] = true;
+                        skipped.push(next);
+                        skip(next);
+                    }
+                });
+            }
+            var roots = names.filter(function(name) { return depsOf(name).length === 0; });
+            schedule('workflow.dag', roots.map(function(name) { return tasks[name]; }), concurrency, reserve, function(task, outcome) {
+                if (outcome.skipped) {
+                    finished[task.name] = true;
+                    skipped.push(task.name);
+                    skip(task.name);
+                    return [];
+                }
+                var endMs = Date.now() - started;
+                timings[task.name] = {
+                    startMs: task.startMs,
+                    endMs: endMs,
+                    durationMs: endMs - task.startMs,
+                    attempts: task.attempts
+                };
+                finished[task.name] = true;
+                if ('error' in outcome) {
+                    if (!opts.continueOnError) {
+                        throw outcome.error;
+                    }
+                    errors[task.name] = errorMessage(outcome.error);
+                    skip(task.name);
+                    return [];
+                }
+                results[task.name] = outcome.value === undefined ? null : outcome.value;
+                return readyAfter(task.name).map(function(name) { return tasks[name]; });
+            });
+            var report = {results: results, timings: timings};
+            if (opts.continueOnError || reserve !== null) {
+                report.errors = errors;
+                report.skipped = skipped;
+            }
+            return report;
+        }
+        globalThis.workflow = {map: map, dag: dag};
+    })();
+"#;
+
+/// A round of calls from `workflow.map` or `workflow.dag`, each with its arguments as the
+/// JSON text the tool wrappers pass to `__raw_tools`.
+#[derive(Debug, Deserialize)]
+struct BatchRequest {
+    calls: Vec<BatchCall>,
+    concurrency: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BatchCall {
+    name: String,
+    args: String,
+    /// Backoff before a retried step's first call.
+    #[serde(default)]
+    delay_ms: u64,
+    /// What is left of the step's `timeout`.
+    timeout_ms: Option<u64>,
+}
+
+/// Serve `__raw_batch(json)`: make the calls concurrently and return the
+/// text `__raw_tools` would have returned for each, as a JSON array, or
+/// `{"error": ...}`. Neither a step's backoff nor its timeout may run past
+/// the execution's `deadline`, which isn't checked while the calls are made.
+pub(crate) fn call_batch(
+    bridge: &ToolBridge,
+    request: &str,
+    deadline: Option<Instant>,
+    tool_names: &[String],
+    lazy_tools: &[String],
+    lazy_store: &Mutex<Vec<String>>,
+    tool_calls: &AtomicUsize,
+) -> String {
+    let request = match serde_json::from_str::<BatchRequest>(request) {
+        Ok(request) => request,
+        Err(e) => {
+            return serde_json::json!({ "error": format!("Invalid workflow request: {e}") })
+                .to_string();
+        }
+    };
+    // Only the tools the script was given, as with `__raw_tools`
+    if let Some(call) = request
+        .calls
+        .iter()
+        .find(|call| !tool_names.contains(&call.name))
+    {
+        return serde_json::json!({ "error": format!("Unknown tool `{}`", call.name) }).to_string();
+    }
+    tool_calls.fetch_add(request.calls.len(), Ordering::Relaxed);
+    let names: Vec<String> = request.calls.iter().map(|c| c.name.clone()).collect();
+    let left = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+    let calls = request
+        .calls
+        .into_iter()
+        .map(|call| {
+            let delay = Duration::from_millis(call.delay_ms);
+            let timeout = [
+                call.timeout_ms.map(Duration::from_millis),
+                left.map(|left| left.saturating_sub(delay)),
+            ]
+            .into_iter()
+            .flatten()
+            .min();
+            BatchedCall {
+                name: call.name,
+                args: serde_json::from_str(&call.args).ok(),
+                delay: left.map_or(delay, |left| delay.min(left)),
+                timeout,
+            }
+        })
+        .collect();
+    match bridge.call_tools(calls, request.concurrency.unwrap_or(DEFAULT_CONCURRENCY)) {
+        Ok(results) => {
+            let texts: Vec<String> = results
+                .into_iter()
+                .zip(&names)
+                .map(|(result, name)| {
+                    raw_tool_result(result, lazy_tools.contains(name).then_some(lazy_store))
+                })
+                .collect();
+            serde_json::json!(texts).to_string()
+        }
+        Err(e) => serde_json::json!({ "error": e.to_string() }).to_string(),
+    }
+}
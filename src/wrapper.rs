@@ -1,74 +1,50 @@
-use crate::config::{CodeModeConfig, CodeModeExposure};
-use crate::runtime::JsRuntime;
-use crate::typescript::generate_typescript_interface;
+use crate::alias::ToolAliases;
+use crate::approval::{
+    self, ApprovalRequest, Approver, approver_from_config, guarded_tools, intended_calls,
+};
+use crate::background::{
+    BackgroundExecutions, CANCEL_TOOL, GET_TOOL, START_TOOL, cancel_execution, get_execution,
+    make_background_tools, started,
+};
+use crate::bundle::RunBundle;
+use crate::cache::ResultCache;
+use crate::circuit::CircuitBreaker;
+use crate::concurrency::ConcurrencyLimits;
+use crate::config::{CodeModeConfig, CodeModeExposure, capability_report};
+use crate::cost::{CostLedger, CostModel};
+use crate::docs::{DOCS_URI, HELPER_DOCS, docs_resource};
+use crate::downstream::ToolsChanged;
+use crate::drain::Drain;
+use crate::engine::{ScriptRuntime, create_runtime};
+use crate::idempotent::{declares_idempotent, destructive_calls, destructive_tools};
+use crate::notes::FailureNotes;
+use crate::policy::{self, Policy, PolicyInput, client_name};
+use crate::proxy::{
+    ABANDON_TOOL, ExecuteCodeOutput, ExecuteCodeParams, TOOL_DOCS_TOOL, abandon_continuation,
+    error_response, execute_tool_description, json_to_content, list_tools_meta, make_abandon_tool,
+    make_tool_docs_tool, parse_execute_params, response_value, structured_output, tool_docs,
+    tools_interface, tools_page,
+};
+use crate::redact::ArgRedactions;
+use crate::retry::{RetryPolicy, is_retryable};
+use crate::roots::PathArgs;
+use crate::runtime::{ExecutionOptions, ExecutionResult, HandlerToolCaller, random_id};
+use crate::slo::{self, LatencySlos};
+use crate::stack::CallerStack;
+use crate::storage::{Storage, storage_from_config};
+use crate::summary::{ExecutionSummary, SUMMARY_URI, SummaryLog, summary_resource};
+use crate::telemetry::{execution_span, record_execution, traced_call};
+use crate::timeout::CallTimeouts;
+use crate::typescript::add_examples;
+use crate::upstream::ClientLogLevel;
+use crate::usage::{STATS_TOOL, UsageStats, make_stats_tool, stats};
 use rmcp::ServerHandler;
 use rmcp::model::*;
-use rmcp::schemars::JsonSchema;
-use rmcp::service::{RequestContext, RoleServer};
-use serde::Deserialize;
+use rmcp::service::{Peer, RequestContext, RoleServer};
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::sync::{Mutex, RwLock};
-
-/// Convert a JSON value to Content items.
-/// Recognizes image objects with {type: "image", data: "...", mimeType: "..."} format
-/// and converts them back to proper Content::image.
-fn json_to_content(value: &serde_json::Value) -> Vec<Content> {
-    // Check if it's an image object
-    if let Some(obj) = value.as_object() {
-        if obj.get("type").and_then(|v| v.as_str()) == Some("image") {
-            if let (Some(data), Some(mime_type)) = (
-                obj.get("data").and_then(|v| v.as_str()),
-                obj.get("mimeType").and_then(|v| v.as_str()),
-            ) {
-                return vec![Content::image(data, mime_type)];
-            }
-        }
-        // Check if it has a "result" field (from logs wrapper)
-        if let Some(result) = obj.get("result") {
-            let mut content = json_to_content(result);
-            if let Some(logs) = obj.get("logs") {
-                if let Some(logs_arr) = logs.as_array() {
-                    if !logs_arr.is_empty() {
-                        content.push(Content::text(format!(
-                            "Logs:\n{}",
-                            logs_arr
-                                .iter()
-                                .filter_map(|v| v.as_str())
-                                .collect::<Vec<_>>()
-                                .join("\n")
-                        )));
-                    }
-                }
-            }
-            return content;
-        }
-    }
-
-    // Check if it's an array that might contain images
-    if let Some(arr) = value.as_array() {
-        let mut content = Vec::new();
-        for item in arr {
-            content.extend(json_to_content(item));
-        }
-        if !content.is_empty() {
-            return content;
-        }
-    }
-
-    // Default: convert to text
-    vec![Content::text(
-        serde_json::to_string_pretty(value).unwrap_or_else(|_| value.to_string()),
-    )]
-}
-
-#[derive(Debug, Deserialize, JsonSchema)]
-pub struct ExecuteCodeParams {
-    #[schemars(
-        description = "JavaScript code to execute. The code has access to a `tools` object with synchronous functions for each tool. The last expression is returned. IMPORTANT: Semicolons are required after statements, and object literals must be wrapped in parentheses: ({key: value});"
-    )]
-    #[allow(dead_code)]
-    pub code: String,
-}
+use tracing::Instrument;
 
 /// A wrapper that adds code-mode capability to any ServerHandler.
 ///
@@ -77,115 +53,407 @@ pub struct ExecuteCodeParams {
 pub struct CodeModeWrapper<H: ServerHandler + Send + Sync + 'static> {
     config: CodeModeConfig,
     inner: Arc<H>,
-    cached_tools: RwLock<Vec<Tool>>,
-    cached_ts_interface: RwLock<String>,
-    runtime: Arc<Mutex<Option<JsRuntime>>>,
+    /// Marked to list the wrapped server's tools again before the next
+    /// execution.
+    tools_changed: ToolsChanged,
+    cached_tools: Arc<RwLock<Vec<Tool>>>,
+    cached_ts_interface: Arc<RwLock<String>>,
+    runtime: Arc<Mutex<Option<Box<dyn ScriptRuntime>>>>,
+    limits: Arc<ConcurrencyLimits>,
+    aliases: Arc<ToolAliases>,
+    redactions: Arc<ArgRedactions>,
+    path_args: Arc<PathArgs>,
+    costs: Arc<CostModel>,
+    session_costs: Arc<CostLedger>,
+    summaries: Arc<SummaryLog>,
+    usage: Arc<UsageStats>,
+    slos: Arc<LatencySlos>,
+    timeouts: Arc<CallTimeouts>,
+    retry: Arc<RetryPolicy>,
+    circuit: Arc<CircuitBreaker>,
+    cache: Arc<ResultCache>,
+    notes: Arc<FailureNotes>,
+    background: BackgroundExecutions,
+    /// Decides whether streamed `console.log` lines reach the client.
+    log_level: ClientLogLevel,
+    approver: Option<Arc<dyn Approver>>,
+    policy: Option<Arc<dyn Policy>>,
+    /// Where executions are recorded, if anywhere.
+    storage: Option<Arc<dyn Storage>>,
+    drain: Drain,
+}
+
+// Not derived, which would need `H: Clone`. Clones share all state, so a
+// background execution can run on its own task.
+impl<H: ServerHandler + Send + Sync + 'static> Clone for CodeModeWrapper<H> {
+    fn clone(&self) -> Self {
+        Self {
+            config: self.config.clone(),
+            inner: self.inner.clone(),
+            tools_changed: self.tools_changed.clone(),
+            cached_tools: self.cached_tools.clone(),
+            cached_ts_interface: self.cached_ts_interface.clone(),
+            runtime: self.runtime.clone(),
+            limits: self.limits.clone(),
+            aliases: self.aliases.clone(),
+            redactions: self.redactions.clone(),
+            path_args: self.path_args.clone(),
+            costs: self.costs.clone(),
+            session_costs: self.session_costs.clone(),
+            summaries: self.summaries.clone(),
+            usage: self.usage.clone(),
+            slos: self.slos.clone(),
+            timeouts: self.timeouts.clone(),
+            retry: self.retry.clone(),
+            circuit: self.circuit.clone(),
+            cache: self.cache.clone(),
+            notes: self.notes.clone(),
+            background: self.background.clone(),
+            log_level: self.log_level.clone(),
+            approver: self.approver.clone(),
+            policy: self.policy.clone(),
+            storage: self.storage.clone(),
+            drain: self.drain.clone(),
+        }
+    }
 }
 
 impl<H: ServerHandler + Send + Sync + 'static> CodeModeWrapper<H> {
     pub fn new(inner: H, config: CodeModeConfig) -> Self {
+        let limits = Arc::new(ConcurrencyLimits::from_config(&config));
+        let aliases = Arc::new(ToolAliases::from_config(&config));
+        let redactions = Arc::new(ArgRedactions::from_config(&config, &aliases));
+        let path_args = Arc::new(PathArgs::from_config(&config, &aliases));
+        let costs = Arc::new(CostModel::from_config(&config));
+        let session_costs = Arc::new(CostLedger::new(config.session_ttl()));
+        let summaries = Arc::new(SummaryLog::new(config.summary_history));
+        let usage = Arc::new(UsageStats::new(config.stats_tool));
+        let slos = Arc::new(LatencySlos::from_config(&config, &aliases));
+        let timeouts = Arc::new(CallTimeouts::from_config(&config, slos.clone()));
+        let retry = Arc::new(RetryPolicy::from_config(&config));
+        let circuit = Arc::new(CircuitBreaker::from_config(&config));
+        let cache = Arc::new(ResultCache::from_config(&config, &aliases));
+        let notes = Arc::new(FailureNotes::new(config.failure_notes));
+        let background = BackgroundExecutions::new(config.session_ttl());
+        let approver = approver_from_config(&config);
+        let storage = storage_from_config(&config);
         Self {
             config,
             inner: Arc::new(inner),
-            cached_tools: RwLock::new(Vec::new()),
-            cached_ts_interface: RwLock::new(String::new()),
+            tools_changed: ToolsChanged::default(),
+            cached_tools: Arc::default(),
+            cached_ts_interface: Arc::default(),
             runtime: Arc::new(Mutex::new(None)),
+            limits,
+            aliases,
+            redactions,
+            path_args,
+            costs,
+            session_costs,
+            summaries,
+            usage,
+            slos,
+            timeouts,
+            retry,
+            circuit,
+            cache,
+            notes,
+            background,
+            log_level: ClientLogLevel::default(),
+            approver,
+            policy: None,
+            storage,
+            drain: Drain::new(),
         }
     }
 
+    /// Decide on scripts and guarded tool calls with `approver` instead of
+    /// the one selected by the config.
+    pub fn with_approver(mut self, approver: Arc<dyn Approver>) -> Self {
+        self.approver = Some(approver);
+        self
+    }
+
+    /// Check every downstream tool call against `policy`; see
+    /// `policy::policy_from_config`.
+    pub fn with_policy(mut self, policy: Arc<dyn Policy>) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Keep recorded executions in `storage` instead of the `record_dir`
+    /// directory, recording them even without one.
+    pub fn with_storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Share a drain handle so the embedding process can stop new executions
+    /// and wait for running ones before shutting down.
+    pub fn with_drain(mut self, drain: Drain) -> Self {
+        self.drain = drain;
+        self
+    }
+
     pub fn with_default_config(inner: H) -> Self {
         Self::new(inner, CodeModeConfig::default())
     }
 
-    async fn make_execute_tools_tool(&self) -> Tool {
+    async fn make_execute_tools_tool(&self, client: Option<&ClientCapabilities>) -> Tool {
         use rmcp::handler::server::common::schema_for_type;
 
         let ts_interface = self.cached_ts_interface.read().await.clone();
-        let description = if ts_interface.is_empty() {
-            self.config.tool_description.clone()
-        } else {
-            format!(
-                "{}\n\n## Available Tools (synchronous)\n\n```typescript\n{}\n```\n\n## Notes\n\n- All tool calls are **synchronous** (no async/await needed)\n- Use `console.log(value)` to debug - logs are returned in the result",
-                self.config.tool_description, ts_interface
-            )
-        };
+        let capabilities = self.inner.get_info().capabilities;
+        let description =
+            execute_tool_description(&self.config, &ts_interface, &capabilities, client);
 
         Tool {
             name: self.config.tool_name.clone().into(),
             description: Some(description.into()),
             input_schema: Arc::new(schema_for_type::<ExecuteCodeParams>()),
             title: None,
-            output_schema: None,
+            output_schema: Some(Arc::new(schema_for_type::<ExecuteCodeOutput>())),
             annotations: None,
-            icons: None,
+            icons: self.config.tool_icons.clone(),
             meta: None,
         }
     }
 
+    /// Marking it makes the next execution list the wrapped server's tools
+    /// again, e.g. after it registers new ones.
+    pub fn tools_changed(&self) -> ToolsChanged {
+        self.tools_changed.clone()
+    }
+
     fn filter_tools(&self, tools: Vec<Tool>) -> Vec<Tool> {
-        match &self.config.include_tools {
-            Some(include) => tools
-                .into_iter()
-                .filter(|t| include.iter().any(|name| name == t.name.as_ref()))
-                .collect(),
-            None => tools,
-        }
+        let included = tools
+            .into_iter()
+            .filter(|t| self.config.includes_tool(&t.name))
+            .collect();
+        add_examples(self.aliases.rename_tools(included), &self.config)
     }
 
     async fn ensure_tools_cached(
         &self,
         context: &RequestContext<RoleServer>,
     ) -> Result<(), ErrorData> {
-        let cached = self.cached_tools.read().await;
-        if !cached.is_empty() {
+        let changed =
+            self.tools_changed.take() || self.tools_changed.expired(self.config.tools_ttl());
+        if !changed && !self.cached_tools.read().await.is_empty() {
             return Ok(());
         }
-        drop(cached);
+        self.refresh_tools(context).await?;
+        Ok(())
+    }
 
-        let inner_result = self.inner.list_tools(None, context.clone()).await?;
-        let inner_tools = self.filter_tools(inner_result.tools);
+    /// List every page of the inner server's tools and cache what scripts
+    /// see.
+    async fn refresh_tools(
+        &self,
+        context: &RequestContext<RoleServer>,
+    ) -> Result<Vec<Tool>, ErrorData> {
+        self.tools_changed.take();
+        let mut tools = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = self
+                .inner
+                .list_tools(Some(PaginatedRequestParam { cursor }), context.clone())
+                .await?;
+            tools.extend(page.tools);
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        let inner_tools = self.filter_tools(tools);
 
         {
             let mut cached = self.cached_tools.write().await;
             *cached = inner_tools.clone();
         }
+        self.tools_changed.listed();
         {
             let mut cached = self.cached_ts_interface.write().await;
-            *cached = generate_typescript_interface(&inner_tools, "tools");
+            *cached = tools_interface(
+                &self.config,
+                &self.notes.annotate(&inner_tools),
+                &self.aliases.script_namespace(),
+            );
         }
-
-        Ok(())
+        Ok(inner_tools)
     }
 
     async fn execute_code(
         &self,
-        code: &str,
+        params: &ExecuteCodeParams,
         context: &RequestContext<RoleServer>,
+        background: bool,
     ) -> Result<crate::runtime::ExecutionResult, ErrorData> {
         self.ensure_tools_cached(context).await?;
 
         let tools = self.cached_tools.read().await.clone();
         let tool_names: Vec<String> = tools.iter().map(|t| t.name.to_string()).collect();
 
-        let full_code = code.to_string();
+        let full_code = params.code.clone();
 
-        let mut runtime_guard = self.runtime.lock().await;
-        if runtime_guard.is_none() {
-            *runtime_guard = Some(
-                JsRuntime::new()
-                    .await
-                    .map_err(|e| ErrorData::internal_error(e.to_string(), None))?,
-            );
+        // Before taking the shared runtime, so executions don't wait on a
+        // human approving this one
+        if self.config.approve_scripts {
+            let request = ApprovalRequest::Script {
+                code: params.code.clone(),
+                intended_calls: intended_calls(&params.code, &tool_names),
+            };
+            if !approval::check(self.approver.as_ref(), &request).await {
+                return Ok(ExecutionResult {
+                    is_error: true,
+                    error_message: Some("Script was not approved".to_string()),
+                    ..Default::default()
+                });
+            }
         }
 
-        let runtime = runtime_guard.as_ref().unwrap();
-        let inner = self.inner.clone();
-        let context = context.clone();
+        // A background execution runs on a runtime of its own so foreground
+        // ones don't wait for it, unless it needs a session or a paused
+        // execution kept in the shared one
+        let mut shared_runtime = None;
+        let own_runtime;
+        let runtime: &dyn ScriptRuntime =
+            if background && params.session.is_none() && params.continuation.is_none() {
+                own_runtime = create_runtime(&self.config)
+                    .await
+                    .map_err(|e| ErrorData::internal_error(e.to_string(), None))?;
+                own_runtime.as_ref()
+            } else {
+                let runtime_guard = shared_runtime.insert(self.runtime.lock().await);
+                if runtime_guard.is_none() {
+                    **runtime_guard = Some(
+                        create_runtime(&self.config)
+                            .await
+                            .map_err(|e| ErrorData::internal_error(e.to_string(), None))?,
+                    );
+                }
+                runtime_guard.as_deref().unwrap()
+            };
 
-        runtime
-            .execute_with_handler(&full_code, &tool_names, inner, context)
+        let session_spent = match &params.session {
+            Some(session) if params.reset => {
+                self.session_costs.reset(session);
+                0.0
+            }
+            Some(session) => self.session_costs.total(session),
+            None => 0.0,
+        };
+        let execution_id = random_id("exec");
+        let stack = CallerStack {
+            config: &self.config,
+            tools: &tools,
+            limits: &self.limits,
+            aliases: &self.aliases,
+            redactions: &self.redactions,
+            path_args: &self.path_args,
+            costs: &self.costs,
+            usage: &self.usage,
+            slos: &self.slos,
+            timeouts: &self.timeouts,
+            retry: &self.retry,
+            circuit: &self.circuit,
+            cache: &self.cache,
+            notes: &self.notes,
+            approver: &self.approver,
+            policy: &self.policy,
+            log_level: self.log_level.get(),
+            record: self.storage.is_some(),
+            peer: Some(context.peer.clone()),
+            progress_token: context.meta.get_progress_token(),
+            cancellation: context.ct.clone(),
+            verbose: params.verbose,
+            session_spent,
+        }
+        .build(HandlerToolCaller::new(self.inner.clone(), context.clone()));
+
+        let options = ExecutionOptions {
+            timeout: self.config.timeout(),
+            cancellation: Some(context.ct.clone()),
+            cancel_grace: self.config.cancel_grace(),
+            lazy_tools: self.config.lazy_tools(),
+            session: params.session.clone(),
+            reset_session: params.reset,
+            typescript: self.config.typescript,
+            stats: self.config.stats_helpers,
+            hashes: self.config.hash_helpers,
+            llm: self.config.llm_helpers,
+            safe_regex: self.config.safe_regex,
+            stream_logs: self.config.stream_logs,
+            namespace: self.config.namespace.clone(),
+            camel_case: self.config.camel_case_aliases,
+            continuation: params.continuation.clone(),
+        };
+        let started = Instant::now();
+        let span = execution_span(&execution_id, params.session.as_deref());
+        let mut result = runtime
+            .execute(&full_code, &tool_names, stack.caller.clone(), options)
+            .instrument(span.clone())
             .await
-            .map_err(|e| ErrorData::internal_error(format!("Code execution failed: {e}"), None))
+            .map_err(|e| ErrorData::internal_error(format!("Code execution failed: {e}"), None))?;
+        stack.report(&mut result);
+        if !self.costs.is_empty() {
+            let cost = *stack.cost.lock().unwrap();
+            result.metrics.cost = Some(cost);
+            if let Some(session) = &params.session {
+                result.metrics.session_cost = Some(self.session_costs.add(session, cost));
+            }
+        }
+        record_execution(&span, &result);
+        result.execution_id = Some(execution_id.clone());
+        result.idempotent = params.idempotent || declares_idempotent(&params.code);
+        result.destructive_calls = destructive_calls(
+            &stack.tool_calls.lock().unwrap(),
+            &destructive_tools(&tools, &self.config),
+        )
+        .into_iter()
+        .collect();
+        if let Some(storage) = &self.storage {
+            let calls = std::mem::take(&mut *stack.recorded.lock().unwrap());
+            RunBundle::new(
+                &execution_id,
+                &full_code,
+                &self.config,
+                &tools,
+                &self.aliases,
+                calls,
+            )
+            .with_result(&result)
+            .record(storage.as_ref());
+        }
+        self.usage.record_execution(&result, started.elapsed());
+        self.summaries.record(ExecutionSummary::new(
+            &result,
+            started.elapsed(),
+            params.session.clone(),
+            stack.tool_calls.lock().unwrap().clone(),
+        ));
+        self.publish_notes(Some(&context.peer)).await;
+        Ok(result)
+    }
+
+    /// Put new failure notes in the execute tool's description and tell the
+    /// client to list the tools again.
+    async fn publish_notes(&self, peer: Option<&Peer<RoleServer>>) {
+        if !self.notes.take_changed() {
+            return;
+        }
+        let tools = self.cached_tools.read().await.clone();
+        *self.cached_ts_interface.write().await = tools_interface(
+            &self.config,
+            &self.notes.annotate(&tools),
+            &self.aliases.script_namespace(),
+        );
+        if let Some(peer) = peer
+            && let Err(e) = peer.notify_tool_list_changed().await
+        {
+            tracing::debug!("Failed to notify the client of new tool notes: {e}");
+        }
     }
 }
 
@@ -197,71 +465,168 @@ impl<H: ServerHandler + Send + Sync + 'static> ServerHandler for CodeModeWrapper
             info.instructions.unwrap_or_default(),
             self.config.tool_name
         ));
+        let report = capability_report(&self.config, Some(&info.server_info));
+        info.capabilities
+            .experimental
+            .get_or_insert_default()
+            .insert("codemoder".to_string(), report);
+        // For codemode://docs
+        info.capabilities.resources.get_or_insert_default();
+        if self.config.stream_logs {
+            info.capabilities.logging.get_or_insert_default();
+        }
+        // New failure notes change the execute tool's description
+        if self.config.failure_notes {
+            info.capabilities.tools.get_or_insert_default().list_changed = Some(true);
+        }
         info
     }
 
+    async fn set_level(
+        &self,
+        request: SetLevelRequestParam,
+        context: RequestContext<RoleServer>,
+    ) -> Result<(), ErrorData> {
+        self.log_level.set(request.level);
+        // The wrapped server may log too
+        if self.inner.get_info().capabilities.logging.is_some() {
+            self.inner.set_level(request, context).await
+        } else {
+            Ok(())
+        }
+    }
+
     async fn list_tools(
         &self,
         request: Option<PaginatedRequestParam>,
         context: RequestContext<RoleServer>,
     ) -> Result<ListToolsResult, ErrorData> {
-        let inner_result = self.inner.list_tools(request, context).await?;
-        let inner_tools = self.filter_tools(inner_result.tools);
-
-        {
-            let mut cached = self.cached_tools.write().await;
-            *cached = inner_tools.clone();
-        }
-        {
-            let mut cached = self.cached_ts_interface.write().await;
-            *cached = generate_typescript_interface(&inner_tools, "tools");
-        }
+        // The first page lists the inner tools again; later ones are cut
+        // from the same list so cursors stay valid
+        let inner_tools = if request.as_ref().is_none_or(|r| r.cursor.is_none()) {
+            self.refresh_tools(&context).await?
+        } else {
+            self.ensure_tools_cached(&context).await?;
+            self.cached_tools.read().await.clone()
+        };
 
+        let meta = list_tools_meta(&self.config, &inner_tools);
         let mut result_tools = match self.config.mode {
             CodeModeExposure::ReplaceTools => vec![],
-            CodeModeExposure::Add => inner_tools,
+            CodeModeExposure::Add => self.aliases.upstream_tools(inner_tools),
         };
 
-        result_tools.push(self.make_execute_tools_tool().await);
+        let client = context.peer.peer_info().map(|info| &info.capabilities);
+        result_tools.push(self.make_execute_tools_tool(client).await);
+        if self.config.lazy_tool_docs {
+            result_tools.push(make_tool_docs_tool());
+        }
+        if self.config.continuations_enabled() {
+            result_tools.push(make_abandon_tool());
+        }
+        if self.config.stats_tool {
+            result_tools.push(make_stats_tool());
+        }
+        if self.config.background_executions {
+            result_tools.extend(make_background_tools());
+        }
+        let (tools, next_cursor) =
+            tools_page(result_tools, request.as_ref(), self.config.tools_page_size)?;
 
         Ok(ListToolsResult {
-            tools: result_tools,
-            next_cursor: None,
-            meta: None,
+            tools,
+            next_cursor,
+            meta: Some(meta),
         })
     }
 
+    async fn list_prompts(
+        &self,
+        request: Option<PaginatedRequestParam>,
+        context: RequestContext<RoleServer>,
+    ) -> Result<ListPromptsResult, ErrorData> {
+        self.inner.list_prompts(request, context).await
+    }
+
+    async fn get_prompt(
+        &self,
+        request: GetPromptRequestParam,
+        context: RequestContext<RoleServer>,
+    ) -> Result<GetPromptResult, ErrorData> {
+        self.inner.get_prompt(request, context).await
+    }
+
+    async fn list_resources(
+        &self,
+        request: Option<PaginatedRequestParam>,
+        context: RequestContext<RoleServer>,
+    ) -> Result<ListResourcesResult, ErrorData> {
+        let first_page = request.as_ref().is_none_or(|r| r.cursor.is_none());
+        let mut result = self.inner.list_resources(request, context).await?;
+        if first_page {
+            if self.summaries.is_enabled() {
+                result.resources.insert(0, summary_resource());
+            }
+            result.resources.insert(0, docs_resource());
+        }
+        Ok(result)
+    }
+
+    async fn list_resource_templates(
+        &self,
+        request: Option<PaginatedRequestParam>,
+        context: RequestContext<RoleServer>,
+    ) -> Result<ListResourceTemplatesResult, ErrorData> {
+        self.inner.list_resource_templates(request, context).await
+    }
+
+    async fn read_resource(
+        &self,
+        request: ReadResourceRequestParam,
+        context: RequestContext<RoleServer>,
+    ) -> Result<ReadResourceResult, ErrorData> {
+        if request.uri == DOCS_URI {
+            return Ok(ReadResourceResult {
+                contents: vec![ResourceContents::text(HELPER_DOCS, DOCS_URI)],
+            });
+        }
+        if request.uri == SUMMARY_URI && self.summaries.is_enabled() {
+            return Ok(ReadResourceResult {
+                contents: vec![ResourceContents::text(self.summaries.render(), SUMMARY_URI)],
+            });
+        }
+        self.inner.read_resource(request, context).await
+    }
+
+    async fn complete(
+        &self,
+        request: CompleteRequestParam,
+        context: RequestContext<RoleServer>,
+    ) -> Result<CompleteResult, ErrorData> {
+        self.inner.complete(request, context).await
+    }
+
     async fn call_tool(
         &self,
         request: CallToolRequestParam,
         context: RequestContext<RoleServer>,
     ) -> Result<CallToolResult, ErrorData> {
         if request.name.as_ref() == self.config.tool_name {
-            let code = request
-                .arguments
-                .as_ref()
-                .and_then(|args| args.get("code"))
-                .and_then(|v| v.as_str())
-                .ok_or_else(|| ErrorData::invalid_params("Missing 'code' parameter", None))?;
+            let params = parse_execute_params(&request)?;
+            let Some(_in_flight) = self.drain.enter() else {
+                return Err(ErrorData::internal_error(
+                    "Server is draining and not accepting new executions",
+                    None,
+                ));
+            };
 
-            let result = self.execute_code(code, &context).await?;
+            let result = self.execute_code(&params, &context, false).await?;
 
-            let response_value = if result.logs.is_empty() {
-                result.value.clone()
-            } else {
-                serde_json::json!({
-                    "result": result.value,
-                    "logs": result.logs
-                })
-            };
+            let response_value = response_value(&result);
 
             let content = if result.is_error {
-                let error_response = serde_json::json!({
-                    "error": result.error_message.as_deref().unwrap_or("Unknown error"),
-                    "logs": result.logs
-                });
                 vec![Content::text(
-                    serde_json::to_string_pretty(&error_response).unwrap_or_default(),
+                    serde_json::to_string_pretty(&error_response(&result)).unwrap_or_default(),
                 )]
             } else {
                 json_to_content(&response_value)
@@ -270,64 +635,140 @@ impl<H: ServerHandler + Send + Sync + 'static> ServerHandler for CodeModeWrapper
             return Ok(CallToolResult {
                 content,
                 is_error: Some(result.is_error),
-                structured_content: None,
-                meta: None,
+                structured_content: Some(structured_output(&result)),
+                meta: Some(result.meta()),
             });
         }
 
-        self.inner.call_tool(request, context).await
-    }
-}
+        if self.config.continuations_enabled() && request.name.as_ref() == ABANDON_TOOL {
+            let runtime = self.runtime.lock().await;
+            return abandon_continuation(&request, runtime.as_deref());
+        }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        if self.config.background_executions {
+            match request.name.as_ref() {
+                START_TOOL => {
+                    let params = parse_execute_params(&request)?;
+                    let Some(in_flight) = self.drain.enter() else {
+                        return Err(ErrorData::internal_error(
+                            "Server is draining and not accepting new executions",
+                            None,
+                        ));
+                    };
+                    let (id, ct) = self.background.start();
+                    let wrapper = self.clone();
+                    let execution = id.clone();
+                    // Outlives the request, so cancelled on its own
+                    let mut context = context;
+                    context.ct = ct;
+                    tokio::spawn(async move {
+                        let _in_flight = in_flight;
+                        let result = wrapper.execute_code(&params, &context, true).await;
+                        wrapper.background.finish(&execution, result);
+                    });
+                    return Ok(started(&id));
+                }
+                GET_TOOL => return get_execution(&request, &self.background),
+                CANCEL_TOOL => return cancel_execution(&request, &self.background),
+                _ => {}
+            }
+        }
 
-    #[test]
-    fn test_json_to_content_text() {
-        let value = serde_json::json!("hello world");
-        let content = json_to_content(&value);
-        assert_eq!(content.len(), 1);
-        assert!(content[0].as_text().is_some());
-    }
+        if self.config.stats_tool && request.name.as_ref() == STATS_TOOL {
+            return Ok(stats(&self.usage, &self.slos, &self.circuit));
+        }
 
-    #[test]
-    fn test_json_to_content_image() {
-        let value = serde_json::json!({
-            "type": "image",
-            "data": "SGVsbG8=",
-            "mimeType": "image/png"
-        });
-        let content = json_to_content(&value);
-        assert_eq!(content.len(), 1);
-        let img = content[0].as_image().expect("should be image content");
-        assert_eq!(img.data, "SGVsbG8=");
-        assert_eq!(img.mime_type, "image/png");
-    }
+        if self.config.lazy_tool_docs && request.name.as_ref() == TOOL_DOCS_TOOL {
+            self.ensure_tools_cached(&context).await?;
+            let tools = self.cached_tools.read().await;
+            return tool_docs(
+                &request,
+                &self.notes.annotate(&tools),
+                &self.aliases.script_namespace(),
+                &self.config,
+            );
+        }
 
-    #[test]
-    fn test_json_to_content_array_with_images() {
-        let value = serde_json::json!([
-            {"type": "image", "data": "abc123", "mimeType": "image/png"},
-            "some text"
-        ]);
-        let content = json_to_content(&value);
-        assert_eq!(content.len(), 2);
-        assert!(content[0].as_image().is_some());
-        assert!(content[1].as_text().is_some());
-    }
+        let name = self.aliases.strip_namespace(&request.name).to_string();
+        if !self.config.includes_tool(self.aliases.downstream(&name)) {
+            return Err(ErrorData::invalid_params(
+                format!("Unknown tool: {}", request.name),
+                None,
+            ));
+        }
+        if self.policy.is_some() {
+            self.ensure_tools_cached(&context).await?;
+            let tools = self.cached_tools.read().await;
+            let input = PolicyInput {
+                client: client_name(&context.peer),
+                tool: self.aliases.downstream(&name).to_string(),
+                args: serde_json::Value::Object(request.arguments.clone().unwrap_or_default()),
+                annotations: tools
+                    .iter()
+                    .find(|tool| tool.name == name)
+                    .and_then(|tool| tool.annotations.clone()),
+            };
+            drop(tools);
+            if let Err(message) = policy::check(self.policy.as_ref(), &input).await {
+                return Ok(CallToolResult::error(vec![Content::text(message)]));
+            }
+        }
+        if guarded_tools(&self.config).contains(&name) {
+            let approval = ApprovalRequest::ToolCall {
+                name: name.clone(),
+                args: request.arguments.clone().map(serde_json::Value::Object),
+            };
+            if !approval::check(self.approver.as_ref(), &approval).await {
+                return Ok(CallToolResult::error(vec![Content::text(format!(
+                    "Call to {name} was not approved"
+                ))]));
+            }
+        }
 
-    #[test]
-    fn test_json_to_content_result_with_logs() {
-        let value = serde_json::json!({
-            "result": {"type": "image", "data": "abc123", "mimeType": "image/png"},
-            "logs": ["log1", "log2"]
-        });
-        let content = json_to_content(&value);
-        assert_eq!(content.len(), 2);
-        assert!(content[0].as_image().is_some());
-        let text = content[1].as_text().expect("should have logs text");
-        assert!(text.text.contains("log1"));
-        assert!(text.text.contains("log2"));
+        let _permit = self.limits.acquire(&name).await;
+        let downstream_name = self.aliases.downstream(&name).to_string();
+        let retryable = self.retry.is_enabled() && {
+            self.ensure_tools_cached(&context).await?;
+            self.cached_tools
+                .read()
+                .await
+                .iter()
+                .find(|tool| tool.name == name)
+                .is_some_and(|tool| is_retryable(tool, &self.config))
+        };
+        let mut arguments = request.arguments;
+        if let Some(arguments) = arguments.as_mut() {
+            self.redactions.apply(&downstream_name, arguments);
+        }
+        let request = CallToolRequestParam {
+            name: downstream_name.clone().into(),
+            arguments,
+        };
+        if let Err(e) = self.circuit.check() {
+            return Err(ErrorData::internal_error(e.to_string(), None));
+        }
+        let started = Instant::now();
+        let ct = context.ct.clone();
+        let attempt = || {
+            self.timeouts.run(
+                &downstream_name,
+                self.inner.call_tool(request.clone(), context.clone()),
+                |message| ErrorData::internal_error(message, None),
+            )
+        };
+        let result = traced_call(
+            &downstream_name,
+            "passthrough",
+            self.retry.run(&downstream_name, retryable, &ct, attempt),
+        )
+        .await;
+        self.circuit.record(&result);
+        let ok = result.as_ref().is_ok_and(|r| r.is_error != Some(true));
+        self.usage
+            .record_call(&downstream_name, started.elapsed(), ok);
+        if let Some(alert) = self.slos.record(&downstream_name, started.elapsed()) {
+            slo::notify(&context.peer, self.log_level.get(), &alert).await;
+        }
+        result
     }
 }
@@ -1,4 +1,4 @@
-use rmcp::model::CallToolRequestParam;
+use rmcp::model::{CallToolRequestParam, PaginatedRequestParam};
 use rmcp::service::{RoleClient, RunningService};
 use rmcp::{ServiceExt, transport::TokioChildProcess};
 use std::path::PathBuf;
@@ -75,6 +75,62 @@ async fn test_list_tools_includes_execute_code() {
     assert!(tool_names.contains(&"get_items"));
 }
 
+#[tokio::test(flavor = "multi_thread")]
+async fn test_list_tools_pages() {
+    let client = setup_client_with_args(&["--tools-page-size", "2"]).await;
+
+    let first = client.peer().list_tools(None).await.unwrap();
+    assert_eq!(first.tools.len(), 2);
+    let second = client
+        .peer()
+        .list_tools(Some(PaginatedRequestParam {
+            cursor: first.next_cursor.clone(),
+        }))
+        .await
+        .unwrap();
+    assert_eq!(second.tools.len(), 2);
+    assert_ne!(first.tools[0].name, second.tools[0].name);
+
+    // Following the cursors lists every tool once
+    let tools = client.peer().list_all_tools().await.unwrap();
+    let names: std::collections::HashSet<_> = tools.iter().map(|t| t.name.as_ref()).collect();
+    assert!(names.contains("execute_tools"));
+    assert_eq!(names.len(), tools.len());
+
+    let error = client
+        .peer()
+        .list_tools(Some(PaginatedRequestParam {
+            cursor: Some("bogus".to_string()),
+        }))
+        .await
+        .unwrap_err();
+    assert!(error.to_string().contains("Invalid cursor"), "{error}");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_tool_name_collision() {
+    let client = setup_client_with_args(&["--tool-name", "echo"]).await;
+    let tools = client.peer().list_all_tools().await.unwrap();
+    // The downstream echo stays reachable, and the execute tool moves aside
+    assert!(tools.iter().any(|t| t.name == "echo"));
+    assert!(tools.iter().any(|t| t.name == "echo_2"));
+    let result = call_tool(&client, "echo", serde_json::json!({"message": "hi"})).await;
+    assert!(result.contains("hi"), "{result}");
+
+    let output = std::process::Command::new(get_codemoder_path())
+        .args(["--tool-name", "echo", "--tool-name-collision", "fail", "--"])
+        .arg(get_mock_server_path())
+        .stdin(std::process::Stdio::null())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("already has a tool named `echo`"),
+        "{stderr}"
+    );
+}
+
 #[tokio::test(flavor = "multi_thread")]
 async fn test_proxy_tool_call() {
     let client = setup_client().await;
@@ -237,3 +293,1996 @@ async fn test_tool_includes_typescript_interface() {
     assert!(description.contains("function multiply"));
     assert!(description.contains("console.log"));
 }
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_execution_metrics_in_meta() {
+    let client = setup_client().await;
+
+    let code = r#"
+        tools.add({a: 1, b: 2});
+        tools.add({a: 1, b: 2});
+        "done"
+    "#;
+
+    let result = client
+        .peer()
+        .call_tool(CallToolRequestParam {
+            name: "execute_tools".into(),
+            arguments: Some(
+                serde_json::json!({"code": code})
+                    .as_object()
+                    .unwrap()
+                    .clone(),
+            ),
+        })
+        .await
+        .unwrap();
+
+    let meta = result
+        .meta
+        .expect("execute_tools result should carry _meta");
+    assert_eq!(meta.0["metrics"]["toolCalls"], 2);
+    assert_eq!(meta.0["metrics"]["dedupHits"], 0);
+    // Two results were read, only "done" was returned
+    assert!(meta.0["metrics"]["consumedBytes"].as_u64().unwrap() > 0);
+    assert_eq!(meta.0["metrics"]["returnedBytes"], 6);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_execution_timeout() {
+    let client = setup_client_with_args(&["--timeout-ms", "200"]).await;
+
+    let code = r#"
+        console.log("starting");
+        while (true) {}
+    "#;
+
+    let result = client
+        .peer()
+        .call_tool(CallToolRequestParam {
+            name: "execute_tools".into(),
+            arguments: Some(
+                serde_json::json!({"code": code})
+                    .as_object()
+                    .unwrap()
+                    .clone(),
+            ),
+        })
+        .await
+        .unwrap();
+
+    assert!(result.is_error.unwrap_or(false));
+    let text = &result.content[0].as_text().unwrap().text;
+    let json: serde_json::Value = serde_json::from_str(text).unwrap();
+    assert_eq!(json["error"], "Execution timed out after 200ms");
+    assert_eq!(json["logs"][0], "starting");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_session_persists_between_executions() {
+    let client = setup_client().await;
+
+    call_tool(
+        &client,
+        "execute_tools",
+        serde_json::json!({"code": "var total = tools.add({a: 1, b: 2}).result;", "session": "s1"}),
+    )
+    .await;
+
+    let result = call_tool(
+        &client,
+        "execute_tools",
+        serde_json::json!({"code": "total * 10", "session": "s1"}),
+    )
+    .await;
+    let json: serde_json::Value = serde_json::from_str(&result).unwrap();
+    assert_eq!(json.as_f64().unwrap() as i64, 30);
+
+    let result = call_tool(
+        &client,
+        "execute_tools",
+        serde_json::json!({"code": "typeof total", "session": "s1", "reset": true}),
+    )
+    .await;
+    let json: serde_json::Value = serde_json::from_str(&result).unwrap();
+    assert_eq!(json, "undefined");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_server_info_reports_capabilities() {
+    let client = setup_client_with_args(&["--timeout-ms", "500"]).await;
+
+    let info = client.peer().peer_info().unwrap();
+    let report = &info.capabilities.experimental.as_ref().unwrap()["codemoder"];
+    assert_eq!(report["engine"], "javascript");
+    assert_eq!(report["toolName"], "execute_tools");
+    assert_eq!(report["limits"]["timeoutMs"], 500);
+    assert_eq!(report["downstream"]["name"], "mock-mcp-server");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_list_tools_meta_reports_exposure() {
+    let client = setup_client_with_args(&["--mode", "replace"]).await;
+
+    let result = client.peer().list_tools(None).await.unwrap();
+    let meta = result.meta.unwrap();
+    let report = &meta.0["codemoder"];
+    assert_eq!(report["mode"], "replace_tools");
+    assert_eq!(report["tools"]["add"], "sandbox_only");
+    assert_eq!(report["tools"]["execute_tools"], "virtual");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_tool_icons_and_meta_pass_through() {
+    let client = setup_client_with_args(&["--include-tools", "echo"]).await;
+
+    let result = client.peer().list_all_tools().await.unwrap();
+    let echo = result.iter().find(|t| t.name == "echo").unwrap();
+    let icons = echo.icons.as_ref().unwrap();
+    assert_eq!(icons[0].src, "https://example.com/echo.svg");
+    assert_eq!(echo.meta.as_ref().unwrap().0["category"], "text");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_passthrough_refuses_excluded_tools() {
+    let client = setup_client_with_args(&["--exclude-tools", "multiply"]).await;
+
+    let result = client
+        .peer()
+        .call_tool(CallToolRequestParam {
+            name: "multiply".into(),
+            arguments: Some(
+                serde_json::json!({"a": 2, "b": 3})
+                    .as_object()
+                    .unwrap()
+                    .clone(),
+            ),
+        })
+        .await;
+    let error = result.unwrap_err().to_string();
+    assert!(error.contains("Unknown tool: multiply"), "{error}");
+
+    let sum = call_tool(&client, "add", serde_json::json!({"a": 2, "b": 3})).await;
+    assert!(sum.contains('5'), "{sum}");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_structured_content() {
+    let client = setup_client().await;
+
+    let code = r#"console.log("hi"); tools.add({a: 2, b: 2}).result"#;
+    let result = client
+        .peer()
+        .call_tool(CallToolRequestParam {
+            name: "execute_tools".into(),
+            arguments: Some(
+                serde_json::json!({"code": code})
+                    .as_object()
+                    .unwrap()
+                    .clone(),
+            ),
+        })
+        .await
+        .unwrap();
+
+    let structured = result.structured_content.unwrap();
+    assert_eq!(structured["result"].as_f64(), Some(4.0));
+    assert_eq!(structured["logs"], serde_json::json!(["hi"]));
+    assert!(structured.get("error").is_none());
+
+    let tools = client.peer().list_all_tools().await.unwrap();
+    let execute = tools.iter().find(|t| t.name == "execute_tools").unwrap();
+    let schema = execute.output_schema.as_ref().unwrap();
+    assert_eq!(schema["type"], "object");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_image_results() {
+    let client = setup_client().await;
+    let execute = |code: &str| {
+        client.peer().call_tool(CallToolRequestParam {
+            name: "execute_tools".into(),
+            arguments: Some(
+                serde_json::json!({"code": code})
+                    .as_object()
+                    .unwrap()
+                    .clone(),
+            ),
+        })
+    };
+
+    let result = execute(
+        r#"console.log("captured");
+        [{type: "image", data: "iVBORw0KGgo=", mimeType: "image/png"}, "a screenshot"]"#,
+    )
+    .await
+    .unwrap();
+    assert_eq!(result.content.len(), 3);
+    let image = result.content[0].raw.as_image().unwrap();
+    assert_eq!(
+        (image.data.as_str(), image.mime_type.as_str()),
+        ("iVBORw0KGgo=", "image/png")
+    );
+    assert_eq!(
+        result.content[1].raw.as_text().unwrap().text,
+        "\"a screenshot\""
+    );
+    let rest: serde_json::Value =
+        serde_json::from_str(&result.content[2].raw.as_text().unwrap().text).unwrap();
+    assert_eq!(rest, serde_json::json!({"logs": ["captured"]}));
+
+    // Other values are still a single text item
+    let result = execute(r#"({type: "image", data: 1})"#).await.unwrap();
+    assert_eq!(result.content.len(), 1);
+    assert!(result.content[0].raw.as_text().is_some());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_assertion_failure_is_structured() {
+    let client = setup_client().await;
+
+    let code = r#"var sum = tools.add({a: 2, b: 2}).result; expectEqual(sum, 5, "bad sum")"#;
+    let result = client
+        .peer()
+        .call_tool(CallToolRequestParam {
+            name: "execute_tools".into(),
+            arguments: Some(
+                serde_json::json!({"code": code})
+                    .as_object()
+                    .unwrap()
+                    .clone(),
+            ),
+        })
+        .await
+        .unwrap();
+
+    assert_eq!(result.is_error, Some(true));
+    let structured = result.structured_content.unwrap();
+    assert_eq!(structured["assertion"]["message"], "bad sum");
+    assert_eq!(structured["assertion"]["expected"], 5);
+    assert_eq!(structured["assertion"]["actual"], 4);
+    let text: serde_json::Value =
+        serde_json::from_str(&result.content[0].as_text().unwrap().text).unwrap();
+    assert_eq!(text["assertion"], structured["assertion"]);
+}
+
+#[tokio::test]
+async fn test_exec_expect_fixture() {
+    let dir = std::env::temp_dir().join(format!("codemoder-exec-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let script = dir.join("script.js");
+    std::fs::write(
+        &script,
+        "({sum: tools.add({a: 2, b: 3}).result, ids: [1, 2]})",
+    )
+    .unwrap();
+    let fixture = dir.join("expected.json");
+
+    let exec = |fixture_text: &str, extra: &[&str]| {
+        std::fs::write(&fixture, fixture_text).unwrap();
+        std::process::Command::new(get_codemoder_path())
+            .arg("exec")
+            .arg(&script)
+            .arg("--expect")
+            .arg(&fixture)
+            .args(extra)
+            .arg("--")
+            .arg(get_mock_server_path())
+            .output()
+            .unwrap()
+    };
+
+    let output = exec(r#"{"sum": 5, "ids": [2, 1]}"#, &["--ignore-order"]);
+    assert!(output.status.success());
+
+    let output = exec(r#"{"sum": 6, "ids": [1, 2]}"#, &[]);
+    assert!(!output.status.success());
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["ok"], false);
+    assert_eq!(report["differences"][0]["path"], "$.sum");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_selftest() {
+    let output = std::process::Command::new(get_codemoder_path())
+        .arg("selftest")
+        .arg("--")
+        .arg(get_mock_server_path())
+        .output()
+        .unwrap();
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert!(output.status.success(), "{report:#}");
+    let names: Vec<&str> = report["checks"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|check| check["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        names,
+        [
+            "initialize",
+            "list_tools",
+            "pagination",
+            "call_tool",
+            "errors",
+            "notifications",
+            "cancellation"
+        ]
+    );
+    assert_eq!(report["checks"][2]["detail"], "12 tools in 12 pages");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_tool_aliases() {
+    let config =
+        std::env::temp_dir().join(format!("codemoder-aliases-{}.toml", std::process::id()));
+    std::fs::write(&config, "[aliases]\nadd = \"sum_numbers\"\n").unwrap();
+    let client = setup_client_with_args(&["--config", config.to_str().unwrap()]).await;
+
+    let tools = client.peer().list_all_tools().await.unwrap();
+    assert!(tools.iter().any(|t| t.name == "sum_numbers"));
+    assert!(!tools.iter().any(|t| t.name == "add"));
+    let execute = tools.iter().find(|t| t.name == "execute_tools").unwrap();
+    assert!(
+        execute
+            .description
+            .as_ref()
+            .unwrap()
+            .contains("sum_numbers")
+    );
+
+    let result = call_tool(&client, "sum_numbers", serde_json::json!({"a": 1, "b": 2})).await;
+    assert!(result.contains('3'), "{result}");
+    let result = call_tool(
+        &client,
+        "execute_tools",
+        serde_json::json!({"code": "tools.sum_numbers({a: 2, b: 5}).result"}),
+    )
+    .await;
+    assert_eq!(result.trim(), "7.0");
+
+    std::fs::remove_file(&config).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_memoized_tool() {
+    let config =
+        std::env::temp_dir().join(format!("codemoder-memoize-{}.toml", std::process::id()));
+    std::fs::write(&config, "[tools.flaky]\nmemoize = true\n").unwrap();
+    let client = setup_client_with_args(&["--config", config.to_str().unwrap()]).await;
+
+    // The mock counts calls, so a repeated count means the call was reused
+    let result = call_tool(
+        &client,
+        "execute_tools",
+        serde_json::json!({"code": "[1, 2, 3].map(() => tools.flaky({failures: 0}).calls).join(',')"}),
+    )
+    .await;
+    assert_eq!(result.trim(), "\"1,1,1\"");
+
+    // Only for the execution
+    let result = call_tool(
+        &client,
+        "execute_tools",
+        serde_json::json!({"code": "tools.flaky({failures: 0}).calls"}),
+    )
+    .await;
+    assert_eq!(result.trim(), "2.0");
+
+    std::fs::remove_file(&config).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_result_cache() {
+    let config = std::env::temp_dir().join(format!("codemoder-cache-{}.toml", std::process::id()));
+    std::fs::write(
+        &config,
+        "[tools.flaky]\nread_only = true\ncache_ttl_secs = 60\n",
+    )
+    .unwrap();
+    let client = setup_client_with_args(&["--config", config.to_str().unwrap()]).await;
+    let execute = |code: &str| {
+        client.peer().call_tool(CallToolRequestParam {
+            name: "execute_tools".into(),
+            arguments: serde_json::json!({"code": code}).as_object().cloned(),
+        })
+    };
+    let text =
+        |result: &rmcp::model::CallToolResult| result.content[0].as_text().unwrap().text.clone();
+
+    let first = execute("tools.flaky({failures: 0}).calls").await.unwrap();
+    assert_eq!(text(&first).trim(), "1.0");
+
+    // A later execution gets the kept result
+    let second = execute("tools.flaky({failures: 0}).calls").await.unwrap();
+    assert_eq!(text(&second).trim(), "1.0");
+    assert_eq!(second.meta.unwrap().0["metrics"]["cacheHits"], 1);
+
+    let fresh = execute("tools.flaky({failures: 0}, {cache: false}).calls")
+        .await
+        .unwrap();
+    assert_eq!(text(&fresh).trim(), "2.0");
+
+    std::fs::remove_file(&config).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_provenance() {
+    let client = setup_client_with_args(&["--provenance"]).await;
+    let code = r#"
+        var items = tools.get_items({}).items;
+        tools.echo({message: 'unused'});
+        ({best: items[2], total: tools.add({a: 1, b: 2}).result})
+    "#;
+    let result = client
+        .peer()
+        .call_tool(CallToolRequestParam {
+            name: "execute_tools".into(),
+            arguments: serde_json::json!({"code": code, "verbose": true})
+                .as_object()
+                .cloned(),
+        })
+        .await
+        .unwrap();
+
+    // The sum is a number, too common to trace
+    assert_eq!(
+        result.meta.unwrap().0["provenance"],
+        serde_json::json!([{"call": 0, "tool": "get_items"}])
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_guarded_tool_denied_without_approver() {
+    let config =
+        std::env::temp_dir().join(format!("codemoder-approval-{}.toml", std::process::id()));
+    std::fs::write(&config, "[tools.add]\nrequire_approval = true\n").unwrap();
+    let client = setup_client_with_args(&["--config", config.to_str().unwrap()]).await;
+
+    let result = call_tool(&client, "add", serde_json::json!({"a": 1, "b": 2})).await;
+    assert_eq!(result, "Call to add was not approved");
+    let result = call_tool(
+        &client,
+        "execute_tools",
+        serde_json::json!({"code": "tools.echo({message: 'hi'}); tools.add({a: 1, b: 2})"}),
+    )
+    .await;
+    assert!(result.contains("Call to add was not approved"), "{result}");
+
+    std::fs::remove_file(&config).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_camel_case_aliases() {
+    let client = setup_client_with_args(&["--camel-case-aliases"]).await;
+
+    let tools = client.peer().list_all_tools().await.unwrap();
+    let execute = tools.iter().find(|t| t.name == "execute_tools").unwrap();
+    let description = execute.description.as_deref().unwrap();
+    assert!(
+        description.contains("const getItems: typeof get_items;"),
+        "{description}"
+    );
+    assert!(!description.contains("typeof add"), "{description}");
+
+    let result = call_tool(
+        &client,
+        "execute_tools",
+        serde_json::json!({"code": "tools.getItems === tools.get_items && tools.countTo !== undefined"}),
+    )
+    .await;
+    assert_eq!(result.trim(), "true");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_retry_failed_calls() {
+    // The mock's `flaky` is unannotated, so destructive: it has to opt in
+    let config = std::env::temp_dir().join(format!("codemoder-retry-{}.toml", std::process::id()));
+    std::fs::write(&config, "[tools.flaky]\nretry = true\n").unwrap();
+    let client = setup_client_with_args(&[
+        "--retry-attempts",
+        "3",
+        "--config",
+        config.to_str().unwrap(),
+    ])
+    .await;
+
+    // Two failures are retried away, from a script and passed through
+    let result = call_tool(
+        &client,
+        "execute_tools",
+        serde_json::json!({"code": "tools.flaky({failures: 2}).calls"}),
+    )
+    .await;
+    assert_eq!(result.trim(), "3.0");
+    let result = call_tool(&client, "flaky", serde_json::json!({"failures": 5})).await;
+    assert!(result.contains("\"calls\":6"), "{result}");
+
+    // Three aren't
+    let result = call_tool(
+        &client,
+        "execute_tools",
+        serde_json::json!({"code": "tools.flaky({failures: 9}).calls"}),
+    )
+    .await;
+    assert!(result.contains("Temporarily unavailable"), "{result}");
+    std::fs::remove_file(&config).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_destructive_tools_are_not_retried() {
+    let client = setup_client_with_args(&["--retry-attempts", "3"]).await;
+
+    let result = call_tool(
+        &client,
+        "execute_tools",
+        serde_json::json!({"code": "tools.flaky({failures: 1}).calls"}),
+    )
+    .await;
+    assert!(result.contains("Temporarily unavailable"), "{result}");
+    let passthrough = client
+        .peer()
+        .call_tool(CallToolRequestParam {
+            name: "flaky".into(),
+            arguments: serde_json::json!({"failures": 2}).as_object().cloned(),
+        })
+        .await
+        .unwrap_err();
+    assert!(
+        passthrough.to_string().contains("Temporarily unavailable"),
+        "{passthrough}"
+    );
+
+    // One call each reached the mock
+    let result = call_tool(&client, "flaky", serde_json::json!({"failures": 0})).await;
+    assert!(result.contains("\"calls\":3"), "{result}");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_circuit_breaker() {
+    let client =
+        setup_client_with_args(&["--circuit-failure-threshold", "2", "--stats-tool"]).await;
+
+    // The third call fails fast, with a code scripts can check
+    let result = call_tool(
+        &client,
+        "execute_tools",
+        serde_json::json!({"code": r#"
+            const codes = [];
+            for (let i = 0; i < 3; i++) {
+                try { tools.flaky({failures: 9}); } catch (e) { codes.push(e.code || "failed"); }
+            }
+            codes.join(",")
+        "#}),
+    )
+    .await;
+    assert_eq!(result.trim(), "\"failed,failed,circuit_open\"");
+
+    let passthrough = client
+        .peer()
+        .call_tool(CallToolRequestParam {
+            name: "flaky".into(),
+            arguments: serde_json::json!({"failures": 9}).as_object().cloned(),
+        })
+        .await
+        .unwrap_err();
+    assert!(
+        passthrough.to_string().contains("circuit open"),
+        "{passthrough}"
+    );
+
+    let result = call_tool(&client, "codemode_stats", serde_json::json!({})).await;
+    let stats: serde_json::Value = serde_json::from_str(&result).unwrap();
+    assert_eq!(stats["circuit"]["state"], "open", "{result}");
+    assert_eq!(stats["tools"]["flaky"]["calls"], 2, "{result}");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_call_timeouts() {
+    let client = setup_client_with_args(&["--call-timeout-ms", "200"]).await;
+
+    let result = call_tool(
+        &client,
+        "execute_tools",
+        serde_json::json!({"code": r#"
+            const fast = tools.sleep({ms: 1}).slept;
+            let error;
+            try { tools.sleep({ms: 2000}); } catch (e) { error = e.message; }
+            [fast, error]
+        "#}),
+    )
+    .await;
+    assert!(
+        result.contains("Call to sleep timed out after 200ms"),
+        "{result}"
+    );
+
+    let passthrough = client
+        .peer()
+        .call_tool(CallToolRequestParam {
+            name: "sleep".into(),
+            arguments: serde_json::json!({"ms": 2000}).as_object().cloned(),
+        })
+        .await
+        .unwrap_err();
+    assert!(passthrough.to_string().contains("timeout"), "{passthrough}");
+
+    // Both timed-out calls were cancelled downstream rather than left running
+    let mut cancelled = String::new();
+    for _ in 0..50 {
+        cancelled = call_tool(&client, "cancelled_sleeps", serde_json::json!({})).await;
+        if cancelled.contains(r#""cancelled":2"#) {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert!(cancelled.contains(r#""cancelled":2"#), "{cancelled}");
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_switch_to_standby() {
+    use tokio::io::AsyncBufReadExt;
+
+    let mut cmd = Command::new(get_codemoder_path());
+    cmd.arg("--standby-command")
+        .arg(get_mock_server_path())
+        .arg("--")
+        .arg(get_mock_server_path());
+    let (transport, stderr) = TokioChildProcess::builder(cmd)
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let pid = transport.id().unwrap();
+    let client = HostClient::default().serve(transport).await.unwrap();
+
+    // The standby starts in the background and says when it is ready
+    let (ready, standby_ready) = tokio::sync::oneshot::channel();
+    let mut lines = tokio::io::BufReader::new(stderr.unwrap()).lines();
+    tokio::spawn(async move {
+        let mut ready = Some(ready);
+        while let Ok(Some(line)) = lines.next_line().await {
+            if line.contains("Standby downstream server ready")
+                && let Some(ready) = ready.take()
+            {
+                let _ = ready.send(());
+            }
+        }
+    });
+
+    // Each server counts its own calls
+    let run = |name: &str, args: serde_json::Value| {
+        client.peer().call_tool(CallToolRequestParam {
+            name: name.to_string().into(),
+            arguments: args.as_object().cloned(),
+        })
+    };
+    let calls = || async {
+        let result = run("flaky", serde_json::json!({"failures": 0}))
+            .await
+            .unwrap();
+        let text = &result.content[0].as_text().unwrap().text;
+        serde_json::from_str::<serde_json::Value>(text).unwrap()["calls"].clone()
+    };
+    assert_eq!(calls().await, 1);
+    assert_eq!(calls().await, 2);
+
+    tokio::time::timeout(std::time::Duration::from_secs(10), standby_ready)
+        .await
+        .expect("the standby never became ready")
+        .unwrap();
+    std::process::Command::new("kill")
+        .args(["-USR2", &pid.to_string()])
+        .status()
+        .unwrap();
+
+    // The client is told the tools changed once the standby has taken over
+    let changed = client.service().tools_changed.clone();
+    for _ in 0..100 {
+        if changed.load(std::sync::atomic::Ordering::SeqCst) > 0 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(changed.load(std::sync::atomic::Ordering::SeqCst), 1);
+    assert_eq!(calls().await, 1);
+
+    // The new server's tools are listed, and calls keep working
+    let result = run(
+        "execute_tools",
+        serde_json::json!({"code": "tools.add({a: 1, b: 2}).result"}),
+    )
+    .await
+    .unwrap();
+    assert_eq!(result.content[0].as_text().unwrap().text.trim(), "3.0");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_strict_args() {
+    let client = setup_client_with_args(&["--strict-args"]).await;
+
+    let result = call_tool(
+        &client,
+        "execute_tools",
+        serde_json::json!({"code": "try { tools.add({a: 1, b: 2, c: 3}); } catch (e) { e.message }"}),
+    )
+    .await;
+    assert!(
+        result.contains("Unexpected argument `c` for add; its parameters are `a`, `b`"),
+        "{result}"
+    );
+    let result = call_tool(
+        &client,
+        "execute_tools",
+        serde_json::json!({"code": "tools.add({a: 1, b: 2}).result"}),
+    )
+    .await;
+    assert_eq!(result.trim(), "3.0");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_coerce_args() {
+    let client = setup_client_with_args(&["--coerce-args"]).await;
+
+    let result = call_tool(
+        &client,
+        "execute_tools",
+        serde_json::json!({"code": "tools.add({a: '2', b: 3}).result + tools.echo({message: 42}).echo.length"}),
+    )
+    .await;
+    assert_eq!(result.trim(), "7.0");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_namespace_prefix() {
+    let client = setup_client_with_args(&["--namespace", "mock"]).await;
+
+    let tools = client.peer().list_all_tools().await.unwrap();
+    assert!(tools.iter().any(|t| t.name == "mock_add"));
+    assert!(tools.iter().any(|t| t.name == "execute_tools"));
+    let execute = tools.iter().find(|t| t.name == "execute_tools").unwrap();
+    assert!(
+        execute
+            .description
+            .as_ref()
+            .unwrap()
+            .contains("declare namespace tools.mock")
+    );
+
+    let result = call_tool(&client, "mock_add", serde_json::json!({"a": 1, "b": 2})).await;
+    assert!(result.contains('3'), "{result}");
+    let result = call_tool(
+        &client,
+        "execute_tools",
+        serde_json::json!({"code": "tools.mock.add({a: 2, b: 5}).result"}),
+    )
+    .await;
+    assert_eq!(result.trim(), "7.0");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_lazy_tool_docs() {
+    let client = setup_client_with_args(&["--lazy-tool-docs"]).await;
+
+    let tools = client.peer().list_all_tools().await.unwrap();
+    assert!(tools.iter().any(|t| t.name == "get_tool_docs"));
+    let execute = tools.iter().find(|t| t.name == "execute_tools").unwrap();
+    let description = execute.description.as_ref().unwrap();
+    assert!(description.contains("- tools.add"), "{description}");
+    assert!(!description.contains("declare namespace"), "{description}");
+
+    let docs = call_tool(
+        &client,
+        "get_tool_docs",
+        serde_json::json!({"tools": ["add"]}),
+    )
+    .await;
+    assert!(docs.contains("function add("), "{docs}");
+    assert!(!docs.contains("function echo("), "{docs}");
+    let docs = call_tool(
+        &client,
+        "get_tool_docs",
+        serde_json::json!({"tools": ["nope"]}),
+    )
+    .await;
+    assert_eq!(docs, "Unknown tools: nope");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_interface_detail_names() {
+    let client = setup_client_with_args(&["--interface-detail", "names"]).await;
+
+    let tools = client.peer().list_all_tools().await.unwrap();
+    assert!(!tools.iter().any(|t| t.name == "get_tool_docs"));
+    let execute = tools.iter().find(|t| t.name == "execute_tools").unwrap();
+    let description = execute.description.as_ref().unwrap();
+    assert!(description.contains("- tools.add\n"), "{description}");
+    assert!(!description.contains("declare namespace"), "{description}");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_yield_and_abandon_continuation() {
+    let client = setup_client_with_args(&["--max-continuations", "2"]).await;
+
+    let tools = client.peer().list_all_tools().await.unwrap();
+    assert!(tools.iter().any(|t| t.name == "abandon_continuation"));
+
+    let result = call_tool(
+        &client,
+        "execute_tools",
+        serde_json::json!({"code": "var sum = tools.add({a: 2, b: 3}).result; yieldToAgent({sum: sum});"}),
+    )
+    .await;
+    let yielded: serde_json::Value = serde_json::from_str(&result).unwrap();
+    assert_eq!(yielded["yielded"]["sum"], 5.0);
+    let token = yielded["continuation"].as_str().unwrap().to_string();
+
+    let result = call_tool(
+        &client,
+        "execute_tools",
+        serde_json::json!({"code": "yieldToAgent(sum * 2)", "continuation": token}),
+    )
+    .await;
+    let yielded: serde_json::Value = serde_json::from_str(&result).unwrap();
+    assert_eq!(yielded["yielded"], 10.0);
+    assert_eq!(yielded["continuation"], token.as_str());
+
+    let result = call_tool(
+        &client,
+        "abandon_continuation",
+        serde_json::json!({"continuation": token}),
+    )
+    .await;
+    assert_eq!(result, format!("Abandoned continuation `{token}`"));
+    let result = call_tool(
+        &client,
+        "execute_tools",
+        serde_json::json!({"code": "sum", "continuation": token}),
+    )
+    .await;
+    assert!(
+        result.contains("Unknown or expired continuation"),
+        "{result}"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_prompts_pass_through() {
+    let client = setup_client().await;
+
+    let info = client.peer().peer_info().unwrap();
+    assert!(info.capabilities.prompts.is_some());
+
+    let prompts = client.peer().list_all_prompts().await.unwrap();
+    assert_eq!(prompts.len(), 1);
+    assert_eq!(prompts[0].name, "summarize");
+
+    let prompt = client
+        .peer()
+        .get_prompt(rmcp::model::GetPromptRequestParam {
+            name: "summarize".to_string(),
+            arguments: Some(
+                serde_json::json!({"topic": "the board"})
+                    .as_object()
+                    .unwrap()
+                    .clone(),
+            ),
+        })
+        .await
+        .unwrap();
+    assert_eq!(
+        prompt.messages[0].content,
+        rmcp::model::PromptMessageContent::Text {
+            text: "Summarize the board".to_string()
+        }
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_resource_templates_and_completion_pass_through() {
+    let client = setup_client().await;
+
+    let info = client.peer().peer_info().unwrap();
+    assert!(info.capabilities.resources.is_some());
+    assert!(info.capabilities.completions.is_some());
+
+    let templates = client.peer().list_all_resource_templates().await.unwrap();
+    assert_eq!(templates.len(), 1);
+    assert_eq!(templates[0].uri_template, "mock://items/{id}");
+
+    let ids = client
+        .peer()
+        .complete_resource_argument("mock://items/{id}", "id", "2", None)
+        .await
+        .unwrap();
+    assert_eq!(ids.values, vec!["2"]);
+
+    let item = client
+        .peer()
+        .read_resource(rmcp::model::ReadResourceRequestParam {
+            uri: "mock://items/2".to_string(),
+        })
+        .await
+        .unwrap();
+    assert_eq!(
+        item.contents,
+        vec![rmcp::model::ResourceContents::text(
+            r#"{"id":"2"}"#,
+            "mock://items/2"
+        )]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_tool_costs_in_meta() {
+    let config = std::env::temp_dir().join(format!("codemoder-costs-{}.toml", std::process::id()));
+    std::fs::write(&config, "[tools.add]\ncost = 0.25\n").unwrap();
+    let client = setup_client_with_args(&["--config", config.to_str().unwrap()]).await;
+
+    let mut session_costs = Vec::new();
+    for _ in 0..2 {
+        let result = client
+            .peer()
+            .call_tool(CallToolRequestParam {
+                name: "execute_tools".into(),
+                arguments: Some(
+                    serde_json::json!({
+                        "code": "tools.add({a: 1, b: 2}); tools.add({a: 3, b: 4}); tools.echo({message: 'hi'})",
+                        "session": "costs",
+                    })
+                    .as_object()
+                    .unwrap()
+                    .clone(),
+                ),
+            })
+            .await
+            .unwrap();
+        let meta = result.meta.unwrap();
+        assert_eq!(meta.0["metrics"]["cost"], 0.5);
+        session_costs.push(meta.0["metrics"]["sessionCost"].clone());
+    }
+    assert_eq!(session_costs, vec![0.5, 1.0]);
+
+    std::fs::remove_file(&config).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_cost_budget_exceeded() {
+    let config = std::env::temp_dir().join(format!("codemoder-budget-{}.toml", std::process::id()));
+    std::fs::write(
+        &config,
+        "max_execution_cost = 1.0\n\n[tools.add]\ncost = 0.5\n",
+    )
+    .unwrap();
+    let client = setup_client_with_args(&["--config", config.to_str().unwrap()]).await;
+
+    let code = r#"
+        var calls = 0;
+        var code;
+        try {
+            while (true) {
+                tools.add({a: 1, b: 2});
+                calls++;
+            }
+        } catch (e) {
+            code = e.code;
+        }
+        ({calls: calls, code: code})
+    "#;
+    let result = call_tool(&client, "execute_tools", serde_json::json!({"code": code})).await;
+    let json: serde_json::Value = serde_json::from_str(&result).unwrap();
+    assert_eq!(json["calls"], 2);
+    assert_eq!(json["code"], "budget_exceeded");
+
+    std::fs::remove_file(&config).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_summary_resource() {
+    let client = setup_client().await;
+
+    call_tool(
+        &client,
+        "execute_tools",
+        serde_json::json!({"code": "tools.add({a: 1, b: 2}); tools.add({a: 2, b: 3}); tools.echo({message: 'hi'})"}),
+    )
+    .await;
+
+    let resources = client.peer().list_all_resources().await.unwrap();
+    assert!(resources.iter().any(|r| r.uri == "codemode://summary"));
+    assert!(resources.iter().any(|r| r.uri == "mock://config"));
+
+    let summary = client
+        .peer()
+        .read_resource(rmcp::model::ReadResourceRequestParam {
+            uri: "codemode://summary".to_string(),
+        })
+        .await
+        .unwrap();
+    let rmcp::model::ResourceContents::TextResourceContents { text, .. } = &summary.contents[0]
+    else {
+        panic!("summary should be text");
+    };
+    assert!(text.starts_with("Last 1 executions"), "{text}");
+    assert!(text.contains("3 calls (add x2, echo), ok"), "{text}");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_docs_resource() {
+    let client = setup_client().await;
+
+    let tools = client.peer().list_all_tools().await.unwrap();
+    let execute = tools.iter().find(|t| t.name == "execute_tools").unwrap();
+    let description = execute.description.as_deref().unwrap();
+    assert!(description.contains("`codemode://docs`"), "{description}");
+    assert!(!description.contains("continueOnError"), "{description}");
+
+    let resources = client.peer().list_all_resources().await.unwrap();
+    assert!(resources.iter().any(|r| r.uri == "codemode://docs"));
+    let docs = client
+        .peer()
+        .read_resource(rmcp::model::ReadResourceRequestParam {
+            uri: "codemode://docs".to_string(),
+        })
+        .await
+        .unwrap();
+    let rmcp::model::ResourceContents::TextResourceContents { text, .. } = &docs.contents[0] else {
+        panic!("docs should be text");
+    };
+    assert!(text.contains("workflow.map(items, fn"), "{text}");
+    assert!(text.contains("expectEqual"), "{text}");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_read_resource_from_script() {
+    let client = setup_client().await;
+
+    let result = call_tool(
+        &client,
+        "execute_tools",
+        serde_json::json!({"code": "({config: resources.read('mock://config'), item: JSON.parse(resources.read('mock://items/7'))})"}),
+    )
+    .await;
+    let json: serde_json::Value = serde_json::from_str(&result).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({"config": "debug = true", "item": {"id": "7"}})
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_prompts_from_script() {
+    let client = setup_client().await;
+
+    let code = r#"
+        var names = prompts.list().map(function(p) { return p.name; });
+        var messages = prompts.get("summarize", {topic: tools.echo({message: "the board"}).echo});
+        ({names: names, messages: messages})
+    "#;
+    let result = call_tool(&client, "execute_tools", serde_json::json!({"code": code})).await;
+    let json: serde_json::Value = serde_json::from_str(&result).unwrap();
+    assert_eq!(json["names"], serde_json::json!(["summarize"]));
+    assert_eq!(
+        json["messages"],
+        serde_json::json!([{
+            "role": "user",
+            "content": {"type": "text", "text": "Summarize the board"},
+        }])
+    );
+}
+
+/// An upstream client that answers sampling requests with the prompt's
+/// length, elicitations asking for an `item`, or asking "Which ...?" of
+/// `ask()`, with "Third Item" (declining others) and roots requests with a
+/// single root.
+#[derive(Default)]
+struct HostClient {
+    /// Elicitations cancelled while waiting for an answer
+    cancelled: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    /// `tools/list_changed` notifications received
+    tools_changed: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    /// Progress notifications received, as JSON
+    progress: std::sync::Arc<std::sync::Mutex<Vec<serde_json::Value>>>,
+    /// Logging notifications received, as JSON
+    logs: std::sync::Arc<std::sync::Mutex<Vec<serde_json::Value>>>,
+}
+
+impl rmcp::ClientHandler for HostClient {
+    async fn create_message(
+        &self,
+        params: rmcp::model::CreateMessageRequestParam,
+        _context: rmcp::service::RequestContext<RoleClient>,
+    ) -> Result<rmcp::model::CreateMessageResult, rmcp::ErrorData> {
+        let prompt = params.messages[0].content.as_text().unwrap().text.clone();
+        Ok(rmcp::model::CreateMessageResult {
+            model: "mock".to_string(),
+            stop_reason: Some(rmcp::model::CreateMessageResult::STOP_REASON_END_TURN.to_string()),
+            message: rmcp::model::SamplingMessage {
+                role: rmcp::model::Role::Assistant,
+                content: rmcp::model::Content::text(format!(
+                    "{} chars, max {}",
+                    prompt.len(),
+                    params.max_tokens
+                )),
+            },
+        })
+    }
+
+    async fn create_elicitation(
+        &self,
+        params: rmcp::model::CreateElicitationRequestParam,
+        context: rmcp::service::RequestContext<RoleClient>,
+    ) -> Result<rmcp::model::CreateElicitationResult, rmcp::ErrorData> {
+        if params.message == "Wait" {
+            context.ct.cancelled().await;
+            self.cancelled
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            return Err(rmcp::ErrorData::internal_error("cancelled", None));
+        }
+        let properties = &params.requested_schema.properties;
+        let content = if properties.contains_key("item") {
+            Some(serde_json::json!({"item": "Third Item"}))
+        } else if properties.contains_key("answer") && params.message.starts_with("Which") {
+            Some(serde_json::json!({"answer": "Third Item"}))
+        } else {
+            None
+        };
+        Ok(match content {
+            Some(content) => rmcp::model::CreateElicitationResult {
+                action: rmcp::model::ElicitationAction::Accept,
+                content: Some(content),
+            },
+            None => rmcp::model::CreateElicitationResult {
+                action: rmcp::model::ElicitationAction::Decline,
+                content: None,
+            },
+        })
+    }
+
+    async fn list_roots(
+        &self,
+        _context: rmcp::service::RequestContext<RoleClient>,
+    ) -> Result<rmcp::model::ListRootsResult, rmcp::ErrorData> {
+        Ok(rmcp::model::ListRootsResult {
+            roots: vec![rmcp::model::Root {
+                uri: "file:///work/board".to_string(),
+                name: Some("board".to_string()),
+            }],
+        })
+    }
+
+    async fn on_tool_list_changed(&self, _context: rmcp::service::NotificationContext<RoleClient>) {
+        self.tools_changed
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    async fn on_progress(
+        &self,
+        params: rmcp::model::ProgressNotificationParam,
+        _context: rmcp::service::NotificationContext<RoleClient>,
+    ) {
+        self.progress
+            .lock()
+            .unwrap()
+            .push(serde_json::to_value(params).unwrap());
+    }
+
+    async fn on_logging_message(
+        &self,
+        params: rmcp::model::LoggingMessageNotificationParam,
+        _context: rmcp::service::NotificationContext<RoleClient>,
+    ) {
+        self.logs
+            .lock()
+            .unwrap()
+            .push(serde_json::to_value(params).unwrap());
+    }
+
+    fn get_info(&self) -> rmcp::model::ClientInfo {
+        rmcp::model::ClientInfo {
+            capabilities: rmcp::model::ClientCapabilities::builder()
+                .enable_elicitation()
+                .enable_roots()
+                .enable_sampling()
+                .build(),
+            ..Default::default()
+        }
+    }
+}
+
+async fn setup_host_client() -> RunningService<RoleClient, HostClient> {
+    setup_host_client_with_args(&[]).await
+}
+
+async fn setup_host_client_with_args(args: &[&str]) -> RunningService<RoleClient, HostClient> {
+    let mut cmd = Command::new(get_codemoder_path());
+    cmd.args(args).arg(get_mock_server_path());
+    let transport = TokioChildProcess::new(cmd).expect("Failed to create transport");
+    HostClient::default()
+        .serve(transport)
+        .await
+        .expect("Failed to connect to codemoder")
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_sample_from_script() {
+    let code = r#"
+        var items = tools.get_items({});
+        sample(items, {maxTokens: 50})
+    "#;
+
+    let client = setup_host_client().await;
+    let result = client
+        .peer()
+        .call_tool(CallToolRequestParam {
+            name: "execute_tools".into(),
+            arguments: Some(
+                serde_json::json!({"code": code})
+                    .as_object()
+                    .unwrap()
+                    .clone(),
+            ),
+        })
+        .await
+        .unwrap();
+    let text = result.content[0].as_text().unwrap().text.clone();
+    assert!(text.ends_with(" chars, max 50\""), "{text}");
+
+    // Clients that don't support sampling get an error
+    let client = setup_client().await;
+    let result = call_tool(&client, "execute_tools", serde_json::json!({"code": code})).await;
+    assert!(
+        result.contains("the client does not support sampling"),
+        "{result}"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_elicit_from_script() {
+    let code = r#"
+        var names = tools.get_items({}).items.map(function(item) { return item.name; });
+        var answer = elicit(
+            {properties: {item: {type: "string", enum: names}}, required: ["item"]},
+            "Which item should be deleted?"
+        );
+        var declined = elicit({properties: {ok: {type: "boolean"}}}, "Go ahead?");
+        ({answer: answer, declined: declined})
+    "#;
+
+    let client = setup_host_client().await;
+    let result = client
+        .peer()
+        .call_tool(CallToolRequestParam {
+            name: "execute_tools".into(),
+            arguments: Some(
+                serde_json::json!({"code": code})
+                    .as_object()
+                    .unwrap()
+                    .clone(),
+            ),
+        })
+        .await
+        .unwrap();
+    let text = result.content[0].as_text().unwrap().text.clone();
+    let json: serde_json::Value = serde_json::from_str(&text).unwrap();
+    assert_eq!(json["answer"]["item"], "Third Item", "{text}");
+    assert_eq!(json["declined"], serde_json::Value::Null);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_ask_from_script() {
+    let code = r#"
+        var candidates = tools.get_items({}).items.map(function(item) { return item.name; });
+        var picked = ask("Which component should be placed?", candidates);
+        var declined = ask("Any notes?");
+        ({picked: picked, declined: declined})
+    "#;
+
+    let client = setup_host_client().await;
+    let result = client
+        .peer()
+        .call_tool(CallToolRequestParam {
+            name: "execute_tools".into(),
+            arguments: serde_json::json!({"code": code}).as_object().cloned(),
+        })
+        .await
+        .unwrap();
+    let text = result.content[0].as_text().unwrap().text.clone();
+    let json: serde_json::Value = serde_json::from_str(&text).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({"picked": "Third Item", "declined": null}),
+        "{text}"
+    );
+}
+
+#[tokio::test]
+async fn test_llm_complete_from_script() {
+    let client = setup_host_client_with_args(&["--llm-helpers"]).await;
+    let execute = |code: &str| {
+        client.peer().call_tool(CallToolRequestParam {
+            name: "execute_tools".into(),
+            arguments: serde_json::json!({"code": code}).as_object().cloned(),
+        })
+    };
+
+    let result = execute("llm.complete('Is this a bug?', {maxTokens: 10})")
+        .await
+        .unwrap();
+    let text = &result.content[0].as_text().unwrap().text;
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(text).unwrap(),
+        "14 chars, max 10"
+    );
+
+    let result = execute("llm.complete('Is this a bug?', {json: true})")
+        .await
+        .unwrap();
+    assert_eq!(result.is_error, Some(true));
+    let text = &result.content[0].as_text().unwrap().text;
+    assert!(
+        text.contains("llm.complete: the completion is not JSON"),
+        "{text}"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_downstream_sampling_is_forwarded() {
+    let code = r#"tools.summarize({text: "twelve chars"})"#;
+    let arguments = serde_json::json!({"code": code}).as_object().cloned();
+
+    let client = setup_host_client().await;
+    let result = client
+        .peer()
+        .call_tool(CallToolRequestParam {
+            name: "execute_tools".into(),
+            arguments: arguments.clone(),
+        })
+        .await
+        .unwrap();
+    let text = &result.content[0].as_text().unwrap().text;
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(text).unwrap(),
+        "12 chars, max 100"
+    );
+
+    // A client without the sampling capability
+    let client = setup_client().await;
+    let result = client
+        .peer()
+        .call_tool(CallToolRequestParam {
+            name: "execute_tools".into(),
+            arguments,
+        })
+        .await
+        .unwrap();
+    assert_eq!(result.is_error, Some(true));
+    let text = &result.content[0].as_text().unwrap().text;
+    assert!(
+        text.contains("the upstream client does not support sampling"),
+        "{text}"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_downstream_elicitation_is_forwarded() {
+    let client = setup_host_client().await;
+    let call = |code: &str| {
+        client.peer().call_tool(CallToolRequestParam {
+            name: "execute_tools".into(),
+            arguments: serde_json::json!({"code": code}).as_object().cloned(),
+        })
+    };
+
+    let result = call(r#"tools.pick_item({message: "Which item?"})"#)
+        .await
+        .unwrap();
+    let text = &result.content[0].as_text().unwrap().text;
+    let json: serde_json::Value = serde_json::from_str(text).unwrap();
+    assert_eq!(json["action"], "accept", "{text}");
+    assert_eq!(json["content"]["item"], "Third Item");
+
+    // The downstream server gives up; the forwarded request is cancelled
+    let result = call(r#"tools.pick_item({message: "Wait", timeout_ms: 200})"#)
+        .await
+        .unwrap();
+    assert_eq!(result.is_error, Some(true));
+    for _ in 0..50 {
+        if client
+            .service()
+            .cancelled
+            .load(std::sync::atomic::Ordering::SeqCst)
+            > 0
+        {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(
+        client
+            .service()
+            .cancelled
+            .load(std::sync::atomic::Ordering::SeqCst),
+        1
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_roots_from_script() {
+    let code = r#"roots().map(function(root) { return root.name + " " + root.uri; })"#;
+    let arguments = serde_json::json!({"code": code}).as_object().cloned();
+
+    let client = setup_host_client().await;
+    let result = client
+        .peer()
+        .call_tool(CallToolRequestParam {
+            name: "execute_tools".into(),
+            arguments: arguments.clone(),
+        })
+        .await
+        .unwrap();
+    let text = result.content[0].as_text().unwrap().text.clone();
+    let json: serde_json::Value = serde_json::from_str(&text).unwrap();
+    assert_eq!(json, serde_json::json!(["board file:///work/board"]));
+
+    // A client without the roots capability
+    let client = setup_client().await;
+    let result = client
+        .peer()
+        .call_tool(CallToolRequestParam {
+            name: "execute_tools".into(),
+            arguments,
+        })
+        .await
+        .unwrap();
+    assert_eq!(result.is_error, Some(true));
+    let text = &result.content[0].as_text().unwrap().text;
+    assert!(
+        text.contains("roots is not available: the client does not support roots"),
+        "{text}"
+    );
+}
+
+#[tokio::test]
+async fn test_stats_tool() {
+    let client = setup_client_with_args(&["--stats-tool"]).await;
+    let call = |name: &'static str, arguments: serde_json::Value| {
+        client.peer().call_tool(CallToolRequestParam {
+            name: name.into(),
+            arguments: arguments.as_object().cloned(),
+        })
+    };
+    let tools = client.peer().list_all_tools().await.unwrap();
+    assert!(tools.iter().any(|tool| tool.name == "codemode_stats"));
+
+    call("echo", serde_json::json!({"message": "direct"}))
+        .await
+        .unwrap();
+    call(
+        "execute_tools",
+        serde_json::json!({"code": "tools.echo({message: 'a'}); tools.echo({message: 'b'})"}),
+    )
+    .await
+    .unwrap();
+    call(
+        "execute_tools",
+        serde_json::json!({"code": "throw new Error('no')"}),
+    )
+    .await
+    .unwrap();
+
+    let result = call("codemode_stats", serde_json::json!({})).await.unwrap();
+    let text = &result.content[0].as_text().unwrap().text;
+    let stats: serde_json::Value = serde_json::from_str(text).unwrap();
+    assert_eq!(stats["tools"]["echo"]["calls"], 3, "{text}");
+    assert_eq!(stats["tools"]["echo"]["errors"], 0, "{text}");
+    assert_eq!(stats["executions"]["count"], 2, "{text}");
+    assert_eq!(stats["executions"]["errors"], 1, "{text}");
+    assert_eq!(stats["dedup"]["scriptCalls"], 2, "{text}");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_failure_notes_in_description() {
+    let client = setup_client_with_args(&["--failure-notes"]).await;
+    let description = |tools: &[rmcp::model::Tool]| {
+        let execute = tools.iter().find(|t| t.name == "execute_tools").unwrap();
+        execute
+            .description
+            .as_deref()
+            .unwrap_or_default()
+            .to_string()
+    };
+    let tools = client.peer().list_all_tools().await.unwrap();
+    assert!(!description(&tools).contains("Note:"));
+
+    let result = client
+        .peer()
+        .call_tool(CallToolRequestParam {
+            name: "execute_tools".into(),
+            arguments: serde_json::json!({"code": "tools.add({a: '1', b: 2})"})
+                .as_object()
+                .cloned(),
+        })
+        .await
+        .unwrap();
+    assert_eq!(result.is_error, Some(true));
+
+    let tools = client.peer().list_all_tools().await.unwrap();
+    let description = description(&tools);
+    assert!(
+        description.contains("Note: `a` is not of type \"integer\" (got a string)"),
+        "{description}"
+    );
+}
+
+#[tokio::test]
+async fn test_paths_outside_roots_are_refused() {
+    let client = setup_host_client_with_args(&["--check-paths"]).await;
+    let execute = |code: &str| {
+        client.peer().call_tool(CallToolRequestParam {
+            name: "execute_tools".into(),
+            arguments: serde_json::json!({"code": code}).as_object().cloned(),
+        })
+    };
+
+    let result = execute("tools.echo({message: 'hi', path: '/work/board/main.kicad_pcb'})")
+        .await
+        .unwrap();
+    assert_eq!(result.is_error, Some(false));
+
+    let result = execute("tools.echo({message: 'hi', path: '/work/board/../../etc/passwd'})")
+        .await
+        .unwrap();
+    assert_eq!(result.is_error, Some(true));
+    let text = &result.content[0].as_text().unwrap().text;
+    assert!(
+        text.contains("echo: `path` path /work/board/../../etc/passwd is outside the directories the client shared"),
+        "{text}"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_export_run_and_replay() {
+    let dir = std::env::temp_dir().join(format!("codemoder-export-{}", std::process::id()));
+    let record_dir = dir.join("runs");
+    let client = setup_client_with_args(&["--record-dir", record_dir.to_str().unwrap()]).await;
+
+    let result = client
+        .peer()
+        .call_tool(CallToolRequestParam {
+            name: "execute_tools".into(),
+            arguments: Some(
+                serde_json::json!({"code": "var s = tools.add({a: 2, b: 3}); tools.echo({message: 'sum ' + s.result})"})
+                    .as_object()
+                    .unwrap()
+                    .clone(),
+            ),
+        })
+        .await
+        .unwrap();
+    let execution_id = result.meta.unwrap()["executionId"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let recorded = result.content[0].as_text().unwrap().text.clone();
+
+    let bundle = dir.join("bundle.json");
+    let output = std::process::Command::new(get_codemoder_path())
+        .args(["export-run", &execution_id, "--record-dir"])
+        .arg(&record_dir)
+        .arg("--output")
+        .arg(&bundle)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+
+    // No downstream server: the calls are answered from the bundle
+    let output = std::process::Command::new(get_codemoder_path())
+        .arg("exec")
+        .arg("--replay")
+        .arg(&bundle)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    let replayed: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let recorded: serde_json::Value = serde_json::from_str(&recorded).unwrap();
+    assert_eq!(replayed, recorded);
+    assert_eq!(replayed["echo"], "sum 5");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_idempotent_scripts_replay_live() {
+    let dir = std::env::temp_dir().join(format!("codemoder-idempotent-{}", std::process::id()));
+    let client = setup_client_with_args(&["--record-dir", dir.to_str().unwrap()]).await;
+
+    let mut bundles = Vec::new();
+    for code in [
+        "tools.add({a: 2, b: 3})",
+        "'use idempotent';\ntools.add({a: 2, b: 3})",
+    ] {
+        let result = client
+            .peer()
+            .call_tool(CallToolRequestParam {
+                name: "execute_tools".into(),
+                arguments: serde_json::json!({"code": code}).as_object().cloned(),
+            })
+            .await
+            .unwrap();
+        let meta = result.meta.unwrap();
+        bundles.push((
+            dir.join("runs")
+                .join(format!("{}.json", meta["executionId"].as_str().unwrap())),
+            meta["retrySafe"].as_bool().unwrap(),
+        ));
+    }
+    // The mock server's tools are unannotated, so destructive
+    assert!(!bundles[0].1);
+    assert!(bundles[1].1);
+
+    let replay_live = |bundle: &std::path::Path, force: bool| {
+        let mut cmd = std::process::Command::new(get_codemoder_path());
+        cmd.arg("exec").arg("--replay").arg(bundle);
+        if force {
+            cmd.arg("--force");
+        }
+        cmd.arg("--").arg(get_mock_server_path()).output().unwrap()
+    };
+    let output = replay_live(&bundles[0].0, false);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("called destructive tools (add) and is not marked idempotent"),
+        "{stderr}"
+    );
+    assert!(replay_live(&bundles[0].0, true).status.success());
+    let output = replay_live(&bundles[1].0, false);
+    assert!(output.status.success(), "{output:?}");
+    let replayed: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(replayed["result"].as_f64(), Some(5.0));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_tool_list_changes_are_picked_up() {
+    let client = setup_host_client().await;
+    assert_eq!(
+        client
+            .peer_info()
+            .unwrap()
+            .capabilities
+            .tools
+            .as_ref()
+            .unwrap()
+            .list_changed,
+        Some(true)
+    );
+    let run = |code: &str| {
+        client.peer().call_tool(CallToolRequestParam {
+            name: "execute_tools".into(),
+            arguments: serde_json::json!({"code": code}).as_object().cloned(),
+        })
+    };
+    let text =
+        |result: rmcp::model::CallToolResult| result.content[0].as_text().unwrap().text.clone();
+
+    let result = run("typeof tools.divide").await.unwrap();
+    assert_eq!(text(result), r#""undefined""#);
+    run("tools.enable_divide({})").await.unwrap();
+
+    // The downstream notification is passed on
+    let changed = client.service().tools_changed.clone();
+    for _ in 0..100 {
+        if changed.load(std::sync::atomic::Ordering::SeqCst) > 0 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(changed.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+    // Scripts see the new tool without the client listing tools again
+    let result = run("tools.divide({a: 7, b: 2}).result").await.unwrap();
+    assert_eq!(result.is_error, Some(false), "{:?}", result.content);
+    assert_eq!(text(result), "3.0");
+
+    let tools = client.peer().list_all_tools().await.unwrap();
+    assert!(tools.iter().any(|t| t.name == "divide"));
+    let execute = tools.iter().find(|t| t.name == "execute_tools").unwrap();
+    assert!(execute.description.as_ref().unwrap().contains("divide("));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_downstream_progress_is_forwarded() {
+    let client = setup_host_client().await;
+    let mut meta = rmcp::model::Meta::new();
+    meta.set_progress_token(rmcp::model::ProgressToken(
+        rmcp::model::NumberOrString::String("client-token".into()),
+    ));
+    let request = rmcp::model::ClientRequest::CallToolRequest(rmcp::model::CallToolRequest {
+        method: Default::default(),
+        params: CallToolRequestParam {
+            name: "count_to".into(),
+            arguments: serde_json::json!({"n": 3}).as_object().cloned(),
+        },
+        extensions: Default::default(),
+    });
+    let options = rmcp::service::PeerRequestOptions {
+        timeout: None,
+        meta: Some(meta),
+    };
+    let response = client
+        .peer()
+        .send_request_with_option(request, options)
+        .await
+        .unwrap()
+        .await_response()
+        .await
+        .unwrap();
+    let rmcp::model::ServerResult::CallToolResult(result) = response else {
+        panic!("unexpected response {response:?}");
+    };
+    assert_eq!(
+        result.content[0].as_text().unwrap().text,
+        r#"{"counted":3}"#
+    );
+
+    // Notifications are handled concurrently, so may arrive out of order
+    let mut progress = client.service().progress.lock().unwrap().clone();
+    progress.sort_by_key(|p| p["progress"].as_f64().map(|p| p as u32));
+    let step = |n: u32| {
+        serde_json::json!({
+            "progressToken": "client-token",
+            "progress": n as f64,
+            "total": 3.0,
+            "message": format!("step {n}")
+        })
+    };
+    assert_eq!(progress, vec![step(1), step(2), step(3)]);
+}
+
+#[tokio::test]
+async fn test_script_progress_is_reported() {
+    let client = setup_host_client().await;
+    let mut meta = rmcp::model::Meta::new();
+    meta.set_progress_token(rmcp::model::ProgressToken(
+        rmcp::model::NumberOrString::String("script-token".into()),
+    ));
+    let code = "progress(1, 2, 'half'); progress(1, 2, 'stalled'); progress(2, 2); 'done'";
+    let request = rmcp::model::ClientRequest::CallToolRequest(rmcp::model::CallToolRequest {
+        method: Default::default(),
+        params: CallToolRequestParam {
+            name: "execute_tools".into(),
+            arguments: serde_json::json!({"code": code}).as_object().cloned(),
+        },
+        extensions: Default::default(),
+    });
+    let options = rmcp::service::PeerRequestOptions {
+        timeout: None,
+        meta: Some(meta),
+    };
+    let response = client
+        .peer()
+        .send_request_with_option(request, options)
+        .await
+        .unwrap()
+        .await_response()
+        .await
+        .unwrap();
+    let rmcp::model::ServerResult::CallToolResult(result) = response else {
+        panic!("unexpected response {response:?}");
+    };
+    assert_eq!(result.is_error, Some(false));
+
+    // Notifications are handled concurrently, so may trail the response
+    let mut progress = Vec::new();
+    for _ in 0..50 {
+        progress = client.service().progress.lock().unwrap().clone();
+        if progress.len() >= 2 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    progress.sort_by_key(|p| p["progress"].as_f64().map(|p| p as u32));
+    // The report that didn't move forward is dropped
+    assert_eq!(
+        progress,
+        vec![
+            serde_json::json!({
+                "progressToken": "script-token",
+                "progress": 1.0,
+                "total": 2.0,
+                "message": "half"
+            }),
+            serde_json::json!({"progressToken": "script-token", "progress": 2.0, "total": 2.0}),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_console_log_is_streamed() {
+    let client = setup_host_client_with_args(&["--stream-logs"]).await;
+    let capabilities = &client.peer_info().unwrap().capabilities;
+    assert!(capabilities.logging.is_some());
+
+    let execute = |code: &str| {
+        client.peer().call_tool(CallToolRequestParam {
+            name: "execute_tools".into(),
+            arguments: serde_json::json!({"code": code}).as_object().cloned(),
+        })
+    };
+    let result = execute("console.log('fetched', {n: 2}); console.log('done'); 1")
+        .await
+        .unwrap();
+    assert_eq!(result.is_error, Some(false));
+
+    // Notifications are handled concurrently, so may trail the response
+    let mut logs = Vec::new();
+    for _ in 0..50 {
+        logs = client.service().logs.lock().unwrap().clone();
+        if logs.len() >= 2 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    let lines: Vec<&str> = logs.iter().map(|l| l["data"].as_str().unwrap()).collect();
+    assert!(lines.contains(&r#"fetched {"n":2}"#), "{lines:?}");
+    assert!(lines.contains(&"done"), "{lines:?}");
+    assert!(
+        logs.iter()
+            .all(|l| l["level"] == "info" && l["logger"] == "console")
+    );
+
+    // Lines below the client's level are not sent
+    client
+        .peer()
+        .set_level(rmcp::model::SetLevelRequestParam {
+            level: rmcp::model::LoggingLevel::Warning,
+        })
+        .await
+        .unwrap();
+    execute("console.log('quiet')").await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    assert_eq!(client.service().logs.lock().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn test_verbose_execution_returns_trace() {
+    let client = setup_client().await;
+    let code = r#"
+        var sum = tools.add({a: 2, b: 3}).result;
+        try { tools.multiply({a: 'x', b: 2}); } catch (e) {}
+        sum
+    "#;
+    let text = call_tool(
+        &client,
+        "execute_tools",
+        serde_json::json!({"code": code, "verbose": true}),
+    )
+    .await;
+    let response: serde_json::Value = serde_json::from_str(&text).unwrap();
+    assert_eq!(response["result"].as_f64(), Some(5.0));
+    let trace = response["trace"].as_array().unwrap();
+    assert_eq!(trace.len(), 2);
+    assert_eq!(trace[0]["tool"], "add");
+    assert_eq!(trace[0]["args"], serde_json::json!({"a": 2, "b": 3}));
+    assert_eq!(trace[0]["ok"], true);
+    assert_eq!(trace[0]["result"], r#"{"result":5}"#);
+    assert!(trace[0]["durationMs"].is_u64());
+    assert_eq!(trace[1]["tool"], "multiply");
+    assert_eq!(trace[1]["ok"], false);
+    assert!(trace[1]["error"].is_string());
+
+    // Not asked for, no trace
+    let text = call_tool(&client, "execute_tools", serde_json::json!({"code": code})).await;
+    assert_eq!(text.parse::<f64>().ok(), Some(5.0));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_downstream_calls_overlap() {
+    let client = setup_client().await;
+
+    // Four 300ms calls at once take about as long as one
+    let code = "workflow.map([1, 2, 3, 4], function () { return tools.sleep({ms: 300}).slept; }, {concurrency: 4})";
+    let started = std::time::Instant::now();
+    let text = call_tool(&client, "execute_tools", serde_json::json!({"code": code})).await;
+    let results: serde_json::Value = serde_json::from_str(&text).unwrap();
+    assert_eq!(results, serde_json::json!([300.0, 300.0, 300.0, 300.0]));
+    assert!(started.elapsed() < std::time::Duration::from_millis(900));
+
+    // And so do passthrough calls
+    let started = std::time::Instant::now();
+    let calls = (0..4).map(|_| call_tool(&client, "sleep", serde_json::json!({"ms": 300})));
+    for text in futures::future::join_all(calls).await {
+        assert_eq!(text, r#"{"slept":300}"#);
+    }
+    assert!(started.elapsed() < std::time::Duration::from_millis(900));
+}
+
+#[tokio::test]
+async fn test_background_executions() {
+    let client = setup_client_with_args(&["--background-executions"]).await;
+    let get = |id: String| {
+        let client = &client;
+        async move {
+            let text = call_tool(client, "get_execution", serde_json::json!({"id": id})).await;
+            serde_json::from_str::<serde_json::Value>(&text).unwrap()
+        }
+    };
+    let wait = |id: String| {
+        let get = &get;
+        async move {
+            for _ in 0..100 {
+                let report = get(id.clone()).await;
+                if report["status"] != "running" {
+                    return report;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            }
+            panic!("execution {id} did not finish");
+        }
+    };
+
+    let code = "console.log('counting'); tools.count_to({n: 3}).counted";
+    let started = call_tool(
+        &client,
+        "start_execution",
+        serde_json::json!({"code": code}),
+    )
+    .await;
+    let started: serde_json::Value = serde_json::from_str(&started).unwrap();
+    assert_eq!(started["status"], "running");
+    let id = started["id"].as_str().unwrap().to_string();
+    let report = wait(id.clone()).await;
+    assert_eq!(report["status"], "completed");
+    assert_eq!(report["result"], 3.0);
+    assert_eq!(report["logs"], serde_json::json!(["counting"]));
+
+    let code = "while (true) {}";
+    let started = call_tool(
+        &client,
+        "start_execution",
+        serde_json::json!({"code": code}),
+    )
+    .await;
+    let started: serde_json::Value = serde_json::from_str(&started).unwrap();
+    let id = started["id"].as_str().unwrap().to_string();
+    assert_eq!(get(id.clone()).await["status"], "running");
+    let cancelled = call_tool(&client, "cancel_execution", serde_json::json!({"id": id})).await;
+    assert_eq!(cancelled, format!("Cancelling execution `{id}`"));
+    let report = wait(id).await;
+    assert_eq!(report["status"], "cancelled");
+    assert_eq!(report["error"], "Execution cancelled");
+
+    let unknown = call_tool(&client, "get_execution", serde_json::json!({"id": "bg-x"})).await;
+    assert_eq!(unknown, "Unknown or expired execution `bg-x`");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_background_execution_does_not_block_foreground() {
+    let client = setup_client_with_args(&["--background-executions"]).await;
+    let code = "var t = Date.now(); while (Date.now() - t < 3000) {} 'slow'";
+    let started = call_tool(
+        &client,
+        "start_execution",
+        serde_json::json!({"code": code}),
+    )
+    .await;
+    let started: serde_json::Value = serde_json::from_str(&started).unwrap();
+    let id = started["id"].as_str().unwrap().to_string();
+
+    let text = call_tool(
+        &client,
+        "execute_tools",
+        serde_json::json!({"code": "1 + 1"}),
+    )
+    .await;
+    assert_eq!(text.parse::<f64>().ok(), Some(2.0));
+    let report = call_tool(&client, "get_execution", serde_json::json!({"id": id})).await;
+    let report: serde_json::Value = serde_json::from_str(&report).unwrap();
+    assert_eq!(report["status"], "running");
+}